@@ -1,11 +1,18 @@
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the commit the agent is built from, reported by iwm_agent_build_info and /api/v1/status
+    if let Ok(out) = std::process::Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output() {
+        if out.status.success() {
+            println!("cargo:rustc-env=GIT_SHA={}", String::from_utf8_lossy(&out.stdout).trim());
+        }
+    }
 
-    ["push"]
+    ["push", "health"]
         .iter()
         .for_each(|name| {
             tonic_build::configure()
                 .build_server(false)
+                .bytes(&[".push.v1.RawSample.raw_profile", ".push.v1.PushChunk.data"])
                 .out_dir(format!("src/gen/{}", name))
                 .compile(
                     &[format!("proto/{}/v1/{}.proto", name, name)],
@@ -11,6 +11,8 @@ use crate::ebpf::ebpf_linux::push_api::RawSample;
 use crate::write::write::FanOutClient;
 
 pub trait Appender {
+    // append hands the profiles over for pushing without waiting for the endpoints. It fails when they
+    // can't be queued, rejections by the endpoints are logged and counted by the write metrics.
     fn append(&self, labels: Labels, samples: Vec<push_api::RawSample>) -> Result<()>;
 }
 
@@ -61,11 +63,16 @@ pub struct AppenderImpl {
 impl Appender for AppenderImpl {
     fn append(&self, labels: Labels, samples: Vec<RawSample>) -> Result<()> {
         let start_time = Instant::now();
+        // profile bytes are reference counted, cloning samples per child doesn't copy them.
+        // A failing child doesn't keep the others from getting the samples, its error is returned after.
+        let mut result = Ok(());
         for child in self.children.iter() {
-            child.append(labels.clone(), samples.clone()).unwrap();
+            if let Err(err) = child.append(labels.clone(), samples.clone()) {
+                result = result.and(Err(err));
+            }
         }
         let duration = start_time.elapsed();
         self.write_latency.observe(duration.as_secs_f64());
-        Ok(())
+        result
     }
 }
@@ -0,0 +1,30 @@
+use uuid::Uuid;
+
+// DEFAULT_USER_AGENT identifies the requests of the agent in the access logs of backends and kubelets
+pub const DEFAULT_USER_AGENT: &str = concat!("iwm-agent/", env!("CARGO_PKG_VERSION"));
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+// TraceContext is a W3C trace context, the requests of an operation share the trace id and every
+// attempt gets its own span id, so retries can be told apart in the backend
+#[derive(Debug, Clone, Copy)]
+pub struct TraceContext {
+    trace_id: u128,
+}
+
+impl TraceContext {
+    pub fn new() -> Self {
+        Self { trace_id: Uuid::new_v4().as_u128() }
+    }
+
+    // traceparent returns the traceparent header value of a new sampled span of the trace
+    pub fn traceparent(&self) -> String {
+        let span_id = Uuid::new_v4().as_u64_pair().0;
+        format!("00-{:032x}-{:016x}-01", self.trace_id, span_id)
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,7 +1,7 @@
-
-
+use tokio_util::sync::CancellationToken;
 
 #[allow(async_fn_in_trait)]
 pub trait Component {
-    async fn run(&mut self);
-}
\ No newline at end of file
+    // run runs the component until cancel is cancelled, it returns once the tasks it started finished
+    async fn run(&mut self, cancel: CancellationToken);
+}
@@ -0,0 +1,122 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use iwm::error::Error;
+use iwm::error::Result;
+
+// LAYOUT_VERSION is the version of the state layout this agent reads and writes. Bump it with a
+// migration in MIGRATIONS whenever the on-disk format of any state changes.
+pub const LAYOUT_VERSION: u32 = 1;
+
+// LAYOUT_FILE holds the version of the layout in the data dir, written once its migration finished
+const LAYOUT_FILE: &str = "LAYOUT";
+// LOCK_FILE serializes the migrations of agents sharing the data dir, e.g. during a rolling update
+const LOCK_FILE: &str = "LOCK";
+
+const JOURNAL_DIR: &str = "journal";
+
+// Migration upgrades the layout of the data dir from version - 1 to version
+struct Migration {
+    version: u32,
+    migrate: fn(data_path: &Path) -> Result<()>,
+}
+
+// MIGRATIONS in version order, each one runs at most once per data dir
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, migrate: migrate_v1 },
+];
+
+// DataDir is the versioned state layout under Options.data_path:
+//
+//   LAYOUT         version of the layout, missing before the first migration
+//   v<N>/journal   rotated journal of the pid to target associations
+//
+// State added later gets its directory under v<N> next to the journal. Every layout version has its
+// own directory, so a downgraded agent keeps to the state of its version instead of misreading the
+// state of a newer one.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    // open creates the layout under data_path, migrating the state of older agents first
+    pub fn open(data_path: impl AsRef<Path>) -> Result<Self> {
+        let data_path = data_path.as_ref();
+        fs::create_dir_all(data_path)
+            .map_err(|e| Error::from_io(format!("creating data dir {}", data_path.display()), &e))?;
+        let _lock = lock(&data_path.join(LOCK_FILE))?;
+
+        let current = read_layout_version(data_path)?;
+        if current > LAYOUT_VERSION {
+            warn!("data dir {} has layout v{} of a newer agent, using the v{} state",
+                data_path.display(), current, LAYOUT_VERSION);
+        }
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current && m.version <= LAYOUT_VERSION) {
+            info!("migrating data dir {} to layout v{}", data_path.display(), migration.version);
+            (migration.migrate)(data_path)?;
+            // the version is only bumped once the migration is complete, an interrupted one runs again
+            write_layout_version(data_path, migration.version)?;
+        }
+
+        let dir = Self { root: version_dir(data_path, LAYOUT_VERSION) };
+        let journal = dir.journal();
+        fs::create_dir_all(&journal).map_err(|e| Error::from_io(format!("creating {}", journal.display()), &e))?;
+        Ok(dir)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn journal(&self) -> PathBuf {
+        self.root.join(JOURNAL_DIR)
+    }
+}
+
+fn version_dir(data_path: &Path, version: u32) -> PathBuf {
+    data_path.join(format!("v{}", version))
+}
+
+// migrate_v1 creates the first layout, there was no state on disk before it
+fn migrate_v1(data_path: &Path) -> Result<()> {
+    let dir = version_dir(data_path, 1);
+    fs::create_dir_all(&dir).map_err(|e| Error::from_io(format!("creating {}", dir.display()), &e))
+}
+
+fn read_layout_version(data_path: &Path) -> Result<u32> {
+    let path = data_path.join(LAYOUT_FILE);
+    match fs::read_to_string(&path) {
+        Ok(s) => s.trim().parse().map_err(|_| Error::InvalidData {
+            path: Some(path.display().to_string()),
+            reason: format!("invalid layout version {:?}", s.trim()),
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(Error::from_io(format!("reading {}", path.display()), &e)),
+    }
+}
+
+// write_layout_version replaces the layout file atomically, a crash leaves the old or the new version
+fn write_layout_version(data_path: &Path, version: u32) -> Result<()> {
+    let path = data_path.join(LAYOUT_FILE);
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp).map_err(|e| Error::from_io(format!("creating {}", tmp.display()), &e))?;
+    writeln!(f, "{}", version)
+        .and_then(|_| f.sync_all())
+        .map_err(|e| Error::from_io(format!("writing {}", tmp.display()), &e))?;
+    fs::rename(&tmp, &path).map_err(|e| Error::from_io(format!("renaming {}", tmp.display()), &e))
+}
+
+// lock takes an exclusive flock on the file, released when the returned file is dropped
+fn lock(path: &Path) -> Result<File> {
+    let f = OpenOptions::new().create(true).truncate(false).write(true).open(path)
+        .map_err(|e| Error::from_io(format!("opening {}", path.display()), &e))?;
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(Error::last_os_error(format!("flock {}", path.display())));
+    }
+    Ok(f)
+}
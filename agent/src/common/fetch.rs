@@ -0,0 +1,71 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+
+use iwm::error::Error;
+use iwm::error::Error::NotFound;
+use iwm::error::Result;
+
+// DEFAULT_MAX_PROFILE_BYTES bounds a fetched profile, compressed and decompressed. A cpu profile of
+// a busy process is a few MB decompressed, compressed responses inflating past this are rejected.
+pub const DEFAULT_MAX_PROFILE_BYTES: usize = 64 << 20;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// fetch_profile fetches a pprof profile and returns it decoded. The body is read as it streams in,
+// chunked or over http/2, and decoded from its gzip or zstd content encoding. pprof files are
+// gzipped themselves, e.g. by go's net/http/pprof, a gzipped payload is decoded as well.
+pub async fn fetch_profile(client: &reqwest::Client, url: &str, max_bytes: usize) -> Result<Vec<u8>> {
+    let mut res = client.get(url)
+        .header(ACCEPT_ENCODING, "gzip, zstd")
+        .send().await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| NotFound(format!("fetching {}: {}", url, e)))?;
+    if res.content_length().is_some_and(|len| len > max_bytes as u64) {
+        return Err(too_large(url, max_bytes));
+    }
+    let encoding = res.headers().get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await.map_err(|e| NotFound(format!("reading {}: {}", url, e)))? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large(url, max_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let body = match encoding.as_str() {
+        "" | "identity" => body,
+        "gzip" | "x-gzip" => read_limited(GzDecoder::new(body.as_slice()), url, max_bytes)?,
+        "zstd" => {
+            let decoder = ruzstd::StreamingDecoder::new(body.as_slice())
+                .map_err(|e| Error::invalid_data(format!("decoding zstd from {}: {}", url, e)))?;
+            read_limited(decoder, url, max_bytes)?
+        }
+        _ => return Err(Error::invalid_data(format!("unsupported content encoding {:?} from {}", encoding, url))),
+    };
+    if body.starts_with(&GZIP_MAGIC) {
+        return read_limited(GzDecoder::new(body.as_slice()), url, max_bytes);
+    }
+    Ok(body)
+}
+
+// read_limited decompresses up to max_bytes, one byte more tells the payload is larger
+fn read_limited(reader: impl Read, url: &str, max_bytes: usize) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+    reader.take(max_bytes as u64 + 1).read_to_end(&mut res)
+        .map_err(|e| Error::invalid_data(format!("decompressing {}: {}", url, e)))?;
+    if res.len() > max_bytes {
+        return Err(too_large(url, max_bytes));
+    }
+    Ok(res)
+}
+
+fn too_large(url: &str, max_bytes: usize) -> Error {
+    Error::invalid_data(format!("profile from {} is larger than {} bytes", url, max_bytes))
+}
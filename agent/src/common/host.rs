@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::Path;
+
+use crate::metrics::build_info::kernel_release;
+
+// CGROUP2_CONTROLLERS only exists at the root of the unified cgroup v2 hierarchy
+const CGROUP2_CONTROLLERS: &str = "/sys/fs/cgroup/cgroup.controllers";
+
+// HostInfo is the node context of the profiles, read once at startup. Backends show the profile
+// comments next to the flamegraph, which helps telling hardware or kernel specific regressions apart.
+#[derive(Debug, Clone)]
+pub struct HostInfo {
+    pub kernel_release: String,
+    pub cpu_model: String,
+    pub cpu_cores: usize,
+    // cgroup_driver is systemd or cgroupfs, as the kubelet and container runtime name it
+    pub cgroup_driver: String,
+    pub cgroup_version: String,
+}
+
+impl HostInfo {
+    pub fn detect() -> Self {
+        let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+        let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+        Self {
+            kernel_release: kernel_release(),
+            cpu_model: cpu_model(&cpuinfo),
+            cpu_cores: cpu_cores(&cpuinfo),
+            cgroup_driver: cgroup_driver(&cgroups).to_string(),
+            cgroup_version: if Path::new(CGROUP2_CONTROLLERS).exists() { "v2" } else { "v1" }.to_string(),
+        }
+    }
+
+    // comments are the host info as key=value pprof comments
+    pub fn comments(&self) -> Vec<String> {
+        self.pairs().into_iter().map(|(k, v)| format!("{}={}", k, v)).collect()
+    }
+
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("kernel_release", self.kernel_release.clone()),
+            ("cpu_model", self.cpu_model.clone()),
+            ("cpu_cores", self.cpu_cores.to_string()),
+            ("cgroup_driver", self.cgroup_driver.clone()),
+            ("cgroup_version", self.cgroup_version.clone()),
+        ]
+    }
+}
+
+// cpu_model returns the model name of the first cpu. arm64 kernels report no model name,
+// the implementer and part ids are the closest to it.
+fn cpu_model(cpuinfo: &str) -> String {
+    let field = |name: &str| cpuinfo.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim() == name)
+        .map(|(_, v)| v.trim().to_string());
+    if let Some(model) = field("model name") {
+        return model;
+    }
+    match (field("CPU implementer"), field("CPU part")) {
+        (Some(implementer), Some(part)) => format!("implementer {} part {}", implementer, part),
+        _ => "unknown".to_string(),
+    }
+}
+
+// cpu_cores counts the online cpus of the node, not those the agent's cgroup may use
+fn cpu_cores(cpuinfo: &str) -> usize {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n > 0 {
+        return n as usize;
+    }
+    cpuinfo.lines().filter(|l| l.starts_with("processor")).count()
+}
+
+// cgroup_driver tells the drivers apart by the agent's own cgroup path, the systemd driver
+// puts pods and services into .slice units
+fn cgroup_driver(cgroups: &str) -> &'static str {
+    if cgroups.lines().any(|l| l.contains(".slice")) {
+        "systemd"
+    } else {
+        "cgroupfs"
+    }
+}
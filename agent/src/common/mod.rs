@@ -1,2 +1,6 @@
+pub mod client;
 pub mod component;
+pub mod data_dir;
+pub mod fetch;
+pub mod host;
 pub mod registry;
\ No newline at end of file
@@ -5,7 +5,7 @@ use std::sync::Arc;
 use iwm::error::Result;
 use regex::Regex;
 use iwm::ebpf::metrics::registry::Registerer;
-use iwm::error::Error::OSError;
+use iwm::error::Error;
 
 type ParsedName = Vec<String>;
 
@@ -16,7 +16,6 @@ pub struct Options {
     pub id: String,
     pub data_path: String, // A path to a directory with this component may use for storage.
     pub registerer: Arc<dyn Registerer>,
-    pub get_service_data: fn(name: &str) -> Result<Box<dyn Any>, String>
 }
 
 type Arguments = Box<dyn Any>;
@@ -48,16 +47,16 @@ fn register(r: Registration, registered: &mut HashMap<String, Registration>, par
 fn parse_component_name(name: &str) -> Result<ParsedName> {
     let parts: Vec<&str> = name.split('.').collect();
     if parts.is_empty() {
-        return Err(OSError("missing name".to_string()));
+        return Err(Error::invalid_data("missing name"));
     }
 
     let identifier_regex = Regex::new(r"^[A-Za-z][0-9A-Za-z_]*$").unwrap();
     for part in &parts {
         if part.is_empty() {
-            return Err(OSError("found empty identifier".to_string()));
+            return Err(Error::invalid_data(format!("found empty identifier in {}", name)));
         }
         if !identifier_regex.is_match(part) {
-            return Err(OSError(format!("identifier {} is not valid", part.to_string())));
+            return Err(Error::invalid_data(format!("identifier {} is not valid", part)));
         }
     }
     Ok(parts.iter().map(|s| s.to_string()).collect())
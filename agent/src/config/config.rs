@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::LevelFilter;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+
+use iwm::ebpf::pprof::rewrite::{FrameRule, StackRewrite};
+use iwm::ebpf::probe::HookAttach;
+use iwm::error::Error;
+use iwm::error::Result;
+
+use crate::discover::discover;
+use crate::discover::discover::Target;
+use crate::ebpf::ebpf_linux;
+use crate::ebpf::schedule::CollectSchedule;
+use crate::http::http;
+use crate::write::inspect::DryRun;
+use crate::write::metadata::MetadataOptions;
+use crate::write::scrub::{LabelScrubbing, RedactRule};
+use crate::write::write;
+
+// Config is the agent configuration file, yaml passed with --config. Every field is optional, what
+// is left out keeps the default of the agent. Durations are written like 15s, 500ms or 5m. The agent
+// reads the file again on SIGHUP, the few arguments the session is set up with wait for a restart.
+//
+//   data_path: /var/lib/iwm-agent
+//   log_level: info
+//   http:
+//     listen_address: 0.0.0.0:12345
+//     admin_token: s3cr3t
+//   discovery:
+//     host: unix:///var/run/docker.sock
+//   targets:
+//     - service_name: batch
+//       __container_id__: 3f2a..
+//   write:
+//     external_labels: {cluster: prod}
+//     endpoints:
+//       - url: https://profiles.example.com
+//         tenant_id: team-a
+//     name_convention: template
+//     name_template: "{service}.cpu"
+//     scrubbing:
+//       drop: [pod_uid]
+//       redact:
+//         - {label: customer, pattern: "customer-(\\d+)", replacement: customer-xxx}
+//       hash: [pod]
+//       hash_key: s3cr3t
+//   ebpf:
+//     collect_interval: 15s
+//     collect_schedule: absolute
+//     sample_rate: 97
+//     hook_attach: tp_btf
+//     frame_rules:
+//       - {action: collapse, pattern: "^(std::\\w+)<.*>::", replacement: "$1<>::"}
+//       - {action: drop, pattern: "^(__libc_start_main|_start)$"}
+//       - {action: group, pattern: "^ThreadPoolExecutor::worker$", replacement: "[thread pool]"}
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_path: Option<String>,
+    pub log_level: Option<String>,
+    pub http: HttpConfig,
+    pub discovery: DiscoveryConfig,
+    pub kubelet: KubeletConfig,
+    // targets are profiled in addition to the discovered containers, as label sets
+    pub targets: Vec<Target>,
+    pub write: WriteConfig,
+    pub ebpf: EbpfConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub listen_address: Option<SocketAddr>,
+    // admin_token lets clients other than loopback ones pause and resume the ingestion
+    pub admin_token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub host_networking_host: Option<String>,
+    #[serde(deserialize_with = "de_duration")]
+    pub refresh_interval: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubeletConfig {
+    pub url: Option<String>,
+    pub token_path: Option<String>,
+    pub ca_path: Option<String>,
+    pub insecure_skip_verify: Option<bool>,
+    #[serde(deserialize_with = "de_duration")]
+    pub timeout: Option<Duration>,
+    pub allowed_labels: Option<Vec<String>>,
+    pub allowed_annotations: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteConfig {
+    pub external_labels: Option<HashMap<String, String>>,
+    // endpoints replace the default endpoint
+    pub endpoints: Option<Vec<EndpointConfig>>,
+    // name_convention is labels, template or pyroscope, the latter two render name_template, see
+    // write::NameConvention
+    pub name_convention: Option<String>,
+    pub name_template: Option<String>,
+    // metadata_path is the file of organizational labels joined onto the series, see MetadataOptions
+    pub metadata_path: Option<PathBuf>,
+    pub trace_context: Option<bool>,
+    pub queue_capacity: Option<usize>,
+    #[serde(deserialize_with = "de_duration")]
+    pub health_check_interval: Option<Duration>,
+    // dry_run is log to log the requests instead of pushing them, or a directory to write them to,
+    // see DryRun
+    pub dry_run: Option<String>,
+    pub user_agent: Option<String>,
+    pub scrubbing: Option<ScrubbingConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrubbingConfig {
+    pub drop: Vec<String>,
+    pub redact: Vec<RedactConfig>,
+    pub hash: Vec<String>,
+    pub hash_key: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactConfig {
+    pub label: String,
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointConfig {
+    pub name: Option<String>,
+    pub url: String,
+    #[serde(deserialize_with = "de_duration")]
+    pub remote_timeout: Option<Duration>,
+    pub headers: Option<HashMap<String, String>>,
+    pub tenant_id: Option<String>,
+    pub bearer_token: Option<String>,
+    pub labels: Option<HashMap<String, String>>,
+    #[serde(deserialize_with = "de_duration")]
+    pub min_backoff: Option<Duration>,
+    #[serde(deserialize_with = "de_duration")]
+    pub max_backoff: Option<Duration>,
+    pub max_backoff_retries: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub chunk_size: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EbpfConfig {
+    #[serde(deserialize_with = "de_duration")]
+    pub collect_interval: Option<Duration>,
+    // collect_schedule is absolute or relative, see CollectSchedule
+    pub collect_schedule: Option<String>,
+    pub sample_rate: Option<i32>,
+    pub sample_period: Option<u64>,
+    pub precise_ip: Option<u8>,
+    pub pid_cache_size: Option<i32>,
+    pub build_id_cache_size: Option<i32>,
+    pub same_file_cache_size: Option<i32>,
+    pub container_id_cache_size: Option<i32>,
+    pub cache_rounds: Option<i32>,
+    pub collect_user_profile: Option<bool>,
+    pub collect_kernel_profile: Option<bool>,
+    pub python_enabled: Option<bool>,
+    pub java_enabled: Option<bool>,
+    pub python_full_file_path: Option<bool>,
+    pub unknown_symbol_module_offset: Option<bool>,
+    pub unknown_symbol_address: Option<bool>,
+    pub unknown_symbol_symbolizable: Option<bool>,
+    pub per_pid_profile: Option<bool>,
+    pub max_pids_per_service: Option<usize>,
+    pub targets_only: Option<bool>,
+    pub process_metrics: Option<bool>,
+    pub hook_attach: Option<String>,
+    pub retention_rounds: Option<usize>,
+    pub retention_bytes: Option<usize>,
+    pub symbolization_threads: Option<usize>,
+    #[serde(deserialize_with = "de_duration")]
+    pub target_time_slice: Option<Duration>,
+    pub early_round_fill_ratio: Option<f64>,
+    // pid_journal turns the pid journal in the data dir on or off
+    pub pid_journal: Option<bool>,
+    // frame_rules rewrite the stacks of the pushed profiles in order, see FrameRule
+    pub frame_rules: Option<Vec<FrameRuleConfig>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrameRuleConfig {
+    // action is collapse, drop or group
+    pub action: String,
+    pub pattern: String,
+    pub replacement: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| Error::from_io(format!("reading config {}", path), &e))?;
+        let config: Config = serde_yaml::from_str(&data)
+            .map_err(|e| Error::invalid_file(path, e.to_string()))?;
+        config.log_level()
+            .map_err(|e| Error::invalid_file(path, e))?;
+        Ok(config)
+    }
+
+    // log_level is the level of the agent log, debug when not set
+    pub fn log_level(&self) -> std::result::Result<LevelFilter, String> {
+        match &self.log_level {
+            None => Ok(LevelFilter::Debug),
+            Some(level) => level.parse().map_err(|_| format!("invalid log_level {:?}", level)),
+        }
+    }
+
+    pub fn apply_http(&self, args: &mut http::Arguments) {
+        set(&mut args.listen_address, &self.http.listen_address);
+        if self.http.admin_token.is_some() {
+            args.admin_token = self.http.admin_token.clone();
+        }
+    }
+
+    pub fn apply_discovery(&self, args: &mut discover::Arguments) {
+        let c = &self.discovery;
+        set(&mut args.host, &c.host);
+        set(&mut args.port, &c.port);
+        set(&mut args.host_networking_host, &c.host_networking_host);
+        set(&mut args.refresh_interval, &c.refresh_interval);
+    }
+
+    pub fn apply_kubelet(&self, args: &mut discover::KubeletArguments) {
+        let c = &self.kubelet;
+        set(&mut args.url, &c.url);
+        if c.token_path.is_some() {
+            args.token_path = c.token_path.clone();
+        }
+        if c.ca_path.is_some() {
+            args.ca_path = c.ca_path.clone();
+        }
+        set(&mut args.insecure_skip_verify, &c.insecure_skip_verify);
+        set(&mut args.timeout, &c.timeout);
+        set(&mut args.allowed_labels, &c.allowed_labels);
+        set(&mut args.allowed_annotations, &c.allowed_annotations);
+    }
+
+    // apply_write sets the write arguments of the file, an unknown name_convention or an invalid redact
+    // pattern is an error. The arguments are validated when the component is created.
+    pub fn apply_write(&self, args: &mut write::Arguments) -> Result<()> {
+        let c = &self.write;
+        set(&mut args.external_labels, &c.external_labels);
+        if let Some(endpoints) = &c.endpoints {
+            args.endpoints = endpoints.iter().map(EndpointConfig::options).collect();
+        }
+        if let Some(convention) = &c.name_convention {
+            let template = c.name_template.clone().unwrap_or_default();
+            args.name_convention = match convention.as_str() {
+                "labels" => write::NameConvention::Labels,
+                "template" => write::NameConvention::Template(template),
+                "pyroscope" => write::NameConvention::Pyroscope(template),
+                _ => return Err(Error::invalid_data(format!(
+                    "write name_convention {:?}, expected labels, template or pyroscope", convention))),
+            };
+        }
+        if let Some(path) = &c.metadata_path {
+            args.metadata = Some(MetadataOptions::new(path.clone()));
+        }
+        set(&mut args.trace_context, &c.trace_context);
+        set(&mut args.queue_capacity, &c.queue_capacity);
+        set(&mut args.health_check_interval, &c.health_check_interval);
+        if let Some(dry_run) = &c.dry_run {
+            args.dry_run = Some(match dry_run.as_str() {
+                "log" => DryRun::Log,
+                dir => DryRun::Dir(PathBuf::from(dir)),
+            });
+        }
+        set(&mut args.user_agent, &c.user_agent);
+        if let Some(scrubbing) = &c.scrubbing {
+            args.scrubbing = scrubbing.scrubbing()?;
+        }
+        Ok(())
+    }
+
+    // apply_ebpf sets the ebpf arguments of the file, an unknown hook_attach or collect_schedule or an
+    // invalid frame rule is an error. The arguments are validated when the component is created.
+    pub fn apply_ebpf(&self, args: &mut ebpf_linux::Arguments) -> Result<()> {
+        let c = &self.ebpf;
+        set(&mut args.collect_interval, &c.collect_interval);
+        if let Some(schedule) = &c.collect_schedule {
+            args.collect_schedule = CollectSchedule::parse(schedule)
+                .ok_or_else(|| Error::invalid_data(format!(
+                    "ebpf collect_schedule {:?}, expected absolute or relative", schedule)))?;
+        }
+        set(&mut args.sample_rate, &c.sample_rate);
+        if c.sample_period.is_some() {
+            args.sample_period = c.sample_period;
+        }
+        set(&mut args.precise_ip, &c.precise_ip);
+        set(&mut args.pid_cache_size, &c.pid_cache_size);
+        set(&mut args.build_id_cache_size, &c.build_id_cache_size);
+        set(&mut args.same_file_cache_size, &c.same_file_cache_size);
+        set(&mut args.container_id_cache_size, &c.container_id_cache_size);
+        set(&mut args.cache_rounds, &c.cache_rounds);
+        set(&mut args.collect_user_profile, &c.collect_user_profile);
+        set(&mut args.collect_kernel_profile, &c.collect_kernel_profile);
+        set(&mut args.python_enabled, &c.python_enabled);
+        set(&mut args.java_enabled, &c.java_enabled);
+        set(&mut args.python_full_file_path, &c.python_full_file_path);
+        set(&mut args.unknown_symbol_module_offset, &c.unknown_symbol_module_offset);
+        set(&mut args.unknown_symbol_address, &c.unknown_symbol_address);
+        set(&mut args.unknown_symbol_symbolizable, &c.unknown_symbol_symbolizable);
+        set(&mut args.per_pid_profile, &c.per_pid_profile);
+        set(&mut args.max_pids_per_service, &c.max_pids_per_service);
+        set(&mut args.targets_only, &c.targets_only);
+        set(&mut args.process_metrics, &c.process_metrics);
+        if let Some(mode) = &c.hook_attach {
+            args.hook_attach = HookAttach::parse(mode)
+                .ok_or_else(|| Error::invalid_data(format!(
+                    "ebpf hook_attach {:?}, expected auto, kprobe, raw_tracepoint or tp_btf", mode)))?;
+        }
+        set(&mut args.retention_rounds, &c.retention_rounds);
+        set(&mut args.retention_bytes, &c.retention_bytes);
+        set(&mut args.symbolization_threads, &c.symbolization_threads);
+        if c.target_time_slice.is_some() {
+            args.target_time_slice = c.target_time_slice;
+        }
+        if c.early_round_fill_ratio.is_some() {
+            args.early_round_fill_ratio = c.early_round_fill_ratio;
+        }
+        if c.pid_journal == Some(false) {
+            args.pid_journal = None;
+        }
+        if let Some(rules) = &c.frame_rules {
+            let rules = rules.iter()
+                .map(|r| FrameRule::parse(&r.action, &r.pattern, r.replacement.as_deref()))
+                .collect::<Result<Vec<_>>>()?;
+            args.stack_rewrite = Arc::new(StackRewrite::new(rules));
+        }
+        args.targets.extend(self.targets.iter().cloned());
+        Ok(())
+    }
+}
+
+impl EndpointConfig {
+    fn options(&self) -> write::EndpointOptions {
+        let mut options = write::EndpointOptions { url: self.url.clone(), ..Default::default() };
+        set(&mut options.name, &self.name);
+        set(&mut options.remote_timeout, &self.remote_timeout);
+        set(&mut options.headers, &self.headers);
+        set(&mut options.tenant_id, &self.tenant_id);
+        set(&mut options.bearer_token, &self.bearer_token);
+        set(&mut options.labels, &self.labels);
+        set(&mut options.min_backoff, &self.min_backoff);
+        set(&mut options.max_backoff, &self.max_backoff);
+        set(&mut options.max_backoff_retries, &self.max_backoff_retries);
+        set(&mut options.max_message_size, &self.max_message_size);
+        set(&mut options.chunk_size, &self.chunk_size);
+        options
+    }
+}
+
+impl ScrubbingConfig {
+    fn scrubbing(&self) -> Result<LabelScrubbing> {
+        let redact = self.redact.iter()
+            .map(|r| {
+                let pattern = Regex::new(&r.pattern)
+                    .map_err(|e| Error::invalid_data(format!("write scrubbing redact pattern {:?}: {}", r.pattern, e)))?;
+                Ok(RedactRule { label: r.label.clone(), pattern, replacement: r.replacement.clone() })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(LabelScrubbing {
+            drop: self.drop.clone(),
+            redact,
+            hash: self.hash.clone(),
+            hash_key: self.hash_key.clone(),
+        })
+    }
+}
+
+// set overwrites the argument with the value of the file, when the file has one
+fn set<T: Clone>(arg: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *arg = value.clone();
+    }
+}
+
+fn de_duration<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|s| humantime::parse_duration(&s).map_err(|e| de::Error::custom(format!("duration {:?}: {}", s, e))))
+        .transpose()
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::Duration;
+
+use iwm::ebpf::session::SessionDebugInfo;
+use iwm::ebpf::symtab::elf::symbol_table::SymTabDebugInfo;
+use iwm::ebpf::symtab::gcache::GCacheDebugInfo;
+use iwm::error::Error;
+use iwm::error::Error::NotFound;
+use iwm::error::Result;
+
+use crate::http::http::SESSION_DEBUG_PATH;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:12345";
+const DEFAULT_SECONDS: u64 = 60;
+
+// DiffArguments are the arguments of `agent debug diff`, which snapshots the symbol caches of a
+// running agent twice and prints what grew in between, to track down symbol cache leaks in the field
+#[derive(Debug, Clone)]
+pub struct DiffArguments {
+    // url is the address of the agent's http server
+    pub url: String,
+    pub interval: Duration,
+}
+
+impl DiffArguments {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut res = Self { url: DEFAULT_URL.to_string(), interval: Duration::from_secs(DEFAULT_SECONDS) };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next()
+                .ok_or_else(|| Error::invalid_data(format!("{} needs a value", arg)));
+            match arg.as_str() {
+                "--url" => res.url = value()?.trim_end_matches('/').to_string(),
+                "--seconds" => {
+                    let seconds = value()?;
+                    res.interval = Duration::from_secs(seconds.parse()
+                        .map_err(|_| Error::invalid_data(format!("invalid --seconds {:?}", seconds)))?);
+                }
+                _ => return Err(Error::invalid_data(format!("unknown argument {:?}", arg))),
+            }
+        }
+        Ok(res)
+    }
+}
+
+pub async fn run(args: DiffArguments) -> Result<()> {
+    let url = format!("{}{}", args.url, SESSION_DEBUG_PATH);
+    let before = snapshot(&url).await?;
+    println!("took the first snapshot, taking the second in {:?}", args.interval);
+    tokio::time::sleep(args.interval).await;
+    let after = snapshot(&url).await?;
+    print!("{}", diff(&before, &after));
+    Ok(())
+}
+
+async fn snapshot(url: &str) -> Result<SessionDebugInfo> {
+    let body = reqwest::get(url).await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| NotFound(format!("fetching {}: {}", url, e)))?
+        .text().await
+        .map_err(|e| NotFound(format!("reading {}: {}", url, e)))?;
+    serde_json::from_str(&body).map_err(|e| Error::invalid_data(format!("decoding {}: {}", url, e)))
+}
+
+// diff renders the growth between the snapshots: the sizes of the caches, then the elf tables and
+// pid cache entries that changed, largest growth first. Entries only in one snapshot count as growing
+// from or shrinking to zero.
+pub fn diff(before: &SessionDebugInfo, after: &SessionDebugInfo) -> String {
+    let mut out = String::new();
+    let caches = [
+        ("build_id_cache", cache_sizes(&before.elf_cache.build_id_cache), cache_sizes(&after.elf_cache.build_id_cache)),
+        ("same_file_cache", cache_sizes(&before.elf_cache.same_file_cache), cache_sizes(&after.elf_cache.same_file_cache)),
+        ("pid_cache", cache_sizes(&before.pid_cache), cache_sizes(&after.pid_cache)),
+    ];
+    let _ = writeln!(out, "cache\tlru\tround");
+    for (name, (lru_before, round_before), (lru_after, round_after)) in caches {
+        let _ = writeln!(out, "{}\t{} -> {}\t{} -> {}", name, lru_before, lru_after, round_before, round_after);
+    }
+
+    let _ = writeln!(out, "\nelf tables\n+symbols\tbefore\tafter\tfile");
+    for (file, b, a) in changed(elf_tables(before), elf_tables(after)) {
+        let _ = writeln!(out, "{:+}\t{}\t{}\t{}", a as i64 - b as i64, b, a, file);
+    }
+
+    let _ = writeln!(out, "\npid cache\n+symbols\tbefore\tafter\ttables\tpid");
+    let tables = pid_tables(after);
+    for (pid, b, a) in changed(pid_symbols(before), pid_symbols(after)) {
+        let _ = writeln!(out, "{:+}\t{}\t{}\t{}\t{}", a as i64 - b as i64, b, a,
+            tables.get(&pid).copied().unwrap_or_default(), pid);
+    }
+    out
+}
+
+fn cache_sizes<T>(cache: &GCacheDebugInfo<T>) -> (usize, usize) {
+    (cache.lru_size, cache.round_size)
+}
+
+fn entries<T>(cache: &GCacheDebugInfo<T>) -> impl Iterator<Item = &T> {
+    cache.lru_dump.iter().chain(cache.round_dump.iter())
+}
+
+// elf_tables maps the files of the cached elf tables to their symbol count
+fn elf_tables(info: &SessionDebugInfo) -> HashMap<String, usize> {
+    let cache = &info.elf_cache;
+    entries(&cache.build_id_cache).chain(entries(&cache.same_file_cache))
+        .map(|t: &SymTabDebugInfo| (t.file.clone(), t.size))
+        .collect()
+}
+
+// pid_symbols maps the pids of the pid cache to the symbol count of their elf tables
+fn pid_symbols(info: &SessionDebugInfo) -> HashMap<i32, usize> {
+    entries(&info.pid_cache)
+        .map(|p| (p.pid, p.elf_tables.values().map(|t| t.size).sum()))
+        .collect()
+}
+
+fn pid_tables(info: &SessionDebugInfo) -> HashMap<i32, usize> {
+    entries(&info.pid_cache).map(|p| (p.pid, p.size)).collect()
+}
+
+// changed returns the keys whose value differs with the values before and after, largest growth first
+fn changed<K: Ord + Clone>(before: HashMap<K, usize>, after: HashMap<K, usize>) -> Vec<(K, usize, usize)> {
+    let mut keys = BTreeMap::new();
+    for (k, v) in &before {
+        keys.insert(k.clone(), (*v, 0));
+    }
+    for (k, v) in &after {
+        keys.entry(k.clone()).or_insert((0, 0)).1 = *v;
+    }
+    let mut res: Vec<(K, usize, usize)> = keys.into_iter()
+        .filter(|(_, (b, a))| b != a)
+        .map(|(k, (b, a))| (k, b, a))
+        .collect();
+    res.sort_by_key(|(_, b, a)| std::cmp::Reverse(*a as i64 - *b as i64));
+    res
+}
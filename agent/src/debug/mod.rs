@@ -0,0 +1,16 @@
+pub mod diff;
+pub mod validate;
+
+use iwm::error::Error;
+use iwm::error::Result;
+
+// run_command runs `agent debug <command>`, args are the arguments after debug
+pub async fn run_command(args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("diff") => diff::run(diff::DiffArguments::parse(&args[1..])?).await,
+        Some("validate") => validate::run(validate::ValidateArguments::parse(&args[1..])?).await,
+        Some(command) => Err(Error::invalid_data(format!("unknown debug command {:?}, expected diff or validate", command))),
+        None => Err(Error::invalid_data(
+            "usage: agent debug diff [--url URL] [--seconds N]\n       agent debug validate --pid PID [--url URL] [--seconds N] [--perf PATH] [--frequency HZ] [--top N]".to_string())),
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use prost::Message;
+
+use iwm::ebpf::pprof::profile::Profile;
+use iwm::error::Error;
+use iwm::error::Error::NotFound;
+use iwm::error::Result;
+
+use crate::common::fetch;
+use crate::common::fetch::DEFAULT_MAX_PROFILE_BYTES;
+use crate::http::http::PROFILE_PATH;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:12345";
+const DEFAULT_SECONDS: u64 = 30;
+// the agent samples at 97Hz, perf is run at the same frequency
+const DEFAULT_FREQUENCY: u32 = 97;
+const DEFAULT_TOP: usize = 20;
+const UNKNOWN_FRAME: &str = "[unknown]";
+
+// ValidateArguments are the arguments of `agent debug validate`, which profiles a pid with the running
+// agent and with perf record at the same time and reports how much the two agree, frame by frame
+#[derive(Debug, Clone)]
+pub struct ValidateArguments {
+    // url is the address of the agent's http server
+    pub url: String,
+    pub pid: u32,
+    pub duration: Duration,
+    // perf is the perf binary, perf record needs the same privileges as the agent
+    pub perf: String,
+    pub frequency: u32,
+    // top is the number of functions listed with their shares in both profiles
+    pub top: usize,
+}
+
+impl ValidateArguments {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut res = Self {
+            url: DEFAULT_URL.to_string(),
+            pid: 0,
+            duration: Duration::from_secs(DEFAULT_SECONDS),
+            perf: "perf".to_string(),
+            frequency: DEFAULT_FREQUENCY,
+            top: DEFAULT_TOP,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next()
+                .ok_or_else(|| Error::invalid_data(format!("{} needs a value", arg)));
+            let number = |v: &String| v.parse::<u64>()
+                .map_err(|_| Error::invalid_data(format!("invalid {} {:?}", arg, v)));
+            match arg.as_str() {
+                "--url" => res.url = value()?.trim_end_matches('/').to_string(),
+                "--pid" => res.pid = number(value()?)? as u32,
+                "--seconds" => res.duration = Duration::from_secs(number(value()?)?),
+                "--perf" => res.perf = value()?.clone(),
+                "--frequency" => res.frequency = number(value()?)? as u32,
+                "--top" => res.top = number(value()?)? as usize,
+                _ => return Err(Error::invalid_data(format!("unknown argument {:?}", arg))),
+            }
+        }
+        if res.pid == 0 {
+            return Err(Error::invalid_data("--pid is required".to_string()));
+        }
+        if res.duration.is_zero() || res.frequency == 0 {
+            return Err(Error::invalid_data("--seconds and --frequency must be positive".to_string()));
+        }
+        Ok(res)
+    }
+}
+
+// Stacks are the sampled stacks of a profile, leaf first, with their weight
+type Stacks = HashMap<Vec<String>, u64>;
+
+pub async fn run(args: ValidateArguments) -> Result<()> {
+    let data = std::env::temp_dir().join(format!("iwm-validate-{}.data", args.pid));
+    let data = data.to_string_lossy().into_owned();
+    let mut perf = Command::new(&args.perf)
+        .args(["record", "-q", "-g", "-F", &args.frequency.to_string(), "-p", &args.pid.to_string(), "-o", &data])
+        .args(["--", "sleep", &args.duration.as_secs().to_string()])
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| NotFound(format!("running {}: {}", args.perf, e)))?;
+    println!("profiling pid {} with the agent and perf for {:?}", args.pid, args.duration);
+
+    let ebpf = fetch_profile(&args).await;
+    let status = tokio::task::spawn_blocking(move || perf.wait()).await
+        .map_err(|e| Error::invalid_data(format!("waiting for perf: {}", e)))?
+        .map_err(|e| Error::invalid_data(format!("waiting for perf: {}", e)))?;
+    let perf = if status.success() {
+        perf_stacks(&args.perf, &data)
+    } else {
+        Err(Error::invalid_data(format!("perf record exited with {}", status)))
+    };
+    let _ = fs::remove_file(&data);
+
+    print!("{}", compare(&ebpf?, &perf?, args.top));
+    Ok(())
+}
+
+// fetch_profile records the pid with the agent, the answer comes with the first round after the window
+async fn fetch_profile(args: &ValidateArguments) -> Result<Stacks> {
+    let url = format!("{}{}?pid={}&seconds={}", args.url, PROFILE_PATH, args.pid, args.duration.as_secs());
+    let body = fetch::fetch_profile(&reqwest::Client::new(), &url, DEFAULT_MAX_PROFILE_BYTES).await?;
+    let profile = Profile::decode(body.as_slice())
+        .map_err(|e| Error::invalid_data(format!("decoding {}: {}", url, e)))?;
+    Ok(profile_stacks(&profile))
+}
+
+fn profile_stacks(profile: &Profile) -> Stacks {
+    let string = |i: i64| profile.string_table.get(i as usize).cloned().unwrap_or_default();
+    let functions: HashMap<u64, String> = profile.function.iter().map(|f| (f.id, string(f.name))).collect();
+    let locations: HashMap<u64, Vec<String>> = profile.location.iter()
+        .map(|l| (l.id, l.line.iter().map(|line| functions.get(&line.function_id).cloned().unwrap_or_default()).collect()))
+        .collect();
+    let mut stacks = Stacks::new();
+    for sample in &profile.sample {
+        let stack: Vec<String> = sample.location_id.iter()
+            .flat_map(|id| locations.get(id).cloned().unwrap_or_default())
+            .map(|name| normalize(&name))
+            .collect();
+        *stacks.entry(stack).or_default() += sample.value.first().copied().unwrap_or_default().max(0) as u64;
+    }
+    stacks
+}
+
+// perf_stacks reads the samples of perf record. perf script prints a header line per sample
+// followed by its frames, leaf first, one "ip symbol" per indented line.
+fn perf_stacks(perf: &str, data: &str) -> Result<Stacks> {
+    let out = Command::new(perf)
+        .args(["script", "-i", data, "-F", "comm,tid,ip,sym"])
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| NotFound(format!("running {} script: {}", perf, e)))?;
+    if !out.status.success() {
+        return Err(Error::invalid_data(format!("perf script exited with {}", out.status)));
+    }
+    Ok(parse_perf_script(&String::from_utf8_lossy(&out.stdout)))
+}
+
+fn parse_perf_script(out: &str) -> Stacks {
+    let mut stacks = Stacks::new();
+    let mut stack = Vec::new();
+    let mut in_sample = false;
+    for line in out.lines() {
+        if line.trim().is_empty() {
+            if in_sample {
+                *stacks.entry(std::mem::take(&mut stack)).or_default() += 1;
+            }
+            in_sample = false;
+        } else if line.starts_with('\t') {
+            let symbol = line.trim().split_once(char::is_whitespace).map(|(_, s)| s).unwrap_or_default();
+            // older perf versions print the dso of the frame even when it was not asked for
+            let symbol = match symbol.rsplit_once(" (") {
+                Some((symbol, dso)) if dso.ends_with(')') => symbol,
+                _ => symbol,
+            };
+            stack.push(normalize(symbol));
+        } else {
+            in_sample = true;
+        }
+    }
+    if in_sample {
+        *stacks.entry(stack).or_default() += 1;
+    }
+    stacks
+}
+
+// normalize makes the frame names of both tools comparable: perf and the agent's unknown frames
+// become [unknown], perf's symbol offsets are dropped
+fn normalize(name: &str) -> String {
+    let name = name.trim();
+    let name = match name.rsplit_once("+0x") {
+        Some((symbol, offset)) if offset.bytes().all(|b| b.is_ascii_hexdigit()) => symbol,
+        _ => name,
+    };
+    if name.is_empty() || name == "[unknown]" || name.contains("!0x") {
+        return UNKNOWN_FRAME.to_string();
+    }
+    name.to_string()
+}
+
+// compare reports the agreement of the profiles as histogram intersections of their normalized
+// weights: of the leaf frames, the functions on the stacks and the whole stacks. 100% means both
+// tools saw the same distribution, differences point at the unwinder (stacks) or the symbolizer
+// (leaves, unknown frames).
+fn compare(ebpf: &Stacks, perf: &Stacks, top: usize) -> String {
+    let mut out = String::new();
+    let (ebpf_total, perf_total) = (total(ebpf), total(perf));
+    let _ = writeln!(out, "samples\tebpf {} stacks, weight {}\tperf {} stacks, {} samples",
+        ebpf.len(), ebpf_total, perf.len(), perf_total);
+    if ebpf_total == 0 || perf_total == 0 {
+        let _ = writeln!(out, "nothing to compare, one of the profiles is empty");
+        return out;
+    }
+
+    let leaves = |s: &Stacks| shares(s, |stack| stack.first().cloned().into_iter().collect());
+    let functions = |s: &Stacks| shares(s, |stack| stack.iter().cloned().collect::<HashSet<_>>().into_iter().collect());
+    let whole = |s: &Stacks| shares(s, |stack| vec![stack.join(";")]);
+    let (ebpf_functions, perf_functions) = (functions(ebpf), functions(perf));
+    let _ = writeln!(out, "agreement\tleaf {:.1}%\tfunctions {:.1}%\tstacks {:.1}%",
+        intersection(&leaves(ebpf), &leaves(perf)) * 100.0,
+        intersection(&ebpf_functions, &perf_functions) * 100.0,
+        intersection(&whole(ebpf), &whole(perf)) * 100.0);
+    let _ = writeln!(out, "unknown frames\tebpf {:.1}%\tperf {:.1}%", unknown_share(ebpf) * 100.0, unknown_share(perf) * 100.0);
+    let depth = |s: &Stacks, total: u64| s.iter().map(|(stack, w)| stack.len() as f64 * *w as f64).sum::<f64>() / total as f64;
+    let _ = writeln!(out, "mean depth\tebpf {:.1}\tperf {:.1}", depth(ebpf, ebpf_total), depth(perf, perf_total));
+
+    // the functions the tools disagree most on, by their share of the samples that have them on the stack
+    let mut names: Vec<&String> = ebpf_functions.keys().chain(perf_functions.keys()).collect::<HashSet<_>>().into_iter().collect();
+    let share = |m: &HashMap<String, f64>, name: &String| m.get(name).copied().unwrap_or_default();
+    names.sort_by(|a, b| {
+        let diff = |n: &String| (share(&ebpf_functions, n) - share(&perf_functions, n)).abs();
+        diff(b).total_cmp(&diff(a)).then_with(|| a.cmp(b))
+    });
+    let _ = writeln!(out, "\nebpf\tperf\tdiff\tfunction");
+    for name in names.into_iter().take(top) {
+        let (e, p) = (share(&ebpf_functions, name), share(&perf_functions, name));
+        let _ = writeln!(out, "{:.1}%\t{:.1}%\t{:+.1}%\t{}", e * 100.0, p * 100.0, (e - p) * 100.0, name);
+    }
+    out
+}
+
+fn total(stacks: &Stacks) -> u64 {
+    stacks.values().sum()
+}
+
+// shares maps the keys of every stack to the share of the weight of the stacks having them
+fn shares(stacks: &Stacks, keys: impl Fn(&Vec<String>) -> Vec<String>) -> HashMap<String, f64> {
+    let total = total(stacks) as f64;
+    let mut res = HashMap::new();
+    for (stack, weight) in stacks {
+        for key in keys(stack) {
+            *res.entry(key).or_default() += *weight as f64 / total;
+        }
+    }
+    res
+}
+
+// intersection is the weighted overlap of two share maps, sum(min) / sum(max)
+fn intersection(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let keys: HashSet<&String> = a.keys().chain(b.keys()).collect();
+    let (mut min, mut max) = (0.0, 0.0);
+    for key in keys {
+        let (x, y) = (a.get(key).copied().unwrap_or_default(), b.get(key).copied().unwrap_or_default());
+        min += x.min(y);
+        max += x.max(y);
+    }
+    if max == 0.0 { 0.0 } else { min / max }
+}
+
+fn unknown_share(stacks: &Stacks) -> f64 {
+    let (mut unknown, mut frames) = (0.0, 0.0);
+    for (stack, weight) in stacks {
+        frames += (stack.len() as u64 * weight) as f64;
+        unknown += (stack.iter().filter(|f| *f == UNKNOWN_FRAME).count() as u64 * weight) as f64;
+    }
+    if frames == 0.0 { 0.0 } else { unknown / frames }
+}
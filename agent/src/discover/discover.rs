@@ -4,10 +4,15 @@ use std::collections::HashMap;
 
 use std::time::Duration;
 
+use crate::common::client::DEFAULT_USER_AGENT;
+
 
 
 
 pub const ADDRESS_LABEL: &str = "__address__";
+// NODE_IP_ENV is the ip of the node set from status.hostIP through the downward API, the kubelet
+// listens on it
+pub const NODE_IP_ENV: &str = "NODE_IP";
 pub type Target = HashMap<String, String>;
 
 #[derive(Debug)]
@@ -27,4 +32,38 @@ impl Default for Arguments {
 			refresh_interval: Duration::from_secs(60),
 		}
 	}
-}
\ No newline at end of file
+}
+
+// KubeletArguments configure reading the pods of the node from the kubelet. Pod labels and annotations
+// are kept as profile labels only when they are in the allow lists, to keep series cardinality down.
+#[derive(Debug)]
+pub struct KubeletArguments {
+	pub url: String,
+	pub token_path: Option<String>,
+	// ca_path is the CA the kubelet serving certificate is verified with, besides the system roots
+	pub ca_path: Option<String>,
+	pub insecure_skip_verify: bool,
+	pub timeout: Duration,
+	pub allowed_labels: Vec<String>,
+	pub allowed_annotations: Vec<String>,
+	pub user_agent: String,
+	// trace_context adds a W3C traceparent header to the pod list requests
+	pub trace_context: bool,
+}
+
+impl Default for KubeletArguments {
+	fn default() -> Self {
+		let node = std::env::var(NODE_IP_ENV).unwrap_or_else(|_| String::from("localhost"));
+		Self {
+			url: format!("https://{}:10250", node),
+			token_path: Some(String::from("/var/run/secrets/kubernetes.io/serviceaccount/token")),
+			ca_path: Some(String::from("/var/run/secrets/kubernetes.io/serviceaccount/ca.crt")),
+			insecure_skip_verify: false,
+			timeout: Duration::from_secs(10),
+			allowed_labels: vec![String::from("app.kubernetes.io/name"), String::from("app.kubernetes.io/version")],
+			allowed_annotations: vec![],
+			user_agent: DEFAULT_USER_AGENT.to_string(),
+			trace_context: false,
+		}
+	}
+}
@@ -7,7 +7,7 @@ use docker_api::Docker;
 use docker_api::opts::ContainerListOpts;
 
 use log::{info};
-use regex::Regex;
+use iwm::common::labels::sanitize_label_name;
 
 
 
@@ -137,8 +137,3 @@ impl DockerDiscovery {
 		tg
 	}
 }
-
-pub fn sanitize_label_name(name: &str) -> String {
-	let invalid_label_char_re = Regex::new(r"[^a-zA-Z0-9_]").unwrap();
-	invalid_label_char_re.replace_all(name, "_").to_string()
-}
\ No newline at end of file
@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use log::warn;
+use serde::Deserialize;
+
+use iwm::common::labels::sanitize_label_name;
+use iwm::ebpf::sd::container_id::get_container_id_from_k8s;
+use iwm::error::Error;
+use iwm::error::Error::NotFound;
+use iwm::error::Result;
+
+use crate::common::client::{TraceContext, TRACEPARENT_HEADER};
+use crate::discover::discover::{KubeletArguments, Target};
+
+const K8S_LABEL_NAMESPACE: &str = "__meta_kubernetes_namespace";
+const K8S_LABEL_POD_NAME: &str = "__meta_kubernetes_pod_name";
+const K8S_LABEL_POD_UID: &str = "__meta_kubernetes_pod_uid";
+const K8S_LABEL_POD_NODE_NAME: &str = "__meta_kubernetes_pod_node_name";
+const K8S_LABEL_POD_CONTAINER_NAME: &str = "__meta_kubernetes_pod_container_name";
+const K8S_LABEL_POD_CONTAINER_ID: &str = "__meta_kubernetes_pod_container_id";
+const K8S_LABEL_POD_CONTAINER_INIT: &str = "__meta_kubernetes_pod_container_init";
+// K8S_LABEL_CONTAINER_TYPE is regular, init or ephemeral
+const K8S_LABEL_CONTAINER_TYPE: &str = "__meta_kubernetes_container_type";
+const K8S_LABEL_POD_CONTROLLER_KIND: &str = "__meta_kubernetes_pod_controller_kind";
+const K8S_LABEL_POD_CONTROLLER_NAME: &str = "__meta_kubernetes_pod_controller_name";
+const K8S_LABEL_POD_LABEL_PREFIX: &str = "__meta_kubernetes_pod_label_";
+const K8S_LABEL_POD_ANNOTATION_PREFIX: &str = "__meta_kubernetes_pod_annotation_";
+const DOCKER_LABEL_CONTAINER_ID: &str = "__meta_docker_container_id";
+
+// labels attached to the profiles of every pod container, the pod labels and annotations
+// only when they are in the allow lists
+const LABEL_NAMESPACE: &str = "namespace";
+const LABEL_POD: &str = "pod";
+const LABEL_CONTAINER: &str = "container";
+const LABEL_NODE: &str = "node";
+const LABEL_WORKLOAD_KIND: &str = "workload_kind";
+const LABEL_WORKLOAD_NAME: &str = "workload_name";
+// RESERVED_LABELS are set from the pod and container, pod labels and annotations of the same name
+// can't replace them
+const RESERVED_LABELS: [&str; 6] = [
+    LABEL_NAMESPACE, LABEL_POD, LABEL_CONTAINER, LABEL_NODE, LABEL_WORKLOAD_KIND, LABEL_WORKLOAD_NAME,
+];
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Pod {
+    metadata: PodMetadata,
+    spec: PodSpec,
+    status: PodStatus,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct PodMetadata {
+    name: String,
+    namespace: String,
+    uid: String,
+    labels: HashMap<String, String>,
+    annotations: HashMap<String, String>,
+    owner_references: Vec<OwnerReference>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct OwnerReference {
+    kind: String,
+    name: String,
+    controller: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct PodSpec {
+    node_name: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct PodStatus {
+    container_statuses: Vec<ContainerStatus>,
+    init_container_statuses: Vec<ContainerStatus>,
+    ephemeral_container_statuses: Vec<ContainerStatus>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct ContainerStatus {
+    name: String,
+    #[serde(rename = "containerID")]
+    container_id: String,
+}
+
+// KubeletDiscovery reads the pods of the node from the kubelet, so container targets get their pod
+// metadata without an api server connection or a sidecar
+pub struct KubeletDiscovery {
+    url: String,
+    token_path: Option<String>,
+    allowed_labels: HashSet<String>,
+    allowed_annotations: HashSet<String>,
+    trace_context: bool,
+    client: reqwest::Client,
+}
+
+impl KubeletDiscovery {
+    pub fn new(args: KubeletArguments) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(args.timeout)
+            .user_agent(args.user_agent)
+            // for kubelets serving self-signed certificates, verification is on by default
+            .danger_accept_invalid_certs(args.insecure_skip_verify);
+        // the CA of the service account is missing outside of a pod, the system roots are used then
+        if let Some(ca) = args.ca_path.as_ref().and_then(|path| fs::read(path).ok()) {
+            let cert = reqwest::Certificate::from_pem(&ca)
+                .map_err(|e| Error::invalid_data(format!("kubelet ca {:?}: {}", args.ca_path, e)))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        let client = builder.build()
+            .map_err(|e| Error::invalid_data(format!("kubelet client: {}", e)))?;
+        Ok(Self {
+            url: format!("{}/pods", args.url.trim_end_matches('/')),
+            token_path: args.token_path,
+            allowed_labels: args.allowed_labels.into_iter().collect(),
+            allowed_annotations: args.allowed_annotations.into_iter().collect(),
+            trace_context: args.trace_context,
+            client,
+        })
+    }
+
+    // refresh returns a target per started container of the pods on the node
+    pub async fn refresh(&self) -> Result<Vec<Target>> {
+        let mut req = self.client.get(&self.url);
+        if let Some(path) = &self.token_path {
+            // the token is rotated, read it on every request
+            let token = fs::read_to_string(path)
+                .map_err(|e| NotFound(format!("reading kubelet token {}: {}", path, e)))?;
+            req = req.bearer_auth(token.trim());
+        }
+        if self.trace_context {
+            req = req.header(TRACEPARENT_HEADER, TraceContext::new().traceparent());
+        }
+        let res = req.send().await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| NotFound(format!("listing pods from {}: {}", self.url, e)))?;
+        let body = res.text().await
+            .map_err(|e| NotFound(format!("reading pods from {}: {}", self.url, e)))?;
+        let pods: PodList = serde_json::from_str(&body)
+            .map_err(|e| Error::invalid_data(format!("decoding pods from {}: {}", self.url, e)))?;
+
+        let mut targets = Vec::new();
+        for pod in &pods.items {
+            let pod_labels = self.pod_labels(pod);
+            let statuses = pod.status.container_statuses.iter().map(|c| (c, "regular"))
+                .chain(pod.status.init_container_statuses.iter().map(|c| (c, "init")))
+                .chain(pod.status.ephemeral_container_statuses.iter().map(|c| (c, "ephemeral")));
+            for (c, container_type) in statuses {
+                // containers that didn't start yet have no id
+                if c.container_id.is_empty() {
+                    continue;
+                }
+                let mut target = pod_labels.clone();
+                target.insert(K8S_LABEL_POD_CONTAINER_NAME.to_string(), c.name.clone());
+                target.insert(K8S_LABEL_POD_CONTAINER_ID.to_string(), c.container_id.clone());
+                target.insert(K8S_LABEL_POD_CONTAINER_INIT.to_string(), (container_type == "init").to_string());
+                target.insert(K8S_LABEL_CONTAINER_TYPE.to_string(), container_type.to_string());
+                target.insert(LABEL_CONTAINER.to_string(), c.name.clone());
+                targets.push(target);
+            }
+        }
+        Ok(targets)
+    }
+
+    // pod_labels are the labels shared by the containers of the pod
+    fn pod_labels(&self, pod: &Pod) -> Target {
+        let m = &pod.metadata;
+        let mut labels = Target::new();
+        labels.insert(K8S_LABEL_NAMESPACE.to_string(), m.namespace.clone());
+        labels.insert(K8S_LABEL_POD_NAME.to_string(), m.name.clone());
+        labels.insert(K8S_LABEL_POD_UID.to_string(), m.uid.clone());
+        labels.insert(K8S_LABEL_POD_NODE_NAME.to_string(), pod.spec.node_name.clone());
+        labels.insert(LABEL_NAMESPACE.to_string(), m.namespace.clone());
+        labels.insert(LABEL_POD.to_string(), m.name.clone());
+        if !pod.spec.node_name.is_empty() {
+            labels.insert(LABEL_NODE.to_string(), pod.spec.node_name.clone());
+        }
+        if let Some(owner) = m.owner_references.iter().find(|o| o.controller) {
+            labels.insert(K8S_LABEL_POD_CONTROLLER_KIND.to_string(), owner.kind.clone());
+            labels.insert(K8S_LABEL_POD_CONTROLLER_NAME.to_string(), owner.name.clone());
+            let (kind, name) = workload(owner, &m.labels);
+            labels.insert(LABEL_WORKLOAD_KIND.to_string(), kind);
+            labels.insert(LABEL_WORKLOAD_NAME.to_string(), name);
+        }
+        for (k, v) in &m.labels {
+            let name = sanitize_label_name(k);
+            labels.insert(format!("{}{}", K8S_LABEL_POD_LABEL_PREFIX, name), v.clone());
+            if self.allowed_labels.contains(k) && !is_reserved(&name) {
+                labels.insert(name, v.clone());
+            }
+        }
+        for (k, v) in &m.annotations {
+            let name = sanitize_label_name(k);
+            labels.insert(format!("{}{}", K8S_LABEL_POD_ANNOTATION_PREFIX, name), v.clone());
+            if self.allowed_annotations.contains(k) && !is_reserved(&name) {
+                labels.insert(name, v.clone());
+            }
+        }
+        labels
+    }
+}
+
+// is_reserved tells if the label is one the pod metadata sets or an internal one
+fn is_reserved(name: &str) -> bool {
+    RESERVED_LABELS.contains(&name) || name.starts_with("__")
+}
+
+// workload resolves the controller of a pod to the workload users deal with: replica sets created by
+// a deployment are reported as the deployment, jobs created by a cron job as the cron job
+fn workload(owner: &OwnerReference, pod_labels: &HashMap<String, String>) -> (String, String) {
+    match owner.kind.as_str() {
+        "ReplicaSet" => match pod_labels.get("pod-template-hash") {
+            Some(hash) => match owner.name.strip_suffix(&format!("-{}", hash)) {
+                Some(deployment) => ("Deployment".to_string(), deployment.to_string()),
+                None => (owner.kind.clone(), owner.name.clone()),
+            },
+            None => (owner.kind.clone(), owner.name.clone()),
+        },
+        // cron jobs name their jobs <cronjob>-<scheduled minutes>
+        "Job" => match owner.name.rsplit_once('-') {
+            Some((cronjob, suffix)) if suffix.len() >= 8 && suffix.bytes().all(|b| b.is_ascii_digit()) => {
+                ("CronJob".to_string(), cronjob.to_string())
+            }
+            _ => (owner.kind.clone(), owner.name.clone()),
+        },
+        _ => (owner.kind.clone(), owner.name.clone()),
+    }
+}
+
+// enrich merges the pod labels into the targets of the same container. Pod containers found by no
+// other discovery, e.g. on nodes without docker, are added as targets of their own.
+pub fn enrich(targets: &mut Vec<Target>, pod_targets: Vec<Target>) {
+    let mut by_container_id = HashMap::with_capacity(pod_targets.len());
+    for pod_target in pod_targets {
+        let cid = pod_target.get(K8S_LABEL_POD_CONTAINER_ID).and_then(|cid| get_container_id_from_k8s(cid));
+        match cid {
+            Some(cid) => {
+                by_container_id.insert(cid, (pod_target, false));
+            }
+            None => warn!("unknown container runtime of pod container {:?}",
+                pod_target.get(K8S_LABEL_POD_CONTAINER_ID)),
+        }
+    }
+    for target in targets.iter_mut() {
+        let Some(cid) = target.get(DOCKER_LABEL_CONTAINER_ID).cloned() else {
+            continue;
+        };
+        if let Some((pod_target, matched)) = by_container_id.get_mut(&cid) {
+            for (k, v) in pod_target.iter() {
+                target.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+            *matched = true;
+        }
+    }
+    targets.extend(by_container_id.into_values()
+        .filter(|(_, matched)| !matched)
+        .map(|(pod_target, _)| pod_target));
+}
@@ -1,3 +1,4 @@
 pub mod discover;
 pub mod docker_discovery;
+pub mod kubelet;
 mod network;
\ No newline at end of file
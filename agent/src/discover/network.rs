@@ -2,11 +2,10 @@ use docker_api::Docker;
 use std::collections::HashMap;
 use std::string::String;
 
-use iwm::error::Error::OSError;
+use iwm::common::labels::sanitize_label_name;
+use iwm::error::Error::NotFound;
 use iwm::error::Result;
 
-use crate::discover::docker_discovery::sanitize_label_name;
-
 const LABEL_NETWORK_PREFIX: &str = "network_";
 const LABEL_NETWORK_ID: &str = "network_id";
 const LABEL_NETWORK_NAME: &str = "network_name";
@@ -19,14 +18,8 @@ pub async fn get_networks_labels(
     client: &Docker,
     label_prefix: &str,
 ) -> Result<HashMap<String, HashMap<String, String>>> {
-    let networks = match client.networks().list(&Default::default()).await {
-        Ok(networks) => Ok(networks),
-        Err(e) => Err(OSError(format!(
-            "error while listing networks: {}",
-            e.to_string()
-        ))),
-    }
-    .unwrap();
+    let networks = client.networks().list(&Default::default()).await
+        .map_err(|e| NotFound(format!("error while listing networks: {}", e)))?;
 
     let mut labels = HashMap::<String, HashMap<String, String>>::new();
     for network in networks {
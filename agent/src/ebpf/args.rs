@@ -16,4 +16,5 @@ pub struct Arguments {
     pub collect_kernel_profile: Option<bool>,
     pub demangle: Option<String>,
     pub python_enabled: Option<bool>,
+    pub java_enabled: Option<bool>,
 }
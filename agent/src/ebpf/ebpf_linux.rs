@@ -1,7 +1,7 @@
 #[allow(unused_imports)]
 use std::{
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use std::fs::File;
@@ -10,22 +10,32 @@ use std::borrow::Borrow;
 
 
 
-use log::{error};
-use tokio::time::interval;
+use log::{error, info, warn};
+use prost::bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
 use iwm::common::collector;
 use iwm::ebpf::metrics::ebpf_metrics::EbpfMetrics;
 use iwm::ebpf::metrics::metrics::ProfileMetrics;
 
 use iwm::ebpf::{pprof};
 use iwm::ebpf::pprof::BuildersOptions;
+use iwm::ebpf::pprof::rewrite::StackRewrite;
+use iwm::ebpf::pressure::{LoadSheddingOptions, PressureMonitor};
+use iwm::ebpf::probe::HookAttach;
+use iwm::ebpf::ring::perf_buffer::PerfBufferOptions;
+use iwm::ebpf::ring::perf_event::MAX_PRECISE_IP;
 
+use iwm::ebpf::sd::journal::JournalOptions;
 use iwm::ebpf::sd::target::{LABEL_SERVICE_NAME, TargetFinder, TargetsOptions};
-use iwm::ebpf::session::{Session, SessionDebugInfo, SessionOptions};
+use iwm::ebpf::session::{RoundSummary, Session, SessionDebugInfo, SessionOptions};
 use iwm::ebpf::symtab::elf_module::SymbolOptions;
 use iwm::ebpf::symtab::gcache::{GCacheOptions};
 use iwm::ebpf::symtab::symbols::CacheOptions;
 
-use iwm::error::Error::OSError;
+use iwm::error::Error;
 
 use iwm::error::Result;
 
@@ -33,35 +43,187 @@ use crate::appender::{Appendable, Fanout};
 use crate::common::component::Component;
 use crate::common::registry::Options;
 use crate::discover::discover::Target;
+use crate::ebpf::pause::{IngestionPause, PauseMode};
+use crate::ebpf::retention::{ProfileRetention, RetainedProfile};
+use crate::ebpf::schedule::{CollectSchedule, RoundSchedule};
+use crate::ebpf::window::ProfileWindows;
 use crate::write::write::FanOutClient;
 pub mod push_api {
     include!("../gen/push/push.v1.rs");
 }
 
+// COUNTS_FILL_CHECK_INTERVAL is how often the counts map is checked against early_round_fill_ratio
+const COUNTS_FILL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct Arguments {
     pub forward_to: Arc<Vec<Box<FanOutClient>>>,
     pub targets: Vec<Target>,
     pub collect_interval: Duration,
     pub sample_rate: i32,
+    // sample_period switches the perf events from sample_rate samples per second to a fixed period,
+    // precise_ip (0-3) asks for precise samples of the cpu cycles event
+    pub sample_period: Option<u64>,
+    pub precise_ip: u8,
     pub pid_cache_size: i32,
     pub build_id_cache_size: i32,
     pub same_file_cache_size: i32,
     pub container_id_cache_size: i32,
+    // cache_rounds is the number of rounds symbol cache entries are kept without being used
     pub cache_rounds: i32,
     pub collect_user_profile: bool,
     pub collect_kernel_profile: bool,
-    pub python_enabled: bool
+    pub python_enabled: bool,
+    pub java_enabled: bool,
+    // python_full_file_path keeps the full path of python files in frames instead of the file name
+    pub python_full_file_path: bool,
+    // unknown_symbol_module_offset renders unresolved frames as module+offset instead of the module name,
+    // unknown_symbol_address renders frames without a module as their address instead of [unknown]
+    pub unknown_symbol_module_offset: bool,
+    pub unknown_symbol_address: bool,
+    // unknown_symbol_symbolizable renders unresolved frames as module!0xoffset for server side symbolization
+    pub unknown_symbol_symbolizable: bool,
+    // per_pid_profile splits the profiles of a service by process
+    pub per_pid_profile: bool,
+    pub max_pids_per_service: usize,
+    // targets_only drops samples of pids matched by no target, when off their kernel stacks
+    // are kept under service_name="kernel"
+    pub targets_only: bool,
+    // process_metrics exports cpu time and rss gauges of the targets per service
+    pub process_metrics: bool,
+    // hook_attach selects kprobes or tracepoints for the exec and exit hooks, Auto probes the kernel
+    pub hook_attach: HookAttach,
+    // retention_rounds and retention_bytes bound the profiles of the last rounds kept in memory
+    // for the debug api, zero of either disables retention
+    pub retention_rounds: usize,
+    pub retention_bytes: usize,
+    // symbolization_threads sizes the symbolization pool, 0 is one thread per cpu. target_time_slice
+    // caps the symbolization time of a target per round, so one target with huge stacks can't
+    // hold up the others. Stacks over the slice are reported as raw addresses.
+    pub symbolization_threads: usize,
+    pub target_time_slice: Option<Duration>,
+    // profile_comments are added to every profile, e.g. the host info
+    pub profile_comments: Vec<String>,
+    // load_shedding thins out the samples and skips python unwinding while the node is under
+    // sustained cpu pressure, None never sheds
+    pub load_shedding: Option<LoadSheddingOptions>,
+    // ring_options size the perf rings of the pid events and pick when the reader is woken, a byte
+    // watermark wakes it less often on nodes with many exec and exit events
+    pub ring_options: PerfBufferOptions,
+    // pid_journal records which target every profiled pid belonged to, for postmortems of series
+    // whose containers are gone, None keeps no journal
+    pub pid_journal: Option<JournalOptions>,
+    // early_round_fill_ratio starts a round before the collect interval is up once the counts map is
+    // that full, so the bpf program doesn't drop new stacks, None only collects on the interval
+    pub early_round_fill_ratio: Option<f64>,
+    // stack_rewrite collapses, drops and groups frames of the pushed profiles, see StackRewrite
+    pub stack_rewrite: Arc<StackRewrite>,
+    // collect_schedule places the rounds at fixed points or relative to the last one, see CollectSchedule
+    pub collect_schedule: CollectSchedule,
+}
+
+impl Arguments {
+    // features lists the enabled profiling features, as reported by the build info
+    pub fn features(&self) -> Vec<String> {
+        [
+            ("user_stacks", self.collect_user_profile),
+            ("kernel_stacks", self.collect_kernel_profile),
+            ("python", self.python_enabled),
+            ("java", self.java_enabled),
+            ("per_pid_profile", self.per_pid_profile),
+            ("targets_only", self.targets_only),
+            ("process_metrics", self.process_metrics),
+            ("precise_ip", self.precise_ip > 0),
+            ("load_shedding", self.load_shedding.is_some()),
+            ("early_rounds", self.early_round_fill_ratio.is_some()),
+            ("stack_rewrite", !self.stack_rewrite.is_empty()),
+        ].iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    // validate checks the arguments and their cross-field constraints, reporting every problem at once
+    pub fn validate(&self) -> Result<()> {
+        let mut errs = Vec::new();
+        if self.collect_interval.is_zero() {
+            errs.push("collect_interval must be positive".to_string());
+        }
+        match self.sample_period {
+            Some(0) => errs.push("sample_period must be positive".to_string()),
+            Some(_) => {}
+            None if self.sample_rate <= 0 => errs.push(format!("sample_rate {} must be positive", self.sample_rate)),
+            None => {}
+        }
+        if self.precise_ip > MAX_PRECISE_IP {
+            errs.push(format!("precise_ip {} is greater than {}", self.precise_ip, MAX_PRECISE_IP));
+        }
+        for (name, size) in [
+            ("pid_cache_size", self.pid_cache_size),
+            ("build_id_cache_size", self.build_id_cache_size),
+            ("same_file_cache_size", self.same_file_cache_size),
+            ("container_id_cache_size", self.container_id_cache_size),
+        ] {
+            if size <= 0 {
+                errs.push(format!("{} {} must be positive", name, size));
+            }
+        }
+        if !self.collect_user_profile && !self.collect_kernel_profile {
+            errs.push("at least one of collect_user_profile and collect_kernel_profile must be enabled".to_string());
+        }
+        if self.target_time_slice.is_some_and(|slice| slice.is_zero()) {
+            errs.push("target_time_slice must be positive".to_string());
+        }
+        if let Some(shed) = &self.load_shedding {
+            if shed.exit_pressure >= shed.enter_pressure || shed.enter_pressure > 100.0 {
+                errs.push(format!("load shedding exit_pressure {} must be below enter_pressure {}, at most 100",
+                    shed.exit_pressure, shed.enter_pressure));
+            }
+            if shed.sample_every < 2 && !shed.skip_python {
+                errs.push("load shedding must thin out samples or skip python".to_string());
+            }
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        if let Err(err) = self.ring_options.validate(page_size) {
+            errs.push(format!("ring options: {}", err));
+        }
+        if let Some(ratio) = self.early_round_fill_ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                errs.push(format!("early_round_fill_ratio {} must be in (0, 1]", ratio));
+            }
+        }
+        if self.per_pid_profile && self.max_pids_per_service == 0 {
+            errs.push("max_pids_per_service must be positive with per_pid_profile".to_string());
+        }
+        if errs.is_empty() {
+            return Ok(());
+        }
+        Err(Error::invalid_data(format!("ebpf arguments: {}", errs.join("; "))))
+    }
 }
 
 pub struct EbpfLinuxComponent<'a> {
     options: Options,
     args: Arguments,
     pub session: Arc<Mutex<Session<'a>>>,
+    // target_finder is shared with the session, targets updates go to it without the session lock,
+    // which a round holds for its whole duration
+    pub target_finder: Arc<TargetFinder>,
+    // session_options are the reloaded session options, applied when the next round starts
+    session_options: Option<SessionOptions>,
 
-    appendable: Box<Fanout>,
+    appendable: Arc<Fanout>,
     debug_info: DebugInfo,
-    metrics: Arc<EbpfMetrics>
+    metrics: Arc<EbpfMetrics>,
+    // pprof encode buffer, kept across rounds so it only grows to the largest profile once
+    encode_buf: Arc<Mutex<Vec<u8>>>,
+    pub windows: Arc<ProfileWindows>,
+    pub retention: Arc<ProfileRetention>,
+    pub pause: Arc<IngestionPause>,
+    pressure: Option<Arc<Mutex<PressureMonitor>>>,
+    // updates are the arguments of configuration reloads, applied between rounds by run
+    updates: mpsc::Receiver<Arguments>,
+    update_sender: mpsc::Sender<Arguments>,
 }
 
 struct DebugInfo {
@@ -79,137 +241,415 @@ impl Default for DebugInfo {
 }
 
 
-impl Component for EbpfLinuxComponent<'_> {
-    async fn run(&mut self) {
-        let opts = TargetsOptions {
-            targets: self.args.targets.clone(),
-            targets_only: true,
-            container_cache_size: 1024,
-        };
-        {
-            let mut s = self.session.lock().unwrap();
-            s.update_targets(&opts);
-        }
+impl Component for EbpfLinuxComponent<'static> {
+    // run collects a round at every point of the collect schedule until cancelled. The round in
+    // flight is finished and pushed, then the session is stopped, its probes detached, and a last
+    // round hands the samples taken since to the write component.
+    async fn run(&mut self, cancel: CancellationToken) {
+        self.target_finder.update(&targets_options(&self.args));
 
-        let mut interval = interval(self.args.collect_interval);
+        let mut fill_check = interval(COUNTS_FILL_CHECK_INTERVAL);
+        fill_check.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut schedule = RoundSchedule::new(self.args.collect_schedule, self.args.collect_interval);
+        let mut in_flight: Option<JoinHandle<(Result<()>, Duration)>> = None;
         loop {
             tokio::select! {
-                _ = interval.tick() => {
-                    let result = self.collect_profiles();
-                    if let Err(err) = result {
-                        dbg!(format!("ebpf profiling session failed: {}", err));
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep_until(schedule.deadline()) => {
+                    let skipped = schedule.advance(tokio::time::Instant::now());
+                    if skipped > 0 {
+                        // the loop woke up late, the rounds keep their points rather than bunching up
+                        self.metrics.collection_overruns.inc_by(skipped as f64);
+                        warn!("ebpf collection fell behind its schedule, skipped {} rounds", skipped);
+                    }
+                    if in_flight.is_some() {
+                        // the previous round is still running, skip rather than queue rounds up
+                        self.metrics.collection_overruns.inc();
+                        warn!("ebpf collection still running, skipping this round");
+                        continue;
+                    }
+                    in_flight = self.start_round();
+                }
+                _ = fill_check.tick(), if in_flight.is_none() && self.args.early_round_fill_ratio.is_some() => {
+                    let threshold = self.args.early_round_fill_ratio.unwrap();
+                    let fill = self.with_session(|session| session.counts_fill_ratio()).await;
+                    if fill < threshold {
+                        continue;
+                    }
+                    info!("counts map is {:.0}% full, collecting before the interval is up", fill * 100.0);
+                    self.metrics.early_rounds.inc();
+                    schedule.early_round(tokio::time::Instant::now());
+                    in_flight = self.start_round();
+                }
+                done = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                    in_flight = None;
+                    self.round_done(done);
+                    self.update_debug_info().await;
+                }
+                Some(args) = self.updates.recv() => {
+                    let (collect_interval, collect_schedule) = (self.args.collect_interval, self.args.collect_schedule);
+                    match self.update(args) {
+                        Ok(()) => info!("reloaded ebpf arguments"),
+                        Err(err) => {
+                            error!("reloading ebpf arguments: {}", err);
+                            continue;
+                        }
+                    }
+                    if self.args.collect_interval != collect_interval || self.args.collect_schedule != collect_schedule {
+                        schedule = RoundSchedule::new(self.args.collect_schedule, self.args.collect_interval);
+                        // a relative schedule would collect right away, the last round was less than an interval ago
+                        schedule.early_round(tokio::time::Instant::now());
                     }
-                    self.update_debug_info();
                 }
             }
         }
+        if let Some(round) = in_flight.take() {
+            self.round_done(round.await);
+        }
+        // the probes are detached first, so the last round drains everything sampled before the stop
+        self.with_session(|session| session.stop()).await;
+        info!("collecting the samples of the last round before stopping");
+        if let Some(round) = self.start_round() {
+            self.round_done(round.await);
+        }
+    }
+}
+
+impl EbpfLinuxComponent<'static> {
+    // with_session runs f with the session on a blocking thread, the http handlers may hold the
+    // session lock for a while and waiting for it would block a runtime thread
+    async fn with_session<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Session<'static>) -> T + Send + 'static,
+    {
+        let session = self.session.clone();
+        tokio::task::spawn_blocking(move || f(&mut session.lock().unwrap())).await.unwrap()
+    }
+
+    async fn update_debug_info(&mut self) {
+        self.debug_info = self.with_session(|s| DebugInfo {
+            targets: s.target_finder.debug_info(),
+            session: s.debug_info().unwrap_or_default(),
+        }).await;
+    }
+
+    fn round_done(&self, done: std::result::Result<(Result<()>, Duration), JoinError>) {
+        match done {
+            Ok((result, elapsed)) => {
+                match result {
+                    Err(err) if err.is_per_target() => warn!("ebpf collection skipped a target: {}", err),
+                    Err(err) => error!("ebpf profiling session failed: {}", err),
+                    Ok(()) => {}
+                }
+                if elapsed > self.args.collect_interval {
+                    warn!("ebpf collection took {:?}, longer than the collect interval {:?}",
+                        elapsed, self.args.collect_interval);
+                }
+            }
+            Err(err) => error!("ebpf collection task failed: {}", err),
+        }
+    }
+
+    // start_round spawns a collection round, None when the round is skipped because sampling is paused
+    fn start_round(&mut self) -> Option<JoinHandle<(Result<()>, Duration)>> {
+        let pause_mode = self.pause.mode();
+        if let Some(mode) = pause_mode {
+            self.pause.round_paused(mode);
+        }
+        if pause_mode == Some(PauseMode::Sampling) {
+            return None;
+        }
+        let session = self.session.clone();
+        // while pushing is paused the round is collected and retained, but not pushed
+        let appendable = pause_mode.is_none().then(|| self.appendable.clone());
+        let metrics = self.metrics.clone();
+        let encode_buf = self.encode_buf.clone();
+        let windows = self.windows.clone();
+        let retention = self.retention.clone();
+        let pressure = self.pressure.clone();
+        let session_options = self.session_options.take();
+        let builders = pprof::ProfileBuilders::new(builders_options(&self.args))
+            .with_comments(self.args.profile_comments.clone())
+            .with_rewrite(self.args.stack_rewrite.clone());
+        Some(tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            if let Some(options) = session_options {
+                session.lock().unwrap().update(options);
+            }
+            let mut encode_buf = encode_buf.lock().unwrap();
+            let result = collect_profiles(&session, appendable.as_deref(), &metrics, &windows, &retention, builders, &mut encode_buf);
+            windows.close_expired();
+            if let Some(pressure) = &pressure {
+                shed_load(&session, &mut pressure.lock().unwrap(), &metrics);
+            }
+            (result, started.elapsed())
+        }))
     }
 }
 
 impl EbpfLinuxComponent<'_> {
 
-    async fn update(&mut self, _args: Arguments) -> Result<()> {
+    // updates returns the sender of configuration reloads, see update
+    pub fn updates(&self) -> mpsc::Sender<Arguments> {
+        self.update_sender.clone()
+    }
+
+    // update applies reloaded arguments to the running session, the targets right away and the session
+    // options when the next round starts, so the round in flight finishes with the previous ones. The profiles keep going to the write component the component was created with.
+    // Arguments the bpf programs, the perf events or the session were set up with keep their values
+    // until a restart.
+    fn update(&mut self, mut args: Arguments) -> Result<()> {
+        args.validate()?;
+        let current = &self.args;
+        let mut restart = Vec::new();
+        for (name, changed) in [
+            ("sample_rate", args.sample_rate != current.sample_rate),
+            ("sample_period", args.sample_period != current.sample_period),
+            ("precise_ip", args.precise_ip != current.precise_ip),
+            ("hook_attach", args.hook_attach != current.hook_attach),
+            // pyperf is only loaded when the session starts with python enabled
+            ("python_enabled", args.python_enabled != current.python_enabled),
+            ("ring_options", args.ring_options != current.ring_options),
+            ("pid_journal", args.pid_journal.as_ref().map(|j| &j.dir) != current.pid_journal.as_ref().map(|j| &j.dir)),
+            ("symbolization_threads", args.symbolization_threads != current.symbolization_threads),
+            ("retention_rounds", args.retention_rounds != current.retention_rounds),
+            ("retention_bytes", args.retention_bytes != current.retention_bytes),
+        ] {
+            if changed {
+                restart.push(name);
+            }
+        }
+        if !restart.is_empty() {
+            warn!("the reloaded {} take effect on restart", restart.join(", "));
+        }
+        args = Arguments {
+            forward_to: current.forward_to.clone(),
+            sample_rate: current.sample_rate,
+            sample_period: current.sample_period,
+            precise_ip: current.precise_ip,
+            hook_attach: current.hook_attach,
+            python_enabled: current.python_enabled,
+            ring_options: current.ring_options,
+            pid_journal: current.pid_journal.clone(),
+            symbolization_threads: current.symbolization_threads,
+            retention_rounds: current.retention_rounds,
+            retention_bytes: current.retention_bytes,
+            ..args
+        };
+
+        if args.load_shedding != current.load_shedding {
+            self.pressure = args.load_shedding.map(|opts| Arc::new(Mutex::new(PressureMonitor::new(opts))));
+        }
+        self.target_finder.update(&targets_options(&args));
+        self.session_options = Some(convert_session_options(&args, self.metrics.profile_metrics.clone()));
+        self.args = args;
         Ok(())
     }
 
     pub async fn new(opts: Options, args: Arguments) -> Result<Self> {
-        let target_finder = Arc::new(Mutex::new(TargetFinder::new(
-            1024,
+        args.validate()?;
+        let target_finder = Arc::new(TargetFinder::new(
+            args.container_id_cache_size as usize,
             File::open("/").unwrap()
-        )));
+        ));
         let ms = Arc::new(EbpfMetrics::new(opts.registerer.borrow()));
         let sesstion_opts = convert_session_options(&args.clone(), ms.clone().profile_metrics.clone());
-        let session = Session::new(target_finder, sesstion_opts).unwrap();
+        let session = Session::new(target_finder.clone(), sesstion_opts)?;
+        let (update_sender, updates) = mpsc::channel(1);
 
         Ok(Self {
             options: opts.clone(),
             args: args.clone(),
             session: Arc::new(Mutex::new(session)),
-            appendable: Box::new(Fanout::new(args.clone().forward_to, opts.id, opts.registerer.clone())),
+            target_finder,
+            session_options: None,
+            appendable: Arc::new(Fanout::new(args.clone().forward_to, opts.id, opts.registerer.clone())),
             debug_info: DebugInfo { targets: vec![], session: SessionDebugInfo::default() },
-            metrics: ms.clone()
+            metrics: ms.clone(),
+            encode_buf: Arc::new(Mutex::new(Vec::new())),
+            windows: Arc::new(ProfileWindows::new(builders_options(&args))),
+            retention: Arc::new(ProfileRetention::new(args.retention_rounds, args.retention_bytes)),
+            pause: Arc::new(IngestionPause::new(ms.clone())),
+            pressure: args.load_shedding.map(|opts| Arc::new(Mutex::new(PressureMonitor::new(opts)))),
+            updates,
+            update_sender,
         })
     }
+}
 
-    fn collect_profiles(&mut self) -> Result<()> {
-        let builders = Arc::new(Mutex::new(pprof::ProfileBuilders::new(
-            BuildersOptions { sample_rate: 97, per_pid_profile: false }
-        )));
-        {
-            let mut s = self.session.lock().unwrap();
-            collector::collect(builders.clone(), &mut s).unwrap();
-        }
-
-        let bb = builders.clone();
-        let b = bb.lock().unwrap();
-        for (_, builder) in &b.builders {
-            //dbg!(&builder.pprof_builder.profile.string_table);
-            let sn = builder.labels.get(LABEL_SERVICE_NAME);
-            let a = sn.unwrap();
-            let service_name = a.trim();
-
-            self.metrics.pprofs_total
-                .with_label_values(&[service_name]).inc();
-            self.metrics.pprof_samples_total
-                .with_label_values(&[service_name])
-                .inc_by(builder.pprof_builder.profile.sample.len() as f64);
-
-            let mut buf = vec![];
-            //info!("{:?}",&builder.pprof_builder.profile);
-            builder.write(&mut buf);
-
-            let raw_profile: Vec<u8> = buf.into();
-            self.metrics.pprof_bytes_total.with_label_values(&[service_name]).inc_by(raw_profile.len() as f64);
-            let samples = vec![
-                push_api::RawSample { raw_profile, id: "".to_string() }
-            ];
-            let appender = self.appendable.appender();
-            if let Err(err) = appender.append(
-                builder.labels.clone(),
-                samples
-            ) {
-                error!("ebpf pprof write err {}", err);
-                return Err(OSError(format!("{}", err)));
-            }
-        }
-        Ok(())
+fn targets_options(args: &Arguments) -> TargetsOptions {
+    TargetsOptions {
+        targets: args.targets.clone(),
+        targets_only: args.targets_only,
+        container_cache_size: args.container_id_cache_size as usize,
     }
+}
 
-    fn update_debug_info(&mut self) {
-        let mut s = self.session.lock().unwrap();
-        let targets = {
-            let mut target_finder = s.target_finder.lock().unwrap() ;
-            target_finder.debug_info().clone()
-        };
-        let debug_info = DebugInfo {
-            targets,
-            session: s.debug_info().unwrap(),
-        };
-        self.debug_info = debug_info;
+// builders_options are the profile options of the sampling setup of the session, see convert_session_options
+fn builders_options(args: &Arguments) -> BuildersOptions {
+    BuildersOptions {
+        sample_rate: args.sample_rate as i64,
+        per_pid_profile: args.per_pid_profile,
+        max_pids_per_service: args.max_pids_per_service,
+        sample_period: args.sample_period,
+        cycles: args.precise_ip > 0,
     }
 }
 
-fn convert_session_options(_args: &Arguments, ms: Arc<ProfileMetrics>) -> SessionOptions {
-    let keep_rounds = 3;
+fn convert_session_options(args: &Arguments, ms: Arc<ProfileMetrics>) -> SessionOptions {
+    // an entry has to survive at least the round it was used in
+    let keep_rounds = args.cache_rounds.max(1);
     SessionOptions {
-        collect_user: true,
-        collect_kernel: true,
-        unknown_symbol_module_offset: false,//true,
-        unknown_symbol_address: false,//true,
-        sample_rate: 97,
-        python_enabled: true,
+        collect_user: args.collect_user_profile,
+        collect_kernel: args.collect_kernel_profile,
+        unknown_symbol_module_offset: args.unknown_symbol_module_offset,
+        unknown_symbol_address: args.unknown_symbol_address,
+        unknown_symbol_symbolizable: args.unknown_symbol_symbolizable,
+        sample_rate: args.sample_rate as u32,
+        sample_period: args.sample_period,
+        precise_ip: args.precise_ip,
+        python_enabled: args.python_enabled,
+        java_enabled: args.java_enabled,
         cache_options: CacheOptions {
             pid_cache_options: GCacheOptions {
-                size: 32, keep_rounds
+                size: args.pid_cache_size as usize, keep_rounds
             },
             build_id_cache_options: GCacheOptions {
-                size: 64, keep_rounds
+                size: args.build_id_cache_size as usize, keep_rounds
             },
+            // identical binaries of every container share a same file cache entry, see Stat
             same_file_cache_options: GCacheOptions {
-                size: 8, keep_rounds
+                size: args.same_file_cache_size as usize, keep_rounds
             },
-            symbol_options: SymbolOptions::default()
+            symbol_options: SymbolOptions::new(args.python_full_file_path)
         },
         metrics: ms,
+        round_budget: args.collect_interval.checked_sub(ROUND_BUDGET_MARGIN),
+        process_metrics: args.process_metrics,
+        hook_attach: args.hook_attach,
+        symbolization_threads: args.symbolization_threads,
+        target_time_slice: args.target_time_slice,
+        ring_options: args.ring_options,
+        journal: args.pid_journal.clone(),
+    }
+}
+
+// shed_load checks the cpu pressure after a round and starts or stops shedding load. The samples
+// of the next round are all taken with the new state, so the session scales them right.
+fn shed_load(session: &Mutex<Session<'static>>, pressure: &mut PressureMonitor, metrics: &EbpfMetrics) {
+    let change = pressure.check();
+    metrics.cpu_pressure.set(pressure.avg10());
+    if let Some(shedding) = change {
+        metrics.load_shedding.set(if shedding { 1.0 } else { 0.0 });
+        metrics.load_shedding_transitions
+            .with_label_values(&[if shedding { "started" } else { "stopped" }])
+            .inc();
+        session.lock().unwrap().set_load_shedding(shedding.then(|| *pressure.options()));
+    }
+}
+
+// ROUND_BUDGET_MARGIN is the part of the collect interval left for encoding and pushing the profiles.
+const ROUND_BUDGET_MARGIN: Duration = Duration::from_secs(3);
+
+// collect_profiles runs a collection round and hands the resulting profiles to the appenders,
+// None while pushing is paused. It blocks on the session and symbolization, so it must not run on
+// the async runtime's workers.
+fn collect_profiles(
+    session: &Mutex<Session<'static>>,
+    appendable: Option<&Fanout>,
+    metrics: &EbpfMetrics,
+    windows: &ProfileWindows,
+    retention: &ProfileRetention,
+    builders: pprof::ProfileBuilders,
+    encode_buf: &mut Vec<u8>,
+) -> Result<()> {
+    let started = Instant::now();
+    let builders = Arc::new(Mutex::new(builders));
+    let summary = {
+        let mut s = session.lock().unwrap();
+        collector::collect_with(builders.clone(), &mut *s, |sample| windows.add_sample(sample))?;
+        s.last_round()
+    };
+
+    let stages = &metrics.profile_metrics.stage_duration;
+    let mut encode = Duration::ZERO;
+    let mut push = Duration::ZERO;
+    let bb = builders.clone();
+    let b = bb.lock().unwrap();
+    // profiles are encoded and retained before pushing, so they are kept even when the push fails
+    let mut encoded = Vec::with_capacity(b.builders.len());
+    for (key, builder) in &b.builders {
+        //dbg!(&builder.pprof_builder.profile.string_table);
+        let sn = builder.labels.get(LABEL_SERVICE_NAME);
+        let a = sn.unwrap();
+        let service_name = a.trim();
+
+        metrics.pprofs_total
+            .with_label_values(&[service_name]).inc();
+        metrics.pprof_samples_total
+            .with_label_values(&[service_name])
+            .inc_by(builder.pprof_builder.profile.sample.len() as f64);
+
+        let started = Instant::now();
+        //info!("{:?}",&builder.pprof_builder.profile);
+        builder.write_to(encode_buf);
+        encode += started.elapsed();
+
+        let raw_profile = Bytes::copy_from_slice(encode_buf);
+        metrics.pprof_bytes_total.with_label_values(&[service_name]).inc_by(raw_profile.len() as f64);
+        encoded.push((key, builder, RetainedProfile { service_name: service_name.to_string(), raw_profile }));
+    }
+    if retention.enabled() {
+        retention.add_round(encoded.iter().map(|(_, _, p)| p.clone()).collect());
+    }
+    let profiles = encoded.len();
+    let Some(appendable) = appendable else {
+        stages.with_label_values(&["pprof_encode"]).observe(encode.as_secs_f64());
+        log_round(&summary, profiles, 0, 0, started.elapsed());
+        return Ok(());
+    };
+
+    let mut pushed_bytes = 0;
+    let mut dropped = 0;
+    let mut last_err = None;
+    for (key, builder, profile) in encoded {
+        let raw_profile = profile.raw_profile;
+        let size = raw_profile.len();
+        let id = key.profile_id(builder.pprof_builder.profile.time_nanos);
+        let samples = vec![
+            push_api::RawSample { raw_profile, id }
+        ];
+        let started = Instant::now();
+        let appender = appendable.appender();
+        let result = appender.append(
+            builder.labels.clone(),
+            samples
+        );
+        push += started.elapsed();
+        // the other profiles of the round still get their chance when one can't be queued
+        match result {
+            Ok(()) => pushed_bytes += size,
+            Err(err) => {
+                dropped += 1;
+                last_err = Some(err);
+            }
+        }
+    }
+    if let Some(err) = last_err {
+        warn!("ebpf round dropped {} of {} profiles: {}", dropped, profiles, err);
     }
+    stages.with_label_values(&["pprof_encode"]).observe(encode.as_secs_f64());
+    stages.with_label_values(&["push"]).observe(push.as_secs_f64());
+    log_round(&summary, profiles, dropped, pushed_bytes, started.elapsed());
+    Ok(())
+}
+
+// log_round logs the summary of a round as one logfmt line, for alerting on degradation from the
+// log pipeline. pushed_bytes are the bytes handed to the push queue, 0 while pushing is paused, and
+// dropped_profiles the profiles the queue had no room for.
+fn log_round(summary: &RoundSummary, profiles: usize, dropped_profiles: usize, pushed_bytes: usize, elapsed: Duration) {
+    info!("ebpf round={} targets={} pids={} samples={} dropped_samples={} unknown_frames={:.1}% profiles={} dropped_profiles={} pushed_bytes={} duration={:.3}s",
+        summary.round, summary.targets, summary.pids, summary.samples, summary.dropped_samples,
+        summary.unknown_share() * 100.0, profiles, dropped_profiles, pushed_bytes, elapsed.as_secs_f64());
 }
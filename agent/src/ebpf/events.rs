@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::error;
+use tokio_util::sync::CancellationToken;
+
+use iwm::ebpf::ring::reader::Reader;
+use iwm::ebpf::session::Session;
+use iwm::ebpf::sync::PidOp;
+use iwm::error::Error::DeadlineExceeded;
+
+// READ_TIMEOUT bounds a wait for the rings, so a cancelled reader stops within it
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct PidEvent {
+    pub op: u32,
+    pub pid: u32,
+}
+
+// read_pid_events hands the pid events of the bpf programs to the session until cancelled. It
+// blocks on the rings, run it with spawn_blocking or on its own thread.
+pub fn read_pid_events(mut reader: Reader, session: Arc<Mutex<Session<'static>>>, cancel: CancellationToken) {
+    while !cancel.is_cancelled() {
+        reader.set_deadline(Some(Instant::now() + READ_TIMEOUT));
+        let record = match reader.read_events() {
+            Ok(record) => record,
+            Err(DeadlineExceeded) => continue,
+            Err(err) => {
+                error!("reading from perf event reader: {}", err);
+                continue;
+            }
+        };
+        if record.lost_samples != 0 {
+            error!("perf event ring buffer full, dropped samples: {}", record.lost_samples);
+        }
+        for raw_sample in record.raw_samples.iter() {
+            if raw_sample.len() < 8 {
+                error!("perf event record too small: {}", raw_sample.len());
+                continue;
+            }
+            let e = PidEvent {
+                op: u32::from_le_bytes([raw_sample[0], raw_sample[1], raw_sample[2], raw_sample[3]]),
+                pid: u32::from_le_bytes([raw_sample[4], raw_sample[5], raw_sample[6], raw_sample[7]]),
+            };
+            handle_pid_event(&session, e);
+        }
+    }
+}
+
+fn handle_pid_event(session: &Mutex<Session<'static>>, e: PidEvent) {
+    let mut s = session.lock().unwrap();
+    if e.op == PidOp::RequestUnknownProcessInfo.to_u32() {
+        if s.process_pid_info_requests(e.pid).is_err() {
+            error!("pid info request queue full, dropping request: {}", e.pid);
+        }
+    } else if e.op == PidOp::Dead.to_u32() {
+        if s.process_dead_pids_events(e.pid).is_err() {
+            error!("dead pid info queue full, dropping event: {}", e.pid);
+        }
+    } else if e.op == PidOp::RequestExecProcessInfo.to_u32() {
+        if s.process_pid_exec_requests(e.pid).is_err() {
+            error!("pid exec request queue full, dropping event: {}", e.pid);
+        }
+    } else if e.op == PidOp::MapsChanged.to_u32() {
+        if let Err(err) = s.process_maps_changed(e.pid) {
+            error!("maps changed event of pid {}: {}", e.pid, err);
+        }
+    } else {
+        error!("unknown perf event record: op={}, pid={}", e.op, e.pid);
+    }
+}
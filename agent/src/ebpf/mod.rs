@@ -1,2 +1,7 @@
 pub mod args;
-pub mod ebpf_linux;
\ No newline at end of file
+pub mod ebpf_linux;
+pub mod events;
+pub mod pause;
+pub mod retention;
+pub mod schedule;
+pub mod window;
\ No newline at end of file
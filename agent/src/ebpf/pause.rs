@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use iwm::ebpf::metrics::ebpf_metrics::EbpfMetrics;
+
+// PauseMode is what a pause of the ingestion stops, discovery and the pid events go on either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseMode {
+    // Push keeps collecting, the profiles of the rounds are retained but not pushed
+    Push,
+    // Sampling also stops the perf events, the rounds are skipped
+    Sampling,
+}
+
+impl PauseMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "push" => Some(PauseMode::Push),
+            "sampling" => Some(PauseMode::Sampling),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PauseMode::Push => "push",
+            PauseMode::Sampling => "sampling",
+        }
+    }
+}
+
+// IngestionPause is the pause state set through the admin api, e.g. during a maintenance window of
+// the backend
+pub struct IngestionPause {
+    inner: Mutex<Option<Paused>>,
+    metrics: Arc<EbpfMetrics>,
+}
+
+struct Paused {
+    mode: PauseMode,
+    since: SystemTime,
+    // accounted is up to when the paused time was added to the metrics
+    accounted: Instant,
+}
+
+// PauseStatus is the answer of the admin api
+#[derive(Debug, Serialize)]
+pub struct PauseStatus {
+    pub paused: bool,
+    pub mode: Option<PauseMode>,
+    // since is the unix time in seconds the pause started
+    pub since: Option<u64>,
+}
+
+impl IngestionPause {
+    pub fn new(metrics: Arc<EbpfMetrics>) -> Self {
+        Self { inner: Mutex::new(None), metrics }
+    }
+
+    // pause stops the ingestion. Pausing again switches to the new mode, the pause keeps its start.
+    pub fn pause(&self, mode: PauseMode) -> PauseStatus {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.as_mut() {
+            Some(paused) => {
+                self.account(paused);
+                paused.mode = mode;
+            }
+            None => {
+                *inner = Some(Paused { mode, since: SystemTime::now(), accounted: Instant::now() });
+                self.metrics.ingestion_paused.set(1.0);
+            }
+        }
+        status(inner.as_ref())
+    }
+
+    pub fn resume(&self) -> PauseStatus {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(mut paused) = inner.take() {
+            self.account(&mut paused);
+            self.metrics.ingestion_paused.set(0.0);
+        }
+        status(None)
+    }
+
+    // mode is checked every round, it also keeps the paused time metric current
+    pub fn mode(&self) -> Option<PauseMode> {
+        let mut inner = self.inner.lock().unwrap();
+        let paused = inner.as_mut()?;
+        self.account(paused);
+        Some(paused.mode)
+    }
+
+    pub fn status(&self) -> PauseStatus {
+        status(self.inner.lock().unwrap().as_ref())
+    }
+
+    // round_paused counts a round that was not pushed because of the pause
+    pub fn round_paused(&self, mode: PauseMode) {
+        self.metrics.paused_rounds.with_label_values(&[mode.as_str()]).inc();
+    }
+
+    fn account(&self, paused: &mut Paused) {
+        let now = Instant::now();
+        self.metrics.ingestion_paused_seconds.inc_by(now.duration_since(paused.accounted).as_secs_f64());
+        paused.accounted = now;
+    }
+}
+
+fn status(paused: Option<&Paused>) -> PauseStatus {
+    PauseStatus {
+        paused: paused.is_some(),
+        mode: paused.map(|p| p.mode),
+        since: paused.map(|p| p.since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+    }
+}
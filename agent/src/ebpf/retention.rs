@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use prost::bytes::Bytes;
+
+// ProfileRetention keeps the encoded profiles of the last rounds in memory, bounded by rounds and
+// bytes, so recent profiles of a node can be fetched locally even when pushing them failed.
+pub struct ProfileRetention {
+    max_rounds: usize,
+    max_bytes: usize,
+    inner: Mutex<Retained>,
+}
+
+#[derive(Default)]
+struct Retained {
+    rounds: VecDeque<Round>,
+    bytes: usize,
+}
+
+struct Round {
+    time: SystemTime,
+    profiles: Vec<RetainedProfile>,
+    bytes: usize,
+}
+
+#[derive(Clone)]
+pub struct RetainedProfile {
+    pub service_name: String,
+    pub raw_profile: Bytes,
+}
+
+impl ProfileRetention {
+    // new keeps up to max_rounds rounds and max_bytes of encoded profiles, zero of either disables it
+    pub fn new(max_rounds: usize, max_bytes: usize) -> Self {
+        Self { max_rounds, max_bytes, inner: Mutex::new(Retained::default()) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_rounds > 0 && self.max_bytes > 0
+    }
+
+    // add_round retains the profiles of a collection round, evicting the oldest rounds over the limits.
+    // A round larger than max_bytes on its own is not retained.
+    pub fn add_round(&self, profiles: Vec<RetainedProfile>) {
+        if !self.enabled() {
+            return;
+        }
+        let bytes = profiles.iter().map(|p| p.raw_profile.len()).sum();
+        if bytes > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.bytes += bytes;
+        inner.rounds.push_back(Round { time: SystemTime::now(), profiles, bytes });
+        while inner.rounds.len() > self.max_rounds || inner.bytes > self.max_bytes {
+            let evicted = inner.rounds.pop_front().unwrap();
+            inner.bytes -= evicted.bytes;
+        }
+    }
+
+    // profiles returns the retained profiles of the rounds in the last since, oldest first,
+    // optionally only those of a service
+    pub fn profiles(&self, since: Duration, service_name: Option<&str>) -> Vec<RetainedProfile> {
+        let from = SystemTime::now().checked_sub(since).unwrap_or(SystemTime::UNIX_EPOCH);
+        let inner = self.inner.lock().unwrap();
+        inner.rounds.iter()
+            .filter(|r| r.time >= from)
+            .flat_map(|r| r.profiles.iter())
+            .filter(|p| service_name.is_none() || service_name == Some(p.service_name.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    // usage returns the number of retained rounds and their bytes
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.rounds.len(), inner.bytes)
+    }
+}
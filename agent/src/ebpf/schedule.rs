@@ -0,0 +1,76 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::Instant;
+
+// CollectSchedule is how the collection rounds are placed in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectSchedule {
+    // Absolute places the rounds at multiples of the collect interval since the unix epoch, so the
+    // profiles of every agent are evenly spaced and cover the same windows. Early rounds don't move
+    // the schedule.
+    Absolute,
+    // Relative places the rounds a collect interval apart starting right away, an early round starts
+    // the interval over
+    Relative,
+}
+
+impl CollectSchedule {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "absolute" => Some(CollectSchedule::Absolute),
+            "relative" => Some(CollectSchedule::Relative),
+            _ => None,
+        }
+    }
+}
+
+// RoundSchedule is the time of the next collection round, start + N * period. A round that runs
+// long doesn't push the ones after it back, the points it ran over are skipped instead.
+pub struct RoundSchedule {
+    mode: CollectSchedule,
+    period: Duration,
+    start: Instant,
+    next: u32,
+}
+
+impl RoundSchedule {
+    pub fn new(mode: CollectSchedule, period: Duration) -> Self {
+        let now = Instant::now();
+        match mode {
+            CollectSchedule::Relative => Self { mode, period, start: now, next: 0 },
+            CollectSchedule::Absolute => {
+                // the first round is at the next multiple of period since the epoch. The schedule starts
+                // there rather than at the last one, which can be before the earliest Instant when the
+                // host booted less than a period ago.
+                let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let offset = Duration::from_nanos((since_epoch.as_nanos() % period.as_nanos()) as u64);
+                Self { mode, period, start: now + (period - offset), next: 0 }
+            }
+        }
+    }
+
+    // deadline is when the next round is due
+    pub fn deadline(&self) -> Instant {
+        self.start + self.period * self.next
+    }
+
+    // advance moves on to the first point after now, returning the number of points skipped besides
+    // the one that was due
+    pub fn advance(&mut self, now: Instant) -> u32 {
+        let mut skipped = 0;
+        self.next += 1;
+        while self.deadline() <= now {
+            self.next += 1;
+            skipped += 1;
+        }
+        skipped
+    }
+
+    // early_round moves a relative schedule to a full period after now, an absolute one keeps its points
+    pub fn early_round(&mut self, now: Instant) {
+        if self.mode == CollectSchedule::Relative {
+            self.start = now;
+            self.next = 1;
+        }
+    }
+}
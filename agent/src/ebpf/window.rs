@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::oneshot;
+
+use iwm::common::collector::ProfileSample;
+use iwm::ebpf::pprof::{BuildersOptions, ProfileBuilders};
+
+// MAX_WINDOW caps on-demand windows so a forgotten request doesn't hold samples forever
+pub const MAX_WINDOW: Duration = Duration::from_secs(300);
+// MAX_WINDOWS caps the windows open at once, each one keeps its own profile builders
+pub const MAX_WINDOWS: usize = 8;
+
+// ProfileWindows are on-demand profiles of a single pid. They are fed the samples of the regular
+// collection rounds and answered with the pprof once the first round after their end is done.
+pub struct ProfileWindows {
+    windows: Mutex<Vec<ProfileWindow>>,
+    // options are the sampling setup of the session the windows are fed from
+    options: BuildersOptions,
+}
+
+struct ProfileWindow {
+    pid: u32,
+    started: SystemTime,
+    until: Instant,
+    builders: ProfileBuilders,
+    done: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+impl ProfileWindows {
+    pub fn new(options: BuildersOptions) -> Self {
+        Self { windows: Mutex::new(Vec::new()), options }
+    }
+
+    // check_frequency tells whether a window can sample at frequency. Windows are fed the samples of
+    // the session's perf events, they can't sample faster or slower than the session does.
+    pub fn check_frequency(&self, frequency: u64) -> Result<(), String> {
+        match self.options.sample_period {
+            Some(period) => Err(format!("the session samples every {} events, windows can't set a frequency", period)),
+            None if frequency != self.options.sample_rate as u64 => Err(format!(
+                "windows sample at the session rate of {}Hz, not {}Hz", self.options.sample_rate, frequency)),
+            None => Ok(()),
+        }
+    }
+
+    // open starts a window for the pid, None when MAX_WINDOWS are already open. The receiver gets
+    // the encoded profile, or None when no sample of the pid was taken during the window.
+    pub fn open(&self, pid: u32, duration: Duration) -> Option<oneshot::Receiver<Option<Vec<u8>>>> {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_WINDOWS {
+            return None;
+        }
+        let (done, receiver) = oneshot::channel();
+        windows.push(ProfileWindow {
+            pid,
+            started: SystemTime::now(),
+            until: Instant::now() + duration.min(MAX_WINDOW),
+            builders: ProfileBuilders::new(BuildersOptions {
+                per_pid_profile: true,
+                max_pids_per_service: 0,
+                ..self.options
+            }),
+            done,
+        });
+        Some(receiver)
+    }
+
+    pub fn add_sample(&self, sample: &ProfileSample) {
+        let mut windows = self.windows.lock().unwrap();
+        for w in windows.iter_mut().filter(|w| w.pid == sample.pid) {
+            w.builders.add_sample(sample.clone());
+        }
+    }
+
+    // close_expired answers the windows that ended, it is called after every collection round
+    pub fn close_expired(&self) {
+        let now = Instant::now();
+        let expired: Vec<ProfileWindow> = {
+            let mut windows = self.windows.lock().unwrap();
+            let (expired, active) = windows.drain(..).partition(|w| w.until <= now);
+            *windows = active;
+            expired
+        };
+        for mut w in expired {
+            let started = w.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let profile = w.builders.builders.values_mut().next().map(|builder| {
+                builder.pprof_builder.profile.time_nanos = started.as_nanos() as i64;
+                builder.pprof_builder.profile.duration_nanos = w.started.elapsed().unwrap_or_default().as_nanos() as i64;
+                let mut buf = Vec::new();
+                builder.write_to(&mut buf);
+                buf
+            });
+            // the requester may have gone away
+            let _ = w.done.send(profile);
+        }
+    }
+}
@@ -33,12 +33,30 @@ pub struct RawProfileSeries {
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RawSample {
     /// raw_profile is the set of bytes of the pprof profile
-    #[prost(bytes = "vec", tag = "1")]
-    pub raw_profile: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bytes = "bytes", tag = "1")]
+    pub raw_profile: ::prost::bytes::Bytes,
     /// unique ID of the profile
     #[prost(string, tag = "2")]
     pub id: ::prost::alloc::string::String,
 }
+/// PushChunk is a part of a raw pprof profile sent over PushStream.
+/// Chunks of the same sample are sent in order and the sample is complete once a chunk with last set arrives.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PushChunk {
+    /// labels of the series the sample belongs to, only set on the first chunk of a sample
+    #[prost(message, repeated, tag = "1")]
+    pub labels: ::prost::alloc::vec::Vec<LabelPair>,
+    /// unique ID of the profile
+    #[prost(string, tag = "2")]
+    pub id: ::prost::alloc::string::String,
+    /// data is the next part of the pprof profile bytes
+    #[prost(bytes = "bytes", tag = "3")]
+    pub data: ::prost::bytes::Bytes,
+    /// last is set on the final chunk of a sample
+    #[prost(bool, tag = "4")]
+    pub last: bool,
+}
 /// Generated client implementations.
 pub mod pusher_service_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -146,5 +164,28 @@ pub mod pusher_service_client {
                 .insert(GrpcMethod::new("push.v1.PusherService", "Push"));
             self.inner.unary(req, path, codec).await
         }
+        /// PushStream receives profiles too large for a single message as a stream of chunks
+        pub async fn push_stream(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::PushChunk>,
+        ) -> std::result::Result<tonic::Response<super::PushResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/push.v1.PusherService/PushStream",
+            );
+            let mut req = request.into_streaming_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("push.v1.PusherService", "PushStream"));
+            self.inner.client_streaming(req, path, codec).await
+        }
     }
 }
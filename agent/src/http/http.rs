@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{error, warn};
+use prost::Message;
+use prometheus::{Encoder, Registry, TextEncoder};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use iwm::ebpf::diagnostics::BpfDiagnostics;
+use iwm::ebpf::pprof;
+use iwm::ebpf::pprof::profile::Profile;
+use iwm::ebpf::sd::target::TargetFinder;
+use iwm::ebpf::session::Session;
+
+use crate::common::component::Component;
+use crate::discover::discover::ADDRESS_LABEL;
+use crate::ebpf::pause::{IngestionPause, PauseMode, PauseStatus};
+use crate::ebpf::retention::ProfileRetention;
+use crate::ebpf::window::{ProfileWindows, MAX_WINDOWS};
+use crate::metrics::build_info::BuildInfo;
+
+pub const METRICS_PATH: &str = "/metrics";
+pub const ELF_TABLES_PATH: &str = "/debug/elf_tables";
+pub const SESSION_DEBUG_PATH: &str = "/debug/session";
+pub const BPF_DEBUG_PATH: &str = "/debug/bpf";
+pub const PROFILE_PATH: &str = "/debug/pprof/ebpf";
+pub const RETAINED_PROFILE_PATH: &str = "/debug/pprof/retained";
+pub const STATUS_PATH: &str = "/api/v1/status";
+pub const DISCOVERED_TARGETS_PATH: &str = "/api/v1/discovered-targets";
+pub const PAUSE_PATH: &str = "/-/pause";
+pub const RESUME_PATH: &str = "/-/resume";
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+const DEFAULT_RETAINED_SECONDS: u64 = 300;
+const DEFAULT_ELF_TABLES_LIMIT: usize = 20;
+
+#[derive(Clone)]
+pub struct Arguments {
+    // listen_address is loopback by default, the debug endpoints have no authentication
+    pub listen_address: SocketAddr,
+    // admin_token is the bearer token of the admin endpoints, PAUSE_PATH and RESUME_PATH. Without a
+    // token only loopback clients may use them.
+    pub admin_token: Option<String>,
+}
+
+impl Default for Arguments {
+    fn default() -> Self {
+        Self {
+            listen_address: SocketAddr::from(([127, 0, 0, 1], 12345)),
+            admin_token: None,
+        }
+    }
+}
+
+struct State {
+    admin_token: Option<String>,
+    registry: Arc<Registry>,
+    session: Arc<Mutex<Session<'static>>>,
+    target_finder: Arc<TargetFinder>,
+    windows: Arc<ProfileWindows>,
+    retention: Arc<ProfileRetention>,
+    build_info: Arc<BuildInfo>,
+    pause: Arc<IngestionPause>,
+}
+
+// Status is the body of STATUS_PATH
+#[derive(Serialize)]
+struct Status<'a> {
+    #[serde(flatten)]
+    build_info: &'a BuildInfo,
+    attach_mode: &'static str,
+    hook_attach: &'static str,
+    ingestion: PauseStatus,
+}
+
+// TargetGroup is a target group of the prometheus http service discovery format
+#[derive(Serialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    labels: BTreeMap<String, String>,
+}
+
+// HttpServer serves the agent's own metrics and debug endpoints.
+pub struct HttpServer {
+    args: Arguments,
+    state: Arc<State>,
+}
+
+impl HttpServer {
+    pub fn new(
+        args: Arguments,
+        registry: Arc<Registry>,
+        session: Arc<Mutex<Session<'static>>>,
+        windows: Arc<ProfileWindows>,
+        retention: Arc<ProfileRetention>,
+        build_info: Arc<BuildInfo>,
+        pause: Arc<IngestionPause>,
+    ) -> Self {
+        let target_finder = session.lock().unwrap().target_finder.clone();
+        let admin_token = args.admin_token.clone();
+        Self {
+            args,
+            state: Arc::new(State {
+                admin_token, registry, session, target_finder, windows, retention, build_info, pause }),
+        }
+    }
+}
+
+impl Component for HttpServer {
+    // run serves until cancelled, then closes the open connections. Profile requests waiting for
+    // their window are answered with the window's profile or dropped with the connection.
+    async fn run(&mut self, cancel: CancellationToken) {
+        let listener = match TcpListener::bind(self.args.listen_address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("http server listen on {}: {}", self.args.listen_address, err);
+                return;
+            }
+        };
+        let mut connections = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            // reap the finished connections, the set would grow with every connection otherwise
+            while connections.try_join_next().is_some() {}
+            let (stream, peer) = match accepted {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!("http server accept: {}", err);
+                    continue;
+                }
+            };
+            let state = self.state.clone();
+            connections.spawn(async move {
+                let service = service_fn(move |req| handle(req, peer, state.clone()));
+                if let Err(err) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                    warn!("http server connection: {}", err);
+                }
+            });
+        }
+        connections.shutdown().await;
+    }
+}
+
+async fn handle(req: Request<Incoming>, peer: SocketAddr, state: Arc<State>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let res = match req.uri().path() {
+        METRICS_PATH => metrics(&state),
+        ELF_TABLES_PATH => elf_tables(&state, query_limit(req.uri().query())).await,
+        SESSION_DEBUG_PATH => session_debug_info(&state).await,
+        BPF_DEBUG_PATH => bpf_debug_info(),
+        PROFILE_PATH => profile(&state, req.uri().query()).await,
+        RETAINED_PROFILE_PATH => retained_profile(&state, req.uri().query()),
+        STATUS_PATH => status(&state).await,
+        DISCOVERED_TARGETS_PATH => discovered_targets(&state, req.uri().query()),
+        PAUSE_PATH | RESUME_PATH if req.method() != Method::POST => {
+            response(StatusCode::METHOD_NOT_ALLOWED, "use POST\n".to_string())
+        }
+        PAUSE_PATH | RESUME_PATH if !admin_allowed(&state, &req, peer) => {
+            response(StatusCode::FORBIDDEN, "admin endpoints need the admin token or a loopback client\n".to_string())
+        }
+        PAUSE_PATH => pause(&state, req.uri().query()).await,
+        RESUME_PATH => resume(&state).await,
+        _ => response(StatusCode::NOT_FOUND, "not found\n".to_string()),
+    };
+    Ok(res)
+}
+
+// admin_allowed tells whether the request may use the admin endpoints. With an admin token it has to
+// carry the token as a bearer token, without one it has to come from a loopback address.
+fn admin_allowed(state: &State, req: &Request<Incoming>, peer: SocketAddr) -> bool {
+    match &state.admin_token {
+        Some(token) => req.headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| v.as_bytes() == token.as_bytes()),
+        None => peer.ip().is_loopback(),
+    }
+}
+
+fn metrics(state: &State) -> Response<Full<Bytes>> {
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(err) = encoder.encode(&state.registry.gather(), &mut buf) {
+        return response(StatusCode::INTERNAL_SERVER_ERROR, format!("encoding metrics: {}\n", err));
+    }
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Full::new(Bytes::from(buf)))
+        .unwrap()
+}
+
+// status reports the build info of the agent with how the session is attached, as json
+async fn status(state: &State) -> Response<Full<Bytes>> {
+    let (attach_mode, hook_attach) =
+        with_session(state, |session| (session.attach_mode(), session.hook_attach().as_str())).await;
+    let status = Status { build_info: &state.build_info, attach_mode, hook_attach, ingestion: state.pause.status() };
+    match serde_json::to_vec(&status) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap(),
+        Err(err) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("encoding status: {}\n", err)),
+    }
+}
+
+// bpf_debug_info reports the kernel and the failed loads and attaches of the bpf programs, as json.
+// The hook modes the agent fell back from are listed with their verifier logs.
+fn bpf_debug_info() -> Response<Full<Bytes>> {
+    match serde_json::to_vec(&BpfDiagnostics::current()) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap(),
+        Err(err) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("encoding bpf debug info: {}\n", err)),
+    }
+}
+
+// pause stops pushing the profiles until resumed, ?mode=sampling also stops the perf events.
+// Discovery and the pid events go on, so the agent picks up where it was on resume.
+async fn pause(state: &State, query: Option<&str>) -> Response<Full<Bytes>> {
+    let mode = match query_param(query, "mode") {
+        None => PauseMode::Push,
+        Some(mode) => match PauseMode::parse(mode) {
+            Some(mode) => mode,
+            None => return response(StatusCode::BAD_REQUEST, format!("unknown mode {:?}\n", mode)),
+        },
+    };
+    let sampling = mode != PauseMode::Sampling;
+    if let Err(err) = with_session(state, move |session| session.set_sampling(sampling)).await {
+        return response(StatusCode::INTERNAL_SERVER_ERROR, format!("switching sampling: {}\n", err));
+    }
+    let status = state.pause.pause(mode);
+    warn!("ingestion paused, mode {}", mode.as_str());
+    pause_status(&status)
+}
+
+async fn resume(state: &State) -> Response<Full<Bytes>> {
+    if let Err(err) = with_session(state, |session| session.set_sampling(true)).await {
+        return response(StatusCode::INTERNAL_SERVER_ERROR, format!("restarting sampling: {}\n", err));
+    }
+    let status = state.pause.resume();
+    warn!("ingestion resumed");
+    pause_status(&status)
+}
+
+fn pause_status(status: &PauseStatus) -> Response<Full<Bytes>> {
+    match serde_json::to_vec(status) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap(),
+        Err(err) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("encoding pause status: {}\n", err)),
+    }
+}
+
+// discovered_targets exports the targets of the agent in the prometheus http service discovery format.
+// By default the targets are as discovered, ?stage=profile returns the labels their profiles get
+// instead and leaves out targets that aren't profiled.
+fn discovered_targets(state: &State, query: Option<&str>) -> Response<Full<Bytes>> {
+    let profile_labels = match query_param(query, "stage") {
+        None | Some("discovered") => false,
+        Some("profile") => true,
+        Some(stage) => return response(StatusCode::BAD_REQUEST, format!("unknown stage {:?}\n", stage)),
+    };
+    // the target finder is shared with the session, reading it doesn't wait for a round
+    let snapshot = state.target_finder.discovered_targets();
+    let groups: Vec<TargetGroup> = snapshot.into_iter().filter_map(|(discovered, labels)| {
+        let targets = discovered.get(ADDRESS_LABEL).into_iter().cloned().collect();
+        let labels = if profile_labels {
+            labels?.0.into_iter().map(|l| (l.name, l.value)).collect()
+        } else {
+            discovered.into_iter().filter(|(k, _)| k != ADDRESS_LABEL).collect()
+        };
+        Some(TargetGroup { targets, labels })
+    }).collect();
+    match serde_json::to_vec(&groups) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap(),
+        Err(err) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("encoding targets: {}\n", err)),
+    }
+}
+
+// elf_tables dumps the cached elf symbol tables holding the most memory,
+// to help size build_id_cache_size and same_file_cache_size.
+async fn elf_tables(state: &State, limit: usize) -> Response<Full<Bytes>> {
+    let tables = with_session(state, move |session| session.heaviest_elf_tables(limit)).await;
+    let mut body = String::from("bytes\tsymbols\tlast_used_round\tfile\n");
+    for t in tables {
+        let _ = writeln!(body, "{}\t{}\t{}\t{}", t.bytes, t.symbols, t.last_used_round, t.file);
+    }
+    response(StatusCode::OK, body)
+}
+
+// session_debug_info dumps the symbol caches of the session as json, `agent debug diff` compares two of them
+async fn session_debug_info(state: &State) -> Response<Full<Bytes>> {
+    let Some(info) = with_session(state, |session| session.debug_info()).await else {
+        return response(StatusCode::SERVICE_UNAVAILABLE, "symbol cache is poisoned\n".to_string());
+    };
+    match serde_json::to_vec(&info) {
+        Ok(body) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap(),
+        Err(err) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("encoding session debug info: {}\n", err)),
+    }
+}
+
+// profile records the samples of a pid for ?seconds= (30 by default) and returns them as pprof,
+// e.g. /debug/pprof/ebpf?pid=1234&seconds=60. The answer comes with the first round after the window.
+// Windows sample at the rate of the session, a ?frequency= other than that is rejected.
+async fn profile(state: &State, query: Option<&str>) -> Response<Full<Bytes>> {
+    let Some(pid) = query_param(query, "pid").and_then(|v| v.parse::<u32>().ok()) else {
+        return response(StatusCode::BAD_REQUEST, "pid is required\n".to_string());
+    };
+    let seconds = query_param(query, "seconds")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_PROFILE_SECONDS);
+    if let Some(frequency) = query_param(query, "frequency") {
+        let Ok(frequency) = frequency.parse::<u64>() else {
+            return response(StatusCode::BAD_REQUEST, format!("invalid frequency {:?}\n", frequency));
+        };
+        if let Err(err) = state.windows.check_frequency(frequency) {
+            return response(StatusCode::BAD_REQUEST, format!("{}\n", err));
+        }
+    }
+    let Some(receiver) = state.windows.open(pid, Duration::from_secs(seconds)) else {
+        return response(StatusCode::TOO_MANY_REQUESTS, format!("{} profile windows are already open\n", MAX_WINDOWS));
+    };
+    match receiver.await {
+        Ok(Some(profile)) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header(hyper::header::CONTENT_DISPOSITION, format!("attachment; filename=\"ebpf-{}.pb\"", pid))
+            .body(Full::new(Bytes::from(profile)))
+            .unwrap(),
+        Ok(None) => response(StatusCode::NOT_FOUND, format!("no samples of pid {}\n", pid)),
+        Err(_) => response(StatusCode::SERVICE_UNAVAILABLE, "profiling stopped\n".to_string()),
+    }
+}
+
+// retained_profile merges the retained profiles of the last ?seconds= (300 by default) into one pprof,
+// optionally of a single ?service=, e.g. /debug/pprof/retained?service=api&seconds=120
+fn retained_profile(state: &State, query: Option<&str>) -> Response<Full<Bytes>> {
+    if !state.retention.enabled() {
+        return response(StatusCode::NOT_FOUND, "profile retention is disabled\n".to_string());
+    }
+    let seconds = query_param(query, "seconds")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RETAINED_SECONDS);
+    let service = query_param(query, "service");
+    let mut profiles = Vec::new();
+    for retained in state.retention.profiles(Duration::from_secs(seconds), service) {
+        match Profile::decode(retained.raw_profile) {
+            Ok(profile) => profiles.push(profile),
+            Err(err) => warn!("decoding retained profile of {}: {}", retained.service_name, err),
+        }
+    }
+    if profiles.is_empty() {
+        let (rounds, bytes) = state.retention.usage();
+        return response(StatusCode::NOT_FOUND,
+            format!("no retained profiles in the last {}s ({} rounds, {} bytes retained)\n", seconds, rounds, bytes));
+    }
+    match pprof::merge(&profiles) {
+        Ok(merged) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header(hyper::header::CONTENT_DISPOSITION, "attachment; filename=\"ebpf-retained.pb\"")
+            .body(Full::new(Bytes::from(merged.encode_to_vec())))
+            .unwrap(),
+        Err(err) => response(StatusCode::INTERNAL_SERVER_ERROR, format!("merging retained profiles: {}\n", err)),
+    }
+}
+
+// with_session runs f with the session on a blocking thread. A collection round holds the session for
+// its whole duration, waiting for it would block a runtime thread.
+async fn with_session<T, F>(state: &State, f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Session<'static>) -> T + Send + 'static,
+{
+    let session = state.session.clone();
+    tokio::task::spawn_blocking(move || f(&mut session.lock().unwrap())).await.unwrap()
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query.unwrap_or_default()
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find_map(|(k, v)| (k == name).then_some(v))
+}
+
+fn query_limit(query: Option<&str>) -> usize {
+    query.unwrap_or_default()
+        .split('&')
+        .filter_map(|kv| kv.strip_prefix("limit="))
+        .find_map(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_ELF_TABLES_LIMIT)
+}
+
+fn response(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
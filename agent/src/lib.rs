@@ -2,6 +2,10 @@
 pub mod appender;
 pub mod write;
 pub mod common;
+pub mod config;
 pub mod ebpf;
 pub mod metrics;
 pub mod discover;
+pub mod http;
+pub mod security;
+pub mod debug;
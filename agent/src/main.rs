@@ -1,41 +1,105 @@
-use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::{panic, thread};
 use std::ops::Deref;
 
 
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use log::{error, info};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
 use prometheus::Registry;
-use log::LevelFilter;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 
-use log4rs::append::console::ConsoleAppender;
+use log4rs::append::console::{ConsoleAppender, Target as ConsoleTarget};
 use log4rs::config::{Appender, Root};
-use log4rs::Config;
 
+use agent::common::client::DEFAULT_USER_AGENT;
 use agent::common::component::Component;
+use agent::config::config::Config;
+use agent::common::data_dir::DataDir;
+use agent::common::host::HostInfo;
 use agent::common::registry::Options;
+use agent::debug;
 use agent::discover::discover;
+use agent::discover::discover::Target;
 use agent::discover::docker_discovery::DockerDiscovery;
+use agent::discover::kubelet;
+use agent::discover::kubelet::KubeletDiscovery;
 use agent::ebpf::ebpf_linux;
 use agent::ebpf::ebpf_linux::{EbpfLinuxComponent};
+use agent::ebpf::events::read_pid_events;
+use agent::ebpf::schedule::CollectSchedule;
+use agent::http::http;
+use agent::http::http::HttpServer;
+use agent::metrics::build_info::BuildInfo;
+use agent::security::privileges::{drop_privileges, PrivilegeDrop};
+use agent::security::seccomp;
+use agent::security::seccomp::SeccompMode;
+use agent::write::metadata::MetadataJoin;
+use agent::write::scrub::LabelScrubbing;
 use agent::write::write;
-use agent::write::write::WriteComponent;
+use agent::write::write::{FanOutClient, WriteComponent};
+use iwm::ebpf::metrics::ring::RingMetrics;
+use iwm::ebpf::pressure::LoadSheddingOptions;
+use iwm::ebpf::pprof::rewrite::StackRewrite;
+use iwm::ebpf::probe::HookAttach;
+use iwm::ebpf::ring::perf_buffer::PerfBufferOptions;
 use iwm::ebpf::ring::reader::Reader;
-use iwm::ebpf::sync::PidOp;
+use iwm::ebpf::sd::journal::JournalOptions;
+use iwm::error::Result as IwmResult;
 
-fn my_get_service_data(_name: &str) -> Result<Box<dyn Any>, String> {
-    // Implement your logic here
-    // This is just a placeholder implementation
-    Ok(Box::new(0))
+const DEFAULT_DATA_PATH: &str = "/var/lib/iwm-agent";
+const DEFAULT_ENDPOINT_URL: &str = "http://172.16.68.1:4040";
+// ENDPOINT_URL_ENV overrides the push endpoint, the end-to-end tests point it at their own server
+const ENDPOINT_URL_ENV: &str = "IWM_ENDPOINT_URL";
+
+// Cli is the command line of the agent, without a subcommand it runs the agent
+#[derive(Parser, Debug)]
+#[command(about = "eBPF profiling agent")]
+struct Cli {
+    /// yaml configuration file, see agent/src/config/config.rs
+    #[arg(long, global = true)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Default, Copy, Clone)]
-#[repr(C)]
-pub struct pid_event {
-    pub op: u32,
-    pub pid: u32,
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Run the agent, the default")]
+    Run,
+    #[command(about = "Load the configuration and validate the arguments it results in, without starting anything")]
+    CheckConfig,
+    #[command(about = "Print the targets discovery resolves, as json")]
+    Targets,
+    #[command(about = "Inspect a running agent, see agent debug with no arguments")]
+    Debug {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
+// watch_signals cancels the token on SIGINT or SIGTERM, stopping the agent, and asks for a reload
+// of the configuration on SIGHUP
+fn watch_signals(cancel: CancellationToken, reload: mpsc::Sender<()>) {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).unwrap();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                // a reload that is still waiting reads the file after this signal anyway
+                let _ = reload.try_send(());
+                continue;
+            }
+            info!("received signal {}, stopping", signal);
+            cancel.cancel();
+            return;
+        }
+    });
 }
 
 #[tokio::main]
@@ -43,126 +107,327 @@ pub struct pid_event {
 #[allow(unused_variables)]
 #[allow(async_fn_in_trait)]
 async fn main() -> Result<(), ()> {
-    let stdout = ConsoleAppender::builder().build();
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .build(Root::builder().appender("stdout").build(LevelFilter::Debug))
+    let cli = Cli::parse();
+    if let Some(Command::Debug { args }) = &cli.command {
+        return debug::run_command(args).await.map_err(|err| eprintln!("{}", err));
+    }
+    let config = cli.config.as_deref()
+        .map(Config::load)
+        .transpose()
+        .map_err(|err| eprintln!("{}", err))?
+        .unwrap_or_default();
+    let command = cli.command.unwrap_or(Command::Run);
+    // the other commands print their result on stdout, their log goes to stderr
+    let log_target = match command {
+        Command::Run => ConsoleTarget::Stdout,
+        _ => ConsoleTarget::Stderr,
+    };
+    let console = ConsoleAppender::builder().target(log_target).build();
+    let log_config = log4rs::Config::builder()
+        .appender(Appender::builder().build("console", Box::new(console)))
+        .build(Root::builder().appender("console").build(config.log_level().unwrap()))
         .unwrap();
-    let _handle = log4rs::init_config(config).unwrap();
+    let _handle = log4rs::init_config(log_config).unwrap();
 
-    panic::set_hook(Box::new(|panic_info| {
-        error!("{:?}", panic_info.to_string());
-        let backtrace = std::backtrace::Backtrace::capture();
-        error!("My backtrace: {:#?}", backtrace);
-    }));
+    match command {
+        Command::CheckConfig => check_config(&config).map_err(|err| eprintln!("{}", err)),
+        Command::Targets => {
+            // the static targets of the configuration follow the discovered ones
+            let mut targets = discover_targets(&config).await.map_err(|err| eprintln!("{}", err))?;
+            targets.extend(config.targets.iter().cloned());
+            let targets: Vec<BTreeMap<String, String>> = targets.into_iter()
+                .map(|target| target.into_iter().collect())
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&targets).unwrap());
+            Ok(())
+        }
+        Command::Run => run(cli.config, config).await,
+        Command::Debug { .. } => unreachable!(),
+    }
+}
 
-    let discovery_args = discover::Arguments {
-        ..Default::default()
-    };
+// check_config validates the arguments the configuration results in, the data dir is left alone
+fn check_config(config: &Config) -> IwmResult<()> {
+    let write_args = write_arguments(config)?;
+    write_args.validate()?;
+    if let Some(metadata) = &write_args.metadata {
+        MetadataJoin::open(metadata.clone())?;
+    }
+    let journal = Path::new(&data_path(config)).join("journal");
+    let ebpf_args = ebpf_arguments(config, Arc::new(Vec::new()), Vec::new(), Vec::new(), Some(journal))?;
+    ebpf_args.validate()?;
+    println!("config ok, features: {}", ebpf_args.features().join(", "));
+    Ok(())
+}
+
+// discover_targets returns the docker containers with their pod metadata
+async fn discover_targets(config: &Config) -> IwmResult<Vec<Target>> {
+    let mut discovery_args = discover::Arguments::default();
+    config.apply_discovery(&mut discovery_args);
     let discovery_component = DockerDiscovery::new(discovery_args);
-    let targets = discovery_component.refresh().await;
-    let option = Options {
-        id: "sdf".to_string(),
-        data_path: "/opt".to_string(),
-        registerer: Arc::new(Registry::new()),
-        get_service_data: my_get_service_data
-    };
+    let mut targets = discovery_component.refresh().await;
+    // in a pod, the container targets get their pod metadata from the kubelet
+    if std::env::var_os("KUBERNETES_SERVICE_HOST").is_some() {
+        let mut kubelet_args = discover::KubeletArguments::default();
+        config.apply_kubelet(&mut kubelet_args);
+        let kubelet_discovery = KubeletDiscovery::new(kubelet_args)?;
+        match kubelet_discovery.refresh().await {
+            Ok(pod_targets) => kubelet::enrich(&mut targets, pod_targets),
+            Err(err) => error!("kubelet pod discovery: {}", err),
+        }
+    }
+    Ok(targets)
+}
 
-    let write_args = write::Arguments {
+fn data_path(config: &Config) -> String {
+    config.data_path.clone().unwrap_or_else(|| DEFAULT_DATA_PATH.to_string())
+}
+
+fn write_arguments(config: &Config) -> IwmResult<write::Arguments> {
+    let mut write_args = write::Arguments {
         external_labels: HashMap::new(),
+        metadata: None,
+        scrubbing: LabelScrubbing::default(),
         endpoints: Vec::from([write::EndpointOptions {
-            url: "http://172.16.68.1:4040".to_string(),
+            url: std::env::var(ENDPOINT_URL_ENV).unwrap_or_else(|_| DEFAULT_ENDPOINT_URL.to_string()),
             remote_timeout: Duration::from_secs(10),
             min_backoff: Duration::from_millis(500),
             max_backoff: Duration::from_secs(300),
             max_backoff_retries: 10,
             ..Default::default()
-        }])
+        }]),
+        name_convention: write::NameConvention::Labels,
+        dry_run: None,
+        user_agent: DEFAULT_USER_AGENT.to_string(),
+        trace_context: false,
+        queue_capacity: 2048,
+        health_check_interval: Duration::from_secs(30),
     };
-    let (mut write_component, fanout_client) = WriteComponent::new(option.clone(), write_args).await.unwrap();
+    config.apply_write(&mut write_args)?;
+    Ok(write_args)
+}
 
-    let argument = ebpf_linux::Arguments {
-        forward_to: Arc::new(Vec::from([Box::new(fanout_client)])),
+// ebpf_arguments are the ebpf arguments of the configuration, its static targets are added to targets
+fn ebpf_arguments(
+    config: &Config,
+    forward_to: Arc<Vec<Box<FanOutClient>>>,
+    targets: Vec<Target>,
+    profile_comments: Vec<String>,
+    journal: Option<PathBuf>,
+) -> IwmResult<ebpf_linux::Arguments> {
+    let mut argument = ebpf_linux::Arguments {
+        forward_to,
         targets,
         collect_interval: Duration::from_secs(15),
         sample_rate: 97,
+        sample_period: None,
+        precise_ip: 0,
         pid_cache_size: 32,
         build_id_cache_size: 64,
-        same_file_cache_size: 8,
+        same_file_cache_size: 64,
         container_id_cache_size: 1024,
         cache_rounds: 3,
         collect_user_profile: true,
         collect_kernel_profile: true,
-        python_enabled: true
+        python_enabled: true,
+        java_enabled: false,
+        python_full_file_path: false,
+        unknown_symbol_module_offset: false,
+        unknown_symbol_address: false,
+        unknown_symbol_symbolizable: false,
+        per_pid_profile: false,
+        max_pids_per_service: 16,
+        targets_only: true,
+        process_metrics: true,
+        hook_attach: HookAttach::Auto,
+        // about 5 minutes of 15s rounds
+        retention_rounds: 20,
+        retention_bytes: 64 << 20,
+        symbolization_threads: 0,
+        target_time_slice: Some(Duration::from_secs(2)),
+        profile_comments,
+        load_shedding: Some(LoadSheddingOptions::default()),
+        ring_options: PerfBufferOptions::default(),
+        pid_journal: journal.map(JournalOptions::new),
+        early_round_fill_ratio: Some(0.8),
+        stack_rewrite: Arc::new(StackRewrite::default()),
+        collect_schedule: CollectSchedule::Absolute,
     };
-    let mut ebpf_component = EbpfLinuxComponent::new(option.clone(), argument).await.unwrap();
+    config.apply_ebpf(&mut argument)?;
+    Ok(argument)
+}
 
-    info!("Server started");
-    write_component.run().await;
+// reload reads the configuration file again and hands the resulting arguments to the running write
+// and ebpf components. A file that fails to load or validate leaves the running configuration as it is.
+async fn reload(
+    path: Option<&str>,
+    write_updates: &mpsc::Sender<write::Arguments>,
+    ebpf_updates: &mpsc::Sender<ebpf_linux::Arguments>,
+    profile_comments: &[String],
+    journal: Option<&Path>,
+) {
+    let Some(path) = path else {
+        warn!("received SIGHUP, but there is no --config file to reload");
+        return;
+    };
+    info!("reloading {}", path);
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("reloading {}: {}", path, err);
+            return;
+        }
+    };
+    let write_args = write_arguments(&config).and_then(|args| args.validate().map(|_| args));
+    let write_args = match write_args {
+        Ok(args) => args,
+        Err(err) => {
+            error!("reloading {}: {}", path, err);
+            return;
+        }
+    };
+    // the containers are discovered again, the ebpf component keeps pushing to its write component
+    let targets = match discover_targets(&config).await {
+        Ok(targets) => targets,
+        Err(err) => {
+            error!("reloading {}: {}", path, err);
+            return;
+        }
+    };
+    let ebpf_args = ebpf_arguments(&config, Arc::new(Vec::new()), targets, profile_comments.to_vec(), journal.map(Path::to_path_buf))
+        .and_then(|args| args.validate().map(|_| args));
+    let ebpf_args = match ebpf_args {
+        Ok(args) => args,
+        Err(err) => {
+            error!("reloading {}: {}", path, err);
+            return;
+        }
+    };
+    if write_updates.send(write_args).await.is_err() || ebpf_updates.send(ebpf_args).await.is_err() {
+        warn!("reloading {}: the agent is stopping", path);
+    }
+}
 
-    let events_reader = {
-        let mut s = ebpf_component.session.lock().unwrap();
-        s.start().unwrap();
-        Arc::new(Mutex::new(Reader::new(
-            s.bpf.maps().events().deref()
-        ).unwrap()))
+async fn run(config_path: Option<String>, config: Config) -> Result<(), ()> {
+    panic::set_hook(Box::new(|panic_info| {
+        error!("{:?}", panic_info.to_string());
+        let backtrace = std::backtrace::Backtrace::capture();
+        error!("My backtrace: {:#?}", backtrace);
+    }));
+
+    let targets = discover_targets(&config).await.map_err(|err| error!("{}", err))?;
+    let registry = Arc::new(Registry::new());
+    let option = Options {
+        id: env!("CARGO_PKG_NAME").to_string(),
+        data_path: data_path(&config),
+        registerer: registry.clone(),
     };
-    let s = ebpf_component.session.clone();
-    thread::spawn(move || {
-        loop {
-            let mut er = events_reader.lock().unwrap();
-            match er.read_events() {
-                Ok(record) => {
-                    if record.lost_samples != 0 {
-                        error!(
-                                "perf event ring buffer full, dropped samples: {}",
-                                record.lost_samples
-                            );
-                    }
+    // the data dir only holds the pid journal, the agent profiles without it
+    let data_dir = match DataDir::open(&option.data_path) {
+        Ok(data_dir) => {
+            info!("data dir: {}", data_dir.root().display());
+            Some(data_dir)
+        }
+        Err(err) => {
+            error!("opening data dir {}: {}, running without the pid journal", option.data_path, err);
+            None
+        }
+    };
+
+    // the host info goes into the profile comments, external labels would add it to every series
+    let host_info = HostInfo::detect();
+    info!("host: {:?}", host_info);
 
-                    for rs in record.raw_samples.iter() {
-                        let raw_sample = rs.to_vec();
-                        if raw_sample.len() < 8 {
-                            error!("perf event record too small: {}", raw_sample.len());
-                            continue;
-                        }
-
-                        let e = pid_event {
-                            op: u32::from_le_bytes([raw_sample[0], raw_sample[1], raw_sample[2], raw_sample[3]]),
-                            pid: u32::from_le_bytes([raw_sample[4], raw_sample[5], raw_sample[6], raw_sample[7]])
-                        };
-                        dbg!(&e.pid);
-                        if e.op == PidOp::RequestUnknownProcessInfo.to_u32() {
-                            let mut ss = s.lock().unwrap();
-                            match ss.process_pid_info_requests(e.pid) {
-                                Ok(_) => {}
-                                Err(_) => { error!("pid info request queue full, dropping request: {}", e.pid); }
-                            }
-                        } else if e.op == PidOp::Dead.to_u32() {
-                            let mut ss = s.lock().unwrap();
-                            match ss.process_dead_pids_events(e.pid) {
-                                Ok(_) => {}
-                                Err(_) => { error!("dead pid info queue full, dropping event: {}", e.pid); }
-                            }
-                        } else if e.op == PidOp::RequestExecProcessInfo.to_u32() {
-                            let mut ss = s.lock().unwrap();
-                            match ss.process_pid_exec_requests(e.pid) {
-                                Ok(_) => {}
-                                Err(_) => { error!("pid exec request queue full, dropping event: {}", e.pid); }
-                            }
-                        } else {
-                            error!("unknown perf event record: op={}, pid={}", e.op, e.pid);
-                        }
+    let write_args = write_arguments(&config).map_err(|err| error!("{}", err))?;
+    let (mut write_component, fanout_client) = WriteComponent::new(option.clone(), write_args).await.unwrap();
+
+    let argument = ebpf_arguments(
+        &config,
+        Arc::new(Vec::from([Box::new(fanout_client)])),
+        targets,
+        host_info.comments(),
+        data_dir.as_ref().map(DataDir::journal),
+    ).map_err(|err| error!("{}", err))?;
+    let build_info = Arc::new(BuildInfo::new(argument.features()));
+    build_info.register(registry.as_ref());
+    // a kernel refusing the programs is reported with the verifier log rather than a panic
+    let mut ebpf_component = EbpfLinuxComponent::new(option.clone(), argument).await
+        .map_err(|err| error!("starting ebpf profiling: {}", err))?;
+
+    info!("Server started");
+    let cancel = CancellationToken::new();
+    let (reload_sender, mut reloads) = mpsc::channel(1);
+    watch_signals(cancel.clone(), reload_sender);
+    let mut tasks = JoinSet::new();
+    tasks.spawn({
+        let reload_cancel = cancel.child_token();
+        let write_updates = write_component.updates();
+        let ebpf_updates = ebpf_component.updates();
+        let profile_comments = host_info.comments();
+        let journal = data_dir.as_ref().map(DataDir::journal);
+        async move {
+            loop {
+                tokio::select! {
+                    _ = reload_cancel.cancelled() => break,
+                    Some(()) = reloads.recv() => {
+                        reload(config_path.as_deref(), &write_updates, &ebpf_updates, &profile_comments, journal.as_deref()).await;
                     }
                 }
-                Err(err) => {
-                    error!("reading from perf event reader: {}", err);
-                }
             }
         }
     });
+    // the write component outlives the others, it is cancelled once the last round is queued and
+    // pushes what is left in its queue before stopping
+    let write_cancel = CancellationToken::new();
+    tasks.spawn({
+        let write_cancel = write_cancel.clone();
+        async move { write_component.run(write_cancel).await }
+    });
 
-    ebpf_component.run().await;
+    let events_reader = {
+        let mut s = ebpf_component.session.lock().unwrap();
+        s.start().map_err(|err| error!("starting ebpf profiling: {}", err))?;
+        Reader::new(
+            s.bpf.maps().events().deref(),
+            s.ring_options(),
+            RingMetrics::new(option.registerer.as_ref())
+        ).unwrap()
+    };
+    // the programs are loaded and attached and the perf rings open, the rest runs with fewer privileges
+    let privilege_drop = PrivilegeDrop {
+        user: None,
+        keep_caps: vec![],
+        no_new_privs: true,
+    };
+    drop_privileges(&privilege_drop).map_err(|err| error!("dropping privileges: {}", err))?;
+    // audit logs the syscalls the filter would refuse, switch to enforce once the log stays empty
+    seccomp::install(SeccompMode::Audit).map_err(|err| error!("installing the seccomp filter: {}", err))?;
+
+    let session = ebpf_component.session.clone();
+    let events_cancel = cancel.child_token();
+    tasks.spawn_blocking(move || read_pid_events(events_reader, session, events_cancel));
+
+    let mut http_args = http::Arguments::default();
+    config.apply_http(&mut http_args);
+    let mut http_server = HttpServer::new(
+        http_args,
+        registry.clone(),
+        ebpf_component.session.clone(),
+        ebpf_component.windows.clone(),
+        ebpf_component.retention.clone(),
+        build_info,
+        ebpf_component.pause.clone(),
+    );
+    let http_cancel = cancel.child_token();
+    tasks.spawn(async move { http_server.run(http_cancel).await });
+
+    ebpf_component.run(cancel.clone()).await;
+    write_cancel.cancel();
+    while let Some(res) = tasks.join_next().await {
+        if let Err(err) = res {
+            error!("agent task failed: {}", err);
+        }
+    }
 
     info!("Server stopped");
     Ok(())
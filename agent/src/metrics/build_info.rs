@@ -0,0 +1,59 @@
+use std::ffi::CStr;
+use std::path::Path;
+
+use serde::Serialize;
+
+use iwm::ebpf::metrics::registry::Registerer;
+
+// BTF_PATH is where the kernel exposes its BTF, the CO-RE relocations of the bpf programs need it
+const BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+// BuildInfo describes the agent binary and the host it runs on, what support needs to triage field reports
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_sha: String,
+    // features are the profiling features enabled by the arguments
+    pub features: Vec<String>,
+    pub arch: String,
+    pub kernel_version: String,
+    pub btf: bool,
+}
+
+impl BuildInfo {
+    pub fn new(features: Vec<String>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_sha: option_env!("GIT_SHA").unwrap_or("unknown").to_string(),
+            features,
+            arch: std::env::consts::ARCH.to_string(),
+            kernel_version: kernel_release(),
+            btf: Path::new(BTF_PATH).exists(),
+        }
+    }
+
+    // register exports the build info as the labels of a gauge that is always 1
+    pub fn register(&self, reg: &dyn Registerer) {
+        reg.register_gauge_vec(
+            "iwm_agent_build_info",
+            "Version, git sha and kernel of the agent, the value is always 1",
+            &["version", "git_sha", "arch", "kernel_version", "btf"]
+        )
+            .with_label_values(&[
+                &self.version,
+                &self.git_sha,
+                &self.arch,
+                &self.kernel_version,
+                &self.btf.to_string(),
+            ])
+            .set(1.0);
+    }
+}
+
+pub(crate) fn kernel_release() -> String {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return "unknown".to_string();
+    }
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy().into_owned()
+}
@@ -1 +1,2 @@
+pub mod build_info;
 pub mod config;
\ No newline at end of file
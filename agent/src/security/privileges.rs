@@ -0,0 +1,227 @@
+use std::ffi::{c_int, CString};
+use std::fs;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use iwm::ebpf::probe::kernel_version;
+use iwm::error::Error;
+use iwm::error::Result;
+
+pub const CAP_DAC_READ_SEARCH: u32 = 2;
+pub const CAP_NET_BIND_SERVICE: u32 = 10;
+pub const CAP_SYS_PTRACE: u32 = 19;
+pub const CAP_SYS_ADMIN: u32 = 21;
+pub const CAP_SYS_RESOURCE: u32 = 24;
+pub const CAP_SYSLOG: u32 = 34;
+pub const CAP_PERFMON: u32 = 38;
+pub const CAP_BPF: u32 = 39;
+
+// REQUIRED_CAPS are what the agent needs once the programs are loaded: bpf() on the map fds, perf_event_open
+// for cpus coming online, reading /proc/pid/maps and the files under /proc/pid/root of other users, and
+// the kernel addresses in /proc/kallsyms, which read as zeroes without CAP_SYSLOG when it is refreshed
+const REQUIRED_CAPS: [u32; 5] = [CAP_BPF, CAP_PERFMON, CAP_SYS_PTRACE, CAP_DAC_READ_SEARCH, CAP_SYSLOG];
+
+// _LINUX_CAPABILITY_VERSION_3, capabilities are passed as two 32 bit words
+const CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+// PrivilegeDrop describes the privileges the agent keeps after loading its bpf programs
+#[derive(Debug, Clone, Default)]
+pub struct PrivilegeDrop {
+    // user switches to the user, by name or uid, keeping the capabilities. None stays root.
+    pub user: Option<String>,
+    // keep_caps are kept on top of the required ones
+    pub keep_caps: Vec<u32>,
+    // no_new_privs stops execve from granting privileges again, e.g. through setuid binaries
+    pub no_new_privs: bool,
+}
+
+impl PrivilegeDrop {
+    // caps are the capabilities kept. CAP_BPF and CAP_PERFMON only exist since 5.8, older kernels
+    // check CAP_SYS_ADMIN instead.
+    fn caps(&self) -> u64 {
+        let mut caps = REQUIRED_CAPS.iter().chain(&self.keep_caps)
+            .fold(0u64, |caps, cap| caps | 1 << cap);
+        if !matches!(kernel_version(), Some(v) if v >= (5, 8)) {
+            caps |= 1 << CAP_SYS_ADMIN;
+        }
+        caps
+    }
+}
+
+// Capabilities, keepcaps and no_new_privs are per thread, while the runtime threads are already running
+// when the programs are loaded. Like glibc does for setuid, every thread is signalled to apply the
+// pending phase to itself, see broadcast.
+const PHASE_NONE: u8 = 0;
+// PHASE_BOUND drops the bounding set and sets keepcaps, so the capabilities survive setuid
+const PHASE_BOUND: u8 = 1;
+// PHASE_CAPS sets the kept capabilities as effective and permitted, and no_new_privs
+const PHASE_CAPS: u8 = 2;
+
+static PHASE: AtomicU8 = AtomicU8::new(PHASE_NONE);
+static KEEP_CAPS: AtomicU64 = AtomicU64::new(0);
+static NO_NEW_PRIVS: AtomicU8 = AtomicU8::new(0);
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+// BROADCAST_TIMEOUT bounds the wait for the other threads to apply a phase
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// drop_privileges reduces the privileges of every thread of the agent to opts. It must run after the
+// bpf programs are loaded and attached, and the perf rings opened.
+pub fn drop_privileges(opts: &PrivilegeDrop) -> Result<()> {
+    let caps = opts.caps();
+    KEEP_CAPS.store(caps, Ordering::SeqCst);
+    NO_NEW_PRIVS.store(opts.no_new_privs as u8, Ordering::SeqCst);
+
+    run_phase(PHASE_BOUND)?;
+    if let Some(user) = &opts.user {
+        switch_user(user)?;
+    }
+    run_phase(PHASE_CAPS)?;
+    info!("dropped privileges, user: {}, capabilities: {:#x}, no_new_privs: {}",
+        opts.user.as_deref().unwrap_or("unchanged"), caps, opts.no_new_privs);
+    Ok(())
+}
+
+// run_phase applies the phase on every thread, the calling one included
+fn run_phase(phase: u8) -> Result<()> {
+    PHASE.store(phase, Ordering::SeqCst);
+    broadcast()?;
+    if !apply_phase() {
+        return Err(Error::last_os_error(format!("privilege drop phase {}", phase)));
+    }
+    Ok(())
+}
+
+// broadcast signals every other thread to run apply_phase and waits until they all did
+fn broadcast() -> Result<()> {
+    let sig = libc::SIGRTMIN() + 4;
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(c_int) as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(sig, &action, std::ptr::null_mut()) != 0 {
+            return Err(Error::last_os_error("sigaction"));
+        }
+    }
+
+    let pid = unsafe { libc::getpid() };
+    let me = unsafe { libc::gettid() };
+    let tids: Vec<i32> = fs::read_dir("/proc/self/task")
+        .map_err(|e| Error::from_io("read /proc/self/task", &e))?
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|tid| *tid != me)
+        .collect();
+    FAILED.store(0, Ordering::SeqCst);
+    PENDING.store(tids.len(), Ordering::SeqCst);
+    for tid in tids {
+        let ret = unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, sig) };
+        if ret != 0 {
+            // the thread exited meanwhile
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    let deadline = Instant::now() + BROADCAST_TIMEOUT;
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        if Instant::now() > deadline {
+            return Err(Error::SyscallError(format!(
+                "{} threads did not drop their privileges", PENDING.load(Ordering::SeqCst))));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    let failed = FAILED.load(Ordering::SeqCst);
+    if failed > 0 {
+        return Err(Error::SyscallError(format!("{} threads failed to drop their privileges", failed)));
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(_sig: c_int) {
+    if !apply_phase() {
+        FAILED.fetch_add(1, Ordering::SeqCst);
+    }
+    PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
+// apply_phase applies the current phase to the calling thread. It runs in a signal handler, so it
+// only makes syscalls.
+fn apply_phase() -> bool {
+    let caps = KEEP_CAPS.load(Ordering::SeqCst);
+    match PHASE.load(Ordering::SeqCst) {
+        PHASE_BOUND => unsafe {
+            if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
+                return false;
+            }
+            for cap in 0..64u32 {
+                // EINVAL past the last capability the kernel knows
+                if caps & (1 << cap) == 0 && libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) != 0
+                    && *libc::__errno_location() != libc::EINVAL {
+                    return false;
+                }
+            }
+            true
+        },
+        PHASE_CAPS => unsafe {
+            let header = CapHeader { version: CAPABILITY_VERSION_3, pid: 0 };
+            let data = [
+                CapData { effective: caps as u32, permitted: caps as u32, inheritable: 0 },
+                CapData { effective: (caps >> 32) as u32, permitted: (caps >> 32) as u32, inheritable: 0 },
+            ];
+            if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+                return false;
+            }
+            if libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) != 0 {
+                return false;
+            }
+            NO_NEW_PRIVS.load(Ordering::SeqCst) == 0 || libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+        },
+        _ => true,
+    }
+}
+
+// switch_user sets the uid, gid and supplementary groups of the user. glibc applies setuid and setgid
+// to all threads.
+fn switch_user(user: &str) -> Result<()> {
+    let name = CString::new(user).map_err(|_| Error::invalid_data(format!("user {:?}", user)))?;
+    let pw = unsafe {
+        match user.parse::<u32>() {
+            Ok(uid) => libc::getpwuid(uid),
+            Err(_) => libc::getpwnam(name.as_ptr()),
+        }
+    };
+    if pw.is_null() {
+        return Err(Error::NotFound(format!("user {}", user)));
+    }
+    let (uid, gid, pw_name) = unsafe { ((*pw).pw_uid, (*pw).pw_gid, CString::from(std::ffi::CStr::from_ptr((*pw).pw_name))) };
+    unsafe {
+        if libc::initgroups(pw_name.as_ptr(), gid) != 0 {
+            return Err(Error::last_os_error(format!("initgroups {}", user)));
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(Error::last_os_error(format!("setgid {}", gid)));
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(Error::last_os_error(format!("setuid {}", uid)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::ffi::c_long;
+
+use log::info;
+
+use iwm::error::Error;
+use iwm::error::Result;
+
+// SeccompMode is what happens to syscalls outside of ALLOWED_SYSCALLS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompMode {
+    // Audit lets them through and logs them to the kernel audit log, to find what is missing before enforcing
+    Audit,
+    // Enforce fails them with EPERM, and logs them
+    Enforce,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+
+const SECCOMP_SET_MODE_FILTER: u32 = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: u32 = 1;
+const SECCOMP_FILTER_FLAG_LOG: u32 = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+const SECCOMP_RET_ERRNO: u32 = 0x00050000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+
+// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+
+// offsets in struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// ALLOWED_SYSCALLS are the syscalls of the agent after startup: the runtime and std, bpf and perf events,
+// sockets for the http server and the pushes, and reading /proc
+const ALLOWED_SYSCALLS: &[c_long] = &[
+    // files and /proc
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64, libc::SYS_pwrite64,
+    libc::SYS_openat, libc::SYS_close, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_lseek,
+    libc::SYS_readlinkat, libc::SYS_getdents64, libc::SYS_faccessat, libc::SYS_statfs, libc::SYS_fstatfs,
+    libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_mkdirat, libc::SYS_unlinkat, libc::SYS_renameat, libc::SYS_renameat2,
+    libc::SYS_getcwd,
+    // memory
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk,
+    // threads and signals
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_exit, libc::SYS_exit_group, libc::SYS_futex,
+    libc::SYS_set_robust_list, libc::SYS_rseq, libc::SYS_sched_yield, libc::SYS_sched_getaffinity,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+    libc::SYS_tgkill, libc::SYS_kill, libc::SYS_getpid, libc::SYS_gettid, libc::SYS_getuid, libc::SYS_geteuid,
+    libc::SYS_getgid, libc::SYS_getegid, libc::SYS_prctl, libc::SYS_capget, libc::SYS_prlimit64,
+    libc::SYS_wait4,
+    // time and randomness
+    libc::SYS_clock_gettime, libc::SYS_clock_nanosleep, libc::SYS_nanosleep, libc::SYS_getrandom, libc::SYS_uname,
+    libc::SYS_sysinfo,
+    // polling
+    libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_eventfd2, libc::SYS_ppoll,
+    libc::SYS_pipe2,
+    // sockets
+    libc::SYS_socket, libc::SYS_connect, libc::SYS_accept4, libc::SYS_bind, libc::SYS_listen,
+    libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_setsockopt, libc::SYS_getsockopt,
+    libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_shutdown,
+    // bpf maps and perf events of cpus coming online
+    libc::SYS_bpf, libc::SYS_perf_event_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_access,
+    #[cfg(target_arch = "x86_64")] libc::SYS_readlink,
+    // the pid journal rotation, std removes and renames files with unlink and rename
+    #[cfg(target_arch = "x86_64")] libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")] libc::SYS_getdents,
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")] libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")] libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")] libc::SYS_arch_prctl,
+];
+
+// install applies the filter to every thread of the agent. It needs no_new_privs, see drop_privileges.
+pub fn install(mode: SeccompMode) -> Result<()> {
+    let filter = filter(mode);
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC | SECCOMP_FILTER_FLAG_LOG,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    match ret {
+        0 => {
+            info!("installed seccomp filter in {:?} mode, {} syscalls allowed", mode, ALLOWED_SYSCALLS.len());
+            Ok(())
+        }
+        // with TSYNC a positive value is the thread that couldn't be synchronized
+        tid if tid > 0 => Err(Error::SyscallError(format!("seccomp: thread {} can't take the filter", tid))),
+        _ => Err(Error::last_os_error("seccomp")),
+    }
+}
+
+// filter checks the arch, then compares the syscall number against each allowed syscall
+fn filter(mode: SeccompMode) -> Vec<libc::sock_filter> {
+    let default = match mode {
+        SeccompMode::Audit => SECCOMP_RET_LOG,
+        SeccompMode::Enforce => SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    };
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    for &nr in ALLOWED_SYSCALLS {
+        filter.push(jump(BPF_JMP_JEQ_K, nr as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET_K, default));
+    filter
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
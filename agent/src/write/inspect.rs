@@ -0,0 +1,69 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+use log::info;
+use prost::Message;
+
+use iwm::ebpf::pprof::profile::Profile;
+use iwm::error::Error::WriteError;
+use iwm::error::Result;
+
+use crate::ebpf::ebpf_linux::push_api::{LabelPair, PushRequest, RawSample};
+
+// DryRun replaces pushing with an inspection of the requests, to check label shaping and payload
+// sizes before pointing the agent at a real backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DryRun {
+    // Log logs a summary of every profile
+    Log,
+    // Dir writes every profile to <dir>/<id>.pb with its summary in <dir>/<id>.txt
+    Dir(PathBuf),
+}
+
+impl DryRun {
+    pub fn inspect(&self, req: &PushRequest) -> Result<()> {
+        if let DryRun::Dir(dir) = self {
+            fs::create_dir_all(dir)
+                .map_err(|e| WriteError(format!("creating dry run dir {}: {}", dir.display(), e)))?;
+        }
+        for series in &req.series {
+            for sample in &series.samples {
+                let summary = summary(&series.labels, sample);
+                match self {
+                    DryRun::Log => info!("dry run push: {}", summary),
+                    DryRun::Dir(dir) => {
+                        let path = dir.join(&sample.id);
+                        fs::write(path.with_extension("pb"), &sample.raw_profile)
+                            .and_then(|_| fs::write(path.with_extension("txt"), summary))
+                            .map_err(|e| WriteError(format!("writing dry run profile {}: {}", path.display(), e)))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// summary describes a profile as it would be pushed: its series labels, size, and the sample count
+// and totals of the decoded pprof
+fn summary(labels: &[LabelPair], sample: &RawSample) -> String {
+    let mut labels: Vec<String> = labels.iter().map(|l| format!("{}={:?}", l.name, l.value)).collect();
+    labels.sort();
+    let mut s = format!("id={} bytes={} labels={{{}}}", sample.id, sample.raw_profile.len(), labels.join(", "));
+    match Profile::decode(sample.raw_profile.as_ref()) {
+        Ok(profile) => {
+            let _ = write!(s, " samples={} locations={} functions={}",
+                profile.sample.len(), profile.location.len(), profile.function.len());
+            let string = |i: i64| profile.string_table.get(i as usize).map(String::as_str).unwrap_or_default();
+            for (i, typ) in profile.sample_type.iter().enumerate() {
+                let total: i64 = profile.sample.iter().filter_map(|s| s.value.get(i)).sum();
+                let _ = write!(s, " {}/{}={}", string(typ.r#type), string(typ.unit), total);
+            }
+        }
+        Err(err) => {
+            let _ = write!(s, " decode error: {}", err);
+        }
+    }
+    s
+}
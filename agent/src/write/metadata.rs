@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use iwm::common::labels::is_valid_label_name;
+use iwm::ebpf::sd::target::{LABEL_CONTAINER_ID, LABEL_SERVICE_NAME, RESERVED_LABEL_PREFIX};
+use iwm::error::Error;
+use iwm::error::Error::NotFound;
+use iwm::error::Result;
+
+const KEY_CONTAINER_ID: &str = "container_id";
+const KEY_SERVICE_NAME: &str = "service_name";
+
+// MetadataOptions point at a file mapping container ids or service names to organizational labels,
+// e.g. team, tier or cost center, typically mounted from a config map. The file is json when its
+// name ends with .json and csv otherwise:
+//
+//   json  [{"service_name": "api", "labels": {"team": "payments"}}, {"container_id": "3f2a..", "labels": {..}}]
+//   csv   a header row with a container_id and/or service_name column, the other columns are labels
+//
+// Container ids are the full ids of the runtime. Labels of a container id entry win over the ones of
+// its service, labels the series already has are never replaced.
+#[derive(Debug, Clone)]
+pub struct MetadataOptions {
+    pub path: PathBuf,
+    // check_interval is how often the file is checked for changes
+    pub check_interval: Duration,
+}
+
+impl MetadataOptions {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, check_interval: Duration::from_secs(10) }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JsonEntry {
+    container_id: String,
+    service_name: String,
+    labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+struct Metadata {
+    by_container_id: HashMap<String, HashMap<String, String>>,
+    by_service_name: HashMap<String, HashMap<String, String>>,
+}
+
+// MetadataJoin joins the labels of the metadata file onto the series before push. The file is
+// reloaded when its modification time changes, a file that fails to load keeps the last good metadata.
+#[derive(Clone)]
+pub struct MetadataJoin {
+    options: MetadataOptions,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    metadata: Arc<Metadata>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl MetadataJoin {
+    // open loads the file, it has to exist and be valid when the agent starts
+    pub fn open(options: MetadataOptions) -> Result<Self> {
+        let modified = modified(&options.path)?;
+        let metadata = load(&options.path)?;
+        info!("loaded metadata of {} container ids and {} services from {}",
+            metadata.by_container_id.len(), metadata.by_service_name.len(), options.path.display());
+        Ok(Self {
+            options,
+            state: Arc::new(Mutex::new(State { metadata: Arc::new(metadata), modified: Some(modified), checked: Instant::now() })),
+        })
+    }
+
+    // join adds the metadata labels of the series' container id and service name, returning the
+    // number of labels added
+    pub fn join(&self, labels: &mut HashMap<String, String>) -> usize {
+        let metadata = self.metadata();
+        let entries = [
+            labels.get(LABEL_CONTAINER_ID).and_then(|cid| metadata.by_container_id.get(cid)),
+            labels.get(LABEL_SERVICE_NAME).and_then(|name| metadata.by_service_name.get(name)),
+        ];
+        let mut added = 0;
+        for entry in entries.into_iter().flatten() {
+            for (name, value) in entry {
+                if !labels.contains_key(name) {
+                    labels.insert(name.clone(), value.clone());
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    // metadata returns the current metadata, reloading the file when it changed since the last check
+    fn metadata(&self) -> Arc<Metadata> {
+        let mut state = self.state.lock().unwrap();
+        if state.checked.elapsed() < self.options.check_interval {
+            return state.metadata.clone();
+        }
+        state.checked = Instant::now();
+        let path = &self.options.path;
+        let modified = match modified(path) {
+            Ok(modified) => modified,
+            Err(err) => {
+                warn!("keeping the last metadata: {}", err);
+                return state.metadata.clone();
+            }
+        };
+        if state.modified == Some(modified) {
+            return state.metadata.clone();
+        }
+        // a failed load is retried with the next change of the file
+        state.modified = Some(modified);
+        match load(path) {
+            Ok(metadata) => {
+                info!("reloaded metadata of {} container ids and {} services from {}",
+                    metadata.by_container_id.len(), metadata.by_service_name.len(), path.display());
+                state.metadata = Arc::new(metadata);
+            }
+            Err(err) => warn!("keeping the last metadata: {}", err),
+        }
+        state.metadata.clone()
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| NotFound(format!("metadata file {}: {}", path.display(), e)))
+}
+
+fn load(path: &Path) -> Result<Metadata> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| NotFound(format!("reading metadata file {}: {}", path.display(), e)))?;
+    let entries = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str::<Vec<JsonEntry>>(&data)
+            .map_err(|e| Error::invalid_data(format!("decoding metadata file {}: {}", path.display(), e)))?
+    } else {
+        parse_csv(&data).map_err(|e| Error::invalid_data(format!("metadata file {}: {}", path.display(), e)))?
+    };
+
+    let mut metadata = Metadata::default();
+    for (i, entry) in entries.into_iter().enumerate() {
+        if let Some(name) = entry.labels.keys().find(|name| name.starts_with(RESERVED_LABEL_PREFIX) || !is_valid_label_name(name)) {
+            return Err(Error::invalid_data(format!("metadata file {}: entry {}: invalid label name {:?}", path.display(), i, name)));
+        }
+        let labels = entry.labels.into_iter().filter(|(_, value)| !value.is_empty());
+        match (entry.container_id.is_empty(), entry.service_name.is_empty()) {
+            (false, _) => metadata.by_container_id.entry(entry.container_id).or_default().extend(labels),
+            (true, false) => metadata.by_service_name.entry(entry.service_name).or_default().extend(labels),
+            (true, true) => return Err(Error::invalid_data(format!(
+                "metadata file {}: entry {} has neither a {} nor a {}", path.display(), i, KEY_CONTAINER_ID, KEY_SERVICE_NAME))),
+        }
+    }
+    Ok(metadata)
+}
+
+// parse_csv reads the rows of the csv file as entries. Fields may be quoted, quotes in quoted
+// fields are doubled.
+fn parse_csv(data: &str) -> std::result::Result<Vec<JsonEntry>, String> {
+    let mut rows = data.lines().filter(|line| !line.trim().is_empty());
+    let header = split_csv_line(rows.next().ok_or("no header row")?)?;
+    if !header.iter().any(|h| h == KEY_CONTAINER_ID || h == KEY_SERVICE_NAME) {
+        return Err(format!("the header has neither a {} nor a {} column", KEY_CONTAINER_ID, KEY_SERVICE_NAME));
+    }
+    let mut entries = Vec::new();
+    for (i, row) in rows.enumerate() {
+        let fields = split_csv_line(row)?;
+        if fields.len() != header.len() {
+            return Err(format!("row {} has {} fields, the header has {}", i + 1, fields.len(), header.len()));
+        }
+        let mut entry = JsonEntry::default();
+        for (name, value) in header.iter().zip(fields) {
+            match name.as_str() {
+                KEY_CONTAINER_ID => entry.container_id = value,
+                KEY_SERVICE_NAME => entry.service_name = value,
+                _ => {
+                    entry.labels.insert(name.clone(), value);
+                }
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn split_csv_line(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("unterminated quote in {:?}", line));
+    }
+    fields.push(field.trim().to_string());
+    Ok(fields)
+}
@@ -1 +1,4 @@
+pub mod inspect;
+pub mod metadata;
+pub mod scrub;
 pub mod write;
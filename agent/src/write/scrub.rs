@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use iwm::ebpf::sd::target::{LABEL_SERVICE_NAME, METRIC_NAME};
+
+use crate::write::write::DELTA_LABEL;
+
+// LabelScrubbing drops or anonymizes sensitive labels before the profiles leave the node, e.g. pod
+// names or customer ids under compliance constraints. Labels are dropped first, then redacted, then
+// hashed, a label listed in several rules gets them in that order.
+#[derive(Debug, Clone, Default)]
+pub struct LabelScrubbing {
+    // drop are the names of the labels removed from every series
+    pub drop: Vec<String>,
+    pub redact: Vec<RedactRule>,
+    // hash are the names of the labels whose values are replaced with a keyed hash, the series stay
+    // apart without exposing the values
+    pub hash: Vec<String>,
+    // hash_key salts the hashes, so known values can't be recognized by hashing them
+    pub hash_key: String,
+}
+
+// RedactRule replaces the matches of pattern in the value of the label with replacement, which may
+// refer to the groups of the pattern like Regex::replace_all, e.g. customer-(\d+) -> customer-xxx
+#[derive(Debug, Clone)]
+pub struct RedactRule {
+    pub label: String,
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+// ScrubCounts are the labels changed by the scrubbing of a series, by action
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubCounts {
+    pub dropped: usize,
+    pub redacted: usize,
+    pub hashed: usize,
+}
+
+impl LabelScrubbing {
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_empty() && self.redact.is_empty() && self.hash.is_empty()
+    }
+
+    // validate checks the rules, problems are appended to errs
+    pub fn validate(&self, errs: &mut Vec<String>) {
+        let names = self.drop.iter()
+            .chain(self.redact.iter().map(|r| &r.label))
+            .chain(self.hash.iter());
+        for name in names {
+            // the series would lose their profile type, be pushed as absolute profiles or be rejected
+            // for a missing service name
+            if name == METRIC_NAME || name == DELTA_LABEL || name == LABEL_SERVICE_NAME {
+                errs.push(format!("label scrubbing: {} can't be scrubbed", name));
+            }
+        }
+        if !self.hash.is_empty() && self.hash_key.is_empty() {
+            errs.push("label scrubbing: hash_key is required to hash labels".to_string());
+        }
+    }
+
+    // apply scrubs the labels of a series in place
+    pub fn apply(&self, labels: &mut HashMap<String, String>) -> ScrubCounts {
+        let mut counts = ScrubCounts::default();
+        for name in &self.drop {
+            if labels.remove(name).is_some() {
+                counts.dropped += 1;
+            }
+        }
+        for rule in &self.redact {
+            let Some(value) = labels.get_mut(&rule.label) else {
+                continue;
+            };
+            // replace_all borrows the value when nothing matched
+            if let Cow::Owned(redacted) = rule.pattern.replace_all(value, rule.replacement.as_str()) {
+                *value = redacted;
+                counts.redacted += 1;
+            }
+        }
+        for name in &self.hash {
+            if let Some(value) = labels.get_mut(name) {
+                *value = self.hash_value(value);
+                counts.hashed += 1;
+            }
+        }
+        counts
+    }
+
+    // hash_value is the first 64 bits of sha256(hash_key, value) in hex, short enough for a label
+    // value and still unlikely to collide between the values of a label
+    fn hash_value(&self, value: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash_key.as_bytes());
+        hasher.update([0]);
+        hasher.update(value.as_bytes());
+        let digest = hasher.finalize();
+        digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
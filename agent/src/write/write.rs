@@ -1,35 +1,65 @@
 
 use std::collections::HashMap;
 
-use std::sync::{Arc};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::borrow::Borrow;
+use arc_swap::ArcSwap;
 use log::{info, warn};
 
 
-use tonic::transport::Channel;
-use iwm::common::labels::Labels;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+use iwm::common::labels::{is_valid_label_name, normalize_labels, LabelFixes, LabelRejection, Labels};
 use iwm::ebpf::metrics::write_metrics::WriteMetrics;
-use iwm::ebpf::sd::target::{METRIC_NAME, RESERVED_LABEL_PREFIX};
+use iwm::ebpf::sd::target::{LABEL_SERVICE_NAME, METRIC_NAME, RESERVED_LABEL_PREFIX};
 
+use iwm::error::Error;
+use iwm::error::Error::WriteError;
 use iwm::error::Result;
 
+use crate::common::client::{TraceContext, DEFAULT_USER_AGENT, TRACEPARENT_HEADER};
 use crate::common::registry::{Options};
 use crate::common::component::Component;
 use crate::appender::{Appendable, Appender};
+use crate::write::inspect::DryRun;
+use crate::write::metadata::{MetadataJoin, MetadataOptions};
+use crate::write::scrub::{LabelScrubbing, ScrubCounts};
 use crate::ebpf::ebpf_linux::push_api::pusher_service_client::PusherServiceClient;
-use crate::ebpf::ebpf_linux::push_api::{LabelPair, PushRequest, PushResponse, RawProfileSeries, RawSample};
+use crate::ebpf::ebpf_linux::push_api::{LabelPair, PushChunk, PushRequest, PushResponse, RawProfileSeries, RawSample};
+use health_api::health_check_response::ServingStatus;
+use health_api::health_client::HealthClient;
+use health_api::HealthCheckRequest;
+
+pub mod health_api {
+    include!("../gen/health/grpc.health.v1.rs");
+}
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct EndpointOptions {
     pub name: String,
     pub url: String,
     pub remote_timeout: Duration,
     pub headers: HashMap<String, String>,
+    pub tenant_id: String,
+    pub bearer_token: String,
+    // labels are added to the profiles sent to this endpoint only, over the external labels. They are
+    // set before the labels are scrubbed and normalized, like the external labels.
+    pub labels: HashMap<String, String>,
     pub min_backoff: Duration,
     pub max_backoff: Duration,
     pub max_backoff_retries: usize,
+    // requests with more profile bytes than max_message_size are sent over PushStream in chunks of chunk_size
+    pub max_message_size: usize,
+    pub chunk_size: usize,
 }
 
 impl Default for EndpointOptions {
@@ -39,96 +69,462 @@ impl Default for EndpointOptions {
             url: String::new(),
             remote_timeout: Duration::from_secs(10),
             headers: HashMap::new(),
+            tenant_id: String::new(),
+            bearer_token: String::new(),
+            labels: HashMap::new(),
             min_backoff: Duration::from_millis(500),
             max_backoff: Duration::from_secs(300),
             max_backoff_retries: 10,
+            max_message_size: 3 * 1024 * 1024,
+            chunk_size: 1024 * 1024,
+        }
+    }
+}
+
+impl EndpointOptions {
+    // validate checks the options of the endpoint, problems are appended to errs
+    fn validate(&self, errs: &mut Vec<String>) {
+        let name = if self.name.is_empty() { &self.url } else { &self.name };
+        if self.url.is_empty() {
+            errs.push(format!("endpoint {:?}: url is required", self.name));
+        } else if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            errs.push(format!("endpoint {}: url must start with http:// or https://", name));
+        }
+        if self.remote_timeout.is_zero() {
+            errs.push(format!("endpoint {}: remote_timeout must be positive", name));
+        }
+        if self.min_backoff.is_zero() {
+            errs.push(format!("endpoint {}: min_backoff must be positive", name));
+        }
+        if self.min_backoff > self.max_backoff {
+            errs.push(format!("endpoint {}: min_backoff {:?} is greater than max_backoff {:?}",
+                name, self.min_backoff, self.max_backoff));
+        }
+        if self.chunk_size == 0 || self.chunk_size > self.max_message_size {
+            errs.push(format!("endpoint {}: chunk_size {} must be between 1 and max_message_size {}",
+                name, self.chunk_size, self.max_message_size));
+        }
+        if !self.tenant_id.is_empty() && self.tenant_id.parse::<AsciiMetadataValue>().is_err() {
+            errs.push(format!("endpoint {}: tenant_id is not a valid header value", name));
+        }
+        for label in self.labels.keys() {
+            if label.starts_with(RESERVED_LABEL_PREFIX) || label == LABEL_SERVICE_NAME {
+                errs.push(format!("endpoint {}: label {} is reserved", name, label));
+            } else if !is_valid_label_name(label) {
+                errs.push(format!("endpoint {}: label {:?} is not a valid label name", name, label));
+            }
         }
     }
 }
 
+// NameConvention is how the __name__ of the pushed series is shaped, backends expect different ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameConvention {
+    // Labels keeps __name__ as the profile type, e.g. process_cpu
+    Labels,
+    // Template sets __name__ from a template of label values, e.g. "{service_name}.cpu".
+    // {service} is short for {service_name}, and {name} is the profile type.
+    Template(String),
+    // Pyroscope sets __name__ to the app name of the template followed by the other labels,
+    // app.name{label=value,...} as the pyroscope ingest api names profiles
+    Pyroscope(String),
+}
+
+impl NameConvention {
+    fn apply(&self, labels: &mut HashMap<String, String>) {
+        let template = match self {
+            NameConvention::Labels => return,
+            NameConvention::Template(template) | NameConvention::Pyroscope(template) => template,
+        };
+        let mut name = render_name(template, labels);
+        if let NameConvention::Pyroscope(_) = self {
+            let mut pairs: Vec<String> = labels.iter()
+                .filter(|(k, _)| !k.starts_with(RESERVED_LABEL_PREFIX))
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            pairs.sort();
+            name = format!("{}{{{}}}", name, pairs.join(","));
+        }
+        labels.insert(METRIC_NAME.to_string(), name);
+    }
+}
+
+// render_name replaces the {label} placeholders of the template with the label values, unknown labels are left empty
+fn render_name(template: &str, labels: &HashMap<String, String>) -> String {
+    let mut name = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        name.push_str(&rest[..start]);
+        let label = match &rest[start + 1..start + end] {
+            "service" => LABEL_SERVICE_NAME,
+            "name" => METRIC_NAME,
+            label => label,
+        };
+        name.push_str(labels.get(label).map(String::as_str).unwrap_or_default());
+        rest = &rest[start + end + 1..];
+    }
+    name.push_str(rest);
+    name
+}
+
 #[derive(Clone)]
 pub struct Arguments {
     pub external_labels: HashMap<String, String>,
+    // metadata joins organizational labels from a file onto the series by container id or service
+    // name, before they are scrubbed
+    pub metadata: Option<MetadataOptions>,
+    // scrubbing applies to the labels of every series, the external labels included
+    pub scrubbing: LabelScrubbing,
     pub endpoints: Vec<EndpointOptions>,
+    pub name_convention: NameConvention,
+    // dry_run inspects the requests instead of pushing them
+    pub dry_run: Option<DryRun>,
+    pub user_agent: String,
+    // trace_context adds a W3C traceparent header to the pushes, retries of a push share its trace
+    pub trace_context: bool,
+    // queue_capacity is the number of appended requests waiting to be pushed, appends over it are dropped.
+    // A round appends one request per profile, so it has to hold a few rounds of profiles of the host.
+    pub queue_capacity: usize,
+    // health_check_interval is how often the endpoints are probed, down endpoints get a new connection
+    pub health_check_interval: Duration,
+}
+
+impl Arguments {
+    // validate checks the arguments and their cross-field constraints, reporting every problem at once
+    pub fn validate(&self) -> Result<()> {
+        let mut errs = Vec::new();
+        if self.endpoints.is_empty() && self.dry_run.is_none() {
+            errs.push("at least one endpoint is required".to_string());
+        }
+        for endpoint in &self.endpoints {
+            endpoint.validate(&mut errs);
+        }
+        for name in self.external_labels.keys() {
+            if name.starts_with(RESERVED_LABEL_PREFIX) {
+                errs.push(format!("external label {} uses the reserved prefix {}", name, RESERVED_LABEL_PREFIX));
+            } else if !is_valid_label_name(name) {
+                errs.push(format!("external label {:?} is not a valid label name", name));
+            }
+        }
+        self.scrubbing.validate(&mut errs);
+        if let NameConvention::Template(t) | NameConvention::Pyroscope(t) = &self.name_convention {
+            if t.is_empty() {
+                errs.push("name convention template is empty".to_string());
+            }
+        }
+        if self.user_agent.parse::<AsciiMetadataValue>().is_err() {
+            errs.push(format!("user_agent {:?} is not a valid header value", self.user_agent));
+        }
+        if self.queue_capacity == 0 {
+            errs.push("queue_capacity must be positive".to_string());
+        }
+        if self.health_check_interval.is_zero() {
+            errs.push("health_check_interval must be positive".to_string());
+        }
+        if errs.is_empty() {
+            return Ok(());
+        }
+        Err(Error::invalid_data(format!("write arguments: {}", errs.join("; "))))
+    }
 }
 
 impl Default for Arguments {
     fn default() -> Self {
         Self {
             external_labels: HashMap::new(),
+            metadata: None,
+            scrubbing: LabelScrubbing::default(),
             endpoints: Vec::new(),
+            name_convention: NameConvention::Labels,
+            dry_run: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            trace_context: false,
+            queue_capacity: 2048,
+            health_check_interval: Duration::from_secs(30),
         }
     }
 }
 
-#[derive(Clone)]
 pub struct WriteComponent {
     opts: Options,
     cfg: Arguments,
-    metrics: Arc<WriteMetrics>
+    metrics: Arc<WriteMetrics>,
+    client: FanOutClient,
+    // queue is taken by run, which drains it for as long as the component lives
+    queue: Option<mpsc::Receiver<Queued>>,
+    // updates are the arguments of configuration reloads, applied between pushes by run
+    updates: mpsc::Receiver<Arguments>,
+    update_sender: mpsc::Sender<Arguments>,
 }
 
 impl WriteComponent {
+    // updates returns the sender of configuration reloads, see update
+    pub fn updates(&self) -> mpsc::Sender<Arguments> {
+        self.update_sender.clone()
+    }
 
-    async fn update(&mut self, _new_cfg: Arguments) -> Result<()> {
+    // update applies reloaded arguments to the client shared with the appenders. Pushes already
+    // started finish with the previous endpoints, unchanged endpoints keep their connection. The
+    // queue keeps its capacity until a restart.
+    fn update(&mut self, mut new_cfg: Arguments) -> Result<()> {
+        new_cfg.validate()?;
+        if new_cfg.queue_capacity != self.cfg.queue_capacity {
+            warn!("the reloaded queue_capacity takes effect on restart");
+            new_cfg.queue_capacity = self.cfg.queue_capacity;
+        }
+        self.client.update(new_cfg.clone())?;
+        self.cfg = new_cfg;
         Ok(())
     }
+
     pub async fn new(o: Options, c: Arguments) -> Result<(Self, FanOutClient)> {
+        c.validate()?;
         let metrics = Arc::new(WriteMetrics::new(o.registerer.borrow()));
-        let receiver = FanOutClient::new(o.clone(), c.clone(), metrics.clone()).await.unwrap();
+        let (sender, queue) = mpsc::channel(c.queue_capacity);
+        let receiver = FanOutClient::new(o.clone(), c.clone(), metrics.clone(), sender)?;
+        let (update_sender, updates) = mpsc::channel(1);
 
         Ok((WriteComponent {
             opts: o,
             cfg: c,
             metrics,
+            client: receiver.clone(),
+            queue: Some(queue),
+            updates,
+            update_sender,
         }, receiver))
     }
+
+    fn spawn_health_checks(&self, health_checks: &mut JoinSet<()>) {
+        for endpoint in self.client.state.load().endpoints.iter() {
+            health_checks.spawn(check_health(endpoint.clone(), self.cfg.health_check_interval, self.metrics.clone()));
+        }
+    }
 }
 
 impl Component for WriteComponent {
-    async fn run(&mut self) {
+    // run checks the health of the endpoints in the background and pushes the appended requests
+    // one after another for as long as the component lives
+    async fn run(&mut self, cancel: CancellationToken) {
+        let Some(mut queue) = self.queue.take() else {
+            warn!("write component is already running");
+            return;
+        };
+        let mut health_checks = JoinSet::new();
+        self.spawn_health_checks(&mut health_checks);
+        loop {
+            let req = tokio::select! {
+                _ = cancel.cancelled() => break,
+                Some(cfg) = self.updates.recv() => {
+                    match self.update(cfg) {
+                        Ok(()) => {
+                            info!("reloaded write arguments");
+                            // the checks of the new endpoint list, with the new interval
+                            health_checks.shutdown().await;
+                            self.spawn_health_checks(&mut health_checks);
+                        }
+                        Err(err) => warn!("reloading write arguments: {}", err),
+                    }
+                    continue;
+                }
+                req = queue.recv() => req,
+            };
+            let Some(queued) = req else {
+                break;
+            };
+            self.metrics.queue_depth.dec();
+            if let Err(err) = self.client.push(queued).await {
+                warn!("{}", err);
+            }
+        }
+        // the requests queued by the last rounds are pushed before stopping, as long as the endpoints
+        // answer within SHUTDOWN_TIMEOUT. Retries of failed pushes end with the runtime.
+        queue.close();
+        let drain = async {
+            while let Some(queued) = queue.recv().await {
+                self.metrics.queue_depth.dec();
+                if let Err(err) = self.client.push(queued).await {
+                    warn!("{}", err);
+                }
+            }
+        };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+            warn!("pushing the queued profiles took longer than {:?}, dropping the rest", SHUTDOWN_TIMEOUT);
+        }
+        health_checks.shutdown().await;
+    }
+}
+
+// EndpointClient is the connection to an endpoint. The channel connects lazily and reconnects on its own,
+// the health checks replace it with a freshly resolved one when the endpoint was down.
+struct EndpointClient {
+    options: EndpointOptions,
+    endpoint: Endpoint,
+    client: Mutex<PusherServiceClient<Channel>>,
+    // health is the grpc.health.v1 client on the channel of client
+    health: Mutex<HealthClient<Channel>>,
+    up: AtomicBool,
+    // failures counts the push attempts that failed since the last successful one
+    failures: AtomicU64,
+}
+
+impl EndpointClient {
+    fn client(&self) -> PusherServiceClient<Channel> {
+        self.client.lock().unwrap().clone()
+    }
+
+    fn set_channel(&self, channel: Channel) {
+        *self.health.lock().unwrap() = HealthClient::new(channel.clone());
+        *self.client.lock().unwrap() = PusherServiceClient::new(channel);
+    }
+
+    fn set_up(&self, up: bool, metrics: &WriteMetrics) {
+        if self.up.swap(up, Ordering::Relaxed) != up {
+            info!("endpoint {} is {}", self.options.url, if up { "up" } else { "down" });
+        }
+        metrics.endpoint_up.with_label_values(&[&self.options.url]).set(if up { 1.0 } else { 0.0 });
+    }
+
+    fn push_succeeded(&self, metrics: &WriteMetrics) {
+        self.failures.store(0, Ordering::Relaxed);
+        metrics.consecutive_failures.with_label_values(&[&self.options.url]).set(0.0);
+    }
+
+    fn push_failed(&self, metrics: &WriteMetrics) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics.consecutive_failures.with_label_values(&[&self.options.url]).set(failures as f64);
+    }
+}
+
+// check_health probes the endpoint with the grpc.health.v1 Check of the server every interval, starting
+// right away. An endpoint that was down gets a freshly resolved connection first. Pushes mark the
+// endpoint up when they succeed, and down when they fail with Unavailable.
+async fn check_health(endpoint: Arc<EndpointClient>, interval: Duration, metrics: Arc<WriteMetrics>) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let url = &endpoint.options.url;
+        let timeout = endpoint.options.remote_timeout;
+        if !endpoint.up.load(Ordering::Relaxed) {
+            match tokio::time::timeout(timeout, endpoint.endpoint.connect()).await {
+                Ok(Ok(channel)) => endpoint.set_channel(channel),
+                Ok(Err(err)) => {
+                    warn!("endpoint {} is down: {}", url, err);
+                    continue;
+                }
+                Err(_) => {
+                    warn!("endpoint {} is down: connecting timed out after {:?}", url, timeout);
+                    continue;
+                }
+            }
+        }
+        let mut health = endpoint.health.lock().unwrap().clone();
+        // the empty service is the health of the server as a whole
+        let check = health.check(HealthCheckRequest { service: String::new() });
+        match tokio::time::timeout(timeout, check).await {
+            Ok(Ok(res)) if res.get_ref().status == ServingStatus::Serving as i32 => endpoint.set_up(true, &metrics),
+            Ok(Ok(res)) => {
+                warn!("endpoint {} is not serving: {:?}", url, res.get_ref().status());
+                endpoint.set_up(false, &metrics);
+            }
+            // the server answers but doesn't implement the health service, the pushes tell
+            Ok(Err(status)) if status.code() == Code::Unimplemented => endpoint.set_up(true, &metrics),
+            Ok(Err(status)) => {
+                warn!("endpoint {} health check failed: {:?}", url, status);
+                endpoint.set_up(false, &metrics);
+            }
+            Err(_) => {
+                warn!("endpoint {} health check timed out after {:?}", url, timeout);
+                endpoint.set_up(false, &metrics);
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct FanOutClient {
-    clients: Vec<PusherServiceClient<Channel>>,
-    config: Arguments,
+    // state is replaced by configuration reloads, every clone sees the new one. An append or push
+    // keeps the state it started with.
+    state: Arc<ArcSwap<ClientState>>,
     opts: Options,
     metrics: Arc<WriteMetrics>,
+    queue: mpsc::Sender<Queued>,
+}
+
+// Queued is an appended request waiting to be pushed
+struct Queued {
+    req: PushRequest,
+    // endpoint_labels are the labels of the series for the endpoints with labels of their own, by url,
+    // None when they got the series rejected. Endpoints without an entry get the labels of req.
+    endpoint_labels: HashMap<String, Option<Vec<LabelPair>>>,
+    // appended is when the request was appended, the start of its delivery latency
+    appended: Instant,
+}
+
+struct ClientState {
+    config: Arguments,
+    endpoints: Vec<Arc<EndpointClient>>,
+    metadata: Option<MetadataJoin>,
 }
 
 pub const DELTA_LABEL: &str = "__delta__";
+// SHUTDOWN_TIMEOUT bounds the pushes of the queued profiles when the agent stops
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+pub const TENANT_HEADER: &str = "X-Scope-OrgID";
+pub const AUTHORIZATION_HEADER: &str = "Authorization";
 
 impl Appender for FanOutClient {
     fn append(&self, lbs: Labels, samples: Vec<RawSample>) -> Result<()> {
         // todo: pool label pair arrays and label builder to avoid allocations
+        let state = self.state.load();
         let mut lbs_builder = HashMap::<String, String>::new();
 
         for label in lbs.0 {
-            // filter reserved labels, with exceptions for __name__ and __delta__
-            if label.name.starts_with(RESERVED_LABEL_PREFIX)
-                && label.name != METRIC_NAME
-                && label.name != DELTA_LABEL
-            {
-                continue;
-            }
             lbs_builder.insert(label.name, label.value);
         }
-        for (name, value) in &self.config.external_labels {
+        for (name, value) in &state.config.external_labels {
             lbs_builder.insert(name.clone(), value.clone());
         }
-        let labels = lbs_builder.keys().map(|key| {
-            LabelPair {
-                name: key.clone(),
-                value: lbs_builder.get(key).unwrap().clone(),
+        if let Some(metadata) = &state.metadata {
+            metadata.join(&mut lbs_builder);
+        }
+        let labels = match shape_labels(&state.config, lbs_builder.clone()) {
+            Ok((labels, scrubbed, fixes)) => {
+                self.count_shaping(scrubbed, fixes);
+                labels
             }
-        }).collect();
-        //dbg!(&labels);
-        let samples: Vec<RawSample> = samples.iter().map(|sample| {
+            Err(rejection) => {
+                // pushing would only get the series dropped by the backend, the other profiles of the round go on
+                warn!("dropping {} profiles with invalid labels: {}", samples.len(), rejection);
+                self.metrics.rejected_profiles.with_label_values(&[rejection.as_str()]).inc_by(samples.len() as f64);
+                return Ok(());
+            }
+        };
+        // the series gets its own labels for the endpoints with labels, shaped the same way. The
+        // shaping metrics count the series once, as it is without them.
+        let mut endpoint_labels = HashMap::new();
+        for endpoint in state.endpoints.iter().filter(|e| !e.options.labels.is_empty()) {
+            let mut lbs = lbs_builder.clone();
+            lbs.extend(endpoint.options.labels.clone());
+            let labels = match shape_labels(&state.config, lbs) {
+                Ok((labels, _, _)) => Some(labels),
+                Err(rejection) => {
+                    warn!("dropping {} profiles to endpoint {} with invalid labels: {}",
+                        samples.len(), endpoint.options.url, rejection);
+                    None
+                }
+            };
+            endpoint_labels.insert(endpoint.options.url.clone(), labels);
+        }
+        // the id identifies the profile across retries and chunks, so the server can drop duplicates
+        let samples: Vec<RawSample> = samples.into_iter().map(|sample| {
             RawSample {
-                raw_profile: sample.raw_profile.clone(),
-                id: "0".to_string(),
+                raw_profile: sample.raw_profile,
+                id: if sample.id.is_empty() { Uuid::new_v4().to_string() } else { sample.id },
             }
         }).collect();
 
@@ -140,8 +536,7 @@ impl Appender for FanOutClient {
             }],
         };
         //info!("{:?}", &req);
-        self.push(req).unwrap();
-        Ok(())
+        self.enqueue(Queued { req, endpoint_labels, appended: Instant::now() })
     }
 }
 
@@ -152,52 +547,326 @@ impl Appendable for FanOutClient {
 }
 
 impl FanOutClient {
-    async fn new(opts: Options, config: Arguments, metrics: Arc<WriteMetrics>) -> Result<Self> {
-        let mut clients = Vec::with_capacity(config.endpoints.len());
-        let client = PusherServiceClient::connect("http://172.16.68.1:4040").await.unwrap();
-        clients.push(client);
-        // for endpoint in &config.endpoints {
-        //     let client = PusherServiceClient::connect(&endpoint).await.unwrap();
-        //     clients.push(client);
-        // }
+    fn new(opts: Options, config: Arguments, metrics: Arc<WriteMetrics>, queue: mpsc::Sender<Queued>) -> Result<Self> {
+        let state = ClientState::new(config, None, &metrics)?;
         Ok(Self {
-            clients, config, opts, metrics,
+            state: Arc::new(ArcSwap::from_pointee(state)), opts, metrics, queue,
         })
     }
 
-    fn push(&self, req: PushRequest) -> Result<PushResponse> {
+    // update swaps in the state of the reloaded arguments
+    fn update(&self, config: Arguments) -> Result<()> {
+        let current = self.state.load_full();
+        let state = ClientState::new(config, Some(&current), &self.metrics)?;
+        for endpoint in &current.endpoints {
+            if !state.endpoints.iter().any(|e| e.options.url == endpoint.options.url) {
+                let _ = self.metrics.endpoint_up.remove_label_values(&[&endpoint.options.url]);
+                let _ = self.metrics.consecutive_failures.remove_label_values(&[&endpoint.options.url]);
+            }
+        }
+        self.state.store(Arc::new(state));
+        Ok(())
+    }
+
+    // enqueue hands the request to the run loop of the write component without blocking the caller.
+    // The request is dropped when the queue is full.
+    fn enqueue(&self, queued: Queued) -> Result<()> {
+        match self.queue.try_send(queued) {
+            Ok(()) => {
+                self.metrics.queue_depth.inc();
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Full(queued)) => {
+                let (_, profile_count) = request_size(&queued.req);
+                self.metrics.queue_dropped_profiles.inc_by(profile_count as f64);
+                Err(WriteError(format!("push queue full, dropping {} profiles", profile_count)))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(WriteError("write component stopped".to_string())),
+        }
+    }
+
+    // count_shaping records what the scrubbing and the normalization changed in the labels of a series
+    fn count_shaping(&self, scrubbed: ScrubCounts, fixes: LabelFixes) {
+        for (action, count) in [("dropped", scrubbed.dropped), ("redacted", scrubbed.redacted), ("hashed", scrubbed.hashed)] {
+            if count > 0 {
+                self.metrics.scrubbed_labels.with_label_values(&[action]).inc_by(count as f64);
+            }
+        }
+        for (action, count) in [("renamed", fixes.renamed), ("dropped", fixes.dropped), ("truncated", fixes.truncated)] {
+            if count > 0 {
+                self.metrics.label_fixes.with_label_values(&[action]).inc_by(count as f64);
+            }
+        }
+    }
+
+    // push sends the request to every endpoint. It waits for the first attempt of each endpoint and returns
+    // the errors the profiles are dropped for, retries of retryable errors go on in the background.
+    async fn push(&self, queued: Queued) -> Result<PushResponse> {
+        let Queued { req, endpoint_labels, appended } = queued;
+        let state = self.state.load_full();
+        if let Some(dry_run) = &state.config.dry_run {
+            dry_run.inspect(&req)?;
+            return Ok(PushResponse::default());
+        }
 
         //info!("{:?}",&req);
-        self.clients.iter().enumerate().for_each(|(i, client)| {
-            let r = req.clone();
-            let mut client = client.clone();
-            let config = self.config.endpoints[i].clone();
+        let mut outcomes = Vec::with_capacity(state.endpoints.len());
+        state.endpoints.iter().for_each(|endpoint| {
+            // endpoints added by a reload since the append get the labels without their own
+            let r = match endpoint_labels.get(&endpoint.options.url) {
+                None => req.clone(),
+                Some(Some(labels)) => with_series_labels(&req, labels),
+                Some(None) => {
+                    let (_, profile_count) = request_size(&req);
+                    self.metrics.dropped_profiles.with_label_values(&[&endpoint.options.url]).inc_by(profile_count as f64);
+                    return;
+                }
+            };
+            let (first_attempt, outcome) = oneshot::channel::<std::result::Result<(), String>>();
+            let mut first_attempt = Some(first_attempt);
+            outcomes.push((endpoint.options.url.clone(), outcome));
+            let endpoint = endpoint.clone();
+            let config = endpoint.options.clone();
             let metrics = self.metrics.clone();
+            let trace = state.config.trace_context.then(TraceContext::new);
 
             tokio::spawn(async move {
                 let (req_size, profile_count) = request_size(&r);
-                let result = PusherServiceClient::push(&mut client, r.clone()).await;
-                if result.is_ok() {
-                    metrics.sent_bytes.with_label_values(&[&config.url]).inc_by(req_size as f64);
-                    metrics.sent_profiles.with_label_values(&[&config.url]).inc_by(profile_count as f64);
-                } else if let Err(err) = result {
-                    info!("{}", &config.url);
-                    warn!("failed to push to endpoint: {:?}", err);
-                    //errors.push(err.clone());
-                    metrics.retries.with_label_values(&[&config.url]).inc();
+                let metadata = match request_metadata(&config) {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        warn!("dropping push to endpoint {}: {}", &config.url, err);
+                        endpoint.push_failed(&metrics);
+                        metrics.pushes.with_label_values(&[&config.url, "failure"]).inc();
+                        metrics.dropped_bytes.with_label_values(&[&config.url]).inc_by(req_size as f64);
+                        metrics.dropped_profiles.with_label_values(&[&config.url]).inc_by(profile_count as f64);
+                        if let Some(first_attempt) = first_attempt.take() {
+                            let _ = first_attempt.send(Err(err.to_string()));
+                        }
+                        return;
+                    }
+                };
+                // profiles bigger than the server's message limit are split and streamed instead
+                let chunks = if req_size as usize > config.max_message_size {
+                    Some(split_request(&r, config.chunk_size))
+                } else {
+                    None
+                };
+                let mut backoff = config.min_backoff;
+                let mut retries = 0;
+                let started = Instant::now();
+                loop {
+                    let mut metadata = metadata.clone();
+                    if let Some(trace) = &trace {
+                        metadata.insert(TRACEPARENT_HEADER, trace.traceparent().parse().unwrap());
+                    }
+                    let mut client = endpoint.client();
+                    let result = match &chunks {
+                        Some(chunks) => {
+                            let mut request = tonic::Request::new(futures::stream::iter(chunks.clone()));
+                            *request.metadata_mut() = metadata;
+                            client.push_stream(request).await
+                        }
+                        None => {
+                            let mut request = tonic::Request::new(r.clone());
+                            *request.metadata_mut() = metadata;
+                            client.push(request).await
+                        }
+                    };
+                    match result {
+                        Ok(_) => {
+                            if let Some(first_attempt) = first_attempt.take() {
+                                let _ = first_attempt.send(Ok(()));
+                            }
+                            endpoint.push_succeeded(&metrics);
+                            endpoint.set_up(true, &metrics);
+                            metrics.pushes.with_label_values(&[&config.url, "success"]).inc();
+                            metrics.push_duration.with_label_values(&[&config.url]).observe(started.elapsed().as_secs_f64());
+                            metrics.delivery_duration.with_label_values(&[&config.url]).observe(appended.elapsed().as_secs_f64());
+                            metrics.sent_bytes.with_label_values(&[&config.url]).inc_by(req_size as f64);
+                            metrics.sent_profiles.with_label_values(&[&config.url]).inc_by(profile_count as f64);
+                            return;
+                        }
+                        Err(status) => {
+                            endpoint.push_failed(&metrics);
+                            if status.code() == Code::Unavailable {
+                                endpoint.set_up(false, &metrics);
+                            }
+                            if !is_retryable(&status) || retries >= config.max_backoff_retries {
+                                warn!("failed to push to endpoint {}, dropping profiles (retries: {}): {:?}",
+                                    &config.url, retries, status);
+                                metrics.pushes.with_label_values(&[&config.url, "failure"]).inc();
+                                metrics.dropped_bytes.with_label_values(&[&config.url]).inc_by(req_size as f64);
+                                metrics.dropped_profiles.with_label_values(&[&config.url]).inc_by(profile_count as f64);
+                                if let Some(first_attempt) = first_attempt.take() {
+                                    let _ = first_attempt.send(Err(format!("{:?}", status)));
+                                }
+                                return;
+                            }
+                            // the profiles are queued for retry, which doesn't fail the append
+                            if let Some(first_attempt) = first_attempt.take() {
+                                let _ = first_attempt.send(Ok(()));
+                            }
+                            info!("failed to push to endpoint {}, retrying in {:?}: {:?}", &config.url, backoff, status);
+                            metrics.retries.with_label_values(&[&config.url]).inc();
+                            retries += 1;
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(config.max_backoff);
+                        }
+                    }
                 }
             });
             ()
         });
 
-        // if !errors.is_empty() {
-        //     return Err(WriteError(format!("errors occurred during pushing: {:?}", errors)));
-        // }
+        let mut errors = Vec::new();
+        for (url, outcome) in outcomes {
+            match outcome.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => errors.push(format!("{}: {}", url, err)),
+                Err(_) => errors.push(format!("{}: push task stopped", url)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(WriteError(format!("errors occurred during pushing: {}", errors.join(", "))));
+        }
 
         Ok(PushResponse::default())
     }
 }
 
+impl ClientState {
+    // new connects the endpoints of config lazily and opens its metadata file. The endpoints and
+    // metadata of previous whose options didn't change are kept.
+    fn new(config: Arguments, previous: Option<&ClientState>, metrics: &WriteMetrics) -> Result<Self> {
+        let mut endpoints = Vec::with_capacity(config.endpoints.len());
+        for options in &config.endpoints {
+            let reused = previous
+                .filter(|p| p.config.user_agent == config.user_agent)
+                .and_then(|p| p.endpoints.iter().find(|e| &e.options == options));
+            if let Some(endpoint) = reused {
+                endpoints.push(endpoint.clone());
+                continue;
+            }
+            // remote_timeout bounds every push attempt, so a hung endpoint can't hold up the queue
+            let endpoint = Endpoint::from_shared(options.url.clone())
+                .and_then(|e| e.user_agent(config.user_agent.clone()))
+                .map(|e| e.timeout(options.remote_timeout).connect_timeout(options.remote_timeout))
+                .map_err(|e| WriteError(format!("endpoint {}: {}", options.url, e)))?;
+            let channel = endpoint.connect_lazy();
+            let endpoint = EndpointClient {
+                options: options.clone(),
+                endpoint,
+                client: Mutex::new(PusherServiceClient::new(channel.clone())),
+                health: Mutex::new(HealthClient::new(channel)),
+                up: AtomicBool::new(false),
+                failures: AtomicU64::new(0),
+            };
+            // down until the first health check connects
+            endpoint.set_up(false, metrics);
+            metrics.consecutive_failures.with_label_values(&[&options.url]).set(0.0);
+            endpoints.push(Arc::new(endpoint));
+        }
+        let reused_metadata = previous.and_then(|p| p.metadata.as_ref().zip(p.config.metadata.as_ref()))
+            .filter(|(_, options)| config.metadata.as_ref().is_some_and(|o| o.path == options.path))
+            .map(|(metadata, _)| metadata.clone());
+        let metadata = match reused_metadata {
+            Some(metadata) => Some(metadata),
+            None => config.metadata.clone().map(MetadataJoin::open).transpose()?,
+        };
+        Ok(Self { config, endpoints, metadata })
+    }
+}
+
+// is_retryable reports whether a push rejected with the given status may succeed if sent again.
+// Validation and limit errors (profile too large, too many labels, timestamp out of bounds, auth)
+// are permanent and retrying them would only delay the rest of the queue.
+fn is_retryable(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable
+            | Code::ResourceExhausted
+            | Code::DeadlineExceeded
+            | Code::Aborted
+            | Code::Internal
+            | Code::Unknown
+    )
+}
+
+// request_metadata builds the request metadata from the endpoint's custom headers, tenant and auth token.
+// The tenant and token are set last so they can't be overridden by a custom header with the same name.
+fn request_metadata(endpoint: &EndpointOptions) -> Result<MetadataMap> {
+    let mut metadata = MetadataMap::new();
+    let mut headers: Vec<(&str, String)> = endpoint.headers.iter()
+        .map(|(k, v)| (k.as_str(), v.clone()))
+        .collect();
+    if !endpoint.tenant_id.is_empty() {
+        headers.push((TENANT_HEADER, endpoint.tenant_id.clone()));
+    }
+    if !endpoint.bearer_token.is_empty() {
+        headers.push((AUTHORIZATION_HEADER, format!("Bearer {}", endpoint.bearer_token)));
+    }
+    for (name, value) in headers {
+        let key = AsciiMetadataKey::from_bytes(name.as_bytes())
+            .map_err(|e| WriteError(format!("invalid header name {}: {}", name, e)))?;
+        let value = value.parse::<AsciiMetadataValue>()
+            .map_err(|e| WriteError(format!("invalid value for header {}: {}", name, e)))?;
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+// shape_labels scrubs and normalizes the labels of a series and names it after the name convention
+fn shape_labels(
+    config: &Arguments,
+    mut labels: HashMap<String, String>,
+) -> std::result::Result<(Vec<LabelPair>, ScrubCounts, LabelFixes), LabelRejection> {
+    // scrubbed before the labels are checked, so the names of the series can't leak the values either
+    let scrubbed = if config.scrubbing.is_empty() { ScrubCounts::default() } else { config.scrubbing.apply(&mut labels) };
+    // reserved labels are filtered, with exceptions for __name__ and __delta__
+    let (mut labels, fixes) = normalize_labels(labels, &[METRIC_NAME, DELTA_LABEL])?;
+    config.name_convention.apply(&mut labels);
+    let labels = labels.into_iter().map(|(name, value)| LabelPair { name, value }).collect();
+    Ok((labels, scrubbed, fixes))
+}
+
+// with_series_labels returns the request with the labels set on every series
+fn with_series_labels(req: &PushRequest, labels: &[LabelPair]) -> PushRequest {
+    let mut req = req.clone();
+    for series in req.series.iter_mut() {
+        series.labels = labels.to_vec();
+    }
+    req
+}
+
+// split_request breaks every sample of the request into chunks of at most chunk_size bytes.
+// The series labels are only sent with the first chunk of each sample.
+fn split_request(req: &PushRequest, chunk_size: usize) -> Vec<PushChunk> {
+    let mut chunks = Vec::new();
+    for series in &req.series {
+        for sample in &series.samples {
+            let profile = &sample.raw_profile;
+            let chunk_size = chunk_size.max(1);
+            let mut start = 0;
+            // an empty profile still needs a single terminating chunk
+            loop {
+                let end = (start + chunk_size).min(profile.len());
+                chunks.push(PushChunk {
+                    labels: if start == 0 { series.labels.clone() } else { Vec::new() },
+                    id: sample.id.clone(),
+                    data: profile.slice(start..end),
+                    last: end == profile.len(),
+                });
+                if end == profile.len() {
+                    break;
+                }
+                start = end;
+            }
+        }
+    }
+    chunks
+}
+
 fn request_size(req: &PushRequest) -> (i64, i64) {
     let mut size = 0;
     let mut profiles = 0;
@@ -0,0 +1,279 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use gimli::{AttributeValue, EndianSlice, Operation, Reader, RunTimeEndian};
+use object::{Object, ObjectSection};
+use serde::{Deserialize, Serialize};
+
+impl Typ {
+    fn get_field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    offset: u64,
+}
+
+#[derive(Debug)]
+struct Typedef {
+    type_offsets: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct Typ {
+    name: String,
+    fields: Vec<Field>,
+    size: i64,
+}
+
+// Index holds the structs of a binary keyed by their .debug_info offset, so typedefs can point at them
+#[derive(Debug)]
+struct Index {
+    offset_to_type: HashMap<usize, Typ>,
+    typedefs: HashMap<String, Typedef>,
+}
+
+impl Index {
+    fn new() -> Self {
+        Self {
+            offset_to_type: HashMap::new(),
+            typedefs: HashMap::new(),
+        }
+    }
+
+    // get_type_by_name looks the name up as a typedef first, then as a struct tag
+    fn get_type_by_name(&self, name: &str) -> Option<&Typ> {
+        if name.is_empty() {
+            return None;
+        }
+        if let Some(typedef) = self.typedefs.get(name) {
+            let typ = typedef.type_offsets.iter()
+                .filter_map(|offset| self.offset_to_type.get(offset))
+                .find(|typ| typ.size != 0);
+            if typ.is_some() {
+                return typ;
+            }
+        }
+        self.offset_to_type.values().find(|typ| typ.name == name && typ.size != 0)
+    }
+}
+
+fn struct_member_offsets_from_dwarf<R: Reader<Offset=usize>>(dwarf: &gimli::Dwarf<R>) -> Result<Index> {
+    let mut res = Index::new();
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        let mut depth = 0isize;
+        // the struct whose members are being read and the depth of its entry
+        let mut current: Option<(usize, isize)> = None;
+
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            if let Some((_, struct_depth)) = current {
+                if depth <= struct_depth {
+                    current = None;
+                }
+            }
+            let offset = entry.offset().to_debug_info_offset(&unit.header).unwrap().0;
+
+            match entry.tag() {
+                gimli::DW_TAG_structure_type => {
+                    let name = match entry.attr_value(gimli::DW_AT_name)? {
+                        Some(value) => dwarf.attr_string(&unit, value)?.to_string_lossy()?.into_owned(),
+                        None => String::new(),
+                    };
+                    let size = entry.attr_value(gimli::DW_AT_byte_size)?
+                        .and_then(|v| v.udata_value())
+                        .unwrap_or(0) as i64;
+                    res.offset_to_type.insert(offset, Typ { name, fields: Vec::new(), size });
+                    current = Some((offset, depth));
+                }
+                gimli::DW_TAG_member => {
+                    let Some((struct_offset, struct_depth)) = current else {
+                        continue;
+                    };
+                    if depth != struct_depth + 1 {
+                        continue;
+                    }
+                    let Some(name) = entry.attr_value(gimli::DW_AT_name)? else {
+                        continue;
+                    };
+                    let name = dwarf.attr_string(&unit, name)?.to_string_lossy()?.into_owned();
+                    let member_offset = match entry.attr_value(gimli::DW_AT_data_member_location)? {
+                        Some(AttributeValue::Exprloc(expr)) => parse_expr(expr, unit.encoding())?,
+                        Some(value) => match value.udata_value() {
+                            Some(v) => v,
+                            None => continue,
+                        },
+                        // members of unions have no location
+                        None => 0,
+                    };
+                    let typ = res.offset_to_type.get_mut(&struct_offset).unwrap();
+                    typ.fields.push(Field { name, offset: member_offset });
+                }
+                gimli::DW_TAG_typedef => {
+                    let Some(name) = entry.attr_value(gimli::DW_AT_name)? else {
+                        continue;
+                    };
+                    let name = dwarf.attr_string(&unit, name)?.to_string_lossy()?.into_owned();
+                    let type_offset = match entry.attr_value(gimli::DW_AT_type)? {
+                        Some(AttributeValue::UnitRef(o)) => o.to_debug_info_offset(&unit.header).unwrap().0,
+                        Some(AttributeValue::DebugInfoRef(o)) => o.0,
+                        _ => continue,
+                    };
+                    res.typedefs.entry(name)
+                        .or_insert_with(|| Typedef { type_offsets: Vec::new() })
+                        .type_offsets.push(type_offset);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(res)
+}
+
+// parse_expr evaluates a DW_AT_data_member_location expression, which for members is a constant offset
+fn parse_expr<R: Reader>(expr: gimli::Expression<R>, encoding: gimli::Encoding) -> Result<u64> {
+    let mut ops = expr.operations(encoding);
+    let mut offset = 0;
+    while let Some(op) = ops.next()? {
+        match op {
+            Operation::PlusConstant { value } => offset += value,
+            Operation::UnsignedConstant { value } => offset = value,
+            op => return Err(anyhow!("unsupported member location op {:?}", op)),
+        }
+    }
+    Ok(offset)
+}
+
+// load_index reads the debug sections of the elf file and indexes its structs
+fn load_index(elf_path: &str) -> Result<Index> {
+    let data = fs::read(elf_path)?;
+    let elf_file = object::File::parse(&*data)?;
+    let endian = if elf_file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+
+    let load_section = |id: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(match elf_file.section_by_name(id.name()) {
+            Some(section) => section.uncompressed_data().unwrap_or(Cow::Borrowed(&[][..])),
+            None => Cow::Borrowed(&[][..]),
+        })
+    };
+    let dwarf_cow = gimli::Dwarf::load(load_section)?;
+    let dwarf = dwarf_cow.borrow(|section| EndianSlice::new(section, endian));
+
+    struct_member_offsets_from_dwarf(&dwarf)
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldDump {
+    pub name: String,
+    pub offset: i32,
+}
+
+pub fn dump(elf_path: &str, needs: &[Need]) -> Result<Vec<FieldDump>> {
+    let types = load_index(elf_path)?;
+
+    let mut field_dumps = Vec::new();
+    for need in needs {
+        let typ = types.get_type_by_name(&need.name)
+            .or_else(|| types.get_type_by_name(&need.pretty_name));
+
+        for need_field in &need.fields {
+            let o = match typ.and_then(|t| t.get_field(&need_field.name)) {
+                Some(f) => f.offset as i32,
+                None => -1,
+            };
+            let pname = if need_field.print_name.is_empty() {
+                format!("{}{}", type_name(need), field_name(&need_field.name))
+            } else {
+                need_field.print_name.clone()
+            };
+            field_dumps.push(FieldDump { name: pname, offset: o });
+        }
+        if need.size {
+            let sz_name = if need.size_name.is_empty() {
+                format!("{}Size", type_name(need))
+            } else {
+                need.size_name.clone()
+            };
+            let size = match typ {
+                Some(t) if t.size != 0 => t.size as i32,
+                _ => -1,
+            };
+            field_dumps.push(FieldDump { name: sz_name, offset: size });
+        }
+    }
+    Ok(field_dumps)
+}
+
+// Spec lists the structs and fields to dump, see python.yaml
+#[derive(Debug, Deserialize)]
+pub struct Spec {
+    pub needs: Vec<Need>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Need {
+    pub name: String,
+    #[serde(default)]
+    pub pretty_name: String,
+    #[serde(default)]
+    pub fields: Vec<NeedField>,
+    #[serde(default)]
+    pub size: bool,
+    #[serde(default)]
+    pub size_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NeedField {
+    pub name: String,
+    #[serde(default)]
+    pub print_name: String,
+}
+
+// OffsetsEntry is one row of the offsets database, the offsets of a single interpreter version
+#[derive(Debug, Serialize)]
+pub struct OffsetsEntry {
+    pub version: String,
+    pub offsets: BTreeMap<String, i32>,
+}
+
+impl OffsetsEntry {
+    pub fn new(version: String, fields: Vec<FieldDump>) -> Self {
+        Self {
+            version,
+            offsets: fields.into_iter().map(|f| (f.name, f.offset)).collect(),
+        }
+    }
+}
+
+fn type_name(need: &Need) -> String {
+    let n = if need.pretty_name.is_empty() { &need.name } else { &need.pretty_name };
+    camel_case(n.trim_matches('_'))
+}
+
+fn field_name(field: &str) -> String {
+    camel_case(field.trim_start_matches('_'))
+}
+
+fn camel_case(s: &str) -> String {
+    let mut result = String::new();
+    for part in s.split('_').filter(|p| !p.is_empty()) {
+        let (first, rest) = part.split_at(1);
+        result.push_str(&first.to_uppercase());
+        result.push_str(rest);
+    }
+    result
+}
@@ -0,0 +1,52 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+use crate::dwarfdump::{dump, OffsetsEntry, Spec};
+
+mod dwarfdump;
+
+// dwarfdump prints the struct member offsets of an interpreter binary with debug info,
+// in the format of the offsets database used by the bpf profilers
+#[derive(Parser, Debug)]
+struct Args {
+    /// binary with dwarf debug info, e.g. /usr/bin/python3.11
+    #[arg(long)]
+    binary: String,
+    /// yaml file listing the structs and fields to dump
+    #[arg(long)]
+    spec: String,
+    /// interpreter version of the binary, guessed from the file name when not set
+    #[arg(long)]
+    binary_version: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let spec: Spec = serde_yaml::from_str(&fs::read_to_string(&args.spec)?)?;
+
+    let version = match args.binary_version {
+        Some(v) => v,
+        None => version_from_path(&args.binary)
+            .ok_or_else(|| anyhow!("can not guess the version of {}, pass --binary-version", args.binary))?,
+    };
+
+    let fields = dump(&args.binary, &spec.needs)?;
+    let entry = OffsetsEntry::new(version, fields);
+    print!("{}", serde_yaml::to_string(&[entry])?);
+    Ok(())
+}
+
+// version_from_path takes the trailing version of names like python3.11 or libpython3.11.so.1.0
+fn version_from_path(path: &str) -> Option<String> {
+    let name = path.rsplit('/').next()?;
+    let name = name.split(".so").next()?;
+    let start = name.find(|c: char| c.is_ascii_digit())?;
+    let version = &name[start..];
+    if version.split('.').all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())) {
+        Some(version.to_string())
+    } else {
+        None
+    }
+}
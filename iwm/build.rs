@@ -1,13 +1,24 @@
 use libbpf_cargo::SkeletonBuilder;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // build for the target, not the host, so cross compiled arm64 agents get arm64 kernel types and pt_regs
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let bpf_arch = match arch.as_str() {
+        "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        _ => panic!("unsupported target arch {}", arch),
+    };
 
     ["profile", "pyperf"]
         .iter()
         .for_each(|name| {
             SkeletonBuilder::new()
                 .source(format!("src/ebpf/bpf/{}.bpf.c", name))
-                .clang_args("-I src/ebpf/bpf/vmlinux/aarch64 -I src/ebpf/bpf/libbpf -I src/ebpf/bpf")
+                .clang_args(format!(
+                    "-D__TARGET_ARCH_{} -I src/ebpf/bpf/vmlinux/{} -I src/ebpf/bpf/libbpf -I src/ebpf/bpf",
+                    bpf_arch, arch,
+                ))
                 .build_and_generate(format!("src/ebpf/bpf/{}.skel.rs", name))
                 .unwrap();
     });
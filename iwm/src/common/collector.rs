@@ -1,4 +1,6 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::ebpf::ktime::RoundWindow;
 use crate::ebpf::pprof::ProfileBuilders;
 use crate::ebpf::sd::target::EbpfTarget;
 use crate::ebpf::session::Session;
@@ -11,13 +13,40 @@ pub enum SampleType {
     Mem = 1,
 }
 
-#[derive(Debug)]
+// StackMode tells which stacks a sample is made of, it lets kernel time be filtered out of cpu profiles
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StackMode {
+    User,
+    Kernel,
+    Mixed,
+}
+
+impl StackMode {
+    pub fn from_stacks(user: bool, kernel: bool) -> Self {
+        match (user, kernel) {
+            (true, true) => StackMode::Mixed,
+            (false, true) => StackMode::Kernel,
+            _ => StackMode::User,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StackMode::User => "user",
+            StackMode::Kernel => "kernel",
+            StackMode::Mixed => "mixed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ProfileSample<'a> {
     pub target: &'a EbpfTarget,
     pub pid: u32,
     pub sample_type: SampleType,
     pub aggregation: bool,
     pub stack: Vec<String>,
+    pub mode: StackMode,
     pub value: u64,
     pub value2: u64,
 }
@@ -25,28 +54,47 @@ pub struct ProfileSample<'a> {
 pub const SAMPLE_TYPE_CPU: SampleType = SampleType::Cpu;
 pub const SAMPLE_TYPE_MEM: SampleType = SampleType::Mem;
 
+// SamplesCollector is a source of profile samples. collect and ProfileBuilders only see samples through it,
+// so any profiler can feed the same pipeline as the ebpf session by implementing it.
 pub trait SamplesCollector {
+    // collect_profiles runs a collection round and passes every sample of it to callback
     fn collect_profiles<F>(&mut self, callback: F)-> Result<()>
         where F: Fn(ProfileSample);
+
+    // round_window is the time span of the samples of the last collect_profiles
+    fn round_window(&self) -> RoundWindow;
+}
+
+pub fn collect<S>(builders: Arc<Mutex<ProfileBuilders>>, collector: &mut S) -> Result<()> where S: SamplesCollector {
+    collect_with(builders, collector, |_| {})
 }
 
-pub fn collect<S>(builders: Arc<Mutex<ProfileBuilders>>, collector: &mut MutexGuard<S>) -> Result<()> where S: SamplesCollector {
+// collect_with collects like collect and also shows every sample to tap before it is added
+pub fn collect_with<S, T>(builders: Arc<Mutex<ProfileBuilders>>, collector: &mut S, tap: T) -> Result<()>
+    where S: SamplesCollector, T: Fn(&ProfileSample) {
     collector.collect_profiles(|sample: ProfileSample| {
+        tap(&sample);
         if let Ok(mut b) = builders.lock() {
             b.add_sample(sample);
         }
-    }).unwrap();
+    })?;
+    builders.lock().unwrap().set_round_window(collector.round_window());
     Ok(())
 }
 
 impl SamplesCollector for Session<'_> {
     fn collect_profiles<F>(&mut self, callback: F) -> Result<()> where F: Fn(ProfileSample) {
-        if let Ok(mut sym_cache) = self.sym_cache.lock() {
-            sym_cache.next_round();
-            self.round_number += 1;
-        }
-        self.collect_regular_profile(callback).unwrap();
+        let started = Instant::now();
+        self.next_round();
+        self.collect_regular_profile(callback)?;
         self.cleanup();
+        self.collect_bpf_stats();
+        self.collect_process_metrics();
+        self.metrics().round_duration.observe(started.elapsed().as_secs_f64());
         Ok(())
     }
+
+    fn round_window(&self) -> RoundWindow {
+        Session::round_window(self)
+    }
 }
\ No newline at end of file
@@ -3,6 +3,17 @@ use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
+use crate::ebpf::sd::target::{LABEL_SERVICE_NAME, RESERVED_LABEL_PREFIX};
+
+// the limits pyroscope enforces by default, series over them are dropped by the distributor
+pub const MAX_LABEL_NAME_LENGTH: usize = 1024;
+pub const MAX_LABEL_VALUE_LENGTH: usize = 2048;
+pub const MAX_LABEL_NAMES_PER_SERIES: usize = 30;
+
+lazy_static::lazy_static! {
+    static ref INVALID_LABEL_CHAR_RE: regex::Regex = regex::Regex::new(r"[^a-zA-Z0-9_]").unwrap();
+}
+
 // Define the Label struct
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Label {
@@ -64,19 +75,21 @@ impl Labels {
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
-    pub fn hash(&self) -> u64 {
-        let mut hasher = xxhash_rust::xxh64::Xxh64::new(0);
-        let mut buffer = Vec::with_capacity(1024);
-        let sep: u8 = 0xff;
-        for label in &self.0 {
-            buffer.clear();
-            buffer.extend(label.name.as_bytes());
-            buffer.push(sep);
-            buffer.extend(label.value.as_bytes());
-            buffer.push(sep);
-            hasher.write(&buffer);
+    // fingerprint identifies the label set whatever the order of its labels, which follows the
+    // randomly seeded HashMap it was built from. xxh3 has no per process seed, so the fingerprint
+    // of a series is the same across restarts and can key state that outlives the agent.
+    pub fn fingerprint(&self) -> u64 {
+        let mut labels: Vec<&Label> = self.0.iter().collect();
+        labels.sort();
+        let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+        let sep = [0xffu8];
+        for label in labels {
+            hasher.update(label.name.as_bytes());
+            hasher.update(&sep);
+            hasher.update(label.value.as_bytes());
+            hasher.update(&sep);
         }
-        hasher.finish()
+        hasher.digest()
     }
 }
 
@@ -107,3 +120,108 @@ impl FromStr for Labels {
         Ok(Labels::new(labels))
     }
 }
+
+// is_valid_label_name checks the name against the prometheus label name rules, [a-zA-Z_][a-zA-Z0-9_]*
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+// sanitize_label_name replaces the characters not allowed in label names with _,
+// names starting with a digit get a leading _
+pub fn sanitize_label_name(name: &str) -> String {
+    let name = INVALID_LABEL_CHAR_RE.replace_all(name, "_");
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return format!("_{}", name);
+    }
+    name.to_string()
+}
+
+// LabelFixes counts the labels normalize_labels changed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LabelFixes {
+    // renamed are invalid names replaced by their sanitized name
+    pub renamed: usize,
+    // dropped are reserved, empty, overlong and colliding labels
+    pub dropped: usize,
+    // truncated are values cut to MAX_LABEL_VALUE_LENGTH
+    pub truncated: usize,
+}
+
+// LabelRejection is why a series can't be pushed at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelRejection {
+    MissingServiceName,
+    TooManyLabels,
+}
+
+impl LabelRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LabelRejection::MissingServiceName => "missing_service_name",
+            LabelRejection::TooManyLabels => "too_many_labels",
+        }
+    }
+}
+
+impl fmt::Display for LabelRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelRejection::MissingServiceName => write!(f, "{} is missing or empty", LABEL_SERVICE_NAME),
+            LabelRejection::TooManyLabels => write!(f, "more than {} labels", MAX_LABEL_NAMES_PER_SERIES),
+        }
+    }
+}
+
+// normalize_labels shapes the labels of a series to the rules prometheus and pyroscope validate
+// pushes with, so series aren't silently dropped by the backend. Labels with the reserved __ prefix
+// are dropped unless listed in keep_reserved, empty values are dropped as prometheus treats them as
+// unset, invalid names are sanitized and a sanitized name taken by a valid label is dropped.
+// Series without a service_name or with too many labels are rejected.
+pub fn normalize_labels(
+    labels: HashMap<String, String>,
+    keep_reserved: &[&str],
+) -> std::result::Result<(HashMap<String, String>, LabelFixes), LabelRejection> {
+    let mut fixes = LabelFixes::default();
+    let mut res = HashMap::with_capacity(labels.len());
+    let mut renamed = Vec::new();
+    for (name, mut value) in labels {
+        let reserved = name.starts_with(RESERVED_LABEL_PREFIX) && !keep_reserved.contains(&name.as_str());
+        if reserved || value.is_empty() || name.len() > MAX_LABEL_NAME_LENGTH {
+            fixes.dropped += 1;
+            continue;
+        }
+        if value.len() > MAX_LABEL_VALUE_LENGTH {
+            let mut end = MAX_LABEL_VALUE_LENGTH;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            value.truncate(end);
+            fixes.truncated += 1;
+        }
+        if is_valid_label_name(&name) {
+            res.insert(name, value);
+        } else {
+            renamed.push((sanitize_label_name(&name), value));
+        }
+    }
+    // valid names win over sanitized ones, whatever order the labels came in
+    for (name, value) in renamed {
+        if res.contains_key(&name) {
+            fixes.dropped += 1;
+            continue;
+        }
+        res.insert(name, value);
+        fixes.renamed += 1;
+    }
+    if !res.contains_key(LABEL_SERVICE_NAME) {
+        return Err(LabelRejection::MissingServiceName);
+    }
+    if res.len() > MAX_LABEL_NAMES_PER_SERIES {
+        return Err(LabelRejection::TooManyLabels);
+    }
+    Ok((res, fixes))
+}
@@ -8,6 +8,18 @@
 #include "ume.h"
 
 #define PF_KTHREAD 0x00200000
+#define E2BIG 7
+
+// count_overflow counts a sample of the pid the counts map had no room for
+static __always_inline void count_overflow(u32 pid) {
+    u64 *dropped = bpf_map_lookup_elem(&counts_overflow, &pid);
+    if (dropped) {
+        __sync_fetch_and_add(dropped, 1);
+    } else {
+        u64 one = 1;
+        bpf_map_update_elem(&counts_overflow, &pid, &one, BPF_NOEXIST);
+    }
+}
 
 SEC("perf_event")
 int do_perf_event(struct bpf_perf_event_data *ctx) {
@@ -20,6 +32,7 @@ int do_perf_event(struct bpf_perf_event_data *ctx) {
     if (tgid == 0 || task == 0) {
         return 0;
     }
+    last_sample_ktime = bpf_ktime_get_ns();
 
     int flags = 0;
     if (bpf_probe_read_kernel(&flags, sizeof(flags), &task->flags)) {
@@ -27,7 +40,7 @@ int do_perf_event(struct bpf_perf_event_data *ctx) {
         return 0;
     }
 
-    if (flags & PF_KTHREAD) {
+    if ((flags & PF_KTHREAD) && !collect_kthreads) {
         bpf_dbg_printk("skipping kthread %d\n", tgid);
         return 0;
     }
@@ -38,7 +51,7 @@ int do_perf_event(struct bpf_perf_event_data *ctx) {
                 .profile_type = PROFILING_TYPE_UNKNOWN,
                 .collect_kernel = 0,
                 .collect_user = 0,
-                .padding_ = 0
+                .sample_every = 0
         };
         if (bpf_map_update_elem(&pids, &tgid, &unknown, BPF_NOEXIST)) {
             bpf_dbg_printk("failed to update pids map. probably concurrent update\n");
@@ -56,6 +69,16 @@ int do_perf_event(struct bpf_perf_event_data *ctx) {
         return 0;
     }
 
+    // JVMs are profiled by async-profiler from userspace
+    if (config->profile_type == PROFILING_TYPE_JAVA) {
+        return 0;
+    }
+
+    // services with a lower sample rate than the perf events drop samples at random
+    if (config->sample_every > 1 && bpf_get_prandom_u32() % config->sample_every != 0) {
+        return 0;
+    }
+
     if (config->profile_type == PROFILING_TYPE_PYTHON) {
         bpf_tail_call(ctx, &progs, PROG_IDX_PYTHON);
         return 0;
@@ -74,64 +97,122 @@ int do_perf_event(struct bpf_perf_event_data *ctx) {
         }
 
         val = bpf_map_lookup_elem(&counts, &key);
-        if (val)
+        if (val) {
             (*val)++;
-        else
-            bpf_map_update_elem(&counts, &key, &one, BPF_NOEXIST);
+        } else {
+            long ret = bpf_map_update_elem(&counts, &key, &one, BPF_NOEXIST);
+            if (ret == 0)
+                __sync_fetch_and_add(&counts_inserted, 1);
+            else if (ret == -E2BIG)
+                count_overflow(tgid);
+        }
     }
     return 0;
 }
 
 
-SEC("kprobe/disassociate_ctty")
-int BPF_KPROBE(disassociate_ctty, int on_exit) {
-    bpf_dbg_printk("kprobe/disassociate_ctty\n");
-    if (!on_exit) {
-        return 0;
-    }
+static __always_inline int send_pid_event(void *ctx, u32 op) {
     u32 pid = 0;
     current_pid(&pid);
     if (pid == 0) {
         return 0;
     }
     struct pid_event event = {
-        .op  = OP_PID_DEAD,
-        .pid = pid
+            .op  = op,
+            .pid = pid
     };
     bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU, &event, sizeof(event));
     return 0;
 }
 
-SEC("kprobe/" SYS_PREFIX "sys_execve")
-int BPF_KPROBE(execve, void *_) {
-    bpf_dbg_printk("kprobe/sys_execve\n");
-    u32 pid = 0;
-    current_pid(&pid);
-    if (pid == 0) {
+// sched_process_exit fires for every thread, the process is gone once its group leader exits
+static __always_inline int on_thread_exit(void *ctx) {
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    if ((u32)pid_tgid != (u32)(pid_tgid >> 32)) {
         return 0;
     }
-    struct pid_event event = {
-            .op  = OP_REQUEST_EXEC_PROCESS_INFO,
-            .pid = pid
-    };
-    bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU, &event, sizeof(event));
-    return 0;
+    return send_pid_event(ctx, OP_PID_DEAD);
+}
+
+// The process lifecycle hooks come in three flavors, user space loads one of them depending on what
+// the kernel supports, see HookAttach: kprobes, raw tracepoints (4.17+) and BTF tracepoints (5.5+ with BTF).
+
+SEC("kprobe/disassociate_ctty")
+int BPF_KPROBE(disassociate_ctty, int on_exit) {
+    bpf_dbg_printk("kprobe/disassociate_ctty\n");
+    if (!on_exit) {
+        return 0;
+    }
+    return send_pid_event(ctx, OP_PID_DEAD);
 }
 
-SEC("kprobe/" SYS_PREFIX "sys_execveat")
+// attached from user space to SYS_PREFIX "sys_execve", or sys_execve on kernels without syscall wrappers
+SEC("kprobe")
+int BPF_KPROBE(execve, void *_) {
+    bpf_dbg_printk("kprobe/sys_execve\n");
+    return send_pid_event(ctx, OP_REQUEST_EXEC_PROCESS_INFO);
+}
+
+SEC("kprobe")
 int BPF_KPROBE(execveat, void *_) {
     bpf_dbg_printk("kprobe/sys_execveat\n");
+    return send_pid_event(ctx, OP_REQUEST_EXEC_PROCESS_INFO);
+}
+
+SEC("raw_tracepoint/sched_process_exec")
+int raw_tp_exec(struct bpf_raw_tracepoint_args *ctx) {
+    return send_pid_event(ctx, OP_REQUEST_EXEC_PROCESS_INFO);
+}
+
+SEC("raw_tracepoint/sched_process_exit")
+int raw_tp_exit(struct bpf_raw_tracepoint_args *ctx) {
+    return on_thread_exit(ctx);
+}
+
+SEC("tp_btf/sched_process_exec")
+int BPF_PROG(tp_btf_exec, struct task_struct *p, pid_t old_pid, struct linux_binprm *bprm) {
+    return send_pid_event(ctx, OP_REQUEST_EXEC_PROCESS_INFO);
+}
+
+SEC("tp_btf/sched_process_exit")
+int BPF_PROG(tp_btf_exit, struct task_struct *p) {
+    return on_thread_exit(ctx);
+}
+
+#define PROT_EXEC 0x4
+
+// on_exec_mapping tells user space that a profiled pid maps code, so its /proc/pid/maps is re-read
+// before the next symbolization instead of every round
+static __always_inline int on_exec_mapping(void *ctx, unsigned long prot) {
+    if (!(prot & PROT_EXEC)) {
+        return 0;
+    }
     u32 pid = 0;
     current_pid(&pid);
     if (pid == 0) {
         return 0;
     }
+    struct pid_config *config = bpf_map_lookup_elem(&pids, &pid);
+    if (config == NULL || config->profile_type == PROFILING_TYPE_UNKNOWN || config->profile_type == PROFILING_TYPE_ERROR) {
+        return 0;
+    }
     struct pid_event event = {
-            .op  = OP_REQUEST_EXEC_PROCESS_INFO,
+            .op  = OP_MAPS_CHANGED,
             .pid = pid
     };
     bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU, &event, sizeof(event));
     return 0;
 }
 
+SEC("tracepoint/syscalls/sys_enter_mmap")
+int mmap_enter(struct trace_event_raw_sys_enter *ctx) {
+    return on_exec_mapping(ctx, ctx->args[2]);
+}
+
+// dlopen and jits map code writable first and make it executable afterwards
+SEC("tracepoint/syscalls/sys_enter_mprotect")
+int mprotect_enter(struct trace_event_raw_sys_enter *ctx) {
+    return on_exec_mapping(ctx, ctx->args[2]);
+}
+
 char _license[] SEC("license") = "GPL";
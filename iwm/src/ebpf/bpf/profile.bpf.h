@@ -30,18 +30,21 @@ struct sample_key k__;
 #define PROFILING_TYPE_FRAMEPOINTERS 2
 #define PROFILING_TYPE_PYTHON 3
 #define PROFILING_TYPE_ERROR 4
+#define PROFILING_TYPE_JAVA 5
 
 struct pid_config {
     uint8_t profile_type;
     uint8_t collect_user;
     uint8_t collect_kernel;
-    uint8_t padding_;
+    // sample_every keeps one in that many samples of the pid, 0 and 1 keep all
+    uint8_t sample_every;
 };
 struct pid_config p__;
 
 #define OP_REQUEST_UNKNOWN_PROCESS_INFO 1
 #define OP_PID_DEAD 2
 #define OP_REQUEST_EXEC_PROCESS_INFO 3
+#define OP_MAPS_CHANGED 4
 
 struct pid_event {
     uint32_t op;
@@ -49,6 +52,14 @@ struct pid_event {
 };
 struct pid_event e__;
 
+// last_sample_ktime is the bpf_ktime_get_ns of the latest sample, user space uses it as the end of a round
+volatile u64 last_sample_ktime;
+// collect_kthreads is set from user space when kernel threads are profiled under the kernel target
+volatile u8 collect_kthreads;
+// counts_inserted is the number of entries added to the counts map, with the entries user space
+// drained it tells how full the map is between rounds
+volatile u64 counts_inserted;
+
 struct {
     __uint(type, BPF_MAP_TYPE_HASH);
     __type(key, u32);
@@ -83,4 +94,13 @@ struct {
     __uint(max_entries, PROFILE_MAPS_SIZE);
 } counts SEC(".maps");
 
+// counts_overflow counts the samples of a pid dropped because the counts map was full. Only pids
+// of the pids map are sampled, so it has as many entries.
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __type(key, u32);
+    __type(value, u64);
+    __uint(max_entries, 1024);
+} counts_overflow SEC(".maps");
+
 #endif // PROFILE_BPF_H
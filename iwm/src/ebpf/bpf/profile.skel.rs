@@ -224,7 +224,7 @@ pub struct pid_config {
     pub profile_type: u8,
     pub collect_user: u8,
     pub collect_kernel: u8,
-    pub padding_: u8,
+    pub sample_every: u8,
 }
 #[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
@@ -1,10 +1,11 @@
 use std::fs;
+use crate::error::Error;
 use crate::error::Result;
 
 const CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
 
 pub fn get() -> Result<Vec<u32>> {
-    let buf = fs::read_to_string(CPU_ONLINE).unwrap();
+    let buf = fs::read_to_string(CPU_ONLINE).map_err(|e| Error::from_io(format!("read {}", CPU_ONLINE), &e))?;
     read_cpu_range(&buf)
 }
 
@@ -12,13 +13,15 @@ pub fn read_cpu_range(cpu_range_str: &str) -> Result<Vec<u32>> {
     let mut cpus = Vec::new();
     for cpu_range in cpu_range_str.trim().split(',') {
 
+        let parse = |s: &str| s.parse::<u32>()
+            .map_err(|_| Error::invalid_data(format!("invalid cpu range {:?}", cpu_range)));
         let range_op: Vec<&str> = cpu_range.split('-').collect();
-        let first: u32 = range_op[0].parse().unwrap();
+        let first = parse(range_op[0])?;
         if range_op.len() == 1 {
             cpus.push(first);
             continue;
         }
-        let last: u32 = range_op[1].parse().unwrap();
+        let last = parse(range_op[1])?;
         for n in first..=last {
             cpus.push(n);
         }
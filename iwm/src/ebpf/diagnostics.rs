@@ -0,0 +1,113 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use libbpf_rs::PrintLevel;
+use log::debug;
+use serde::Serialize;
+
+use crate::ebpf::probe::{btf_available, kernel_release, HookAttach};
+
+// MAX_LOG_BYTES bounds a captured log, a level 2 verifier log of a large program runs into megabytes.
+// The tail is kept, the verifier reports the instruction it rejected last.
+const MAX_LOG_BYTES: usize = 256 << 10;
+// MAX_FAILURES bounds the failures kept for the debug endpoint, the oldest are dropped first
+const MAX_FAILURES: usize = 8;
+
+static CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+static FAILURES: Mutex<Vec<LoadFailure>> = Mutex::new(Vec::new());
+
+// LoadFailure is a failed load or attach of the bpf programs, with what is needed to tell why the
+// kernel refused them
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadFailure {
+    // stage is load or attach
+    pub stage: &'static str,
+    // hook_attach is the mode of the process lifecycle hooks that was tried
+    pub hook_attach: &'static str,
+    pub error: String,
+    pub kernel_release: String,
+    pub btf: bool,
+    // log is the libbpf output of the attempt, the verifier log of a rejected program included
+    pub log: String,
+}
+
+impl LoadFailure {
+    pub fn new(stage: &'static str, hook_attach: HookAttach, error: String, log: String) -> Self {
+        Self {
+            stage,
+            hook_attach: hook_attach.as_str(),
+            error,
+            kernel_release: kernel_release().unwrap_or_else(|| "unknown".to_string()),
+            btf: btf_available(),
+            log,
+        }
+    }
+}
+
+impl fmt::Display for LoadFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bpf programs with {} hooks: {} (kernel {}, btf {})",
+            self.stage, self.hook_attach, self.error, self.kernel_release, self.btf)?;
+        if !self.log.is_empty() {
+            write!(f, "\nlibbpf log:\n{}", self.log.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+// BpfDiagnostics is what the debug endpoint reports about loading the bpf programs
+#[derive(Debug, Clone, Serialize)]
+pub struct BpfDiagnostics {
+    pub kernel_release: String,
+    pub btf: bool,
+    // failures are the failed loads and attaches of this process, a failed hook mode that another
+    // mode replaced is listed as well
+    pub failures: Vec<LoadFailure>,
+}
+
+impl BpfDiagnostics {
+    pub fn current() -> Self {
+        Self {
+            kernel_release: kernel_release().unwrap_or_else(|| "unknown".to_string()),
+            btf: btf_available(),
+            failures: FAILURES.lock().unwrap().clone(),
+        }
+    }
+}
+
+// record keeps the failure for the debug endpoint
+pub fn record(failure: LoadFailure) {
+    let mut failures = FAILURES.lock().unwrap();
+    if failures.len() == MAX_FAILURES {
+        failures.remove(0);
+    }
+    failures.push(failure);
+}
+
+// capture_libbpf_log runs f with the libbpf output captured, the output is still logged at debug
+// level. Captures don't nest, the programs are loaded by one session at a time.
+pub fn capture_libbpf_log<T>(f: impl FnOnce() -> T) -> (T, String) {
+    *CAPTURE.lock().unwrap() = Some(String::new());
+    let previous = libbpf_rs::set_print(Some((PrintLevel::Debug, capture_print)));
+    let res = f();
+    libbpf_rs::set_print(previous);
+    let log = CAPTURE.lock().unwrap().take().unwrap_or_default();
+    (res, log)
+}
+
+fn capture_print(_level: PrintLevel, msg: String) {
+    debug!("libbpf: {}", msg.trim_end());
+    let mut capture = CAPTURE.lock().unwrap();
+    let Some(log) = capture.as_mut() else {
+        return;
+    };
+    log.push_str(&msg);
+    if log.len() > MAX_LOG_BYTES {
+        // drop a quarter more than needed, so the front isn't cut at every line
+        let mut cut = log.len() - MAX_LOG_BYTES + MAX_LOG_BYTES / 4;
+        while !log.is_char_boundary(cut) {
+            cut += 1;
+        }
+        log.drain(..cut);
+    }
+}
@@ -1 +0,0 @@
-mod dwarfdump;
\ No newline at end of file
@@ -0,0 +1,277 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::error::Error::{Closed, DeadlineExceeded};
+use crate::error::Result;
+
+// WAKE_KEY is the key of the eventfd used to interrupt wait, it never reaches callers
+const WAKE_KEY: u64 = u64::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+// Poller waits for readiness of a set of fds, each registered with a caller chosen key.
+// Fds can be added and removed while another thread waits, which is what the ring reader
+// needs to follow cpus going on and offline.
+pub struct Poller {
+    epoll: OwnedFd,
+    wake: OwnedFd,
+    registered: AtomicUsize,
+    // event buffer of wait, only one thread is expected to wait at a time
+    events: Mutex<Vec<libc::epoll_event>>,
+    closed: AtomicBool,
+}
+
+impl Poller {
+    pub fn new() -> Result<Self> {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(Error::last_os_error("epoll_create1"));
+        }
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wake < 0 {
+            return Err(Error::last_os_error("eventfd"));
+        }
+        let wake = unsafe { OwnedFd::from_raw_fd(wake) };
+        let poller = Self {
+            epoll,
+            wake,
+            registered: AtomicUsize::new(0),
+            events: Mutex::new(Vec::new()),
+            closed: AtomicBool::new(false),
+        };
+        poller.ctl(libc::EPOLL_CTL_ADD, poller.wake.as_raw_fd(), WAKE_KEY, Trigger::Level)?;
+        Ok(poller)
+    }
+
+    // add registers fd for readability, events of fd are reported with key
+    pub fn add(&self, fd: RawFd, key: u64, trigger: Trigger) -> Result<()> {
+        if key == WAKE_KEY {
+            return Err(Error::invalid_data(format!("epoll key {} is reserved", key)));
+        }
+        self.ctl(libc::EPOLL_CTL_ADD, fd, key, trigger)?;
+        self.registered.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn remove(&self, fd: RawFd) -> Result<()> {
+        let ret = unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+        if ret < 0 {
+            return Err(Error::last_os_error(format!("epoll_ctl del fd {}", fd)));
+        }
+        self.registered.fetch_sub(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, key: u64, trigger: Trigger) -> Result<()> {
+        let mut flags = libc::EPOLLIN as u32;
+        if trigger == Trigger::Edge {
+            flags |= libc::EPOLLET as u32;
+        }
+        let mut event = libc::epoll_event { events: flags, u64: key };
+        let ret = unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) };
+        if ret < 0 {
+            return Err(Error::last_os_error(format!("epoll_ctl fd {}", fd)));
+        }
+        Ok(())
+    }
+
+    // wait blocks until at least one fd is readable and appends the keys of the ready fds to keys.
+    // Without a deadline it blocks until an event, flush or close. A deadline in the past polls
+    // without blocking instead of waiting forever, and DeadlineExceeded is returned when nothing
+    // became ready in time.
+    pub fn wait(&self, keys: &mut Vec<u64>, deadline: Option<Instant>) -> Result<()> {
+        let mut events = self.events.lock().unwrap();
+        // one slot per registered fd plus the wake fd
+        events.resize(self.registered.load(Ordering::Relaxed) + 1, libc::epoll_event { events: 0, u64: 0 });
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(Closed);
+            }
+            let timeout = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    // round up so a sub-millisecond remainder doesn't turn into a busy loop
+                    remaining.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+                }
+            };
+            let n = unsafe {
+                libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, timeout)
+            };
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::from_io("epoll_wait", &err));
+            }
+            let mut woken = false;
+            for event in &events[..n as usize] {
+                if event.u64 == WAKE_KEY {
+                    woken = true;
+                    self.drain_wake();
+                } else {
+                    keys.push(event.u64);
+                }
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return Err(Closed);
+            }
+            if !keys.is_empty() || woken {
+                return Ok(());
+            }
+            if timeout == 0 || deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(DeadlineExceeded);
+            }
+        }
+    }
+
+    // flush interrupts a blocked wait, it returns with whatever keys are ready
+    pub fn flush(&self) -> Result<()> {
+        let one: u64 = 1;
+        let ret = unsafe { libc::write(self.wake.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            // the counter is already non zero, the waiter will wake up anyway
+            if err.kind() != std::io::ErrorKind::WouldBlock {
+                return Err(Error::from_io("eventfd write", &err));
+            }
+        }
+        Ok(())
+    }
+
+    // close makes pending and future waits return Closed
+    pub fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::Release);
+        self.flush()
+    }
+
+    fn drain_wake(&self) {
+        let mut buf: u64 = 0;
+        unsafe { libc::read(self.wake.as_raw_fd(), &mut buf as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::error::Error;
+
+    use super::{Poller, Trigger, WAKE_KEY};
+
+    fn eventfd() -> OwnedFd {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        assert!(fd >= 0);
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    }
+
+    fn signal(fd: &OwnedFd) {
+        let one: u64 = 1;
+        let ret = unsafe { libc::write(fd.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+        assert_eq!(ret, 8);
+    }
+
+    fn poll(poller: &Poller) -> Result<Vec<u64>, Error> {
+        let mut keys = Vec::new();
+        poller.wait(&mut keys, Some(Instant::now())).map(|_| keys)
+    }
+
+    #[test]
+    fn level_triggered_fds_are_reported_until_read() {
+        let poller = Poller::new().unwrap();
+        let (a, b) = (eventfd(), eventfd());
+        poller.add(a.as_raw_fd(), 1, Trigger::Level).unwrap();
+        poller.add(b.as_raw_fd(), 2, Trigger::Level).unwrap();
+        signal(&b);
+        assert_eq!(poll(&poller).unwrap(), vec![2]);
+        assert_eq!(poll(&poller).unwrap(), vec![2]);
+        signal(&a);
+        let mut keys = poll(&poller).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2]);
+    }
+
+    #[test]
+    fn edge_triggered_fds_are_reported_once_per_write() {
+        let poller = Poller::new().unwrap();
+        let fd = eventfd();
+        poller.add(fd.as_raw_fd(), 7, Trigger::Edge).unwrap();
+        signal(&fd);
+        assert_eq!(poll(&poller).unwrap(), vec![7]);
+        assert!(matches!(poll(&poller), Err(Error::DeadlineExceeded)));
+        signal(&fd);
+        assert_eq!(poll(&poller).unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn removed_fds_are_not_reported() {
+        let poller = Poller::new().unwrap();
+        let fd = eventfd();
+        poller.add(fd.as_raw_fd(), 1, Trigger::Level).unwrap();
+        poller.remove(fd.as_raw_fd()).unwrap();
+        signal(&fd);
+        assert!(matches!(poll(&poller), Err(Error::DeadlineExceeded)));
+        assert!(poller.remove(fd.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn the_wake_key_is_reserved() {
+        let poller = Poller::new().unwrap();
+        let fd = eventfd();
+        assert!(poller.add(fd.as_raw_fd(), WAKE_KEY, Trigger::Level).is_err());
+    }
+
+    #[test]
+    fn a_past_deadline_polls_without_blocking() {
+        let poller = Poller::new().unwrap();
+        let started = Instant::now();
+        let mut keys = Vec::new();
+        let past = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        assert!(matches!(poller.wait(&mut keys, Some(past)), Err(Error::DeadlineExceeded)));
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn a_deadline_is_waited_for() {
+        let poller = Poller::new().unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut keys = Vec::new();
+        assert!(matches!(poller.wait(&mut keys, Some(deadline)), Err(Error::DeadlineExceeded)));
+        assert!(Instant::now() >= deadline);
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn flush_and_close_interrupt_a_blocked_wait() {
+        let poller = Arc::new(Poller::new().unwrap());
+        let waiter = {
+            let poller = poller.clone();
+            std::thread::spawn(move || {
+                let mut keys = Vec::new();
+                let flushed = poller.wait(&mut keys, None);
+                let closed = poller.wait(&mut keys, None);
+                (flushed, closed, keys)
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        poller.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        poller.close().unwrap();
+        let (flushed, closed, keys) = waiter.join().unwrap();
+        assert!(flushed.is_ok());
+        assert!(matches!(closed, Err(Error::Closed)));
+        assert!(keys.is_empty());
+        assert!(matches!(poll(&poller), Err(Error::Closed)));
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// monotonic_ns reads CLOCK_MONOTONIC, the clock bpf_ktime_get_ns reports
+pub fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// ktime_to_unix_ns converts a bpf_ktime_get_ns timestamp to nanoseconds since the unix epoch.
+// The offset between the clocks is taken at call time, so wall clock steps (NTP) made since the
+// timestamp was taken are applied to it too, which is what timelines of backends expect.
+pub fn ktime_to_unix_ns(ktime: u64) -> i64 {
+    let mono = monotonic_ns();
+    let wall = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos() as i64;
+    wall - (mono as i64 - ktime as i64)
+}
+
+// RoundWindow is the time span the samples of a collection round were taken in
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundWindow {
+    pub time_nanos: i64,
+    pub duration_nanos: i64,
+}
+
+impl RoundWindow {
+    // from_ktime builds the window between two bpf_ktime_get_ns timestamps
+    pub fn from_ktime(start: u64, end: u64) -> Self {
+        Self {
+            time_nanos: ktime_to_unix_ns(start),
+            duration_nanos: end.saturating_sub(start) as i64,
+        }
+    }
+}
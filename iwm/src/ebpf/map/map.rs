@@ -0,0 +1,127 @@
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd};
+
+use libbpf_rs::libbpf_sys;
+
+use crate::error::Error;
+use crate::error::Result;
+
+// BpfMap is the subset of map operations the session needs to drain and clean up its maps.
+// Keys and values are the raw bytes the kernel sees, errors carry the errno of the failed operation.
+// The session goes through it instead of the skeleton maps, so the collect and cleanup logic can run
+// against mock::MockMap without root or a kernel, see the tests of session.
+pub trait BpfMap {
+    fn name(&self) -> &str;
+    fn max_entries(&self) -> u32;
+    fn keys(&self) -> Vec<Vec<u8>>;
+    // lookup returns None when the key is not in the map
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn update(&self, key: &[u8], value: &[u8]) -> Result<()>;
+    // delete of a missing key is an error with ENOENT, like the kernel's
+    fn delete(&self, key: &[u8]) -> Result<()>;
+}
+
+impl BpfMap for libbpf_rs::Map {
+    fn name(&self) -> &str {
+        libbpf_rs::Map::name(self)
+    }
+
+    fn max_entries(&self) -> u32 {
+        self.info().map(|info| info.info.max_entries).unwrap_or(0)
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        libbpf_rs::Map::keys(self).collect()
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut value = vec![0u8; self.value_size() as usize];
+        let ret = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.as_fd().as_raw_fd(),
+                key.as_ptr() as *const _,
+                value.as_mut_ptr() as *mut _,
+            )
+        };
+        match ret {
+            0 => Ok(Some(value)),
+            ret if -ret == libc::ENOENT => Ok(None),
+            // Error code is returned negative, flip to positive to match errno
+            ret => Err(map_error(BpfMap::name(self), "lookup", -ret)),
+        }
+    }
+
+    fn update(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.as_fd().as_raw_fd(),
+                key.as_ptr() as *const _,
+                value.as_ptr() as *const _,
+                libbpf_sys::BPF_ANY as u64,
+            )
+        };
+        if ret < 0 {
+            return Err(map_error(BpfMap::name(self), "update", -ret));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let ret = unsafe {
+            libbpf_sys::bpf_map_delete_elem(self.as_fd().as_raw_fd(), key.as_ptr() as *const _)
+        };
+        if ret < 0 {
+            return Err(map_error(BpfMap::name(self), "delete", -ret));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn map_error(map: &str, op: &str, errno: i32) -> Error {
+    Error::MapError { map: map.to_string(), op: op.to_string(), errno }
+}
+
+// drain reads and deletes every entry of the map one key at a time. Entries deleted between listing
+// the keys and the lookup, e.g. by a racing cleanup of a dead pid, are skipped, and so are entries
+// whose key or value size doesn't match K or V.
+pub fn drain<K: Copy, V: Copy>(m: &dyn BpfMap) -> Result<Vec<(K, V)>> {
+    let mut entries = Vec::new();
+    for key in m.keys() {
+        let Some(value) = m.lookup(&key)? else {
+            continue;
+        };
+        match m.delete(&key) {
+            Err(err) if err.errno() != Some(libc::ENOENT) => return Err(err),
+            _ => {}
+        }
+        if let (Some(k), Some(v)) = (from_bytes::<K>(&key), from_bytes::<V>(&value)) {
+            entries.push((k, v));
+        }
+    }
+    Ok(entries)
+}
+
+// delete_keys deletes the keys from the map and returns the number of deleted and failed keys
+pub fn delete_keys<'a>(m: &dyn BpfMap, keys: impl IntoIterator<Item = &'a [u8]>) -> (usize, usize) {
+    let mut deleted = 0;
+    let mut failed = 0;
+    for key in keys {
+        match m.delete(key) {
+            Ok(()) => deleted += 1,
+            Err(_) => failed += 1,
+        }
+    }
+    (deleted, failed)
+}
+
+pub fn from_bytes<T: Copy>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() != mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+}
+
+// as_bytes is the memory of the value as the kernel sees it, the counterpart of from_bytes
+pub fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
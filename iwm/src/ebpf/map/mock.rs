@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::ebpf::map::map::{map_error, BpfMap};
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapOp {
+    Lookup,
+    Update,
+    Delete,
+}
+
+// Fault makes the next op on key, or on any key when key is None, fail with errno
+#[derive(Debug, Clone)]
+struct Fault {
+    op: MapOp,
+    key: Option<Vec<u8>>,
+    errno: i32,
+}
+
+// MockMap is an in-memory BpfMap with failure injection, built with the testing feature.
+// Like a hash map in the kernel it refuses new keys once max_entries is reached.
+pub struct MockMap {
+    name: String,
+    max_entries: u32,
+    entries: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    faults: Mutex<Vec<Fault>>,
+    // keys removed right after keys() lists them, as if the bpf side deleted them concurrently
+    racing: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MockMap {
+    pub fn new(name: &str, max_entries: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            max_entries,
+            entries: Mutex::new(BTreeMap::new()),
+            faults: Mutex::new(Vec::new()),
+            racing: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn insert(&self, key: &[u8], value: &[u8]) {
+        self.entries.lock().unwrap().insert(key.to_vec(), value.to_vec());
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // fail makes the next op on key, or on any key when None, fail with errno. Faults are one-shot
+    // and consumed in the order they were added.
+    pub fn fail(&self, op: MapOp, key: Option<&[u8]>, errno: i32) {
+        self.faults.lock().unwrap().push(Fault { op, key: key.map(<[u8]>::to_vec), errno });
+    }
+
+    // race removes key right after the next keys() lists it, so the following lookup misses it
+    pub fn race(&self, key: &[u8]) {
+        self.racing.lock().unwrap().push(key.to_vec());
+    }
+
+    fn check_fault(&self, op: MapOp, key: &[u8]) -> Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        let i = faults.iter().position(|f| f.op == op && f.key.as_deref().map_or(true, |k| k == key));
+        match i {
+            Some(i) => {
+                let fault = faults.remove(i);
+                let op = match op {
+                    MapOp::Lookup => "lookup",
+                    MapOp::Update => "update",
+                    MapOp::Delete => "delete",
+                };
+                Err(map_error(&self.name, op, fault.errno))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl BpfMap for MockMap {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn max_entries(&self) -> u32 {
+        self.max_entries
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<Vec<u8>> = entries.keys().cloned().collect();
+        for key in self.racing.lock().unwrap().drain(..) {
+            entries.remove(&key);
+        }
+        keys
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_fault(MapOp::Lookup, key)?;
+        Ok(self.get(key))
+    }
+
+    fn update(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_fault(MapOp::Update, key)?;
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(key) && entries.len() >= self.max_entries as usize {
+            return Err(map_error(&self.name, "update", libc::E2BIG));
+        }
+        entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.check_fault(MapOp::Delete, key)?;
+        match self.entries.lock().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(map_error(&self.name, "delete", libc::ENOENT)),
+        }
+    }
+}
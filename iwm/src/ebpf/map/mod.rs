@@ -0,0 +1,3 @@
+pub mod map;
+#[cfg(feature = "testing")]
+pub mod mock;
@@ -10,6 +10,14 @@ pub struct EbpfMetrics {
     pub pprofs_total: CounterVec,
     pub pprof_bytes_total: CounterVec,
     pub pprof_samples_total: CounterVec,
+    pub collection_overruns: Counter,
+    pub early_rounds: Counter,
+    pub cpu_pressure: Gauge,
+    pub load_shedding: Gauge,
+    pub load_shedding_transitions: CounterVec,
+    pub ingestion_paused: Gauge,
+    pub ingestion_paused_seconds: Counter,
+    pub paused_rounds: CounterVec,
     pub profile_metrics: Arc<ProfileMetrics>
 }
 
@@ -43,6 +51,40 @@ impl EbpfMetrics {
                 "Total number of pprof profiles collected by the ebpf component",
                 &["service_name"]
             ),
+            collection_overruns: reg.register_counter(
+                "iwm_ebpf_collection_overruns_total",
+                "Total number of collection rounds skipped because the previous round was still running"
+            ),
+            early_rounds: reg.register_counter(
+                "iwm_ebpf_early_collection_rounds_total",
+                "Total number of collection rounds started before the collect interval because the counts map filled up"
+            ),
+            cpu_pressure: reg.register_gauge(
+                "iwm_ebpf_cpu_pressure_avg10",
+                "Percentage of time runnable tasks of the node stalled on the cpu over the last 10s, as of the last round"
+            ),
+            load_shedding: reg.register_gauge(
+                "iwm_ebpf_load_shedding",
+                "Whether the ebpf component is shedding load because of cpu pressure"
+            ),
+            load_shedding_transitions: reg.register_counter_vec(
+                "iwm_ebpf_load_shedding_transitions_total",
+                "Total number of times load shedding started or stopped",
+                &["state"]
+            ),
+            ingestion_paused: reg.register_gauge(
+                "iwm_ebpf_ingestion_paused",
+                "Whether pushing the profiles is paused through the admin api"
+            ),
+            ingestion_paused_seconds: reg.register_counter(
+                "iwm_ebpf_ingestion_paused_seconds_total",
+                "Total time pushing the profiles was paused"
+            ),
+            paused_rounds: reg.register_counter_vec(
+                "iwm_ebpf_paused_rounds_total",
+                "Total number of collection rounds not pushed because of a pause, by what the pause stops",
+                &["mode"]
+            ),
             profile_metrics: Arc::new(ProfileMetrics::new(reg))
         }
     }
@@ -1,16 +1,97 @@
-
+use prometheus::{Counter, CounterVec, exponential_buckets, GaugeVec, Histogram, HistogramVec};
 use crate::ebpf::metrics::registry::Registerer;
 
+use crate::ebpf::metrics::process::ProcessMetrics;
 use crate::ebpf::metrics::symtab::SymtabMetrics;
 
 #[derive(Clone)]
 pub struct ProfileMetrics {
-    pub symtab: SymtabMetrics
+    pub symtab: SymtabMetrics,
+    pub process: ProcessMetrics,
+    pub samples_collected: Counter,
+    pub stacks_truncated: CounterVec,
+    pub map_fill_ratio: GaugeVec,
+    pub round_duration: Histogram,
+    pub stage_duration: HistogramVec,
+    pub dropped_samples: CounterVec,
+    pub prog_run_time: GaugeVec,
+    pub prog_run_count: GaugeVec,
+    pub rounds_over_budget: Counter,
+    pub address_only_stacks: Counter,
+    pub throttled_stacks: CounterVec,
+    pub symbolization_wait: Histogram,
+    pub map_errors: CounterVec,
 }
 
 impl ProfileMetrics {
     pub fn new(reg: &dyn Registerer) -> Self {
         let symtab = SymtabMetrics::new(reg);
-        ProfileMetrics { symtab }
+        ProfileMetrics {
+            symtab,
+            process: ProcessMetrics::new(reg),
+            samples_collected: reg.register_counter(
+                "iwm_ebpf_samples_collected_total",
+                "Total number of samples read from the counts map",
+            ),
+            stacks_truncated: reg.register_counter_vec(
+                "iwm_ebpf_stacks_truncated_total",
+                "Total number of stacks that hit the maximum stack depth",
+                &["service_name"]
+            ),
+            map_fill_ratio: reg.register_gauge_vec(
+                "iwm_ebpf_map_fill_ratio",
+                "Ratio of used to max entries of the bpf maps at the last collection round",
+                &["map"]
+            ),
+            round_duration: reg.register_histogram_with_buckets(
+                "iwm_ebpf_collection_round_duration_seconds",
+                "Duration of a collection round",
+                exponential_buckets(0.01, 2.0, 12).unwrap()
+            ),
+            stage_duration: reg.register_histogram_vec(
+                "iwm_ebpf_collection_stage_duration_seconds",
+                "Time spent in each stage of a collection round: map_drain, stack_walk, symbolization, pprof_encode, push",
+                &["stage"],
+                exponential_buckets(0.001, 2.0, 15).unwrap()
+            ),
+            dropped_samples: reg.register_counter_vec(
+                "iwm_ebpf_dropped_samples_total",
+                "Total number of samples dropped during collection",
+                &["service_name", "reason"]
+            ),
+            prog_run_time: reg.register_gauge_vec(
+                "iwm_bpf_program_run_time_seconds",
+                "Total time spent running the bpf program since stats were enabled",
+                &["program"]
+            ),
+            prog_run_count: reg.register_gauge_vec(
+                "iwm_bpf_program_run_count",
+                "Total number of bpf program runs since stats were enabled",
+                &["program"]
+            ),
+            rounds_over_budget: reg.register_counter(
+                "iwm_ebpf_rounds_over_budget_total",
+                "Total number of collection rounds whose symbolization ran past the round budget",
+            ),
+            address_only_stacks: reg.register_counter(
+                "iwm_ebpf_address_only_stacks_total",
+                "Total number of user stacks reported as raw addresses because the round budget ran out",
+            ),
+            throttled_stacks: reg.register_counter_vec(
+                "iwm_ebpf_throttled_stacks_total",
+                "Total number of user stacks reported as raw addresses because their target used up its symbolization time slice",
+                &["service_name"]
+            ),
+            symbolization_wait: reg.register_histogram_with_buckets(
+                "iwm_ebpf_symbolization_wait_seconds",
+                "Time the stacks of a pid waited for a symbolization worker in a round",
+                exponential_buckets(0.001, 2.0, 15).unwrap()
+            ),
+            map_errors: reg.register_counter_vec(
+                "iwm_ebpf_map_errors_total",
+                "Total number of failed operations on the bpf maps during collection, missing stacks included",
+                &["map", "op"]
+            ),
+        }
     }
-}
\ No newline at end of file
+}
@@ -4,3 +4,5 @@ pub mod python;
 pub mod registry;
 pub mod ebpf_metrics;
 pub mod write_metrics;
+pub mod ring;
+pub mod process;
@@ -0,0 +1,28 @@
+use prometheus::GaugeVec;
+
+use crate::ebpf::metrics::registry::Registerer;
+
+// ProcessMetrics are the resource usage of the profiled processes summed per service,
+// to put the profiles of a service next to what it consumed
+#[derive(Clone)]
+pub struct ProcessMetrics {
+    pub cpu_seconds: GaugeVec,
+    pub resident_memory: GaugeVec,
+}
+
+impl ProcessMetrics {
+    pub fn new(reg: &dyn Registerer) -> Self {
+        ProcessMetrics {
+            cpu_seconds: reg.register_gauge_vec(
+                "iwm_target_cpu_seconds",
+                "User and system cpu time of the live processes of the service",
+                &["service_name"]
+            ),
+            resident_memory: reg.register_gauge_vec(
+                "iwm_target_resident_memory_bytes",
+                "Resident memory of the live processes of the service",
+                &["service_name"]
+            ),
+        }
+    }
+}
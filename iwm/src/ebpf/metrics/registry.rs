@@ -1,10 +1,13 @@
-use prometheus::{Counter, CounterVec, exponential_buckets, Gauge, Histogram, HistogramOpts, Opts, Registry};
+use prometheus::{Counter, CounterVec, exponential_buckets, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, Opts, Registry};
 
 pub trait Registerer {
     fn register_gauge(&self, name: &str, help: &str) -> Gauge;
     fn register_counter(&self, name: &str, help: &str) -> Counter;
     fn register_counter_vec(&self, name: &str, help: &str, labels: &[&str]) -> CounterVec;
+    fn register_gauge_vec(&self, name: &str, help: &str, labels: &[&str]) -> GaugeVec;
     fn register_histogram(&self, name: &str, help: &str) -> Histogram;
+    fn register_histogram_with_buckets(&self, name: &str, help: &str, buckets: Vec<f64>) -> Histogram;
+    fn register_histogram_vec(&self, name: &str, help: &str, labels: &[&str], buckets: Vec<f64>) -> HistogramVec;
 }
 
 impl Registerer for Registry {
@@ -27,6 +30,12 @@ impl Registerer for Registry {
         counter_vec
     }
 
+    fn register_gauge_vec(&self, name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+        let gauge_vec = GaugeVec::new(Opts::new(name, help), labels).unwrap();
+        self.register(Box::new(gauge_vec.clone())).unwrap();
+        gauge_vec
+    }
+
     fn register_histogram(&self, name: &str, help: &str) -> Histogram {
         let histogram = Histogram::with_opts(
             HistogramOpts::new(name, help)
@@ -35,4 +44,21 @@ impl Registerer for Registry {
         self.register(Box::new(histogram.clone())).unwrap();
         histogram
     }
+
+    fn register_histogram_with_buckets(&self, name: &str, help: &str, buckets: Vec<f64>) -> Histogram {
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new(name, help).buckets(buckets)
+        ).unwrap();
+        self.register(Box::new(histogram.clone())).unwrap();
+        histogram
+    }
+
+    fn register_histogram_vec(&self, name: &str, help: &str, labels: &[&str], buckets: Vec<f64>) -> HistogramVec {
+        let histogram_vec = HistogramVec::new(
+            HistogramOpts::new(name, help).buckets(buckets),
+            labels
+        ).unwrap();
+        self.register(Box::new(histogram_vec.clone())).unwrap();
+        histogram_vec
+    }
 }
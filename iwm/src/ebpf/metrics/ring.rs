@@ -0,0 +1,32 @@
+use prometheus::{CounterVec, GaugeVec};
+
+use crate::ebpf::metrics::registry::Registerer;
+
+#[derive(Clone)]
+pub struct RingMetrics {
+    pub lost_samples: CounterVec,
+    pub read_samples: CounterVec,
+    pub utilization: GaugeVec,
+}
+
+impl RingMetrics {
+    pub fn new(reg: &dyn Registerer) -> RingMetrics {
+        RingMetrics {
+            lost_samples: reg.register_counter_vec(
+                "iwm_perf_lost_samples_total",
+                "Total number of perf events lost because the ring buffer was full",
+                &["cpu"]
+            ),
+            read_samples: reg.register_counter_vec(
+                "iwm_perf_read_samples_total",
+                "Total number of perf events read from the ring buffer",
+                &["cpu"]
+            ),
+            utilization: reg.register_gauge_vec(
+                "iwm_perf_ring_utilization_ratio",
+                "Ratio of unread bytes to ring buffer size, sampled before each read",
+                &["cpu"]
+            ),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use prometheus::CounterVec;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec};
 
 use crate::ebpf::metrics::registry::Registerer;
 
@@ -10,6 +10,13 @@ pub struct SymtabMetrics {
     pub unknown_symbols: CounterVec,
     pub unknown_modules: CounterVec,
     pub unknown_stacks: CounterVec,
+    pub cache_hits: CounterVec,
+    pub cache_misses: CounterVec,
+    pub cache_evictions: CounterVec,
+    pub cache_hit_ratio: GaugeVec,
+    pub forked_proc_tables: Counter,
+    pub index_queue: CounterVec,
+    pub index_queue_depth: Gauge,
 }
 
 impl SymtabMetrics {
@@ -45,6 +52,39 @@ impl SymtabMetrics {
                 "Total number of stacks with unknowns > knowns",
                 &["service_name"]
             ),
+            cache_hits: reg.register_counter_vec(
+                "iwm_symtab_cache_hits_total",
+                "Total number of symbol cache lookups that found an entry",
+                &["cache"]
+            ),
+            cache_misses: reg.register_counter_vec(
+                "iwm_symtab_cache_misses_total",
+                "Total number of symbol cache lookups that found no entry",
+                &["cache"]
+            ),
+            cache_evictions: reg.register_counter_vec(
+                "iwm_symtab_cache_evictions_total",
+                "Total number of entries dropped from the symbol caches",
+                &["cache"]
+            ),
+            cache_hit_ratio: reg.register_gauge_vec(
+                "iwm_symtab_cache_hit_ratio",
+                "Share of the symbol cache lookups of the last round that found an entry",
+                &["cache"]
+            ),
+            forked_proc_tables: reg.register_counter(
+                "iwm_symtab_forked_proc_tables_total",
+                "Total number of process tables that share the elf tables of the process they were forked from"
+            ),
+            index_queue: reg.register_counter_vec(
+                "iwm_symtab_index_queue_total",
+                "Total number of exec'd executables by what happened to their background indexing",
+                &["result"]
+            ),
+            index_queue_depth: reg.register_gauge(
+                "iwm_symtab_index_queue_depth",
+                "Number of executables waiting to be indexed in the background"
+            ),
         }
     }
 }
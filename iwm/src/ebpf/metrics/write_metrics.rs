@@ -1,4 +1,4 @@
-use prometheus::{CounterVec};
+use prometheus::{Counter, CounterVec, exponential_buckets, Gauge, GaugeVec, HistogramVec};
 use crate::ebpf::metrics::registry::Registerer;
 
 #[derive(Debug, Clone)]
@@ -8,6 +8,16 @@ pub struct WriteMetrics {
     pub sent_profiles: CounterVec,
     pub dropped_profiles: CounterVec,
     pub retries: CounterVec,
+    pub push_duration: HistogramVec,
+    pub delivery_duration: HistogramVec,
+    pub pushes: CounterVec,
+    pub endpoint_up: GaugeVec,
+    pub consecutive_failures: GaugeVec,
+    pub queue_depth: Gauge,
+    pub queue_dropped_profiles: Counter,
+    pub label_fixes: CounterVec,
+    pub rejected_profiles: CounterVec,
+    pub scrubbed_labels: CounterVec,
 }
 
 impl WriteMetrics {
@@ -38,6 +48,56 @@ impl WriteMetrics {
             "Total number of retries to IWM.",
             &["endpoint"],
         );
+        let push_duration = reg.register_histogram_vec(
+            "iwm_write_push_duration_seconds",
+            "Latency of a push request to IWM, including retries.",
+            &["endpoint"],
+            exponential_buckets(0.005, 2.0, 15).unwrap(),
+        );
+        let delivery_duration = reg.register_histogram_vec(
+            "iwm_write_delivery_duration_seconds",
+            "Latency from the append of a request to its acceptance by the endpoint, queueing and retries included.",
+            &["endpoint"],
+            exponential_buckets(0.005, 2.0, 15).unwrap(),
+        );
+        let pushes = reg.register_counter_vec(
+            "iwm_write_pushes_total",
+            "Total number of requests pushed to an endpoint by outcome, success or failure once the retries are exhausted.",
+            &["endpoint", "outcome"],
+        );
+        let endpoint_up = reg.register_gauge_vec(
+            "iwm_write_endpoint_up",
+            "Whether the last health check or push reached the endpoint.",
+            &["endpoint"],
+        );
+        let consecutive_failures = reg.register_gauge_vec(
+            "iwm_write_endpoint_consecutive_failures",
+            "Number of push attempts to the endpoint that failed since the last successful one.",
+            &["endpoint"],
+        );
+        let queue_depth = reg.register_gauge(
+            "iwm_write_queue_depth",
+            "Number of appended requests waiting to be pushed.",
+        );
+        let queue_dropped_profiles = reg.register_counter(
+            "iwm_write_queue_dropped_profiles_total",
+            "Total number of profiles dropped because the push queue was full.",
+        );
+        let label_fixes = reg.register_counter_vec(
+            "iwm_write_label_fixes_total",
+            "Total number of labels renamed, dropped or truncated to meet the backend's label rules.",
+            &["action"],
+        );
+        let rejected_profiles = reg.register_counter_vec(
+            "iwm_write_rejected_profiles_total",
+            "Total number of profiles not pushed because their labels can't be fixed.",
+            &["reason"],
+        );
+        let scrubbed_labels = reg.register_counter_vec(
+            "iwm_write_scrubbed_labels_total",
+            "Total number of labels dropped, redacted or hashed by the label scrubbing before push.",
+            &["action"],
+        );
 
         WriteMetrics {
             sent_bytes,
@@ -45,6 +105,16 @@ impl WriteMetrics {
             sent_profiles,
             dropped_profiles,
             retries,
+            push_duration,
+            delivery_duration,
+            pushes,
+            endpoint_up,
+            consecutive_failures,
+            queue_depth,
+            queue_dropped_profiles,
+            label_fixes,
+            rejected_profiles,
+            scrubbed_labels,
         }
     }
 }
\ No newline at end of file
@@ -1,12 +1,20 @@
 pub mod metrics;
 pub mod sd;
 pub mod cpuonline;
+pub mod diagnostics;
 pub mod session;
 pub mod pprof;
 pub mod sync;
-pub mod wait_group;
 pub mod symtab;
 pub mod ring;
+pub mod epoll;
+pub mod procfs;
+pub mod pressure;
+pub mod probe;
+pub mod ktime;
+pub mod map;
+pub mod pthread;
+pub mod python;
 
 pub(crate) const PERF_EVENT_IOC_ENABLE: core::ffi::c_int = 9216;
 pub(crate) const PERF_EVENT_IOC_DISABLE: core::ffi::c_int = 9217;
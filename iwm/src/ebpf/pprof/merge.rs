@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use crate::ebpf::pprof::profile::{Function, Label, Line, Location, Mapping, Profile, Sample, ValueType};
+use crate::error::Error;
+use crate::error::Result;
+
+// merge combines profiles of the same sample types into one. Strings, mappings, functions and
+// locations are remapped into shared tables and deduplicated, samples with the same locations and
+// labels are summed. The period, sample types and frame filters come from the first profile, the
+// time span covers all of them.
+pub fn merge(profiles: &[Profile]) -> Result<Profile> {
+    let mut merger = Merger::default();
+    for (i, p) in profiles.iter().enumerate() {
+        if i == 0 {
+            merger.init(p);
+        } else {
+            merger.check_compatible(i, p)?;
+        }
+        merger.add(p);
+    }
+    Ok(merger.profile)
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct MappingKey {
+    memory_start: u64,
+    memory_limit: u64,
+    file_offset: u64,
+    filename: i64,
+    build_id: i64,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct FunctionKey {
+    name: i64,
+    system_name: i64,
+    filename: i64,
+    start_line: i64,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct LocationKey {
+    mapping_id: u64,
+    address: u64,
+    lines: Vec<(u64, i64)>,
+    is_folded: bool,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct SampleKey {
+    location_ids: Vec<u64>,
+    labels: Vec<(i64, i64, i64, i64)>,
+}
+
+#[derive(Default)]
+struct Merger {
+    profile: Profile,
+    strings: HashMap<String, i64>,
+    mappings: HashMap<MappingKey, u64>,
+    functions: HashMap<FunctionKey, u64>,
+    locations: HashMap<LocationKey, u64>,
+    samples: HashMap<SampleKey, usize>,
+    end_nanos: i64,
+}
+
+impl Merger {
+    fn string(&mut self, s: &str) -> i64 {
+        if let Some(&id) = self.strings.get(s) {
+            return id;
+        }
+        let id = self.profile.string_table.len() as i64;
+        self.strings.insert(s.to_string(), id);
+        self.profile.string_table.push(s.to_string());
+        id
+    }
+
+    // string_of remaps index i of the string table of p
+    fn string_of(&mut self, p: &Profile, i: i64) -> i64 {
+        let s = p.string_table.get(i as usize).map(String::as_str).unwrap_or_default();
+        self.string(s)
+    }
+
+    fn value_type_of(&mut self, p: &Profile, vt: &ValueType) -> ValueType {
+        ValueType { r#type: self.string_of(p, vt.r#type), unit: self.string_of(p, vt.unit) }
+    }
+
+    fn init(&mut self, p: &Profile) {
+        // the empty string must be at index 0
+        self.string("");
+        self.profile.sample_type = p.sample_type.iter().map(|vt| self.value_type_of(p, vt)).collect();
+        self.profile.period_type = p.period_type.as_ref().map(|vt| self.value_type_of(p, vt));
+        self.profile.period = p.period;
+        self.profile.drop_frames = self.string_of(p, p.drop_frames);
+        self.profile.keep_frames = self.string_of(p, p.keep_frames);
+        self.profile.default_sample_type = self.string_of(p, p.default_sample_type);
+    }
+
+    fn check_compatible(&mut self, i: usize, p: &Profile) -> Result<()> {
+        let sample_type: Vec<ValueType> = p.sample_type.iter().map(|vt| self.value_type_of(p, vt)).collect();
+        if sample_type != self.profile.sample_type {
+            return Err(Error::invalid_data(format!("merging profile {}: sample types differ from the first profile", i)));
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, p: &Profile) {
+        if p.time_nanos != 0 {
+            if self.profile.time_nanos == 0 || p.time_nanos < self.profile.time_nanos {
+                self.profile.time_nanos = p.time_nanos;
+            }
+            self.end_nanos = self.end_nanos.max(p.time_nanos + p.duration_nanos);
+            self.profile.duration_nanos = self.end_nanos - self.profile.time_nanos;
+        }
+        for &c in &p.comment {
+            let c = self.string_of(p, c);
+            if !self.profile.comment.contains(&c) {
+                self.profile.comment.push(c);
+            }
+        }
+
+        let mapping_ids: HashMap<u64, u64> = p.mapping.iter().map(|m| (m.id, self.add_mapping(p, m))).collect();
+        let function_ids: HashMap<u64, u64> = p.function.iter().map(|f| (f.id, self.add_function(p, f))).collect();
+        let location_ids: HashMap<u64, u64> = p.location.iter()
+            .map(|l| (l.id, self.add_location(l, &mapping_ids, &function_ids)))
+            .collect();
+        for s in &p.sample {
+            self.add_sample(p, s, &location_ids);
+        }
+    }
+
+    fn add_mapping(&mut self, p: &Profile, m: &Mapping) -> u64 {
+        let key = MappingKey {
+            memory_start: m.memory_start,
+            memory_limit: m.memory_limit,
+            file_offset: m.file_offset,
+            filename: self.string_of(p, m.filename),
+            build_id: self.string_of(p, m.build_id),
+        };
+        if let Some(&id) = self.mappings.get(&key) {
+            return id;
+        }
+        let id = self.profile.mapping.len() as u64 + 1;
+        self.profile.mapping.push(Mapping { id, filename: key.filename, build_id: key.build_id, ..m.clone() });
+        self.mappings.insert(key, id);
+        id
+    }
+
+    fn add_function(&mut self, p: &Profile, f: &Function) -> u64 {
+        let key = FunctionKey {
+            name: self.string_of(p, f.name),
+            system_name: self.string_of(p, f.system_name),
+            filename: self.string_of(p, f.filename),
+            start_line: f.start_line,
+        };
+        if let Some(&id) = self.functions.get(&key) {
+            return id;
+        }
+        let id = self.profile.function.len() as u64 + 1;
+        self.profile.function.push(Function {
+            id,
+            name: key.name,
+            system_name: key.system_name,
+            filename: key.filename,
+            start_line: key.start_line,
+        });
+        self.functions.insert(key, id);
+        id
+    }
+
+    fn add_location(&mut self, l: &Location, mapping_ids: &HashMap<u64, u64>, function_ids: &HashMap<u64, u64>) -> u64 {
+        let key = LocationKey {
+            // 0 means no mapping
+            mapping_id: mapping_ids.get(&l.mapping_id).copied().unwrap_or(0),
+            address: l.address,
+            lines: l.line.iter()
+                .map(|line| (function_ids.get(&line.function_id).copied().unwrap_or(0), line.line))
+                .collect(),
+            is_folded: l.is_folded,
+        };
+        if let Some(&id) = self.locations.get(&key) {
+            return id;
+        }
+        let id = self.profile.location.len() as u64 + 1;
+        self.profile.location.push(Location {
+            id,
+            mapping_id: key.mapping_id,
+            address: key.address,
+            line: key.lines.iter().map(|&(function_id, line)| Line { function_id, line }).collect(),
+            is_folded: key.is_folded,
+        });
+        self.locations.insert(key, id);
+        id
+    }
+
+    fn add_sample(&mut self, p: &Profile, s: &Sample, location_ids: &HashMap<u64, u64>) {
+        let mut labels: Vec<(i64, i64, i64, i64)> = s.label.iter()
+            .map(|l| (self.string_of(p, l.key), self.string_of(p, l.str), l.num, self.string_of(p, l.num_unit)))
+            .collect();
+        labels.sort();
+        let key = SampleKey {
+            location_ids: s.location_id.iter().map(|id| location_ids.get(id).copied().unwrap_or(0)).collect(),
+            labels,
+        };
+        if let Some(&idx) = self.samples.get(&key) {
+            let sample = &mut self.profile.sample[idx];
+            for (v, add) in sample.value.iter_mut().zip(&s.value) {
+                *v += add;
+            }
+            return;
+        }
+        self.profile.sample.push(Sample {
+            location_id: key.location_ids.clone(),
+            value: s.value.clone(),
+            label: key.labels.iter()
+                .map(|&(key, str, num, num_unit)| Label { key, str, num, num_unit })
+                .collect(),
+        });
+        self.samples.insert(key, self.profile.sample.len() - 1);
+    }
+}
@@ -1,29 +1,64 @@
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io::Write;
+use std::mem;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 
 
 use prost::Message;
+use uuid::Uuid;
+use xxhash_rust::xxh3::{xxh3_128, xxh3_64};
 
-use profile::{Function, Location, ValueType, Sample, Line};
+use profile::{Function, Location, ValueType, Sample, Line, Label as PProfLabel};
 
 use crate::common::collector::{ProfileSample, SAMPLE_TYPE_CPU, SampleType};
-use crate::common::labels::Labels;
+use crate::common::labels::{Label, Labels};
+use crate::ebpf::ktime::RoundWindow;
 use crate::ebpf::pprof::pprof::PProfBuilder;
 use crate::ebpf::pprof::profile::Mapping;
+use crate::ebpf::pprof::rewrite::StackRewrite;
+use crate::ebpf::sd::target::{LABEL_CONTAINER_ID, LABEL_PID, LABEL_POD_UID};
 
 pub mod profile {
     include!("../../gen/profile/profile.v1.rs");
 }
 pub mod pprof;
+pub mod merge;
+pub mod rewrite;
+
+pub use merge::merge;
+
+// LABEL_STACK_MODE is the pprof label telling whether a sample has user, kernel or both (mixed) stacks
+const LABEL_STACK_MODE: &str = "mode";
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct BuildersOptions {
     pub sample_rate: i64,
     pub per_pid_profile: bool,
+    // max_pids_per_service caps the per pid profiles of a service, samples of further pids go to the
+    // service wide profile. 0 means no limit.
+    pub max_pids_per_service: usize,
+    // sample_period is the fixed period the perf events sample with, None when the kernel keeps
+    // sample_rate samples per second
+    pub sample_period: Option<u64>,
+    // cycles tells the perf events count cpu cycles instead of cpu clock nanoseconds, see PerfEventConfig
+    pub cycles: bool,
+}
+
+impl BuildersOptions {
+    // cpu_period is the unit and the period of a cpu sample of a target keeping one in sample_every
+    // samples. A fixed period of the cycles event is a number of cycles, every other setup samples
+    // nanoseconds of cpu time.
+    fn cpu_period(&self, sample_every: i64) -> (&'static str, i64) {
+        match self.sample_period {
+            Some(period) if self.cycles => ("cycles", period as i64 * sample_every),
+            Some(period) => ("nanoseconds", period as i64 * sample_every),
+            None => ("nanoseconds", (Duration::from_secs(1).as_nanos() as i64) / self.sample_rate * sample_every),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -33,9 +68,27 @@ pub struct BuilderHashKey {
     pub sample_type: SampleType,
 }
 
+impl BuilderHashKey {
+    // profile_id identifies the profile of the key taken at time_nanos. It only depends on the label
+    // fingerprint and the round, so a profile pushed again, e.g. replayed after a restart, keeps its
+    // id and the backend can drop the duplicate. The backend expects a uuid, the xxh3 of the key is one.
+    pub fn profile_id(&self, time_nanos: i64) -> String {
+        let mut key = Vec::with_capacity(24);
+        key.extend_from_slice(&self.labels_hash.to_le_bytes());
+        key.extend_from_slice(&(self.sample_type as u32).to_le_bytes());
+        key.extend_from_slice(&self.pid.to_le_bytes());
+        key.extend_from_slice(&time_nanos.to_le_bytes());
+        Uuid::from_u128(xxh3_128(&key)).to_string()
+    }
+}
+
 pub struct ProfileBuilders {
     pub builders: HashMap<BuilderHashKey, ProfileBuilder>,
-    pub opt: BuildersOptions
+    pub opt: BuildersOptions,
+    // pids with their own profile, by labels hash
+    split_pids: HashMap<u64, HashSet<u32>>,
+    comments: Vec<String>,
+    rewrite: Arc<StackRewrite>,
 }
 
 impl ProfileBuilders {
@@ -43,10 +96,50 @@ impl ProfileBuilders {
         Self {
             builders: HashMap::new(),
             opt: options,
+            split_pids: HashMap::new(),
+            comments: Vec::new(),
+            rewrite: Arc::new(StackRewrite::default()),
         }
     }
 
-    pub(crate) fn add_sample(&mut self, sample: ProfileSample) {
+    // set_round_window stamps the profiles with the time span their samples were taken in
+    pub(crate) fn set_round_window(&mut self, window: RoundWindow) {
+        for builder in self.builders.values_mut() {
+            builder.pprof_builder.profile.time_nanos = window.time_nanos;
+            builder.pprof_builder.profile.duration_nanos = window.duration_nanos;
+        }
+    }
+
+    // with_comments adds the comments to every profile, e.g. the host the profiles were taken on
+    pub fn with_comments(mut self, comments: Vec<String>) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    // with_rewrite rewrites the stacks of the samples before they are added
+    pub fn with_rewrite(mut self, rewrite: Arc<StackRewrite>) -> Self {
+        self.rewrite = rewrite;
+        self
+    }
+
+    // split_pid reports whether the pid gets a profile of its own, which holds for the first
+    // max_pids_per_service pids seen of a service
+    fn split_pid(&mut self, labels_hash: u64, pid: u32) -> bool {
+        let pids = self.split_pids.entry(labels_hash).or_default();
+        if pids.contains(&pid) {
+            return true;
+        }
+        if self.opt.max_pids_per_service != 0 && pids.len() >= self.opt.max_pids_per_service {
+            return false;
+        }
+        pids.insert(pid);
+        true
+    }
+
+    pub fn add_sample(&mut self, mut sample: ProfileSample) {
+        if !self.rewrite.is_empty() {
+            self.rewrite.rewrite(&mut sample.stack);
+        }
         let bb = self.builder_for_sample(&sample);
         bb.create_sample(sample);
     }
@@ -59,19 +152,24 @@ impl ProfileBuilders {
             sample_type: sample.sample_type,
             pid: 0,
         };
-        if self.opt.per_pid_profile {
+        if self.opt.per_pid_profile && self.split_pid(labels_hash, sample.pid) {
             k.pid = sample.pid;
         }
 
+        let opt = self.opt;
+        let comments = &self.comments;
+        // targets with a lower sample rate keep one in sample_every samples, each standing for a longer period
+        let sample_every = sample.target.sample_every(opt.sample_rate as u32) as i64;
         self.builders.entry(k).or_insert_with(|| {
             let mut b = PProfBuilder::default();
             let mut from_b = |s: &str| { b.add_string(&s.to_string()) };
             let (sample_type, period_type, period) = {
                 if sample.sample_type == SAMPLE_TYPE_CPU {
+                    let (unit, period) = opt.cpu_period(sample_every);
                     (
-                        vec![ValueType { r#type: from_b("cpu"), unit: from_b("nanoseconds") }],
-                        ValueType { r#type: from_b("cpu"), unit: from_b("nanoseconds") },
-                        (Duration::from_secs(1).as_nanos() as i64) / self.opt.sample_rate,
+                        vec![ValueType { r#type: from_b("cpu"), unit: from_b(unit) }],
+                        ValueType { r#type: from_b("cpu"), unit: from_b(unit) },
+                        period,
                     )
                 } else {
                     (
@@ -103,9 +201,14 @@ impl ProfileBuilders {
                 .as_nanos() as i64;
             b.profile.period = period;
             b.profile.period_type = Some(period_type);
+            b.profile.comment = comments.iter().map(|c| b.add_string(c)).collect();
 
+            let mut labels = labels.clone();
+            if k.pid != 0 && !labels.0.iter().any(|l| l.name == LABEL_PID) {
+                labels.0.push(Label::new(LABEL_PID.to_string(), k.pid.to_string()));
+            }
             ProfileBuilder {
-                labels: labels.clone(),
+                labels,
                 tmp_location_ids: Vec::with_capacity(128),
                 tmp_locations: Vec::with_capacity(128),
                 pprof_builder: b,
@@ -119,7 +222,8 @@ impl ProfileBuilders {
 pub struct ProfileBuilder {
     pub locations: HashMap<String, Location>,
     pub functions: HashMap<String, Function>,
-    pub sample_hash_to_sample: HashMap<u64, Sample>,
+    // sample_hash_to_sample maps the hash of a sample's location ids to its index in the profile
+    pub sample_hash_to_sample: HashMap<u64, usize>,
     pub labels: Labels,
 
     pub tmp_locations: Vec<Location>,
@@ -146,16 +250,56 @@ impl ProfileBuilder {
 
     fn create_sample(&mut self, input_sample: ProfileSample) {
         // info!("{:?}", input_sample);
+        let mut location_ids = mem::take(&mut self.tmp_location_ids);
+        location_ids.clear();
+        for s in &input_sample.stack {
+            location_ids.push(self.add_location(s.as_str()).id);
+        }
+
+        // samples with the same stack are merged, e.g. the same code running in several pids.
+        // The mode follows from the frames, it's mixed in so a hash collision can't merge across modes.
+        let hash = xxh3_64(location_ids_bytes(&location_ids)) ^ input_sample.mode as u64;
+        if let Some(&idx) = self.sample_hash_to_sample.get(&hash) {
+            if self.pprof_builder.profile.sample[idx].location_id == location_ids {
+                let mut sample = mem::take(&mut self.pprof_builder.profile.sample[idx]);
+                self.add_value(&input_sample, &mut sample);
+                self.pprof_builder.profile.sample[idx] = sample;
+                self.tmp_location_ids = location_ids;
+                return;
+            }
+        }
+
         let mut sample = Sample {
             value: if input_sample.sample_type == SampleType::Cpu { vec![0] } else { vec![0, 0] },
-            location_id: Vec::new(),
-            label: vec![],
+            location_id: location_ids.clone(),
+            label: self.sample_labels(&input_sample),
         };
         self.add_value(&input_sample, &mut sample);
-        for s in input_sample.stack {
-            sample.location_id.push(self.add_location(s.as_str()).id);
-        }
+        self.sample_hash_to_sample.insert(hash, self.pprof_builder.profile.sample.len());
         self.pprof_builder.profile.sample.push(sample);
+        self.tmp_location_ids = location_ids;
+    }
+
+    // sample_labels are the stack mode of the sample, and the container id and pod uid of samples of
+    // container targets. All samples of a builder come from one target, so the labels don't affect
+    // which samples are merged.
+    fn sample_labels(&mut self, input_sample: &ProfileSample) -> Vec<PProfLabel> {
+        let mut labels = vec![PProfLabel {
+            key: self.pprof_builder.add_string(&LABEL_STACK_MODE.to_string()),
+            str: self.pprof_builder.add_string(&input_sample.mode.as_str().to_string()),
+            ..Default::default()
+        }];
+        let target = input_sample.target;
+        for (key, value) in [(LABEL_CONTAINER_ID, target.container_id()), (LABEL_POD_UID, target.pod_uid())] {
+            if let Some(value) = value {
+                labels.push(PProfLabel {
+                    key: self.pprof_builder.add_string(&key.to_string()),
+                    str: self.pprof_builder.add_string(&value.to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+        labels
     }
 
     fn add_value(&mut self, input_sample: &ProfileSample, sample: &mut Sample) {
@@ -225,4 +369,16 @@ impl ProfileBuilder {
         let data = self.pprof_builder.profile.encode_to_vec();
         dst.write(data.as_slice()).unwrap();
     }
+
+    // write_to encodes the profile into buf, reusing its capacity across profiles.
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.reserve(self.pprof_builder.profile.encoded_len());
+        self.pprof_builder.profile.encode(buf).unwrap();
+    }
+}
+
+// location_ids_bytes views the location ids as bytes for hashing.
+fn location_ids_bytes(ids: &[u64]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(ids.as_ptr() as *const u8, ids.len() * mem::size_of::<u64>()) }
 }
@@ -0,0 +1,90 @@
+use regex::Regex;
+
+use crate::error::Error;
+use crate::error::Result;
+
+// FrameRule is a step of a StackRewrite. Stacks are leaf first, the root frame is the comm of the
+// thread.
+#[derive(Debug, Clone)]
+pub enum FrameRule {
+    // Collapse replaces the matching frames with replacement, which may refer to the captures of the
+    // pattern like $1, and merges back to back frames it replaced the same way, e.g. the template
+    // instances of a recursive std function
+    Collapse { pattern: Regex, replacement: String },
+    // Drop removes the matching frames, e.g. wrappers like __libc_start_main or _start
+    Drop { pattern: Regex },
+    // Group replaces the outermost matching frame and every frame toward the root with replacement,
+    // so the work of a thread pool shows under one node whatever thread runs it
+    Group { pattern: Regex, replacement: String },
+}
+
+impl FrameRule {
+    // parse builds a rule from its configuration, action is collapse, drop or group. Collapse and
+    // group need a replacement.
+    pub fn parse(action: &str, pattern: &str, replacement: Option<&str>) -> Result<Self> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| Error::invalid_data(format!("frame rule pattern {:?}: {}", pattern, e)))?;
+        let replacement = || replacement
+            .map(str::to_string)
+            .ok_or_else(|| Error::invalid_data(format!("frame rule {} {:?} needs a replacement", action, pattern)));
+        match action {
+            "collapse" => Ok(FrameRule::Collapse { pattern: regex, replacement: replacement()? }),
+            "drop" => Ok(FrameRule::Drop { pattern: regex }),
+            "group" => Ok(FrameRule::Group { pattern: regex, replacement: replacement()? }),
+            _ => Err(Error::invalid_data(format!("frame rule action {:?}, expected collapse, drop or group", action))),
+        }
+    }
+
+    fn apply(&self, stack: &mut Vec<String>) {
+        match self {
+            FrameRule::Collapse { pattern, replacement } => {
+                let mut collapsed: Vec<String> = Vec::with_capacity(stack.len());
+                let mut last_replaced = false;
+                for frame in stack.drain(..) {
+                    if !pattern.is_match(&frame) {
+                        collapsed.push(frame);
+                        last_replaced = false;
+                        continue;
+                    }
+                    let frame = pattern.replace_all(&frame, replacement.as_str()).into_owned();
+                    if last_replaced && collapsed.last() == Some(&frame) {
+                        continue;
+                    }
+                    collapsed.push(frame);
+                    last_replaced = true;
+                }
+                *stack = collapsed;
+            }
+            FrameRule::Drop { pattern } => stack.retain(|frame| !pattern.is_match(frame)),
+            FrameRule::Group { pattern, replacement } => {
+                if let Some(i) = stack.iter().rposition(|frame| pattern.is_match(frame)) {
+                    stack.truncate(i);
+                    stack.push(replacement.clone());
+                }
+            }
+        }
+    }
+}
+
+// StackRewrite standardizes the shape of the stacks before they are added to the profiles, so teams
+// get the same flamegraphs whatever backend they push to. The rules are applied in order.
+#[derive(Debug, Clone, Default)]
+pub struct StackRewrite {
+    rules: Vec<FrameRule>,
+}
+
+impl StackRewrite {
+    pub fn new(rules: Vec<FrameRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rewrite(&self, stack: &mut Vec<String>) {
+        for rule in &self.rules {
+            rule.apply(stack);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::time::SystemTime;
+
+use log::{info, warn};
+
+// CPU_PRESSURE_PATH is the cpu pressure stall information of the node, kernels 4.20+ built with PSI
+pub const CPU_PRESSURE_PATH: &str = "/proc/pressure/cpu";
+
+// MAX_EVENTS is the number of shedding transitions kept for the event log
+const MAX_EVENTS: usize = 32;
+
+// LoadSheddingOptions configure how the profiler backs off while the node is short of cpu, so it
+// doesn't worsen the incident it is meant to diagnose
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadSheddingOptions {
+    // enter_pressure and exit_pressure are the avg10 percentages of time runnable tasks stalled on
+    // the cpu above which shedding starts and below which it stops, the gap between them keeps
+    // the state from flapping
+    pub enter_pressure: f64,
+    pub exit_pressure: f64,
+    // sustain_rounds is the number of consecutive rounds the pressure has to stay over or under
+    // the thresholds before the state changes
+    pub sustain_rounds: u32,
+    // sample_every keeps one in that many samples of every pid while shedding
+    pub sample_every: u8,
+    // skip_python falls back to frame pointer stacks for python pids while shedding, pyperf
+    // unwinding is far more expensive per sample
+    pub skip_python: bool,
+}
+
+impl Default for LoadSheddingOptions {
+    fn default() -> Self {
+        Self {
+            enter_pressure: 40.0,
+            exit_pressure: 20.0,
+            sustain_rounds: 3,
+            sample_every: 4,
+            skip_python: true,
+        }
+    }
+}
+
+// PressureEvent is a transition of the shedding state
+#[derive(Debug, Clone, Copy)]
+pub struct PressureEvent {
+    pub time: SystemTime,
+    pub shedding: bool,
+    pub avg10: f64,
+}
+
+// PressureMonitor tracks the cpu pressure of the node once per round and decides when to shed load
+pub struct PressureMonitor {
+    opts: LoadSheddingOptions,
+    available: bool,
+    shedding: bool,
+    // streak counts the consecutive rounds past the threshold of the other state
+    streak: u32,
+    last_avg10: f64,
+    events: VecDeque<PressureEvent>,
+}
+
+impl PressureMonitor {
+    pub fn new(opts: LoadSheddingOptions) -> Self {
+        Self {
+            opts,
+            available: true,
+            shedding: false,
+            streak: 0,
+            last_avg10: 0.0,
+            events: VecDeque::with_capacity(MAX_EVENTS),
+        }
+    }
+
+    pub fn options(&self) -> &LoadSheddingOptions {
+        &self.opts
+    }
+
+    pub fn shedding(&self) -> bool {
+        self.shedding
+    }
+
+    // avg10 is the pressure read by the last check
+    pub fn avg10(&self) -> f64 {
+        self.last_avg10
+    }
+
+    // events returns the last shedding transitions, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &PressureEvent> {
+        self.events.iter()
+    }
+
+    // check reads the cpu pressure and returns the new shedding state when it changes.
+    // Without PSI the monitor turns itself off and never sheds.
+    pub fn check(&mut self) -> Option<bool> {
+        if !self.available {
+            return None;
+        }
+        let avg10 = match fs::read_to_string(CPU_PRESSURE_PATH).ok().and_then(|s| parse_cpu_pressure(&s)) {
+            Some(avg10) => avg10,
+            None => {
+                info!("no cpu pressure information in {}, load shedding is disabled", CPU_PRESSURE_PATH);
+                self.available = false;
+                return None;
+            }
+        };
+        self.update(avg10)
+    }
+
+    fn update(&mut self, avg10: f64) -> Option<bool> {
+        self.last_avg10 = avg10;
+        let past_threshold = if self.shedding {
+            avg10 < self.opts.exit_pressure
+        } else {
+            avg10 >= self.opts.enter_pressure
+        };
+        if !past_threshold {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        if self.streak < self.opts.sustain_rounds.max(1) {
+            return None;
+        }
+        self.streak = 0;
+        self.shedding = !self.shedding;
+        if self.shedding {
+            warn!("cpu pressure avg10 {:.1}% over {}% for {} rounds, shedding profiler load",
+                avg10, self.opts.enter_pressure, self.opts.sustain_rounds);
+        } else {
+            info!("cpu pressure avg10 {:.1}% under {}% for {} rounds, stopped shedding profiler load",
+                avg10, self.opts.exit_pressure, self.opts.sustain_rounds);
+        }
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(PressureEvent { time: SystemTime::now(), shedding: self.shedding, avg10 });
+        Some(self.shedding)
+    }
+}
+
+// parse_cpu_pressure returns avg10 of the "some" line, e.g.
+// some avg10=1.23 avg60=0.50 avg300=0.10 total=123456
+pub fn parse_cpu_pressure(s: &str) -> Option<f64> {
+    let line = s.lines().find(|l| l.starts_with("some "))?;
+    line.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))
+        .and_then(|v| v.parse().ok())
+}
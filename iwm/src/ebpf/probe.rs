@@ -0,0 +1,84 @@
+use std::ffi::CStr;
+use std::path::Path;
+
+// BTF_PATH is where the kernel exposes its BTF, tp_btf programs and CO-RE relocations need it
+pub const BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+pub fn btf_available() -> bool {
+    Path::new(BTF_PATH).exists()
+}
+
+// kernel_release returns the release of the running kernel as uname -r prints it
+pub fn kernel_release() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+// kernel_version returns major.minor of the running kernel
+pub fn kernel_version() -> Option<(u32, u32)> {
+    let release = kernel_release()?;
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+// HookAttach is how the process lifecycle hooks (exec and exit) are attached. Tracepoints are cheaper
+// and more stable than kprobes on the syscall functions, whose names differ between kernels and archs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAttach {
+    // Auto picks the best mode the kernel supports, see detect
+    Auto,
+    Kprobe,
+    // RawTracepoint needs 4.17+
+    RawTracepoint,
+    // TpBtf needs 5.5+ and kernel BTF
+    TpBtf,
+}
+
+impl HookAttach {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(HookAttach::Auto),
+            "kprobe" => Some(HookAttach::Kprobe),
+            "raw_tracepoint" => Some(HookAttach::RawTracepoint),
+            "tp_btf" => Some(HookAttach::TpBtf),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookAttach::Auto => "auto",
+            HookAttach::Kprobe => "kprobe",
+            HookAttach::RawTracepoint => "raw_tracepoint",
+            HookAttach::TpBtf => "tp_btf",
+        }
+    }
+
+    // detect probes the kernel for the cheapest mode it supports
+    pub fn detect() -> Self {
+        let version = kernel_version().unwrap_or_default();
+        if version >= (5, 5) && btf_available() {
+            HookAttach::TpBtf
+        } else if version >= (4, 17) {
+            HookAttach::RawTracepoint
+        } else {
+            HookAttach::Kprobe
+        }
+    }
+
+    // candidates lists the modes to try in order, a mode failing to load falls back to the next one
+    pub fn candidates(self) -> Vec<HookAttach> {
+        let first = match self {
+            HookAttach::Auto => Self::detect(),
+            mode => mode,
+        };
+        match first {
+            HookAttach::TpBtf => vec![HookAttach::TpBtf, HookAttach::RawTracepoint, HookAttach::Kprobe],
+            HookAttach::RawTracepoint => vec![HookAttach::RawTracepoint, HookAttach::Kprobe],
+            _ => vec![HookAttach::Kprobe],
+        }
+    }
+}
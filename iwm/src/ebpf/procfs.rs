@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::error::Result;
+
+// ProcFs caches what the session reads from /proc so a pid costs at most one existence check per round,
+// and its exe and comm are read once until it execs or dies.
+// Existence checks are done with statx relative to an fd of /proc instead of resolving the full path each time.
+pub struct ProcFs {
+    dir: OwnedFd,
+    alive: HashMap<u32, bool>,
+    info: HashMap<u32, ProcInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcInfo {
+    pub exe: String,
+    pub comm: String,
+}
+
+// ProcStat is the resource usage of a process from /proc/<pid>/stat and statm
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcStat {
+    // user and system cpu time, children excluded
+    pub cpu_seconds: f64,
+    pub rss_bytes: u64,
+}
+
+impl ProcFs {
+    pub fn new() -> Result<Self> {
+        let path = CString::new("/proc").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error("open /proc"));
+        }
+        Ok(Self {
+            dir: unsafe { OwnedFd::from_raw_fd(fd) },
+            alive: HashMap::new(),
+            info: HashMap::new(),
+        })
+    }
+
+    // next_round drops the existence checks of the previous round.
+    pub fn next_round(&mut self) {
+        self.alive.clear();
+    }
+
+    pub fn exists(&mut self, pid: u32) -> bool {
+        let dir = self.dir.as_raw_fd();
+        *self.alive.entry(pid).or_insert_with(|| stat_pid(dir, pid))
+    }
+
+    // dead returns the pids that no longer exist, checking each pid at most once per round.
+    pub fn dead(&mut self, pids: impl IntoIterator<Item = u32>) -> Vec<u32> {
+        pids.into_iter().filter(|pid| !self.exists(*pid)).collect()
+    }
+
+    pub fn info(&mut self, pid: u32) -> Option<ProcInfo> {
+        if let Some(info) = self.info.get(&pid) {
+            return Some(info.clone());
+        }
+        let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+        let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        let info = ProcInfo {
+            exe: Path::new(&exe)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            comm: comm.trim_end_matches('\n').to_string(),
+        };
+        self.info.insert(pid, info.clone());
+        Some(info)
+    }
+
+    // stat reads the cpu time and resident memory of the pid, it is never cached
+    pub fn stat(&self, pid: u32) -> Option<ProcStat> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // comm may contain spaces and parens, the fields after it start behind the last ')'
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+        // utime and stime are fields 14 and 15 of stat, 12 and 13 after the comm
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+        let statm = fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+        let resident: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        Some(ProcStat {
+            cpu_seconds: (utime + stime) as f64 / ticks,
+            rss_bytes: resident * page_size,
+        })
+    }
+
+    // forget drops everything cached for the pid, after it exec'd or died.
+    pub fn forget(&mut self, pid: u32) {
+        self.alive.remove(&pid);
+        self.info.remove(&pid);
+    }
+}
+
+fn stat_pid(dir: i32, pid: u32) -> bool {
+    let name = CString::new(pid.to_string()).unwrap();
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    // no fields are requested, only whether the entry exists
+    let ret = unsafe { libc::statx(dir, name.as_ptr(), libc::AT_SYMLINK_NOFOLLOW, 0, &mut stx) };
+    ret == 0
+}
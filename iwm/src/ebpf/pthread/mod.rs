@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs;
+
+use goblin::elf::Elf;
+use serde::Deserialize;
+
+use crate::ebpf::symtab::procmap::parse_proc_maps;
+use crate::error::Error;
+use crate::error::Error::{ELFError, NotFound};
+use crate::error::Result;
+
+lazy_static::lazy_static! {
+    static ref OFFSETS_DATABASE: Vec<LibcEntry> = serde_yaml::from_str(include_str!("offsets.yaml")).unwrap();
+    // gnu_get_libc_version banner in .rodata, e.g. "GNU C Library (Debian GLIBC 2.36-9) stable release version 2.36."
+    static ref GLIBC_VERSION_RE: regex::bytes::Regex =
+        regex::bytes::Regex::new(r"(?-u)GNU C Library[^\x00]*? release version (\d+)\.(\d+)").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LibcKind {
+    Glibc,
+    Musl,
+}
+
+#[derive(Deserialize)]
+struct LibcEntry {
+    libc: LibcKind,
+    arch: String,
+    version: String,
+    offsets: HashMap<String, i16>,
+}
+
+// LibcConfig mirrors struct libc in pyoffsets.h
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct LibcConfig {
+    pub musl: bool,
+    pub pthread_size: i16,
+    pub pthread_specific1stblock: i16,
+}
+
+// libc_config detects the libc the process is linked against and returns the struct pthread offsets
+// the bpf programs need to walk thread local storage.
+pub fn libc_config(pid: u32) -> Result<LibcConfig> {
+    let (kind, version) = detect_libc(pid)?;
+    let entry = find_entry(kind, version)
+        .ok_or_else(|| NotFound(format!("no {:?} offsets for {}", kind, std::env::consts::ARCH)))?;
+    let get = |name: &str| entry.offsets.get(name).copied().unwrap_or(-1);
+    let config = LibcConfig {
+        musl: kind == LibcKind::Musl,
+        pthread_size: get("pthread_size"),
+        pthread_specific1stblock: get("pthread_specific1stblock"),
+    };
+    config.validate()?;
+    Ok(config)
+}
+
+impl LibcConfig {
+    // validate rejects configs with unknown offsets the bpf programs would read thread local storage
+    // with, pthread_size is only needed on arm64
+    pub fn validate(&self) -> Result<()> {
+        if self.pthread_specific1stblock < 0 {
+            return Err(NotFound("unknown pthread_specific1stblock offset".to_string()));
+        }
+        if cfg!(target_arch = "aarch64") && self.pthread_size < 0 {
+            return Err(NotFound("unknown pthread_size offset".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// find_entry picks the entry of the closest version, struct pthread rarely changes between releases.
+// musl has no version string, so the latest musl entry is used.
+fn find_entry(kind: LibcKind, version: Option<(u32, u32)>) -> Option<&'static LibcEntry> {
+    let entries = OFFSETS_DATABASE.iter()
+        .filter(|e| e.libc == kind && e.arch == std::env::consts::ARCH);
+    match version {
+        Some((major, minor)) => entries.min_by_key(|e| {
+            let (emajor, eminor) = parse_version(&e.version).unwrap_or_default();
+            (major.abs_diff(emajor), minor.abs_diff(eminor))
+        }),
+        None => entries.max_by_key(|e| parse_version(&e.version)),
+    }
+}
+
+fn parse_version(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.split('.');
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+// detect_libc finds the libc mapped into the process, and for glibc its version
+fn detect_libc(pid: u32) -> Result<(LibcKind, Option<(u32, u32)>)> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|e| Error::proc_error(pid, format!("read maps: {}", e)))?;
+    let modules = parse_proc_maps(&maps, true)?;
+    for m in &modules {
+        let name = m.pathname.rsplit('/').next().unwrap_or_default();
+        if name.starts_with("ld-musl-") || name.starts_with("libc.musl-") {
+            return Ok((LibcKind::Musl, None));
+        }
+        if name.starts_with("libc.so.6") || name.starts_with("libc-2.") {
+            let path = format!("/proc/{}/root{}", pid, m.pathname);
+            return Ok((LibcKind::Glibc, glibc_version(&path).ok()));
+        }
+    }
+    Err(NotFound(format!("no libc mapped into pid {}", pid)))
+}
+
+fn glibc_version(path: &str) -> Result<(u32, u32)> {
+    let data = fs::read(path).map_err(|e| ELFError(format!("{}: {}", path, e)))?;
+    let elf = Elf::parse(&data).map_err(|e| ELFError(e.to_string()))?;
+    let rodata = elf.section_headers.iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".rodata"))
+        .ok_or_else(|| NotFound(format!("{}: no .rodata", path)))?;
+    let start = rodata.sh_offset as usize;
+    let end = start + rodata.sh_size as usize;
+    let rodata = data.get(start..end).ok_or_else(|| ELFError(format!("{}: bad .rodata bounds", path)))?;
+    let c = GLIBC_VERSION_RE.captures(rodata)
+        .ok_or_else(|| NotFound(format!("{}: no glibc version string", path)))?;
+    let number = |i: usize| std::str::from_utf8(&c[i]).unwrap().parse::<u32>().unwrap();
+    Ok((number(1), number(2)))
+}
@@ -0,0 +1,3 @@
+pub mod offsets;
+pub mod perf;
+pub mod version;
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::ebpf::python::version::PythonVersion;
+
+lazy_static::lazy_static! {
+    static ref OFFSETS_DATABASE: OffsetsDatabase = OffsetsDatabase::parse(include_str!("offsets.yaml"));
+}
+
+#[derive(Deserialize)]
+struct OffsetsEntry {
+    version: String,
+    offsets: HashMap<String, i16>,
+}
+
+// OffsetsDatabase holds the struct offsets of the known python versions, generated by dwarfdump
+pub struct OffsetsDatabase {
+    entries: Vec<(PythonVersion, HashMap<String, i16>)>,
+}
+
+// PyOffsetConfig mirrors py_offset_config in pyoffsets.h
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct PyOffsetConfig {
+    pub py_thread_state_frame: i16,
+    pub py_thread_state_cframe: i16,
+    pub py_cframe_current_frame: i16,
+    pub py_code_object_co_filename: i16,
+    pub py_code_object_co_name: i16,
+    pub py_code_object_co_varnames: i16,
+    pub py_code_object_co_localsplusnames: i16,
+    pub py_tuple_object_ob_item: i16,
+
+    pub py_var_object_ob_size: i16,
+    pub py_object_ob_type: i16,
+    pub py_type_object_tp_name: i16,
+
+    pub vframe_code: i16,
+    pub vframe_previous: i16,
+    pub vframe_localsplus: i16,
+    pub py_interpreter_frame_owner: i16,
+    pub py_ascii_object_size: i16,
+    pub py_compact_unicode_object_size: i16,
+}
+
+impl OffsetsDatabase {
+    fn parse(data: &str) -> Self {
+        let entries: Vec<OffsetsEntry> = serde_yaml::from_str(data).unwrap();
+        let mut entries: Vec<(PythonVersion, HashMap<String, i16>)> = entries.into_iter()
+            .map(|e| (PythonVersion::parse(&e.version).unwrap(), e.offsets))
+            .collect();
+        entries.sort_by_key(|(v, _)| *v);
+        Self { entries }
+    }
+
+    pub fn embedded() -> &'static OffsetsDatabase {
+        &OFFSETS_DATABASE
+    }
+
+    // find returns the offsets of the version, or of the closest patch release of the same minor version,
+    // struct layouts don't change between patch releases.
+    pub fn find(&self, version: &PythonVersion) -> Option<PyOffsetConfig> {
+        let same_minor = self.entries.iter()
+            .filter(|(v, _)| v.major == version.major && v.minor == version.minor);
+        let (v, offsets) = same_minor
+            .min_by_key(|(v, _)| (v.patch as i64 - version.patch as i64).abs())?;
+        Some(to_config(v, offsets))
+    }
+}
+
+fn to_config(v: &PythonVersion, offsets: &HashMap<String, i16>) -> PyOffsetConfig {
+    let get = |name: &str| offsets.get(name).copied().unwrap_or(-1);
+    // frames moved from PyFrameObject to _PyInterpreterFrame in 3.11
+    let (code, previous, localsplus) = if (v.major, v.minor) >= (3, 11) {
+        ("PyInterpreterFrame_f_code", "PyInterpreterFrame_previous", "PyInterpreterFrame_localsplus")
+    } else {
+        ("PyFrameObject_f_code", "PyFrameObject_f_back", "PyFrameObject_f_localsplus")
+    };
+    PyOffsetConfig {
+        py_thread_state_frame: get("PyThreadState_frame"),
+        py_thread_state_cframe: get("PyThreadState_cframe"),
+        py_cframe_current_frame: get("PyCFrame_current_frame"),
+        py_code_object_co_filename: get("PyCodeObject_co_filename"),
+        py_code_object_co_name: get("PyCodeObject_co_name"),
+        py_code_object_co_varnames: get("PyCodeObject_co_varnames"),
+        py_code_object_co_localsplusnames: get("PyCodeObject_co_localsplusnames"),
+        py_tuple_object_ob_item: get("PyTupleObject_ob_item"),
+        py_var_object_ob_size: get("PyVarObject_ob_size"),
+        py_object_ob_type: get("PyObject_ob_type"),
+        py_type_object_tp_name: get("PyTypeObject_tp_name"),
+        vframe_code: get(code),
+        vframe_previous: get(previous),
+        vframe_localsplus: get(localsplus),
+        py_interpreter_frame_owner: get("PyInterpreterFrame_owner"),
+        py_ascii_object_size: get("PyASCIIObjectSize"),
+        py_compact_unicode_object_size: get("PyCompactUnicodeObjectSize"),
+    }
+}
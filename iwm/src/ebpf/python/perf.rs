@@ -0,0 +1,90 @@
+use std::os::fd::{AsFd, AsRawFd};
+
+use libbpf_rs::skel::{OpenSkel, SkelBuilder};
+use libbpf_rs::MapFlags;
+
+use crate::ebpf::map::map::as_bytes;
+use crate::ebpf::pthread::LibcConfig;
+use crate::ebpf::python::offsets::PyOffsetConfig;
+use crate::ebpf::python::version::PythonVersion;
+use crate::error::Error;
+use crate::error::Result;
+
+mod skel {
+    include!("../bpf/pyperf.skel.rs");
+}
+
+use skel::*;
+
+// PROG_IDX_PYTHON is the slot of pyperf_collect in the progs map of the profile programs, see profile.bpf.h
+const PROG_IDX_PYTHON: u32 = 0;
+
+// AUTO_TSS_KEY is the pthread key CPython keeps the thread state of a thread under. The interpreter
+// creates it while initializing, before anything else in the process creates a key, so it is the
+// first key of the process unless a host application created keys before embedding python.
+const AUTO_TSS_KEY: i32 = 0;
+
+// PyVersion mirrors py_version in pyperf.bpf.c
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct PyVersion {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+// PyPidData mirrors py_pid_data in pyperf.bpf.c, what pyperf unwinds the stacks of a python pid with
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct PyPidData {
+    offsets: PyOffsetConfig,
+    version: PyVersion,
+    libc: LibcConfig,
+    tss_key: i32,
+}
+
+impl PyPidData {
+    pub fn new(version: PythonVersion, offsets: PyOffsetConfig, libc: LibcConfig) -> Self {
+        Self {
+            offsets,
+            version: PyVersion { major: version.major, minor: version.minor, patch: version.patch },
+            libc,
+            tss_key: AUTO_TSS_KEY,
+        }
+    }
+}
+
+// Pyperf are the python unwinding programs. The profile programs tail call pyperf_collect for the
+// pids of the python profiling type, which walks the interpreter frames with the config of the pid
+// in py_pid_config and skips pids without one.
+pub struct Pyperf<'a> {
+    bpf: PyperfSkel<'a>,
+}
+
+impl Pyperf<'_> {
+    // load loads pyperf with the stacks map of the profile programs, so both record kernel stacks in
+    // the same map, and puts pyperf_collect into their progs map
+    pub fn load(stacks: &libbpf_rs::Map, progs: &libbpf_rs::Map) -> Result<Self> {
+        let mut open_skel = PyperfSkelBuilder::default().open()
+            .map_err(|e| Error::SessionError(format!("open pyperf: {}", e)))?;
+        open_skel.maps_mut().stacks().reuse_fd(stacks.as_fd())
+            .map_err(|e| Error::SessionError(format!("share the stacks map with pyperf: {}", e)))?;
+        let bpf = open_skel.load()
+            .map_err(|e| Error::SessionError(format!("load pyperf: {}", e)))?;
+        let fd = bpf.progs().pyperf_collect().as_fd().as_raw_fd();
+        progs.update(&PROG_IDX_PYTHON.to_ne_bytes(), &fd.to_ne_bytes(), MapFlags::ANY)
+            .map_err(|e| Error::SessionError(format!("install pyperf into the progs map: {}", e)))?;
+        Ok(Self { bpf })
+    }
+
+    // set_pid writes the config of the python pid, it has to be there before the pid gets the python
+    // profiling type
+    pub fn set_pid(&self, pid: u32, data: &PyPidData) -> Result<()> {
+        self.bpf.maps().py_pid_config().update(&pid.to_ne_bytes(), as_bytes(data), MapFlags::ANY)
+            .map_err(|e| Error::SessionError(format!("update py_pid_config of pid {}: {}", pid, e)))
+    }
+
+    pub fn remove_pid(&self, pid: u32) {
+        let _ = self.bpf.maps().py_pid_config().delete(&pid.to_ne_bytes());
+    }
+}
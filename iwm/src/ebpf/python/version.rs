@@ -0,0 +1,102 @@
+use std::fmt;
+use std::fs;
+
+use goblin::elf::Elf;
+
+use crate::ebpf::symtab::procmap::parse_proc_maps;
+use crate::error::Error;
+use crate::error::Error::{ELFError, NotFound};
+use crate::error::Result;
+
+lazy_static::lazy_static! {
+    // python3.11, libpython3.11.so.1.0, libpython3.9d.so
+    static ref PYTHON_NAME_RE: regex::Regex =
+        regex::Regex::new(r"^(?:lib)?python(\d)\.(\d{1,2})[a-z]*(?:\.so.*)?$").unwrap();
+    // PY_VERSION as stored in .rodata, e.g. "3.11.7" or "3.12.0rc1"
+    static ref PY_VERSION_RE: regex::bytes::Regex =
+        regex::bytes::Regex::new(r"(?-u)\x00(\d)\.(\d{1,2})\.(\d{1,2})(?:(?:a|b|rc)\d+)?\+?\x00").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PythonVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl PythonVersion {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('.').map(|p| p.parse::<u32>().ok());
+        Some(Self {
+            major: parts.next()??,
+            minor: parts.next()??,
+            patch: parts.next().unwrap_or(Some(0))?,
+        })
+    }
+}
+
+impl fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// detect_version finds the python interpreter mapped into the process and reads its version.
+// major.minor comes from the name of the python binary or libpython, the patch version from
+// the PY_VERSION string in .rodata. Statically linked interpreters with another name (e.g. uwsgi)
+// are detected from .rodata alone.
+pub fn detect_version(pid: u32) -> Result<PythonVersion> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|e| Error::proc_error(pid, format!("read maps: {}", e)))?;
+    let modules = parse_proc_maps(&maps, true)?;
+
+    let mut from_name = None;
+    let mut binary = None;
+    for m in &modules {
+        let name = m.pathname.rsplit('/').next().unwrap_or_default();
+        if let Some(c) = PYTHON_NAME_RE.captures(name) {
+            from_name = Some(PythonVersion {
+                major: c[1].parse().unwrap(),
+                minor: c[2].parse().unwrap(),
+                patch: 0,
+            });
+            binary = Some(m.pathname.clone());
+            break;
+        }
+    }
+    let binary = match binary {
+        Some(path) => format!("/proc/{}/root{}", pid, path),
+        None => format!("/proc/{}/exe", pid),
+    };
+
+    match (rodata_version(&binary, from_name), from_name) {
+        (Ok(v), _) => Ok(v),
+        (Err(_), Some(v)) => Ok(v),
+        (Err(e), None) => Err(e),
+    }
+}
+
+// rodata_version searches .rodata of the binary for PY_VERSION, matching major.minor when known
+fn rodata_version(path: &str, expected: Option<PythonVersion>) -> Result<PythonVersion> {
+    let data = fs::read(path).map_err(|e| ELFError(format!("{}: {}", path, e)))?;
+    let elf = Elf::parse(&data).map_err(|e| ELFError(e.to_string()))?;
+    let rodata = elf.section_headers.iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".rodata"))
+        .ok_or_else(|| NotFound(format!("{}: no .rodata", path)))?;
+    let start = rodata.sh_offset as usize;
+    let end = start + rodata.sh_size as usize;
+    let rodata = data.get(start..end).ok_or_else(|| ELFError(format!("{}: bad .rodata bounds", path)))?;
+
+    for c in PY_VERSION_RE.captures_iter(rodata) {
+        let number = |i: usize| std::str::from_utf8(&c[i]).unwrap().parse::<u32>().unwrap();
+        let v = PythonVersion { major: number(1), minor: number(2), patch: number(3) };
+        if v.major != 3 && v.major != 2 {
+            continue;
+        }
+        match expected {
+            Some(e) if e.major != v.major || e.minor != v.minor => continue,
+            _ => return Ok(v),
+        }
+    }
+    Err(NotFound(format!("{}: no python version string", path)))
+}
@@ -14,7 +14,7 @@ use libbpf_sys::{bpf_attr, bpf_cmd, perf_event_attr};
 use libc::{pid_t, SYS_bpf, SYS_perf_event_open};
 
 
-use crate::error::Error::PerfBufferError;
+use crate::error::Error;
 
 pub(crate) enum Syscall<'a> {
 	Ebpf {
@@ -68,6 +68,11 @@ impl std::fmt::Debug for Syscall<'_> {
 }
 
 fn syscall(call: Syscall<'_>) -> Result<c_long> {
+	let op = match &call {
+		Syscall::Ebpf { cmd, .. } => format!("bpf cmd {}", cmd),
+		Syscall::PerfEventOpen { cpu, .. } => format!("perf_event_open cpu {}", cpu),
+		Syscall::PerfEventIoctl { fd, request, .. } => format!("perf event ioctl {} fd {}", request, fd),
+	};
 	match unsafe {
 		match call {
 			Syscall::Ebpf { cmd, attr } => {
@@ -81,7 +86,7 @@ fn syscall(call: Syscall<'_>) -> Result<c_long> {
 				flags,
 			} => libc::syscall(SYS_perf_event_open, &attr, pid, cpu, group, flags),
 			Syscall::PerfEventIoctl { fd, request, arg } => {
-				let int = libc::ioctl(fd.as_raw_fd(), request.try_into().unwrap(), arg);
+				let int = libc::ioctl(fd.as_raw_fd(), request as _, arg);
 				#[allow(trivial_numeric_casts)]
 					let int = int as c_long;
 				int
@@ -89,7 +94,7 @@ fn syscall(call: Syscall<'_>) -> Result<c_long> {
 		}
 	} {
 		ret @ 0.. => Ok(ret),
-		_ret => Err(PerfBufferError(io::Error::last_os_error().to_string())),
+		_ret => Err(Error::from_io(op, &io::Error::last_os_error())),
 	}
 }
 
@@ -4,6 +4,7 @@ use std::{
 	os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
 	ptr, slice,
 	sync::atomic::{self, AtomicPtr, Ordering},
+	time::Duration,
 };
 
 
@@ -19,7 +20,8 @@ use nix::fcntl::{fcntl, FcntlArg::*, OFlag};
 
 use crate::ebpf::{PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE};
 use crate::ebpf::ring::sys::{perf_event_ioctl, perf_event_open_bpf};
-use crate::error::Error::{OSError, PerfBufferError};
+use crate::error::Error;
+use crate::error::Error::PerfBufferError;
 use crate::error::Result;
 
 /// Return type of `read_events()`.
@@ -31,6 +33,63 @@ pub struct Events {
 	pub lost: usize,
 }
 
+// Wakeup is when the kernel wakes the reader of a ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wakeup {
+	// Events wakes after every that many records
+	Events(u32),
+	// Watermark wakes once that many bytes of records are in the ring, fewer wakeups on busy
+	// systems at the cost of latency
+	Watermark(u32),
+}
+
+// PerfBufferOptions shape the rings bpf_perf_event_output writes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfBufferOptions {
+	// page_count is the size of a ring in pages, a power of two
+	pub page_count: usize,
+	pub wakeup: Wakeup,
+	// drain_interval bounds how long records below the wakeup threshold wait in the ring,
+	// unused when every record wakes the reader
+	pub drain_interval: Duration,
+}
+
+impl Default for PerfBufferOptions {
+	fn default() -> Self {
+		Self {
+			page_count: 4,
+			wakeup: Wakeup::Events(1),
+			drain_interval: Duration::from_secs(1),
+		}
+	}
+}
+
+impl PerfBufferOptions {
+	// wakes_every_event tells if the reader is woken for every record, so no record waits in a ring
+	pub fn wakes_every_event(&self) -> bool {
+		self.wakeup == Wakeup::Events(1)
+	}
+
+	pub fn validate(&self, page_size: usize) -> Result<()> {
+		if !self.page_count.is_power_of_two() {
+			return Err(PerfBufferError(format!("InvalidPageCount {}", self.page_count)));
+		}
+		let size = page_size * self.page_count;
+		match self.wakeup {
+			Wakeup::Events(0) => return Err(PerfBufferError("wakeup events must be positive".to_string())),
+			// a watermark the ring can't hold would never wake the reader
+			Wakeup::Watermark(bytes) if bytes == 0 || bytes as usize >= size => {
+				return Err(PerfBufferError(format!("wakeup watermark {} must be within the ring size {}", bytes, size)));
+			}
+			_ => {}
+		}
+		if !self.wakes_every_event() && self.drain_interval.is_zero() {
+			return Err(PerfBufferError("drain_interval must be positive".to_string()));
+		}
+		Ok(())
+	}
+}
+
 #[derive(Debug)]
 pub struct PerfBuffer {
 	pub buf: AtomicPtr<perf_event_mmap_page>,
@@ -44,14 +103,12 @@ impl PerfBuffer {
 	pub fn new(
 		cpu_id: i32,
 		page_size: usize,
-		page_count: usize,
+		options: &PerfBufferOptions,
 	) -> Result<Self> {
-		if !page_count.is_power_of_two() {
-			return Err(PerfBufferError(format!("InvalidPageCount {}", page_count)));
-		}
-		let fd = perf_event_open_bpf(cpu_id).unwrap();
+		options.validate(page_size)?;
+		let fd = perf_event_open_bpf(cpu_id, options.wakeup)?;
 		// set_non_blocking(fd).unwrap();
-		let size = page_size * page_count;
+		let size = page_size * options.page_count;
 		let buf = unsafe {
 			mmap(
 				ptr::null_mut(),
@@ -63,7 +120,9 @@ impl PerfBuffer {
 			)
 		};
 		if buf == MAP_FAILED {
-			return Err(PerfBufferError(io::Error::last_os_error().to_string()))
+			let err = Error::from_io(format!("mmap perf buffer of cpu {}", cpu_id), &io::Error::last_os_error());
+			unsafe { libc::close(fd) };
+			return Err(err);
 		}
 
 		let perf_buf = Self {
@@ -73,10 +132,17 @@ impl PerfBuffer {
 			page_size,
 			cpu: cpu_id
 		};
-		perf_event_ioctl(perf_buf.fd, PERF_EVENT_IOC_ENABLE, 0).unwrap();
+		// on error perf_buf is dropped, which unmaps and closes it
+		perf_event_ioctl(perf_buf.fd, PERF_EVENT_IOC_ENABLE, 0)?;
 		Ok(perf_buf)
 	}
 
+	// utilization returns the share of the ring holding events that haven't been read yet.
+	pub(crate) fn utilization(&self) -> f64 {
+		let (_, head, tail) = get_head_and_tail(&self.buf);
+		head.wrapping_sub(tail) as f64 / self.size as f64
+	}
+
 	pub(crate) fn read_events(
 		&mut self,
 		buffers: &mut [BytesMut],
@@ -93,10 +159,10 @@ impl PerfBuffer {
 		let fill_buf = |start_off, base, mmap_size, out_buf: &mut [u8]| {
 			let len = out_buf.len();
 
-			let end = (start_off + len) % mmap_size;
 			let start = start_off % mmap_size;
 
-			if start < end {
+			// a record that doesn't cross the end of the ring, empty ones included
+			if start + len <= mmap_size {
 				out_buf.copy_from_slice(unsafe {
 					slice::from_raw_parts((base + start) as *const u8, len)
 				});
@@ -153,8 +219,7 @@ impl PerfBuffer {
 			}
 		};
 
-		let head = unsafe { (*header).data_head } as usize;
-		let mut tail = unsafe { (*header).data_tail } as usize;
+		let (_, head, mut tail) = get_head_and_tail(&self.buf);
 		while head != tail {
 			if buf_n == buffers.len() {
 				break;
@@ -165,6 +230,14 @@ impl PerfBuffer {
 			let event_start = tail % self.size;
 			let event = unsafe { ptr::read_unaligned((base + event_start) as *const perf_event_header) };
 			let event_size = event.size as usize;
+			// the kernel only moves head past complete records, a record that is empty or reaches
+			// past head means the ring is corrupted and reading on would loop or read garbage
+			if event_size < mem::size_of::<perf_event_header>() || tail + event_size > head {
+				write_tail(header, head);
+				return Err(PerfBufferError(format!(
+					"corrupted record of size {} at {} in perf buffer of cpu {}", event_size, tail, self.cpu
+				)));
+			}
 
 			match read_event(event_start, event.type_, base, buf) {
 				Ok(Some((read, lost))) => {
@@ -178,41 +251,44 @@ impl PerfBuffer {
 				Err(e) => {
 					// we got an error and we didn't process any events, propagate the error
 					// and give the caller a chance to increase buffers
-					atomic::fence(Ordering::SeqCst);
-					unsafe { (*header).data_tail = tail as u64 };
+					write_tail(header, tail);
 					return Err(e);
 				}
 			}
 			tail += event_size;
 		}
 
-		atomic::fence(Ordering::SeqCst);
-		unsafe { (*header).data_tail = tail as u64 };
+		write_tail(header, tail);
 
 		Ok(events)
 	}
 }
 
 pub fn set_non_blocking(fd: RawFd) -> Result<()> {
-	let flags = fcntl(fd, F_GETFL);
-	if flags.is_err() {
-		return Err(OSError(format!("F_GETFL error errorno: {}", flags.err().unwrap())))
-	}
-	let mut oflags = OFlag::from_bits_truncate(flags.unwrap());
+	let flags = fcntl(fd, F_GETFL).map_err(|e| Error::os_error(format!("F_GETFL fd {}", fd), e as i32))?;
+	let mut oflags = OFlag::from_bits_truncate(flags);
 	oflags |= OFlag::O_NONBLOCK;
-	if fcntl(fd, F_SETFL(oflags)).is_err() {
-		return Err(OSError(format!("F_SETFL error errorno: {}", flags.err().unwrap())))
-	}
+	fcntl(fd, F_SETFL(oflags)).map_err(|e| Error::os_error(format!("F_SETFL fd {}", fd), e as i32))?;
 	Ok(())
 }
 
+// get_head_and_tail reads the positions of the ring. head is written by the kernel after the record
+// data, the acquire fence keeps the reads of the records from being done before reading head.
+// Both only grow, offsets into the ring are taken modulo its size.
 fn get_head_and_tail(buf: &AtomicPtr<perf_event_mmap_page>) -> (*mut perf_event_mmap_page, usize, usize) {
 	let header = buf.load(Ordering::SeqCst);
-	let head = unsafe { (*header).data_head } as usize;
-	let tail = unsafe { (*header).data_tail } as usize;
+	let head = unsafe { ptr::read_volatile(ptr::addr_of!((*header).data_head)) } as usize;
+	atomic::fence(Ordering::Acquire);
+	let tail = unsafe { ptr::read_volatile(ptr::addr_of!((*header).data_tail)) } as usize;
 	(header, head, tail)
 }
 
+// write_tail hands the space up to tail back to the kernel, after the records before it have been read
+fn write_tail(header: *mut perf_event_mmap_page, tail: usize) {
+	atomic::fence(Ordering::Release);
+	unsafe { ptr::write_volatile(ptr::addr_of_mut!((*header).data_tail), tail as u64) };
+}
+
 impl AsRawFd for PerfBuffer {
 	fn as_raw_fd(&self) -> RawFd {
 		self.fd
@@ -228,11 +304,215 @@ impl AsFd for PerfBuffer {
 impl Drop for PerfBuffer {
 	fn drop(&mut self) {
 		unsafe {
-			perf_event_ioctl(self.fd, PERF_EVENT_IOC_DISABLE, 0).unwrap();
+			let _ = perf_event_ioctl(self.fd, PERF_EVENT_IOC_DISABLE, 0);
 			munmap(
 				self.buf.load(Ordering::SeqCst) as *mut c_void,
 				self.size + self.page_size,
 			);
+			libc::close(self.fd);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// arm64 kernels run with 4K, 16K or 64K pages, the ring size follows the page size
+	#[test]
+	fn watermarks_are_bounded_by_the_page_size() {
+		let options = PerfBufferOptions { wakeup: Wakeup::Watermark(32 << 10), ..Default::default() };
+		assert!(options.validate(4 << 10).is_err());
+		assert!(options.validate(16 << 10).is_ok());
+		assert!(options.validate(64 << 10).is_ok());
+	}
+
+	#[test]
+	fn invalid_options_are_rejected() {
+		assert!(PerfBufferOptions::default().validate(4 << 10).is_ok());
+		let options = PerfBufferOptions { page_count: 3, ..Default::default() };
+		assert!(options.validate(64 << 10).is_err());
+		let options = PerfBufferOptions { wakeup: Wakeup::Events(0), ..Default::default() };
+		assert!(options.validate(4 << 10).is_err());
+		let options = PerfBufferOptions { wakeup: Wakeup::Events(8), drain_interval: Duration::ZERO, ..Default::default() };
+		assert!(options.validate(4 << 10).is_err());
+	}
+
+	const PAGE_SIZE: usize = 4096;
+	const RING_SIZE: usize = 2 * PAGE_SIZE;
+
+	// Ring plays the kernel side of a perf buffer in anonymous memory, records are written at head
+	// and only become visible to the reader once head is published
+	struct Ring {
+		buffer: PerfBuffer,
+		head: usize,
+	}
+
+	impl Ring {
+		// new starts the ring at position, positions only grow and are taken modulo the ring size
+		fn new(position: usize) -> Self {
+			let buf = unsafe {
+				mmap(ptr::null_mut(), RING_SIZE + PAGE_SIZE, PROT_READ | PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+			};
+			assert_ne!(buf, MAP_FAILED);
+			let buffer = PerfBuffer {
+				buf: AtomicPtr::new(buf as *mut perf_event_mmap_page),
+				size: RING_SIZE,
+				page_size: PAGE_SIZE,
+				fd: -1,
+				cpu: 0,
+			};
+			let ring = Self { buffer, head: position };
+			ring.publish();
+			write_tail(ring.header(), position);
+			ring
 		}
+
+		fn header(&self) -> *mut perf_event_mmap_page {
+			self.buffer.buf.load(Ordering::SeqCst)
+		}
+
+		fn tail(&self) -> usize {
+			get_head_and_tail(&self.buffer.buf).2
+		}
+
+		fn write(&mut self, bytes: &[u8]) {
+			let base = self.header() as usize + PAGE_SIZE;
+			for b in bytes {
+				unsafe { *((base + self.head % RING_SIZE) as *mut u8) = *b };
+				self.head += 1;
+			}
+		}
+
+		fn write_record(&mut self, typ: u32, body: &[u8]) {
+			// records are 8 byte aligned like the kernel's
+			let size = (mem::size_of::<perf_event_header>() + body.len()).next_multiple_of(8);
+			self.write(&typ.to_ne_bytes());
+			self.write(&0u16.to_ne_bytes());
+			self.write(&(size as u16).to_ne_bytes());
+			self.write(body);
+			self.write(&vec![0; size - mem::size_of::<perf_event_header>() - body.len()]);
+		}
+
+		fn write_sample(&mut self, data: &[u8]) {
+			let mut body = (data.len() as u32).to_ne_bytes().to_vec();
+			body.extend_from_slice(data);
+			self.write_record(PERF_RECORD_SAMPLE, &body);
+		}
+
+		fn write_lost(&mut self, lost: u64) {
+			let mut body = 7u64.to_ne_bytes().to_vec();
+			body.extend_from_slice(&lost.to_ne_bytes());
+			self.write_record(PERF_RECORD_LOST, &body);
+		}
+
+		fn publish(&self) {
+			atomic::fence(Ordering::Release);
+			unsafe { ptr::write_volatile(ptr::addr_of_mut!((*self.header()).data_head), self.head as u64) };
+		}
+
+		fn read(&mut self, buffers: usize) -> (Result<Events>, Vec<Vec<u8>>) {
+			let mut out = vec![BytesMut::new(); buffers];
+			let events = self.buffer.read_events(&mut out);
+			let read = events.as_ref().map_or(0, |e| e.read);
+			(events, out.into_iter().take(read).map(|b| b.to_vec()).collect())
+		}
+	}
+
+	fn sample(n: usize, len: usize) -> Vec<u8> {
+		(0..len).map(|i| (n * 31 + i) as u8).collect()
+	}
+
+	#[test]
+	fn reads_the_published_records() {
+		let mut ring = Ring::new(0);
+		ring.write_sample(&sample(1, 20));
+		ring.write_lost(3);
+		ring.write_sample(&sample(2, 100));
+		ring.publish();
+		let (events, samples) = ring.read(4);
+		assert_eq!(events.unwrap(), Events { read: 2, lost: 3 });
+		assert_eq!(samples, vec![sample(1, 20), sample(2, 100)]);
+		assert_eq!(ring.tail(), ring.head);
+		assert_eq!(ring.buffer.utilization(), 0.0);
+	}
+
+	#[test]
+	fn reads_records_wrapping_around_the_end() {
+		for start in [RING_SIZE - 8, RING_SIZE - 16, RING_SIZE - 24, 5 * RING_SIZE - 40] {
+			let mut ring = Ring::new(start);
+			ring.write_sample(&sample(1, 61));
+			ring.write_sample(&sample(2, 3));
+			ring.publish();
+			let (events, samples) = ring.read(4);
+			assert_eq!(events.unwrap(), Events { read: 2, lost: 0 }, "start {}", start);
+			assert_eq!(samples, vec![sample(1, 61), sample(2, 3)], "start {}", start);
+			assert_eq!(ring.tail(), ring.head);
+		}
+	}
+
+	#[test]
+	fn lost_counts_wrapping_around_the_end_are_read() {
+		let mut ring = Ring::new(RING_SIZE - 16);
+		ring.write_lost(u64::MAX - 1);
+		ring.publish();
+		assert_eq!(ring.read(1).0.unwrap(), Events { read: 0, lost: (u64::MAX - 1) as usize });
+	}
+
+	#[test]
+	fn records_not_yet_published_are_left_alone() {
+		let mut ring = Ring::new(RING_SIZE - 8);
+		ring.write_sample(&sample(1, 10));
+		ring.publish();
+		// the kernel is half way through the second record
+		ring.write_sample(&sample(2, 10));
+		let (events, samples) = ring.read(4);
+		assert_eq!(events.unwrap(), Events { read: 1, lost: 0 });
+		assert_eq!(samples, vec![sample(1, 10)]);
+		assert!(ring.tail() < ring.head);
+		ring.publish();
+		let (events, samples) = ring.read(4);
+		assert_eq!(events.unwrap(), Events { read: 1, lost: 0 });
+		assert_eq!(samples, vec![sample(2, 10)]);
+		assert_eq!(ring.tail(), ring.head);
+	}
+
+	#[test]
+	fn reading_stops_when_the_buffers_are_full() {
+		let mut ring = Ring::new(0);
+		for n in 0..5 {
+			ring.write_sample(&sample(n, 8));
+		}
+		ring.publish();
+		let (events, samples) = ring.read(2);
+		assert_eq!(events.unwrap().read, 2);
+		assert_eq!(samples, vec![sample(0, 8), sample(1, 8)]);
+		let (events, samples) = ring.read(8);
+		assert_eq!(events.unwrap().read, 3);
+		assert_eq!(samples, vec![sample(2, 8), sample(3, 8), sample(4, 8)]);
+		assert_eq!(ring.tail(), ring.head);
+	}
+
+	#[test]
+	fn empty_samples_are_read() {
+		let mut ring = Ring::new(RING_SIZE - 8);
+		ring.write_sample(&[]);
+		ring.publish();
+		let (events, samples) = ring.read(1);
+		assert_eq!(events.unwrap().read, 1);
+		assert_eq!(samples, vec![Vec::<u8>::new()]);
+	}
+
+	#[test]
+	fn corrupted_records_drop_the_ring() {
+		let mut ring = Ring::new(0);
+		ring.write_sample(&sample(1, 10));
+		// a record claiming to be empty would never move tail
+		ring.write(&[0; 8]);
+		ring.write_sample(&sample(2, 10));
+		ring.publish();
+		let (events, _) = ring.read(4);
+		assert!(events.is_err());
+		assert_eq!(ring.tail(), ring.head);
 	}
 }
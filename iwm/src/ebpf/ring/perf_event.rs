@@ -5,17 +5,41 @@ use std::os::unix::io::RawFd;
 
 
 use libbpf_rs::{Link, Program};
-use libbpf_rs::libbpf_sys::{PERF_TYPE_SOFTWARE};
+use libbpf_rs::libbpf_sys::{PERF_TYPE_HARDWARE, PERF_TYPE_SOFTWARE};
 
-use libbpf_sys::{PERF_COUNT_SW_CPU_CLOCK};
+use libbpf_sys::{PERF_COUNT_HW_CPU_CYCLES, PERF_COUNT_SW_CPU_CLOCK};
 
 
 
 
-use crate::ebpf::ring::sys::perf_event_open;
+use crate::ebpf::{PERF_EVENT_IOC_DISABLE, PERF_EVENT_IOC_ENABLE};
+use crate::ebpf::ring::perf_buffer::Wakeup;
+use crate::ebpf::ring::sys::{perf_event_ioctl, perf_event_open};
 
+use crate::error::Error;
+use crate::error::Error::PerfBufferError;
 use crate::error::Result;
 
+// MAX_PRECISE_IP is the highest skid constraint of perf_event_attr.precise_ip, 3 requests zero skid
+pub const MAX_PRECISE_IP: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+	// Frequency lets the kernel adjust the period to take about that many samples per second
+	Frequency(u64),
+	// Period takes a sample every that many events, nanoseconds of cpu clock or cpu cycles
+	Period(u64),
+}
+
+// PerfEventConfig is how the sampling perf events are opened.
+// precise_ip above 0 asks the pmu for precise (PEBS/IBS) samples. Only hardware events can be precise,
+// so it switches the event from the cpu clock to cpu cycles, a Period then counts cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfEventConfig {
+	pub sampling: Sampling,
+	pub precise_ip: u8,
+}
+
 #[derive(Debug)]
 pub struct PerfEvent {
 	pub fd: RawFd,
@@ -24,19 +48,39 @@ pub struct PerfEvent {
 }
 
 impl PerfEvent {
-	pub fn new(cpu: i32, sample_rate: u64, prog: &mut Program) -> Result<Self> {
+	pub fn new(cpu: i32, config: PerfEventConfig, prog: &mut Program) -> Result<Self> {
+		if config.precise_ip > MAX_PRECISE_IP {
+			return Err(Error::invalid_data(format!("precise_ip {} above {}", config.precise_ip, MAX_PRECISE_IP)));
+		}
+		let (perf_type, event) = if config.precise_ip > 0 {
+			(PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES as u64)
+		} else {
+			(PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_CLOCK as u64)
+		};
+		let (period, frequency) = match config.sampling {
+			Sampling::Frequency(frequency) => (0, Some(frequency)),
+			Sampling::Period(period) => (period, None),
+		};
 		let fd = perf_event_open(
-			PERF_TYPE_SOFTWARE,
-			PERF_COUNT_SW_CPU_CLOCK as u64,
+			perf_type,
+			event,
 			-1,
 			cpu,
-			sample_rate,
-			None,
-			false,
+			period,
+			frequency,
+			// the samples go to bpf, nobody reads this event
+			Wakeup::Events(0),
 			false,
+			config.precise_ip,
 			0
-		).unwrap();
-		let link = prog.attach_perf_event(fd).unwrap();
+		)?;
+		let link = match prog.attach_perf_event(fd) {
+			Ok(link) => link,
+			Err(err) => {
+				unsafe { libc::close(fd) };
+				return Err(PerfBufferError(format!("attach perf event of cpu {}: {}", cpu, err)));
+			}
+		};
 		// https://ebpf-docs.dylanreimerink.nl/linux/program-type/BPF_PROG_TYPE_PERF_EVENT/#ioctl-method
 		// let err = unsafe { libc::ioctl(fd, PERF_EVENT_IOC_SET_BPF as c_ulong, prog.as_fd().as_raw_fd()) };
 		// if err == -1 {
@@ -49,12 +93,24 @@ impl PerfEvent {
 		Ok(PerfEvent { fd, link: Some(link), ioctl: false })
 	}
 
+	// attach_mode is how the program is attached to the event
+	pub fn attach_mode(&self) -> &'static str {
+		if self.ioctl { "ioctl" } else { "bpf_link" }
+	}
+
+	// set_enabled starts or stops the sampling of the event, the program stays attached
+	pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+		let request = if enabled { PERF_EVENT_IOC_ENABLE } else { PERF_EVENT_IOC_DISABLE };
+		perf_event_ioctl(self.fd, request, 0)?;
+		Ok(())
+	}
+
 	fn close(&mut self) -> Result<()> {
 		unsafe {
 			libc::close(self.fd);
 		}
 		if let Some(link) = self.link.take() {
-			link.detach().unwrap();
+			link.detach().map_err(|err| PerfBufferError(format!("detach perf event: {}", err)))?;
 		}
 		Ok(())
 	}
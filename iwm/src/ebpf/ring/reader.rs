@@ -1,19 +1,21 @@
 
-use std::os::fd::{AsFd};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::os::fd::{AsFd, AsRawFd};
 use std::os::unix::io::RawFd;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::Instant;
 
 use bytes::BytesMut;
 use libbpf_rs::MapHandle;
 
-use object::ReadRef;
-use polling::{Event, Poller, PollMode};
-
-
-use crate::ebpf::ring::perf_buffer::{Events, PerfBuffer};
+use crate::ebpf::cpuonline;
+use crate::ebpf::epoll::poller::{Poller, Trigger};
+use crate::ebpf::metrics::ring::RingMetrics;
+use crate::ebpf::ring::perf_buffer::{Events, PerfBuffer, PerfBufferOptions};
 use crate::ebpf::ring::sys::bpf_map_update_elem;
-use crate::error::Error::MustBePaused;
+use crate::error::Error;
+use crate::error::Error::{DeadlineExceeded, MustBePaused};
 use crate::error::Result;
 
 const PERF_RECORD_LOST: u32 = 2;
@@ -39,70 +41,126 @@ pub struct Record {
 // Reader allows reading bpf_perf_event_output from user space.
 pub struct Reader {
     poller: Arc<Poller>,
-    deadline: Option<Duration>,
+    deadline: Option<Instant>,
 
-    rings: Vec<Arc<Mutex<PerfBuffer>>>,
-    epoll_events: Arc<Mutex<polling::Events>>,
+    // rings by cpu, cpus can be added and removed on hotplug
+    rings: HashMap<u32, Arc<Mutex<PerfBuffer>>>,
+    epoll_keys: Vec<u64>,
     epoll_rings: Vec<Arc<Mutex<PerfBuffer>>>,
     event_header: Vec<u8>,
 
-    pause_fds: Vec<RawFd>,
+    pause_fds: HashMap<u32, RawFd>,
 
     paused: bool,
     overwritable: bool,
 
     buffer_size: usize,
+    options: PerfBufferOptions,
+    // next_drain is when the rings are read regardless of their wakeup threshold, None when every
+    // record wakes the reader
+    next_drain: Option<Instant>,
+    metrics: RingMetrics,
 }
 
 impl Reader {
-    pub fn new(array: &MapHandle) -> Result<Self> {
-        let n_cpu = array.info().unwrap().info.max_entries;
-
-        let poller = Arc::new(Poller::new().unwrap());
-        let mut pause_fds = Vec::with_capacity(n_cpu as usize);
-        let mut rings = Vec::with_capacity(n_cpu as usize);
-
-        let mut buffer_size = 0;
-        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
-
-        for i in 0..n_cpu {
-            let ring = PerfBuffer::new(i as i32, page_size, 4).unwrap();
-            buffer_size = ring.size.clone();
-            unsafe {
-                poller.add_with_mode(ring.fd, Event::all(i as usize), PollMode::Level).unwrap();
-            }
-            pause_fds.push(ring.fd);
-            bpf_map_update_elem(array.as_fd(), Some(&i), &ring.fd, 0).unwrap();
-            rings.push(Arc::new(Mutex::new(ring)));
-        }
-
-        //dbg!(&poller);
-        Ok(Reader {
-            poller,
+    pub fn new(array: &MapHandle, options: PerfBufferOptions, metrics: RingMetrics) -> Result<Self> {
+        let max_entries = array.info()
+            // libbpf leaves errno of the failed bpf syscall
+            .map_err(|_| Error::MapError {
+                map: array.name().to_string(),
+                op: "info".to_string(),
+                errno: std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO),
+            })?
+            .info.max_entries;
+
+        let mut reader = Reader {
+            poller: Arc::new(Poller::new()?),
             deadline: None,
-            rings,
-            epoll_events: Arc::new(Mutex::new(polling::Events::new())),
+            rings: HashMap::new(),
+            epoll_keys: Vec::new(),
             epoll_rings: Vec::new(),
             event_header: vec![0; PERF_EVENT_HEADER_SIZE],
-            pause_fds,
+            pause_fds: HashMap::new(),
             paused: false,
             overwritable: false,
-            buffer_size
-        })
+            buffer_size: 0,
+            options,
+            next_drain: None,
+            metrics,
+        };
+        // cpu ids may have holes, rings are opened for the online cpus only and are
+        // keyed by the cpu id, which is also the index the bpf programs write to
+        for cpu in cpuonline::get()? {
+            if cpu >= max_entries {
+                return Err(Error::invalid_data(format!(
+                    "cpu {} is online but map {} has only {} entries", cpu, array.name(), max_entries
+                )));
+            }
+            reader.add_ring(array, cpu)?;
+        }
+        Ok(reader)
+    }
+
+    // add_ring opens the ring of a cpu, e.g. after it came online
+    pub fn add_ring(&mut self, array: &MapHandle, cpu: u32) -> Result<()> {
+        if self.rings.contains_key(&cpu) {
+            return Ok(());
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let ring = PerfBuffer::new(cpu as i32, page_size, &self.options)?;
+        self.buffer_size = ring.size;
+        self.poller.add(ring.fd, cpu as u64, Trigger::Level)?;
+        self.pause_fds.insert(cpu, ring.fd);
+        bpf_map_update_elem(array.as_fd(), Some(&cpu), &ring.fd, 0)?;
+        self.rings.insert(cpu, Arc::new(Mutex::new(ring)));
+        Ok(())
+    }
+
+    // remove_ring closes the ring of a cpu that went offline
+    pub fn remove_ring(&mut self, array: &MapHandle, cpu: u32) -> Result<()> {
+        let Some(ring) = self.rings.remove(&cpu) else {
+            return Ok(());
+        };
+        self.pause_fds.remove(&cpu);
+        self.epoll_rings.retain(|r| !Arc::ptr_eq(r, &ring));
+        let ret = unsafe {
+            libbpf_sys::bpf_map_delete_elem(array.as_fd().as_raw_fd(), &cpu as *const u32 as *const c_void)
+        };
+        if ret < 0 {
+            return Err(Error::MapError {
+                map: array.name().to_string(),
+                op: format!("delete ring of cpu {}", cpu),
+                errno: -ret,
+            });
+        }
+        let fd = ring.lock().unwrap().fd;
+        self.poller.remove(fd)
+    }
+
+    // set_deadline bounds how long read_events waits, None waits until a ring is readable
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
     }
 
     pub fn read_events(&mut self) -> Result<Record> {
-        let mut events = polling::Events::new();
         loop {
             if self.epoll_rings.len() == 0usize {
-                events.clear();
-                self.poller.wait(&mut events, self.deadline).unwrap();
+                self.epoll_keys.clear();
+                let (deadline, drain) = self.wait_deadline();
+                match self.poller.wait(&mut self.epoll_keys, deadline) {
+                    Err(DeadlineExceeded) if drain => {
+                        self.drain();
+                        continue;
+                    }
+                    result => result?,
+                }
                 if self.overwritable && !self.paused {
                     return Err(MustBePaused);
                 }
-                for event in events.iter() {
-                    let ring = self.rings[event.key].clone();
-                    self.epoll_rings.push(ring);
+                for key in self.epoll_keys.iter() {
+                    if let Some(ring) = self.rings.get(&(*key as u32)) {
+                        self.epoll_rings.push(ring.clone());
+                    }
                 }
                 continue;
             }
@@ -110,11 +168,23 @@ impl Reader {
             if len == 0 { continue; }
 
             let mut buffers = vec![BytesMut::with_capacity(PERF_EVENT_HEADER_SIZE)];
-            let (Events { read: _read, lost }, cpu) = {
+            let (Events { read, lost }, cpu, pending) = {
                 let mut ring = self.epoll_rings[len - 1].lock().unwrap();
-                (ring.read_events(&mut buffers).unwrap(), ring.cpu)
+                self.metrics.utilization
+                    .with_label_values(&[&ring.cpu.to_string()])
+                    .set(ring.utilization());
+                (ring.read_events(&mut buffers)?, ring.cpu, ring.utilization() > 0.0)
             };
-            self.epoll_rings.pop();
+            let cpu_label = cpu.to_string();
+            self.metrics.read_samples.with_label_values(&[&cpu_label]).inc_by(read as f64);
+            if lost > 0 {
+                self.metrics.lost_samples.with_label_values(&[&cpu_label]).inc_by(lost as f64);
+            }
+            // below the wakeup threshold the ring won't become readable again for the records left,
+            // keep reading it until it is empty
+            if !pending || self.options.wakes_every_event() {
+                self.epoll_rings.pop();
+            }
             return Ok(Record {
                 cpu,
                 raw_samples: buffers,
@@ -124,7 +194,32 @@ impl Reader {
         }
     }
 
+    // wait_deadline is the deadline of the caller, or the next drain when it comes first
+    fn wait_deadline(&mut self) -> (Option<Instant>, bool) {
+        if self.options.wakes_every_event() {
+            return (self.deadline, false);
+        }
+        let drain = *self.next_drain.get_or_insert_with(|| Instant::now() + self.options.drain_interval);
+        match self.deadline {
+            Some(deadline) if deadline <= drain => (Some(deadline), false),
+            _ => (Some(drain), true),
+        }
+    }
+
+    // drain queues the rings holding records below their wakeup threshold, so they are read
+    // within drain_interval even when the threshold is not reached
+    fn drain(&mut self) {
+        self.next_drain = Some(Instant::now() + self.options.drain_interval);
+        for ring in self.rings.values() {
+            if ring.lock().unwrap().utilization() > 0.0 {
+                self.epoll_rings.push(ring.clone());
+            }
+        }
+    }
+
     pub(crate) fn close(&mut self) -> Result<()> {
+        self.poller.close()?;
+        self.epoll_rings.clear();
         self.rings.clear();
         Ok(())
     }
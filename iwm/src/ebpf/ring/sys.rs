@@ -9,8 +9,9 @@ use std::os::fd::{AsRawFd, RawFd};
 use libbpf_sys::{bpf_attr, bpf_cmd, BPF_MAP_LOOKUP_AND_DELETE_ELEM, BPF_MAP_UPDATE_ELEM, PERF_COUNT_SW_BPF_OUTPUT, perf_event_attr, PERF_FLAG_FD_CLOEXEC, PERF_SAMPLE_RAW, PERF_TYPE_SOFTWARE};
 use libc::{pid_t};
 use crate::ebpf::ring::{Syscall, syscall};
+use crate::ebpf::ring::perf_buffer::Wakeup;
 
-use crate::error::Error::InvalidData;
+use crate::error::Error;
 use crate::error::Result;
 
 pub fn bpf_map_update_elem<K, V>(
@@ -76,8 +77,9 @@ pub fn perf_event_open(
 	cpu: c_int,
 	sample_period: u64,
 	sample_frequency: Option<u64>,
-	wakeup: bool,
+	wakeup: Wakeup,
 	inherit: bool,
+	precise_ip: u8,
 	flags: u32,
 ) -> Result<RawFd> {
 	let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
@@ -87,7 +89,14 @@ pub fn perf_event_open(
 	attr.type_ = perf_type;
 	attr.sample_type = PERF_SAMPLE_RAW as u64;
 	attr.set_inherit(if inherit { 1 } else { 0 });
-	attr.__bindgen_anon_2.wakeup_events = u32::from(wakeup);
+	attr.set_precise_ip(precise_ip as u64);
+	match wakeup {
+		Wakeup::Events(events) => attr.__bindgen_anon_2.wakeup_events = events,
+		Wakeup::Watermark(bytes) => {
+			attr.set_watermark(1);
+			attr.__bindgen_anon_2.wakeup_watermark = bytes;
+		}
+	}
 
 	if let Some(frequency) = sample_frequency {
 		attr.set_freq(1);
@@ -99,7 +108,7 @@ pub fn perf_event_open(
 	perf_event_sys(attr, pid, cpu, flags)
 }
 
-pub fn perf_event_open_bpf(cpu: c_int) -> Result<RawFd> {
+pub fn perf_event_open_bpf(cpu: c_int, wakeup: Wakeup) -> Result<RawFd> {
 	perf_event_open(
 		PERF_TYPE_SOFTWARE as u32,
 		PERF_COUNT_SW_BPF_OUTPUT as u64,
@@ -107,8 +116,9 @@ pub fn perf_event_open_bpf(cpu: c_int) -> Result<RawFd> {
 		cpu,
 		1,
 		None,
-		true,
+		wakeup,
 		false,
+		0,
 		PERF_FLAG_FD_CLOEXEC,
 	)
 }
@@ -130,12 +140,9 @@ fn perf_event_sys(attr: perf_event_attr, pid: pid_t, cpu: i32, flags: u32) -> Re
 		cpu,
 		group: -1,
 		flags,
-	}).unwrap();
-	if fd < 0 {
-		return Err(InvalidData(format!("perf_event_open: invalid fd returned: {fd}")))
-	}
+	})?;
 	// SAFETY: perf_event_open returns a new file descriptor on success.
-	Ok(fd.try_into().unwrap())
+	RawFd::try_from(fd).map_err(|_| Error::invalid_data(format!("perf_event_open: invalid fd returned: {fd}")))
 }
 
 /*
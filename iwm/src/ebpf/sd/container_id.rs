@@ -43,8 +43,10 @@ pub fn get_container_id_from_k8s(k8s_container_id: &str) -> Option<ContainerID>
 }
 
 lazy_static::lazy_static! {
+    // the deepest path component holding a container id, runtimes nest the processes of some containers
+    // (e.g. ephemeral containers, or init processes as <id>/init.scope) in sub cgroups of the container's
     static ref CGROUP_CONTAINER_ID_RE: regex::Regex =
-        regex::Regex::new(r#"^.*/(?:.*-)?([0-9a-f]{64})(?:\.|\s*$)"#).unwrap();
+        regex::Regex::new(r#"^.*/(?:[^/]*-)?([0-9a-f]{64})(?:\.[a-z]+)?(?:/[^\s]*)?\s*$"#).unwrap();
 }
 
 pub fn get_container_id_from_cgroup(line: &str) -> Option<String> {
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::ebpf::sd::target::EbpfTarget;
+use crate::error::Error;
+use crate::error::Result;
+
+const JOURNAL_FILE: &str = "pids.jsonl";
+
+// JournalOptions are where the pid journal is written and how much of it is kept. The journal is
+// rotated to pids.jsonl.1 .. pids.jsonl.<max_files - 1> when the current file reaches max_file_bytes.
+#[derive(Debug, Clone)]
+pub struct JournalOptions {
+    pub dir: PathBuf,
+    pub max_file_bytes: u64,
+    pub max_files: usize,
+}
+
+impl JournalOptions {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, max_file_bytes: 16 << 20, max_files: 4 }
+    }
+}
+
+// PidJournal records which target every profiled pid belonged to and when, one json line per
+// association start and end. Long after the container is gone, it tells which process a
+// historical series was profiled from. Associations open when the agent stopped have no end line.
+pub struct PidJournal {
+    options: JournalOptions,
+    file: File,
+    size: u64,
+    open: HashMap<u32, Association>,
+}
+
+struct Association {
+    target: EbpfTarget,
+    fingerprint: u64,
+    start: u64,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    event: &'static str,
+    pid: u32,
+    container_id: Option<&'a str>,
+    service_name: &'a str,
+    // fingerprint is the fingerprint of the target labels, stable across restarts
+    fingerprint: String,
+    labels: BTreeMap<&'a str, &'a str>,
+    // start and end are unix times in seconds
+    start: u64,
+    end: Option<u64>,
+}
+
+impl PidJournal {
+    pub fn open(options: JournalOptions) -> Result<Self> {
+        if options.max_file_bytes == 0 || options.max_files == 0 {
+            return Err(Error::invalid_data("pid journal max_file_bytes and max_files must be positive".to_string()));
+        }
+        fs::create_dir_all(&options.dir)
+            .map_err(|e| Error::from_io(format!("creating pid journal dir {}", options.dir.display()), &e))?;
+        let (file, size) = open_file(&options)?;
+        Ok(Self { options, file, size, open: HashMap::new() })
+    }
+
+    // start records the association of the pid with the target. A pid that execs into another
+    // target ends its previous association first.
+    pub fn start(&mut self, pid: u32, target: &EbpfTarget) -> Result<()> {
+        let fingerprint = target.labels.fingerprint();
+        if self.open.get(&pid).is_some_and(|a| a.fingerprint == fingerprint) {
+            return Ok(());
+        }
+        self.end(pid)?;
+        let association = Association { target: target.clone(), fingerprint, start: unix_now() };
+        let line = entry_line("start", pid, &association, None);
+        self.open.insert(pid, association);
+        self.write(line)
+    }
+
+    // end records the end of the association of the pid, pids without one are ignored
+    pub fn end(&mut self, pid: u32) -> Result<()> {
+        match self.open.remove(&pid) {
+            Some(association) => self.write(entry_line("end", pid, &association, Some(unix_now()))),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, line: String) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.options.max_file_bytes {
+            self.rotate()?;
+        }
+        let path = self.options.dir.join(JOURNAL_FILE);
+        self.file.write_all(line.as_bytes())
+            .map_err(|e| Error::from_io(format!("writing {}", path.display()), &e))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // rotate shifts the files by one, dropping the oldest, and starts a new current file
+    fn rotate(&mut self) -> Result<()> {
+        let path = |i: usize| match i {
+            0 => self.options.dir.join(JOURNAL_FILE),
+            i => self.options.dir.join(format!("{}.{}", JOURNAL_FILE, i)),
+        };
+        let last = self.options.max_files - 1;
+        let _ = fs::remove_file(path(last));
+        for i in (0..last).rev() {
+            match fs::rename(path(i), path(i + 1)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::from_io(format!("rotating {}", path(i).display()), &e)),
+            }
+        }
+        (self.file, self.size) = open_file(&self.options)?;
+        Ok(())
+    }
+}
+
+fn open_file(options: &JournalOptions) -> Result<(File, u64)> {
+    let path = options.dir.join(JOURNAL_FILE);
+    let file = OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|e| Error::from_io(format!("opening {}", path.display()), &e))?;
+    let size = file.metadata()
+        .map_err(|e| Error::from_io(format!("stat {}", path.display()), &e))?
+        .len();
+    Ok((file, size))
+}
+
+fn entry_line(event: &'static str, pid: u32, association: &Association, end: Option<u64>) -> String {
+    let target = &association.target;
+    let entry = Entry {
+        event,
+        pid,
+        container_id: target.container_id(),
+        service_name: target.service_name(),
+        fingerprint: format!("{:016x}", association.fingerprint),
+        labels: target.labels.0.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect(),
+        start: association.start,
+        end,
+    };
+    let mut line = serde_json::to_string(&entry).unwrap();
+    line.push('\n');
+    line
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
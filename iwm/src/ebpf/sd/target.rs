@@ -3,7 +3,9 @@ use std::fs::File;
 use std::hash::{Hash};
 use std::num::NonZeroUsize;
 use std::str::FromStr;
-use std::sync::{Mutex};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use arc_swap::ArcSwap;
 use lru::LruCache;
 use log::{debug, warn, info};
 
@@ -15,15 +17,61 @@ use crate::ebpf::session::DiscoveryTarget;
 pub const LABEL_CONTAINER_ID: &str = "__container_id__";
 pub const METRIC_NAME: &str = "__name__";
 pub const LABEL_PID: &str = "__process_pid__";
+pub const LABEL_POD_UID: &str = "__pod_uid__";
+pub const LABEL_K8S_POD_UID: &str = "__meta_kubernetes_pod_uid";
 pub const LABEL_SERVICE_NAME: &str = "service_name";
 pub const LABEL_SERVICE_NAME_K8S: &str = "__meta_kubernetes_pod_annotation_iwm_io_service_name";
 pub const METRIC_VALUE: &str = "process_cpu";
 pub const RESERVED_LABEL_PREFIX: &str = "__";
+pub const KERNEL_SERVICE_NAME: &str = "kernel";
+// LABEL_SAMPLE_RATE sets the samples per second of a target, below the rate of the perf events
+pub const LABEL_SAMPLE_RATE: &str = "__sample_rate__";
+pub const LABEL_PROFILE_MODE: &str = "__profile_mode__";
+
+// ProfileMode is the pipeline a discovery target is profiled by, chosen with the __profile_mode__ label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileMode {
+    Ebpf,
+    Pull,
+    Both,
+}
+
+impl ProfileMode {
+    pub fn ebpf(&self) -> bool {
+        matches!(self, ProfileMode::Ebpf | ProfileMode::Both)
+    }
+
+    pub fn pull(&self) -> bool {
+        matches!(self, ProfileMode::Pull | ProfileMode::Both)
+    }
+}
+
+// profile_mode reads the mode of the target, targets without the label or with an unknown
+// value are profiled with ebpf as before the label existed
+pub fn profile_mode(target: &DiscoveryTarget) -> ProfileMode {
+    match target.get(LABEL_PROFILE_MODE).map(|m| m.trim().to_ascii_lowercase()).as_deref() {
+        Some("pull") => ProfileMode::Pull,
+        Some("both") => ProfileMode::Both,
+        Some("ebpf") | None => ProfileMode::Ebpf,
+        Some(mode) => {
+            warn!("unknown {} {:?}, using ebpf", LABEL_PROFILE_MODE, mode);
+            ProfileMode::Ebpf
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct EbpfTarget {
     pub labels: Labels,
     service_name: String,
+    // container_id and pod_uid are set for targets matched by container id, they are also
+    // attached to every sample so merged service profiles can be split by container
+    container_id: Option<String>,
+    pod_uid: Option<String>,
+    // kernel_only is set on the target of pids matched by no other target when targets_only is off
+    kernel_only: bool,
+    // sample_rate is the rate asked for with __sample_rate__, None samples at the session rate
+    sample_rate: Option<u32>,
     fingerprint: u64,
     fingerprint_calculated: bool,
 }
@@ -49,8 +97,16 @@ impl EbpfTarget {
             lset.insert(LABEL_SERVICE_NAME.into(), service_name.clone());
         }
         if !cid.is_empty() {
-            lset.insert(LABEL_CONTAINER_ID.into(), cid.into());
+            lset.insert(LABEL_CONTAINER_ID.into(), cid.clone());
         }
+        let pod_uid = target.get(LABEL_K8S_POD_UID).filter(|uid| !uid.is_empty()).cloned();
+        let sample_rate = target.get(LABEL_SAMPLE_RATE).and_then(|rate| match rate.trim().parse::<u32>() {
+            Ok(rate) if rate > 0 => Some(rate),
+            _ => {
+                warn!("invalid {} {:?} of service {}, using the session rate", LABEL_SAMPLE_RATE, rate, service_name);
+                None
+            }
+        });
         if pid != 0 {
             lset.insert(LABEL_PID.into(), pid.to_string());
         }
@@ -59,14 +115,31 @@ impl EbpfTarget {
         EbpfTarget {
             labels: Labels::from_map(lset),
             service_name,
+            container_id: Some(cid).filter(|cid| !cid.is_empty()),
+            pod_uid,
+            kernel_only: false,
+            sample_rate,
             fingerprint: 0,
             fingerprint_calculated: false,
         }
     }
 
+    // kernel is the target kernel stacks of otherwise untargeted pids are aggregated under,
+    // kworkers, softirqs and irqs interrupting them included
+    fn kernel() -> Self {
+        let target = HashMap::from([(LABEL_SERVICE_NAME.to_string(), KERNEL_SERVICE_NAME.to_string())]);
+        let mut t = EbpfTarget::new("".to_string(), 0, target);
+        t.kernel_only = true;
+        t
+    }
+
+    pub(crate) fn is_kernel_only(&self) -> bool {
+        self.kernel_only
+    }
+
     pub(crate) fn labels(mut self) -> (u64, Labels) {
         if !self.fingerprint_calculated {
-            self.fingerprint = self.labels.hash();
+            self.fingerprint = self.labels.fingerprint();
             self.fingerprint_calculated = true;
         }
         (self.fingerprint, self.labels)
@@ -79,6 +152,23 @@ impl EbpfTarget {
     pub(crate) fn service_name(&self) -> &str {
         &self.service_name
     }
+
+    pub(crate) fn container_id(&self) -> Option<&str> {
+        self.container_id.as_deref()
+    }
+
+    pub(crate) fn pod_uid(&self) -> Option<&str> {
+        self.pod_uid.as_deref()
+    }
+
+    // sample_every is how many samples taken at the session rate make one sample of the target,
+    // rates above the session rate can't be sampled and are capped to it
+    pub(crate) fn sample_every(&self, session_rate: u32) -> u8 {
+        match self.sample_rate {
+            Some(rate) => (session_rate as f64 / rate as f64).round().clamp(1.0, u8::MAX as f64) as u8,
+            None => 1,
+        }
+    }
 }
 
 fn infer_service_name(target: DiscoveryTarget) -> String {
@@ -112,63 +202,102 @@ pub struct TargetsOptions {
     pub container_cache_size: usize,
 }
 
-pub struct TargetFinder {
+// TargetSnapshot is the result of a targets update. Updates build a new snapshot and swap it in,
+// so resolving the targets of samples never waits for an update, and updates never wait for a round.
+#[derive(Default)]
+struct TargetSnapshot {
     cid2target: HashMap<String, EbpfTarget>,
     pid2target: HashMap<u32, EbpfTarget>,
-    container_id_cache: Mutex<LruCache<u32, String>>,
     default_target: Option<EbpfTarget>,
+    targets_only: bool,
+    // discovered are the targets as given to the update, kept for the discovery snapshot
+    discovered: Vec<DiscoveryTarget>,
+}
+
+pub struct TargetFinder {
+    snapshot: ArcSwap<TargetSnapshot>,
+    // generation counts the targets updates, the session applies an update once it sees a new one
+    generation: AtomicU64,
+    container_id_cache: Mutex<LruCache<u32, String>>,
     fs: File
 }
 
 impl TargetFinder {
     pub fn new(container_cache_size: usize, fs: File) -> TargetFinder {
         TargetFinder {
-            cid2target: HashMap::new(),
-            pid2target: HashMap::new(),
+            snapshot: ArcSwap::from_pointee(TargetSnapshot::default()),
+            generation: AtomicU64::new(0),
             container_id_cache: Mutex::new(
                 LruCache::new(NonZeroUsize::try_from(container_cache_size).unwrap())
             ),
-            default_target: None,
             fs
         }
     }
 
     pub(crate) fn find_target(&self, pid: &u32) -> Option<EbpfTarget> {
-        if let Some(target) = self.pid2target.get(pid) {
+        let snapshot = self.snapshot.load();
+        if let Some(target) = snapshot.pid2target.get(pid) {
             return Some(target.clone());
         }
 
         let mut cache = self.container_id_cache.lock().unwrap();
         if let Some(cid) = cache.get(pid).cloned() {
-            return self.cid2target.get(&cid).cloned()
+            return snapshot.cid2target.get(&cid).cloned().or_else(|| snapshot.default_target.clone());
         }
 
         if let Some(cid) = get_container_id_from_pid(pid) {
             cache.put(pid.clone(), cid.clone());
-            return self.cid2target.get(&cid).cloned();
+            return snapshot.cid2target.get(&cid).cloned().or_else(|| snapshot.default_target.clone());
         }
-        return None;
+        snapshot.default_target.clone()
     }
 
-    pub(crate) fn remove_dead_pid(&mut self, pid: &u32) {
-        self.pid2target.remove(pid);
+    pub(crate) fn remove_dead_pid(&self, pid: &u32) {
+        // pid targets are rare, only swap when the pid had one
+        if self.snapshot.load().pid2target.contains_key(pid) {
+            self.snapshot.rcu(|current| {
+                let mut pid2target = current.pid2target.clone();
+                pid2target.remove(pid);
+                TargetSnapshot {
+                    cid2target: current.cid2target.clone(),
+                    pid2target,
+                    default_target: current.default_target.clone(),
+                    targets_only: current.targets_only,
+                    discovered: current.discovered.clone(),
+                }
+            });
+        }
         let mut cache = self.container_id_cache.lock().unwrap();
         cache.pop(pid);
     }
 
-    pub(crate) fn update(&mut self, args: &TargetsOptions) {
+    // update replaces the targets, it doesn't need the session. The session profiles the pids that
+    // got a target with the update at its next round, see Session::sync_targets.
+    pub fn update(&self, args: &TargetsOptions) {
         info!("target update");
         self.set_targets(args);
         self.resize_container_id_cache(args.container_cache_size);
+        self.generation.fetch_add(1, Ordering::SeqCst);
     }
 
-    fn set_targets(&mut self, opts: &TargetsOptions) {
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn targets_only(&self) -> bool {
+        self.snapshot.load().targets_only
+    }
+
+    fn set_targets(&self, opts: &TargetsOptions) {
         debug!("targets opts {:?}", opts);
         debug!("set targets count {}", opts.targets.len());
         let mut container_id2_target = HashMap::new();
         let mut pid2_target = HashMap::new();
 
         for target in &opts.targets {
+            if !profile_mode(target).ebpf() {
+                continue;
+            }
             if let Some(pid) = pid_from_target(target) {
                 let t = EbpfTarget::new("".to_string(), pid.clone(), target.clone());
                 pid2_target.insert(pid, t);
@@ -194,29 +323,48 @@ impl TargetFinder {
         if !opts.targets.is_empty() && container_id2_target.is_empty() && pid2_target.is_empty() {
             warn!("No targets found");
         }
-        self.cid2target = container_id2_target;
-        self.pid2target = pid2_target;
-
-        self.default_target = None;
-        debug!("created targets: {}", self.cid2target.len());
+        debug!("created targets: {}", container_id2_target.len());
+        self.snapshot.store(Arc::new(TargetSnapshot {
+            cid2target: container_id2_target,
+            pid2target: pid2_target,
+            default_target: if opts.targets_only { None } else { Some(EbpfTarget::kernel()) },
+            targets_only: opts.targets_only,
+            discovered: opts.targets.clone(),
+        }));
     }
 
-    fn resize_container_id_cache(&mut self, size: usize) {
+    fn resize_container_id_cache(&self, size: usize) {
         self.container_id_cache.lock().unwrap().resize(NonZeroUsize::try_from(size).unwrap());
     }
 
-    pub fn debug_info(&mut self) -> Vec<String> {
-        self.cid2target.clone()
-            .iter_mut()
-            .map(|(_, &mut ref target)| {
+    pub fn debug_info(&self) -> Vec<String> {
+        self.snapshot.load().cid2target
+            .values()
+            .map(|target| {
                 let (key, value) = target.clone().labels();
                 format!("{}: {}", key, value)
             })
             .collect()
     }
 
+    // discovered_targets pairs every discovered target with the labels its profiles get, None for
+    // targets not profiled with ebpf or matching no pid nor container
+    pub fn discovered_targets(&self) -> Vec<(DiscoveryTarget, Option<Labels>)> {
+        let snapshot = self.snapshot.load();
+        snapshot.discovered.iter().map(|target| {
+            let ebpf_target = if !profile_mode(target).ebpf() {
+                None
+            } else if let Some(pid) = pid_from_target(target) {
+                snapshot.pid2target.get(&pid)
+            } else {
+                container_id_from_target(target).and_then(|cid| snapshot.cid2target.get(&cid))
+            };
+            (target.clone(), ebpf_target.map(|t| t.labels.clone()))
+        }).collect()
+    }
+
     fn targets(&self) -> Vec<EbpfTarget> {
-        self.cid2target.values().cloned().collect()
+        self.snapshot.load().cid2target.values().cloned().collect()
     }
 }
 
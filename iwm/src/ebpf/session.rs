@@ -4,49 +4,64 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::default::Default;
 use std::ffi::c_void;
 use std::io::Read;
 
 
-use std::os::fd::{AsFd, AsRawFd, RawFd};
-use std::path::Path;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
 
-use libbpf_rs::libbpf_sys::bpf_map_batch_opts;
 use libbpf_rs::skel::{OpenSkel, Skel, SkelBuilder};
 use libbpf_rs::{libbpf_sys, Link, MapFlags, Program};
-use libbpf_sys::{
-    __u32, bpf_map_delete_elem, bpf_map_lookup_batch,
-    bpf_map_lookup_elem, size_t,
-};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use rayon::prelude::*;
 
 
 use tokio::io::AsyncReadExt;
 
 use profile::*;
 
-use crate::common::collector::{ProfileSample, SampleType};
+use crate::common::collector::{ProfileSample, SampleType, StackMode};
 
 use crate::ebpf::metrics::metrics::ProfileMetrics;
-use crate::ebpf::ring::perf_event::PerfEvent;
+use crate::ebpf::diagnostics;
+use crate::ebpf::diagnostics::{capture_libbpf_log, LoadFailure};
+use crate::ebpf::ktime;
+use crate::ebpf::ktime::RoundWindow;
+use crate::ebpf::map::map::{as_bytes, delete_keys, drain, BpfMap};
+use crate::ebpf::pressure::LoadSheddingOptions;
+use crate::ebpf::procfs::{ProcFs, ProcStat};
+use crate::ebpf::probe::HookAttach;
+use crate::ebpf::pthread::{libc_config, LibcConfig};
+use crate::ebpf::python::offsets::{OffsetsDatabase, PyOffsetConfig};
+use crate::ebpf::python::perf::{PyPidData, Pyperf};
+use crate::ebpf::python::version::{detect_version, PythonVersion};
+use crate::ebpf::ring::perf_buffer::PerfBufferOptions;
+use crate::ebpf::ring::perf_event::{PerfEvent, PerfEventConfig, Sampling};
 use crate::ebpf::ring::reader::Reader;
 
 
-use crate::ebpf::sd::target::{EbpfTarget, TargetFinder, TargetsOptions};
+use crate::ebpf::sd::journal::{JournalOptions, PidJournal};
+use crate::ebpf::sd::target::{EbpfTarget, TargetFinder};
 use crate::ebpf::session::profile::profile_bss_types::{pid_config, sample_key};
-use crate::ebpf::symtab::elf_cache::ElfCacheDebugInfo;
+use crate::ebpf::symtab::elf_cache::{ElfCacheDebugInfo, ElfTableMemory};
 use crate::ebpf::symtab::elf_module::ElfTableOptions;
 use crate::ebpf::symtab::gcache::{GCacheDebugInfo, Resource};
 use crate::ebpf::symtab::perf_symbol_table::PerfSymbolTable;
 use crate::ebpf::symtab::proc::{ProcTable, ProcTableDebugInfo};
 use crate::ebpf::symtab::symbols::{CacheOptions, SymbolCache};
 use crate::ebpf::symtab::symtab::SymbolTable;
+use crate::ebpf::symtab::kallsyms::{syscall_symbol, KallsymsIndex};
+use crate::ebpf::symtab::table::Symbol;
 use crate::ebpf::sync::{ProfilingType};
-use crate::ebpf::wait_group::WaitGroup;
-use crate::error::Error::{InvalidData, OSError};
+use crate::error::Error;
 use crate::error::Result;
 
 mod profile {
@@ -59,10 +74,38 @@ pub struct SessionOptions {
     pub collect_kernel: bool,
     pub unknown_symbol_module_offset: bool,
     pub unknown_symbol_address: bool,
+    // unknown_symbol_symbolizable renders unresolved frames of known modules as module!0xoffset,
+    // which the server can symbolize later from the module's debug info. It takes precedence
+    // over unknown_symbol_module_offset.
+    pub unknown_symbol_symbolizable: bool,
     pub python_enabled: bool,
+    pub java_enabled: bool,
     pub metrics: Arc<ProfileMetrics>,
     pub sample_rate: u32,
+    // sample_period samples every that many events instead of letting the kernel keep sample_rate samples
+    // per second, nanoseconds of cpu clock or cpu cycles with precise_ip
+    pub sample_period: Option<u64>,
+    // precise_ip is the skid constraint of the samples, see PerfEventConfig
+    pub precise_ip: u8,
     pub cache_options: CacheOptions,
+    // round_budget is how long after the start of a round user stacks are symbolized, the ones left
+    // are reported as raw addresses and the proc tables not yet refreshed stay as they are. It is
+    // checked before every user stack, it doesn't bound the round: the stack being walked, the kernel
+    // stacks and the draining of the maps all run past it.
+    pub round_budget: Option<Duration>,
+    // process_metrics exports the cpu time and rss of the targeted processes per service every round
+    pub process_metrics: bool,
+    // hook_attach is how the exec and exit hooks are attached, falling back to the next mode when loading fails
+    pub hook_attach: HookAttach,
+    // symbolization_threads sizes the symbolization pool, 0 shares the global pool of one thread per cpu
+    pub symbolization_threads: usize,
+    // target_time_slice bounds the symbolization time of a target's pids in a round, user stacks left
+    // when it runs out are reported as raw addresses, so one target can't hold up the others
+    pub target_time_slice: Option<Duration>,
+    // ring_options are the size and wakeup of the perf rings the pid events are read from
+    pub ring_options: PerfBufferOptions,
+    // journal records the pid to target associations to a rotating file, None keeps no journal
+    pub journal: Option<JournalOptions>,
 }
 
 enum SampleAggregation {
@@ -84,11 +127,29 @@ struct ProcInfoLite {
     pid: u32,
     comm: String,
     typ: ProfilingType,
+    python: Option<PythonProcInfo>,
+    // kernel_only pids are matched by no target but the kernel one, only their kernel stacks are collected
+    kernel_only: bool,
+    // sample_every thins out the samples of the pid to the sample rate of its target
+    sample_every: u8,
+    // java pids are unwound with frame pointers and their JIT compiled frames resolved from the perf map
+    java: bool,
+}
+
+// PythonProcInfo holds what pyperf needs to unwind a python process: the struct offsets of its
+// interpreter version and of its libc's struct pthread, to find the thread state in TLS.
+#[derive(Debug)]
+struct PythonProcInfo {
+    version: PythonVersion,
+    offsets: PyOffsetConfig,
+    libc: LibcConfig,
 }
 
+// SessionDebugInfo is a snapshot of the symbol caches, served as json by the debug api
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SessionDebugInfo {
-    elf_cache: ElfCacheDebugInfo,
-    pid_cache: GCacheDebugInfo<ProcTableDebugInfo>,
+    pub elf_cache: ElfCacheDebugInfo,
+    pub pid_cache: GCacheDebugInfo<ProcTableDebugInfo>,
 }
 
 impl Default for SessionDebugInfo {
@@ -100,182 +161,422 @@ impl Default for SessionDebugInfo {
     }
 }
 
+// RoundSummary are the totals of a collection round: the targets and pids that had samples, the
+// samples taken and the ones dropped for dead pids or empty stacks, and the resolved frames
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RoundSummary {
+    pub round: u32,
+    pub targets: usize,
+    pub pids: usize,
+    pub samples: u64,
+    pub dropped_samples: u64,
+    pub frames: u64,
+    pub unknown_frames: u64,
+}
+
+impl RoundSummary {
+    // unknown_share is the share of the frames with an unknown symbol or module
+    pub fn unknown_share(&self) -> f64 {
+        if self.frames == 0 {
+            return 0.0;
+        }
+        self.unknown_frames as f64 / self.frames as f64
+    }
+}
+
 pub struct Session<'a> {
-    pub target_finder: Arc<Mutex<TargetFinder>>,
+    pub target_finder: Arc<TargetFinder>,
     pub(crate) sym_cache: Arc<Mutex<SymbolCache>>,
     tmp: Option<Arc<Mutex<PerfSymbolTable>>>,
     pub bpf: ProfileSkel<'a>,
+    // pyperf unwinds the python pids, None when python is disabled or pyperf failed to load
+    pyperf: Option<Pyperf<'a>>,
 
     events_reader: Option<Arc<Mutex<Reader>>>,
 
     options: SessionOptions,
     pub(crate) round_number: u32,
     started: bool,
+    // hook_attach is the mode the lifecycle hooks were loaded with, never Auto
+    hook_attach: HookAttach,
     kprobes: Vec<Link>,
 
-    // We have 3 threads
-    // 1 - reading perf events from ebpf. this one does not touch Session fields including mutex
-    // 2 - processing pid info requests. this one Session fields to update pid info and python info, this should be done under mutex
-    // 3 - processing pid dead events
-    // Accessing wg should be done with no Session.mutex held to avoid deadlock, therefore wg access (Start, Stop) should be
-    // synchronized outside
-    wg: WaitGroup,
-
     fds: Vec<RawFd>,
     pids: Arc<Mutex<Pids>>,
     perf_events: Vec<PerfEvent>,
+    procfs: ProcFs,
+    // bpf_ktime_get_ns of the end of the previous round, the start of the current one
+    round_start_ktime: u64,
+    round_window: RoundWindow,
+    // keeps BPF_ENABLE_STATS on for as long as the session lives
+    stats_fd: Option<OwnedFd>,
+    // symbolization_pool is the pool of symbolization_threads, None uses the global pool
+    symbolization_pool: Option<rayon::ThreadPool>,
+    // load_shedding is set while the node is under cpu pressure, see set_load_shedding
+    load_shedding: Option<LoadSheddingOptions>,
+    journal: Option<PidJournal>,
+    last_round: RoundSummary,
+    // targets_generation is the generation of the target finder the pids were last matched against
+    targets_generation: u64,
+    // counts_drained is the number of entries drained from the counts map, see counts_fill_ratio
+    counts_drained: u64,
 }
 
 impl Session<'_> {
-    pub fn new(target_finder: Arc<Mutex<TargetFinder>>, opts: SessionOptions) -> Result<Self> {
+    pub fn new(target_finder: Arc<TargetFinder>, opts: SessionOptions) -> Result<Self> {
         let sym_cache = Arc::new(Mutex::new(
             SymbolCache::new(opts.cache_options, &opts.metrics.symtab).unwrap(),
         ));
-        bump_memlock_rlimit().unwrap();
-        let mut builder = ProfileSkelBuilder::default();
-        builder.obj_builder.debug(true);
-        let open_skel = builder.open().unwrap();
-        let bpf = open_skel.load().unwrap();
+        bump_memlock_rlimit()?;
+        let (bpf, hook_attach) = load_profile_skel(opts.hook_attach)?;
+        let pyperf = if opts.python_enabled {
+            let maps = bpf.maps();
+            Pyperf::load(maps.stacks(), maps.progs())
+                .map_err(|err| warn!("python pids are profiled with frame pointers: {}", err))
+                .ok()
+        } else {
+            None
+        };
+        let symbolization_pool = match opts.symbolization_threads {
+            0 => None,
+            threads => Some(rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("symbolize-{}", i))
+                .build()
+                .map_err(|e| Error::invalid_data(format!("symbolization pool: {}", e)))?),
+        };
+        let journal = opts.journal.clone().map(PidJournal::open).transpose()?;
 
         Ok(Self {
             started: false,
             bpf,
+            pyperf,
+            hook_attach,
             tmp: None,
             target_finder,
             sym_cache,
             options: opts,
             events_reader: None,
-            wg: Default::default(),
             fds: vec![],
             pids: Default::default(),
             kprobes: vec![],
             perf_events: vec![],
             round_number: 0,
+            procfs: ProcFs::new()?,
+            round_start_ktime: ktime::monotonic_ns(),
+            round_window: RoundWindow::default(),
+            stats_fd: None,
+            symbolization_pool,
+            load_shedding: None,
+            journal,
+            last_round: RoundSummary::default(),
+            targets_generation: 0,
+            counts_drained: 0,
         })
     }
 
     pub fn start(&mut self) -> Result<()> {
-        bump_memlock_rlimit().expect("Failed to increase rlimit");
-        self.bpf.attach().unwrap();
-
-        self.perf_events = attach_perf_events(
-            self.options.sample_rate,
-            self.bpf.progs_mut().do_perf_event(),
-        )
-        .unwrap();
-        self.wg.add(4);
+        bump_memlock_rlimit()?;
+        let (res, log) = capture_libbpf_log(|| self.attach());
+        if let Err(err) = res {
+            let failure = LoadFailure::new("attach", self.hook_attach, err.to_string(), log);
+            diagnostics::record(failure.clone());
+            return Err(Error::SessionError(failure.to_string()));
+        }
+        self.stats_fd = enable_bpf_stats();
+        self.round_start_ktime = ktime::monotonic_ns();
 
         self.started = true;
+        self.sync_targets();
         //self.read_events();
         Ok(())
     }
 
-    fn stop_locked(&mut self) {
-        self.wg.done();
+    fn attach(&mut self) -> Result<()> {
+        self.bpf.attach()
+            .map_err(|e| Error::SessionError(format!("attach skeleton: {}", e)))?;
+        // the tracepoint hooks are attached with the skeleton, only the syscall kprobes need a target
+        if self.hook_attach == HookAttach::Kprobe {
+            self.kprobes = vec![
+                attach_syscall_kprobe(self.bpf.progs_mut().execve(), "execve")?,
+                attach_syscall_kprobe(self.bpf.progs_mut().execveat(), "execveat")?,
+            ];
+        }
+        self.perf_events = attach_perf_events(
+            self.perf_event_config(),
+            self.bpf.progs_mut().do_perf_event(),
+        )?;
+        Ok(())
+    }
+
+    // ring_options are the options the reader of the events map opens its rings with
+    pub fn ring_options(&self) -> PerfBufferOptions {
+        self.options.ring_options
     }
 
-    fn stop(&mut self) {
-        self.stop_locked();
-        self.wg.done()
+    // stop detaches the programs and closes the perf events, the maps and the pid state are kept, so
+    // the session can be started again. The pid events reader owns its rings, its owner stops it.
+    pub fn stop(&mut self) {
+        if !self.started {
+            return;
+        }
+        self.perf_events.clear();
+        self.kprobes.clear();
+        self.bpf.links = Default::default();
+        self.stats_fd = None;
+        self.started = false;
     }
 
-    fn update(&mut self, options: SessionOptions) -> Result<(), String> {
-        self.options = options;
-        Ok(())
+    pub fn started(&self) -> bool {
+        self.started
     }
 
-    pub fn update_targets(&mut self, args: &TargetsOptions) {
+    // update applies the options of a reload to the running session. The sampling, the hooks, the
+    // rings, the journal and the symbolization pool are set up once, they keep their options until
+    // the session is created again.
+    pub fn update(&mut self, options: SessionOptions) {
+        self.sym_cache.lock().unwrap().update_options(options.cache_options);
+        let current = &self.options;
+        self.options = SessionOptions {
+            sample_rate: current.sample_rate,
+            sample_period: current.sample_period,
+            precise_ip: current.precise_ip,
+            hook_attach: current.hook_attach,
+            ring_options: current.ring_options,
+            journal: current.journal.clone(),
+            symbolization_threads: current.symbolization_threads,
+            metrics: current.metrics.clone(),
+            ..options
+        };
+    }
+
+    // sync_targets applies the targets update of the target finder made since the last call. Pids
+    // without a target so far are profiled when they got one.
+    fn sync_targets(&mut self) {
+        let generation = self.target_finder.generation();
+        if generation == self.targets_generation {
+            return;
+        }
+        self.targets_generation = generation;
         let mut targets = Vec::new();
         {
-            let mut target_finder = self.target_finder.lock().unwrap();
-            target_finder.update(args);
+            // kernel threads are only sampled when they can end up in the kernel target
+            self.bpf.bss_mut().collect_kthreads = (!self.target_finder.targets_only()) as u8;
 
             let pids = self.pids.lock().unwrap();
             for p in pids.unknown.iter() {
                 let pp = p.0;
                 let pid = pp.clone();
-                let target = target_finder.find_target(&pid);
+                let target = self.target_finder.find_target(&pid);
                 if let Some(target) = target {
                     targets.push((target.clone(), pid));
                 }
             }
         }
-        targets.iter_mut().for_each(|(t, p)| {
-            self.start_profiling_locked(&p, t);
-            let mut pids = self.pids.lock().unwrap();
+        if self.started {
+            for (t, p) in &targets {
+                self.journal_start(*p, t);
+            }
+            let configs: Vec<(u32, ProcInfoLite)> = targets
+                .iter()
+                .map(|(t, p)| (*p, self.select_profiling_type(*p, t)))
+                .collect();
+            self.set_pid_configs(configs);
+        }
+        let mut pids = self.pids.lock().unwrap();
+        for (_, p) in targets.iter() {
             pids.unknown.remove(p);
-        });
+        }
     }
 
     fn start_profiling_locked(&mut self, pid: &u32, target: &EbpfTarget) {
         if !self.started {
             return;
         }
+        self.journal_start(*pid, target);
         let typ = self.select_profiling_type(pid.clone(), target);
         // if typ.typ == ProfilingType::Python {
         //     self.try_start_python_profiling(pid, target, typ)
         // }
-        self.set_pid_config(
-            pid.clone(),
-            typ,
-            self.options.collect_user,
-            self.options.collect_kernel,
-        );
-    }
-
-    fn set_pid_config(
-        &mut self,
-        pid: u32,
-        pi: ProcInfoLite,
-        collect_user: bool,
-        collect_kernel: bool,
-    ) {
-        info!("hello");
-        let config = pid_config {
-            profile_type: pi.typ.to_u8().clone(),
-            collect_user: collect_user as u8,
-            collect_kernel: collect_kernel as u8,
-            padding_: 0,
+        self.set_pid_configs(vec![(pid.clone(), typ)]);
+    }
+
+    // set_pid_configs writes the profiling config of the pids to the pids map with a single
+    // batch update, falling back to one update per pid on kernels without batch map ops.
+    fn set_pid_configs(&mut self, configs: Vec<(u32, ProcInfoLite)>) {
+        if configs.is_empty() {
+            return;
+        }
+        let mut keys = Vec::with_capacity(configs.len());
+        let mut values = Vec::with_capacity(configs.len());
+        {
+            let mut pids = self.pids.lock().unwrap();
+            for (pid, mut pi) in configs {
+                // pyperf skips pids without a config, those are profiled with frame pointers instead
+                if let (Some(pyperf), Some(python)) = (&self.pyperf, &pi.python) {
+                    let data = PyPidData::new(python.version, python.offsets, python.libc);
+                    if let Err(err) = pyperf.set_pid(pid, &data) {
+                        warn!("{}", err);
+                        pi.typ = ProfilingType::FramePointers;
+                        pi.python = None;
+                    }
+                }
+                keys.push(pid);
+                values.push(self.pid_config(&pi));
+                pids.all.insert(pid, pi);
+            }
+        }
+        self.write_pid_configs(keys, values);
+    }
+
+    // journal_start records the association of the pid in the journal. The kernel target holds every
+    // untargeted pid of the node, those are left out.
+    fn journal_start(&mut self, pid: u32, target: &EbpfTarget) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
         };
-        let mut pids = self.pids.lock().unwrap();
-        pids.all.insert(pid, pi);
+        if target.is_kernel_only() {
+            return;
+        }
+        if let Err(err) = journal.start(pid, target) {
+            warn!("pid journal: {}", err);
+        }
+    }
 
-        if let Err(err) = self.bpf.maps().pids().update(
-            &pid.to_ne_bytes(),
-            any_as_u8_slice(&config),
-            MapFlags::ANY,
-        ) {
-            error!("updating pids map err: {:?}", err);
+    // pid_config is the config of the pid in the pids map, thinned out while shedding load
+    fn pid_config(&self, pi: &ProcInfoLite) -> pid_config {
+        let mut typ = &pi.typ;
+        let mut sample_every = pi.sample_every;
+        if let Some(shed) = &self.load_shedding {
+            sample_every = sample_every.max(1) * self.shed_sample_every(pi.sample_every);
+            if shed.skip_python && matches!(pi.typ, ProfilingType::Python) {
+                typ = &ProfilingType::FramePointers;
+            }
+        }
+        pid_config {
+            profile_type: typ.to_u8(),
+            collect_user: (self.options.collect_user && !pi.kernel_only) as u8,
+            collect_kernel: self.options.collect_kernel as u8,
+            sample_every,
+        }
+    }
+
+    // set_load_shedding thins out the samples of every pid and skips the optional unwinders while
+    // the node is under cpu pressure, None restores the configured profiling. The samples of a
+    // round are scaled by the shedding in effect when it is collected, so it should be changed
+    // right after a collection.
+    pub fn set_load_shedding(&mut self, shed: Option<LoadSheddingOptions>) {
+        if self.load_shedding == shed {
+            return;
+        }
+        self.load_shedding = shed;
+        if !self.started {
+            return;
         }
+        let (keys, values) = {
+            let pids = self.pids.lock().unwrap();
+            pids.all.iter().map(|(pid, pi)| (*pid, self.pid_config(pi))).unzip()
+        };
+        self.write_pid_configs(keys, values);
     }
 
-    fn select_profiling_type(&self, pid: u32, _target: &EbpfTarget) -> ProcInfoLite {
-        if let Ok(exe_path) = fs::read_link(format!("/proc/{}/exe", pid)) {
-            if let Ok(comm) = fs::read_to_string(format!("/proc/{}/comm", pid)) {
-                let comm = comm.trim_end_matches('\n').to_string();
-                let exe = Path::new(&exe_path)
-                    .file_name()
-                    .unwrap_or_default()
-                    .to_string_lossy();
+    // shed_sample_every is the factor the samples of a pid keeping one in sample_every samples for its
+    // target's sample rate are thinned out by on top, 1 when not shedding. It is capped so the product
+    // fits the u8 of the pids map, the samples are scaled back by exactly the factor that was applied.
+    pub fn shed_sample_every(&self, sample_every: u8) -> u8 {
+        self.load_shedding.map_or(1, |shed| shed.sample_every.max(1).min(u8::MAX / sample_every.max(1)))
+    }
 
-                info!("exe: {:?}, pid: {}", exe_path, pid);
+    fn write_pid_configs(&mut self, keys: Vec<u32>, values: Vec<pid_config>) {
+        if keys.is_empty() {
+            return;
+        }
+        let maps = self.bpf.maps();
+        let m = maps.pids();
+        let mut count = keys.len() as u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_batch(
+                m.as_fd().as_raw_fd(),
+                keys.as_ptr() as *const c_void,
+                values.as_ptr() as *const c_void,
+                &mut count,
+                std::ptr::null(),
+            )
+        };
+        if ret == 0 {
+            debug!("updated {} pids with a batch update", count);
+            return;
+        }
+        debug!("pids map batch update failed ({}), updating one by one", ret);
+        for (pid, config) in keys.iter().zip(values.iter()) {
+            if let Err(err) = m.update(&pid.to_ne_bytes(), as_bytes(config), MapFlags::ANY) {
+                error!("updating pids map err: {:?}", err);
+            }
+        }
+    }
 
-                return if self.options.python_enabled
-                    && (exe.starts_with("python") || exe == "uwsgi")
-                {
-                    ProcInfoLite {
+    fn select_profiling_type(&mut self, pid: u32, target: &EbpfTarget) -> ProcInfoLite {
+        let sample_every = target.sample_every(self.options.sample_rate);
+        if target.is_kernel_only() {
+            // kernel threads have no exe, the comm is all there is
+            let comm = fs::read_to_string(format!("/proc/{}/comm", pid))
+                .map(|comm| comm.trim().to_string())
+                .unwrap_or_default();
+            return ProcInfoLite {
+                pid,
+                comm,
+                typ: ProfilingType::FramePointers,
+                python: None,
+                kernel_only: true,
+                sample_every,
+                java: false,
+            };
+        }
+        if let Some(info) = self.procfs.info(pid) {
+            let comm = info.comm;
+            let exe = info.exe;
+
+            info!("exe: {:?}, pid: {}", exe, pid);
+
+            if self.options.python_enabled
+                && self.pyperf.is_some()
+                && (exe.starts_with("python") || exe == "uwsgi")
+            {
+                if let Some(python) = python_proc_info(pid) {
+                    return ProcInfoLite {
                         pid,
                         comm,
                         typ: ProfilingType::Python,
-                    }
-                } else {
-                    ProcInfoLite {
-                        pid,
-                        comm,
-                        typ: ProfilingType::FramePointers,
-                    }
-                };
+                        python: Some(python),
+                        kernel_only: false,
+                        sample_every,
+                        java: false,
+                    };
+                }
             }
+            return if self.options.java_enabled && (exe == "java" || has_libjvm_mapping(pid)) {
+                // the JVM has to run with -XX:+PreserveFramePointer and write a perf map, through jcmd
+                // Compiler.perfmap or perf-map-agent, for its JIT compiled frames to be unwound and named
+                ProcInfoLite {
+                    pid,
+                    comm,
+                    typ: ProfilingType::FramePointers,
+                    python: None,
+                    kernel_only: false,
+                    sample_every,
+                    java: true,
+                }
+            } else {
+                ProcInfoLite {
+                    pid,
+                    comm,
+                    typ: ProfilingType::FramePointers,
+                    python: None,
+                    kernel_only: false,
+                    sample_every,
+                    java: false,
+                }
+            };
         }
 
         error!("Failed to read proc information for pid: {}", pid);
@@ -283,6 +584,10 @@ impl Session<'_> {
             pid,
             comm: String::new(),
             typ: ProfilingType::TypeError,
+            python: None,
+            kernel_only: false,
+            sample_every,
+            java: false,
         }
     }
 
@@ -297,8 +602,7 @@ impl Session<'_> {
         }
 
         let target = {
-            let target_finder = self.target_finder.lock().unwrap();
-            target_finder.find_target(&pid)
+            self.target_finder.find_target(&pid)
         };
 
         if target.is_none() {
@@ -322,7 +626,16 @@ impl Session<'_> {
         return Ok(());
     }
 
+    // process_maps_changed handles the mmap events of profiled pids mapping code
+    pub fn process_maps_changed(&mut self, pid: u32) -> Result<()> {
+        self.sym_cache.lock().unwrap().maps_changed(pid);
+        Ok(())
+    }
+
     pub fn process_pid_exec_requests(&mut self, pid: u32) -> Result<()> {
+        // the exe and comm cached for the pid belong to the previous image
+        self.procfs.forget(pid);
+        self.sym_cache.lock().unwrap().maps_changed(pid);
         let already_dead = {
             let pids = self.pids.lock().unwrap();
             pids.dead.contains_key(&pid)
@@ -333,47 +646,69 @@ impl Session<'_> {
         }
 
         let target = {
-            let target_finder = self.target_finder.lock().unwrap();
-            target_finder.find_target(&pid)
+            self.target_finder.find_target(&pid)
         };
 
         if target.is_none() {
             self.save_unknown_pid_locked(&pid);
         } else {
             debug!("pid exec request: pid={}, target={:?}", pid, target);
-            self.start_profiling_locked(&pid, &target.unwrap());
+            let target = target.unwrap();
+            // only user stacks of targets are symbolized
+            if !target.is_kernel_only() {
+                self.sym_cache.lock().unwrap().index_executable(pid);
+            }
+            self.start_profiling_locked(&pid, &target);
         }
         Ok(())
     }
 
     fn get_counts_map_values(&mut self) -> Result<(Vec<sample_key>, Vec<u32>, bool)> {
-        let maps = &self.bpf.maps();
-        let m = maps.counts();
+        let maps = self.bpf.maps();
+        // entries are deleted while iterating, so there is nothing left for clear_counts_map
+        let (result_keys, result_values): (Vec<sample_key>, Vec<u32>) =
+            drain::<sample_key, u32>(maps.counts())?.into_iter().unzip();
+        debug!("drained {} entries of the counts map", result_keys.len());
+        self.counts_drained += result_keys.len() as u64;
+        Ok((result_keys, result_values, true))
+    }
+
+    // counts_fill_ratio is how full the counts map is now, from the entries the bpf program added and
+    // the entries drained since. It is cheap enough to check between rounds.
+    pub fn counts_fill_ratio(&self) -> f64 {
+        let inserted = unsafe { std::ptr::read_volatile(&self.bpf.bss().counts_inserted) };
+        let max_entries = match self.bpf.maps().counts().info() {
+            Ok(info) if info.info.max_entries > 0 => info.info.max_entries,
+            _ => return 0.0,
+        };
+        inserted.saturating_sub(self.counts_drained) as f64 / max_entries as f64
+    }
 
-        let map_size = m.info().unwrap().info.max_entries as usize;
-
-        unsafe {
-            let mut result_keys: Vec<sample_key> = Vec::with_capacity(map_size);
-            let mut result_values: Vec<u32> = Vec::with_capacity(map_size);
-
-            while let Some(bytes) = m.keys().next() {
-                let key = byte_to_value::<sample_key>(&bytes).unwrap();
-                let mut value: u32 = 0;
-                bpf_map_lookup_elem(
-                    m.as_fd().as_raw_fd(),
-                    key as *const _ as *const c_void,
-                    &mut value as *mut _ as *mut c_void,
-                );
-                bpf_map_delete_elem(
-                    m.as_fd().as_raw_fd(),
-                    key as *const _ as *const c_void
-                );
-                result_keys.push(key.clone());
-                result_values.push(value.clone());
+    // collect_counts_overflow reports the samples the bpf program dropped since the last round because
+    // the counts map was full, under the target of their pid when it has one
+    fn collect_counts_overflow(&mut self, summary: &mut RoundSummary) {
+        let maps = self.bpf.maps();
+        let overflow = match drain::<u32, u64>(maps.counts_overflow()) {
+            Ok(overflow) => overflow,
+            Err(err) => {
+                warn!("draining the counts overflow map: {}", err);
+                return;
             }
-            println!("getCountsMapValues iter count: {}", result_keys.len());
-            Ok((result_keys, result_values, true))
+        };
+        let mut dropped = 0;
+        for (pid, samples) in overflow {
+            let service_name = self.target_finder.find_target(&pid)
+                .map(|target| target.service_name().to_string())
+                .unwrap_or_default();
+            self.options.metrics.dropped_samples
+                .with_label_values(&[&service_name, "counts_map_full"])
+                .inc_by(samples as f64);
+            dropped += samples;
+        }
+        if dropped > 0 {
+            warn!("the counts map was full, {} samples were dropped since the last round", dropped);
         }
+        summary.dropped_samples += dropped;
     }
 
     fn clear_counts_map(&mut self, keys: &[sample_key], batch: bool) -> Result<()> {
@@ -393,46 +728,28 @@ impl Session<'_> {
         };
         if ret < 0 {
             // Error code is returned negative, flip to positive to match errno
-            Err(OSError((-ret).to_string()))
+            Err(Error::MapError { map: "counts".to_string(), op: "delete".to_string(), errno: -ret })
         } else {
-            println!("clearCountsMap count: {}", keys.len());
+            debug!("cleared {} entries of the counts map", keys.len());
             Ok(())
         }
     }
 
     fn clear_stacks_map(&mut self, known_keys: &HashMap<u32, bool>) -> Result<()> {
-        let maps = &self.bpf.maps();
-        let m = maps.stacks();
-        let mut cnt = 0;
-        let mut errs = 0;
+        let maps = self.bpf.maps();
+        let m: &dyn BpfMap = maps.stacks();
 
         if self.round_number % 10 == 0 {
             // do a full reset once in a while
-            while let Some(k) = m.keys().next() {
-                if let Err(_e) = m.delete(k.as_slice()) {
-                    errs += 1;
-                } else {
-                    cnt += 1;
-                }
-            }
-            println!(
-                "clearStacksMap deleted all stacks count: {} unsuccessful: {}",
-                cnt, errs
-            );
+            let keys = m.keys();
+            let (cnt, errs) = delete_keys(m, keys.iter().map(Vec::as_slice));
+            debug!("cleared all {} stacks of the stacks map, {} failed", cnt, errs);
             return Ok(());
         }
 
-        for stack_id in known_keys.keys() {
-            if let Err(_e) = m.delete(&stack_id.to_le_bytes()) {
-                errs += 1;
-            } else {
-                cnt += 1;
-            }
-        }
-        println!(
-            "clearStacksMap deleted known stacks count: {} unsuccessful: {}",
-            cnt, errs
-        );
+        let keys: Vec<[u8; 4]> = known_keys.keys().map(|stack_id| stack_id.to_le_bytes()).collect();
+        let (cnt, errs) = delete_keys(m, keys.iter().map(|k| k.as_slice()));
+        debug!("cleared {} known stacks of the stacks map, {} failed", cnt, errs);
         Ok(())
     }
 
@@ -450,11 +767,26 @@ impl Session<'_> {
     where
         F: Fn(ProfileSample),
     {
-        dbg!("collect_regular_profile");
-
-        let mut sb = StackBuilder::new();
+        self.sync_targets();
         let mut known_stacks: HashMap<u32, bool> = HashMap::new();
-        let (keys, values, batch) = self.get_counts_map_values().unwrap();
+        let started = Instant::now();
+        let deadline = self.options.round_budget.map(|budget| started + budget);
+        let metrics = self.options.metrics.clone();
+        let (keys, values, batch) = self.get_counts_map_values().inspect_err(|_| {
+            metrics.map_errors.with_label_values(&["counts", "drain"]).inc();
+        })?;
+        self.end_round_window();
+        metrics.stage_duration.with_label_values(&["map_drain"]).observe(started.elapsed().as_secs_f64());
+        metrics.samples_collected.inc_by(values.iter().map(|v| *v as f64).sum());
+        let mut summary = RoundSummary {
+            round: self.round_number,
+            samples: values.iter().map(|v| *v as u64).sum(),
+            ..Default::default()
+        };
+
+        // read the stacks and group the samples by pid, resolving is done per group below
+        let started = Instant::now();
+        let mut groups: HashMap<u32, PidSamples> = HashMap::new();
         for (i, ck) in keys.iter().enumerate() {
             let value = values[i];
             if ck.user_stack >= 0 {
@@ -463,67 +795,155 @@ impl Session<'_> {
             if ck.kern_stack >= 0 {
                 known_stacks.insert(ck.kern_stack as u32, true);
             }
-            let target_finder = self.target_finder.lock().unwrap();
-            if let Some(labels) = target_finder.find_target(&ck.pid) {
-                let (mut stats, proc) = {
-                    let mut pids = self.pids.lock().unwrap();
-                    if pids.dead.contains_key(&ck.pid) {
-                        debug!("pid {} is dead", &ck.pid);
-                        continue;
-                    }
-                    let stats = StackResolveStats::default();
+            let target = {
+                self.target_finder.find_target(&ck.pid)
+            };
+            let Some(target) = target else {
+                continue;
+            };
+            let group = match groups.entry(ck.pid) {
+                Entry::Occupied(group) => group.into_mut(),
+                Entry::Vacant(group) => {
                     let proc = {
-                        // if self.tmp.is_none() {
-                        //     self.tmp = Some(Arc::new(Mutex::new(PerfSymbolTable::new(ck.pid as i32))));
-                        // }
-                        // self.tmp.clone().unwrap()
-                        let mut sym_cache = self.sym_cache.lock().unwrap();
-                        if sym_cache.get_proc_table(ck.pid).is_none() {
-                            pids.dead.insert(ck.pid, ());
+                        let mut pids = self.pids.lock().unwrap();
+                        if pids.dead.contains_key(&ck.pid) {
+                            None
+                        } else {
+                            let mut sym_cache = self.sym_cache.lock().unwrap();
+                            let proc = sym_cache.get_proc_table(ck.pid);
+                            if proc.is_none() {
+                                pids.dead.insert(ck.pid, ());
+                            }
+                            proc
                         }
-                        sym_cache.get_proc_table(ck.pid).unwrap().clone()
                     };
-                    (stats, proc)
-                };
-                {
-                    let mut a = proc.lock().unwrap();
-                    a.refresh_resource();
-                }
-                let u_stack = self.get_stack(ck.user_stack);
-                let k_stack = self.get_stack(ck.kern_stack);
-                sb.reset();
-                sb.append(self.comm(ck.pid));
-
-                if self.options.collect_user && u_stack.is_some() {
-                    self.walk_stack(&mut sb, &u_stack.unwrap(), proc, &mut stats);
-                }
-                if self.options.collect_kernel && k_stack.is_some() {
-                    let a = {
-                        let mut sym_cache = self.sym_cache.lock().unwrap();
-                        sym_cache.get_kallsyms().clone()
+                    if let Some(proc) = &proc {
+                        if self.pids.lock().unwrap().all.get(&ck.pid).is_some_and(|p| p.java) {
+                            proc.lock().unwrap().enable_perf_map();
+                        }
+                    }
+                    let Some(proc) = proc else {
+                        debug!("pid {} is dead", &ck.pid);
+                        summary.dropped_samples += value as u64;
+                        metrics.dropped_samples
+                            .with_label_values(&[&target.service_name(), "dead_pid"])
+                            .inc_by(value as f64);
+                        continue;
                     };
-                    self.walk_stack(&mut sb, &k_stack.unwrap(), a, &mut stats);
+                    group.insert(PidSamples {
+                        pid: ck.pid,
+                        comm: self.comm(ck.pid),
+                        target_key: target.clone().labels().0,
+                        target,
+                        proc,
+                        samples: Vec::new(),
+                    })
                 }
-                if sb.stack.len() > 1 {
-                    sb.stack.reverse();
+            };
+            let collect_user = self.options.collect_user && !group.target.is_kernel_only();
+            let user_stack = if collect_user { self.get_stack(ck.user_stack) } else { None };
+            let kern_stack = if self.options.collect_kernel { self.get_stack(ck.kern_stack) } else { None };
+            group.samples.push(PendingSample {
+                user_stack,
+                kern_stack,
+                value,
+            });
+        }
+        metrics.stage_duration.with_label_values(&["stack_walk"]).observe(started.elapsed().as_secs_f64());
+
+        // every pid is resolved against its own proc table, so groups don't contend on a shared lock
+        let started = Instant::now();
+        let kallsyms = if self.options.collect_kernel {
+            Some(self.sym_cache.lock().unwrap().get_kallsyms())
+        } else {
+            None
+        };
+        let frame_options = FrameOptions {
+            unknown_symbol_module_offset: self.options.unknown_symbol_module_offset,
+            unknown_symbol_address: self.options.unknown_symbol_address,
+            unknown_symbol_symbolizable: self.options.unknown_symbol_symbolizable,
+            deadline,
+        };
+        summary.pids = groups.len();
+        summary.targets = groups.values().map(|g| g.target_key).collect::<HashSet<_>>().len();
+        let queue = symbolization_queue(groups.into_values().collect());
+        let slices: HashMap<u64, TargetSlice> = match self.options.target_time_slice {
+            Some(limit) => queue.iter().map(|g| (g.target_key, TargetSlice::new(limit))).collect(),
+            None => HashMap::new(),
+        };
+        let resolve = || -> Vec<(PidSamples, Vec<(Vec<String>, StackResolveStats)>)> {
+            // the workers take the groups off the channel in queue order whenever they get free
+            let (sender, receiver) = std::sync::mpsc::channel();
+            for group in queue {
+                sender.send((group, Instant::now())).unwrap();
+            }
+            drop(sender);
+            receiver.into_iter().par_bridge()
+                .map(|(group, queued)| {
+                    metrics.symbolization_wait.observe(queued.elapsed().as_secs_f64());
+                    let slice = slices.get(&group.target_key);
+                    let stacks = resolve_pid_samples(&group, kallsyms.as_deref(), frame_options, slice);
+                    (group, stacks)
+                })
+                .collect()
+        };
+        let resolved = match &self.symbolization_pool {
+            Some(pool) => pool.install(resolve),
+            None => resolve(),
+        };
+        metrics.stage_duration.with_label_values(&["symbolization"]).observe(started.elapsed().as_secs_f64());
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            warn!("collection round exceeded its symbolization budget of {:?}", self.options.round_budget.unwrap());
+            metrics.rounds_over_budget.inc();
+        }
+
+        for (group, stacks) in resolved {
+            // while shedding load every kept sample stands for the ones thinned out
+            let shed_every = self.shed_sample_every(group.target.sample_every(self.options.sample_rate)) as u64;
+            for (sample, (stack, stats)) in group.samples.iter().zip(stacks) {
+                let depth = stack.len();
+                summary.frames += (stats.known + stats.unknown_symbols + stats.unknown_modules) as u64;
+                summary.unknown_frames += (stats.unknown_symbols + stats.unknown_modules) as u64;
+                if depth > 1 {
                     cb(ProfileSample {
-                        target: &labels,
-                        pid: ck.pid,
+                        target: &group.target,
+                        pid: group.pid,
                         sample_type: SampleType::Cpu,
                         aggregation: false,
-                        stack: sb.stack.clone(),
-                        value: value as u64,
+                        stack,
+                        mode: StackMode::from_stacks(sample.user_stack.is_some(), sample.kern_stack.is_some()),
+                        value: sample.value as u64 * shed_every,
                         value2: 0,
                     });
-                    self.collect_metrics(&labels, &stats, &sb);
+                    self.collect_metrics(&group.target, &stats, depth);
+                } else {
+                    summary.dropped_samples += sample.value as u64;
+                    metrics.dropped_samples
+                        .with_label_values(&[&group.target.service_name(), "empty_stack"])
+                        .inc_by(sample.value as f64);
                 }
             }
         }
-        self.clear_counts_map(&keys, batch).unwrap();
-        self.clear_stacks_map(&known_stacks).unwrap();
+        self.update_map_fill_ratio(keys.len(), known_stacks.len());
+        self.collect_counts_overflow(&mut summary);
+        // the entries left behind are drained with the next round
+        if let Err(err) = self.clear_counts_map(&keys, batch) {
+            warn!("clearing the counts map: {}", err);
+            metrics.map_errors.with_label_values(&["counts", "delete"]).inc();
+        }
+        if let Err(err) = self.clear_stacks_map(&known_stacks) {
+            warn!("clearing the stacks map: {}", err);
+            metrics.map_errors.with_label_values(&["stacks", "delete"]).inc();
+        }
+        self.last_round = summary;
         Ok(())
     }
 
+    // last_round is the summary of the last collection round
+    pub fn last_round(&self) -> RoundSummary {
+        self.last_round
+    }
+
     fn comm(&self, pid: u32) -> String {
         let pids = self.pids.lock().unwrap();
         if let Some(proc_info) = pids.all.get(&pid) {
@@ -534,74 +954,21 @@ impl Session<'_> {
         "pid_unknown".to_string()
     }
 
-    fn walk_stack(
-        &self,
-        sb: &mut StackBuilder,
-        stack: &[u8],
-        resolver: Arc<Mutex<dyn SymbolTable>>,
-        stats: &mut StackResolveStats,
-    ) {
-        if stack.is_empty() {
-            info!("stack is empty");
-            return;
-        }
-        let mut stack_frames = Vec::new();
-        for i in 0..127 {
-            let start = i * 8;
-            let end = start + 8;
-            if end > stack.len() {
-                break;
-            }
-            let instruction_pointer_bytes = &stack[i * 8..(i + 1) * 8];
-            let instruction_pointer = u64::from_le_bytes(instruction_pointer_bytes.try_into().unwrap());
-            if instruction_pointer == 0 {
-                break;
-            }
-
-            let mut r = resolver.lock().unwrap();
-            let name = if let Some(sym) = r.resolve(instruction_pointer) {
-                if !sym.name.is_empty() {
-                    stats.known += 1;
-                    sym.name.clone()
-                } else {
-                    if !sym.module.is_empty() {
-                        if self.options.unknown_symbol_module_offset {
-                            format!("{}+{:x}", sym.module, sym.start)
-                        } else {
-                            sym.module.clone()
-                        }
-                    } else {
-                        if self.options.unknown_symbol_address {
-                            format!("{:x}", instruction_pointer)
-                        } else {
-                            "[unknown]".to_string()
-                        }
-                    }
-                }
-            } else {
-                "[unknown]".to_string()
-            };
-            stack_frames.push(name);
-        }
-        stack_frames.reverse();
-        for s in stack_frames {
-            sb.append(s);
-        }
-    }
-
     fn get_stack(&self, stack_id: i64) -> Option<Vec<u8>> {
         if stack_id < 0 {
             return None;
         }
         let stack_id_u32 = stack_id as u32;
-        self.bpf
-            .maps()
-            .stacks()
-            .lookup(stack_id_u32.to_le_bytes().as_slice(), MapFlags::ANY)
-            .unwrap_or_else(|_| None)
+        let maps = self.bpf.maps();
+        // the stack may have been evicted by a hash collision, the sample goes on without it
+        let stack = BpfMap::lookup(maps.stacks(), stack_id_u32.to_le_bytes().as_slice()).unwrap_or_else(|_| None);
+        if stack.is_none() {
+            self.options.metrics.map_errors.with_label_values(&["stacks", "lookup"]).inc();
+        }
+        stack
     }
 
-    fn collect_metrics(&self, labels: &EbpfTarget, stats: &StackResolveStats, sb: &StackBuilder) {
+    fn collect_metrics(&self, labels: &EbpfTarget, stats: &StackResolveStats, depth: usize) {
         let m = &self.options.metrics.symtab;
         let service_name = labels.service_name();
         m.known_symbols
@@ -614,9 +981,168 @@ impl Session<'_> {
             .with_label_values(&[&service_name])
             .inc_by(stats.unknown_modules as f64);
 
-        if sb.stack.len() > 2 && stats.unknown_symbols + stats.unknown_modules > stats.known {
+        if depth > 2 && stats.unknown_symbols + stats.unknown_modules > stats.known {
             m.unknown_stacks.with_label_values(&[&service_name]).inc();
         }
+        self.options.metrics.stacks_truncated
+            .with_label_values(&[&service_name])
+            .inc_by(stats.truncated as f64);
+        self.options.metrics.address_only_stacks.inc_by(stats.address_only as f64);
+        self.options.metrics.throttled_stacks
+            .with_label_values(&[&service_name])
+            .inc_by(stats.throttled as f64);
+    }
+
+    // update_map_fill_ratio reports how full the counts, stacks and pids maps were when the round was drained.
+    // The stacks map is approximated by the stacks referenced from the counts map.
+    fn update_map_fill_ratio(&self, counts: usize, stacks: usize) {
+        let maps = self.bpf.maps();
+        let pids = self.pids.lock().unwrap().all.len();
+        let fill = [
+            ("counts", counts, maps.counts().info()),
+            ("stacks", stacks, maps.stacks().info()),
+            ("pids", pids, maps.pids().info()),
+        ];
+        for (name, used, info) in fill {
+            if let Ok(info) = info {
+                if info.info.max_entries > 0 {
+                    self.options.metrics.map_fill_ratio
+                        .with_label_values(&[name])
+                        .set(used as f64 / info.info.max_entries as f64);
+                }
+            }
+        }
+    }
+
+    // collect_bpf_stats exports the kernel's run time and run count of each program.
+    // The counters only move while BPF_ENABLE_STATS is held, see enable_bpf_stats.
+    pub(crate) fn collect_bpf_stats(&self) {
+        if self.stats_fd.is_none() {
+            return;
+        }
+        let progs = self.bpf.progs();
+        let m = &self.options.metrics;
+        // the programs of the other hook modes are not loaded and have no fd
+        let mut loaded = vec![progs.do_perf_event()];
+        match self.hook_attach {
+            HookAttach::TpBtf => loaded.extend([progs.tp_btf_exec(), progs.tp_btf_exit()]),
+            HookAttach::RawTracepoint => loaded.extend([progs.raw_tp_exec(), progs.raw_tp_exit()]),
+            _ => loaded.extend([progs.disassociate_ctty(), progs.execve(), progs.execveat()]),
+        }
+        for prog in loaded {
+            let name = prog.name().to_string_lossy();
+            let mut info: libbpf_sys::bpf_prog_info = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libbpf_sys::bpf_prog_info>() as u32;
+            let ret = unsafe {
+                libbpf_sys::bpf_obj_get_info_by_fd(
+                    prog.as_fd().as_raw_fd(),
+                    &mut info as *mut _ as *mut c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                debug!("bpf prog info for {}: {}", name, ret);
+                continue;
+            }
+            m.prog_run_time.with_label_values(&[&name]).set(info.run_time_ns as f64 / 1e9);
+            m.prog_run_count.with_label_values(&[&name]).set(info.run_cnt as f64);
+        }
+    }
+
+    // end_round_window closes the time window of the round once the counts are drained. It ends at the
+    // latest sample, or at the drain when no sample was taken since the previous round.
+    fn end_round_window(&mut self) {
+        let last_sample = unsafe { std::ptr::read_volatile(&self.bpf.bss().last_sample_ktime) };
+        let end = if last_sample > self.round_start_ktime {
+            last_sample
+        } else {
+            ktime::monotonic_ns()
+        };
+        self.round_window = RoundWindow::from_ktime(self.round_start_ktime, end);
+        self.round_start_ktime = end;
+    }
+
+    pub(crate) fn round_window(&self) -> RoundWindow {
+        self.round_window
+    }
+
+    // collect_process_metrics sums the cpu time and resident memory of the targeted pids per service
+    pub(crate) fn collect_process_metrics(&self) {
+        if !self.options.process_metrics {
+            return;
+        }
+        let pids: Vec<u32> = self.pids.lock().unwrap().all.keys().copied().collect();
+        let mut usage: HashMap<String, ProcStat> = HashMap::new();
+        {
+            for pid in pids {
+                let Some(target) = self.target_finder.find_target(&pid) else {
+                    continue;
+                };
+                if target.is_kernel_only() {
+                    continue;
+                }
+                let Some(stat) = self.procfs.stat(pid) else {
+                    continue;
+                };
+                let total = usage.entry(target.service_name().to_string()).or_default();
+                total.cpu_seconds += stat.cpu_seconds;
+                total.rss_bytes += stat.rss_bytes;
+            }
+        }
+        let m = &self.options.metrics.process;
+        // services without live processes disappear instead of keeping their last value
+        m.cpu_seconds.reset();
+        m.resident_memory.reset();
+        for (service_name, stat) in usage {
+            m.cpu_seconds.with_label_values(&[&service_name]).set(stat.cpu_seconds);
+            m.resident_memory.with_label_values(&[&service_name]).set(stat.rss_bytes as f64);
+        }
+    }
+
+    // attach_mode is how the sampling program is attached to the perf events, detached before start
+    pub fn attach_mode(&self) -> &'static str {
+        self.perf_events.first().map_or("detached", |e| e.attach_mode())
+    }
+
+    pub fn hook_attach(&self) -> HookAttach {
+        self.hook_attach
+    }
+
+    // set_sampling stops or restarts the perf events of all cpus. The pid events and the targets
+    // are still processed while sampling is stopped.
+    pub fn set_sampling(&self, enabled: bool) -> Result<()> {
+        for event in &self.perf_events {
+            event.set_enabled(enabled)?;
+        }
+        Ok(())
+    }
+
+    fn perf_event_config(&self) -> PerfEventConfig {
+        let sampling = match self.options.sample_period {
+            Some(period) => Sampling::Period(period),
+            None => Sampling::Frequency(self.options.sample_rate as u64),
+        };
+        PerfEventConfig { sampling, precise_ip: self.options.precise_ip }
+    }
+
+    // next_round advances the round counters of the session and its caches.
+    pub(crate) fn next_round(&mut self) {
+        self.round_number += 1;
+        if let Ok(mut sym_cache) = self.sym_cache.lock() {
+            sym_cache.next_round();
+            if self.round_number % KALLSYMS_REFRESH_ROUNDS == 0 {
+                sym_cache.refresh_kallsyms();
+            }
+        }
+        self.procfs.next_round();
+    }
+
+    pub fn heaviest_elf_tables(&self, limit: usize) -> Vec<ElfTableMemory> {
+        self.sym_cache.lock().unwrap().heaviest_elf_tables(limit)
+    }
+
+    pub(crate) fn metrics(&self) -> Arc<ProfileMetrics> {
+        self.options.metrics.clone()
     }
 
     pub(crate) fn cleanup(&mut self) {
@@ -633,85 +1159,100 @@ impl Session<'_> {
             pids.dead.remove(pid);
             pids.unknown.remove(pid);
             pids.all.remove(pid);
+            self.procfs.forget(*pid);
             sym_cache.remove_dead_pid(pid);
-            let _ = self.bpf.maps().pids().delete(&pid.to_le_bytes());
-
-            if let Ok(mut target_finder) = self.target_finder.lock() {
-                target_finder.remove_dead_pid(pid);
+            let _ = BpfMap::delete(self.bpf.maps().pids(), &pid.to_le_bytes());
+            if let Some(pyperf) = &self.pyperf {
+                pyperf.remove_pid(*pid);
             }
-        }
 
-        let mut unknown_pids_to_remove = HashSet::new();
-        let u = pids.unknown.clone();
-        let keys = u.keys();
-        for pid in keys {
-            let proc_path = format!("/proc/{}", pid);
-            if let Err(err) = fs::metadata(&proc_path) {
-                if !matches!(err.kind(), std::io::ErrorKind::NotFound) {
-                    error!("cleanup stat pid: {} err: {}", pid, err);
-                }
-                unknown_pids_to_remove.insert(pid.clone());
-                pids.all.remove(pid);
-                self.bpf.maps().pids().delete(&pid.to_le_bytes()).unwrap_or(());
+            self.target_finder.remove_dead_pid(pid);
+            // the pids and symbol cache stay locked, the journal is borrowed on its own
+            if let Some(Err(err)) = self.journal.as_mut().map(|journal| journal.end(*pid)) {
+                warn!("pid journal: {}", err);
             }
         }
 
+        let unknown_pids_to_remove = self.procfs.dead(pids.unknown.keys().copied().collect::<Vec<_>>());
         for pid in &unknown_pids_to_remove {
             pids.unknown.remove(pid);
+            pids.all.remove(pid);
+            self.procfs.forget(*pid);
+            BpfMap::delete(self.bpf.maps().pids(), &pid.to_le_bytes()).unwrap_or(());
         }
+        drop(pids);
+        drop(sym_cache);
 
         if self.round_number % 10 == 0 {
             self.check_stale_pids();
         }
     }
 
-    fn check_stale_pids(&self) {
-        let m = &self.bpf.maps();
-        let pids = m.pids();
-        let map_size = pids.info().unwrap().info.max_entries as usize;
-        let nkey = 0u32;
-        let mut keys: Vec<u32> = Vec::with_capacity(map_size);
-        let mut values: Vec<pid_config> = Vec::with_capacity(map_size);
-        let mut count: u32 = 10;
-        unsafe {
-            let n = bpf_map_lookup_batch(
-                pids.as_fd().as_raw_fd(),
-                std::ptr::null_mut(),
-                nkey as *mut _,
-                keys.as_mut_ptr() as *mut c_void,
-                values.as_mut_ptr() as *mut c_void,
-                (&mut count) as *mut u32,
-                &bpf_map_batch_opts {
-                    sz: mem::size_of::<bpf_map_batch_opts>() as size_t,
-                    elem_flags: MapFlags::ANY.bits(),
-                    flags: MapFlags::ANY.bits(),
-                } as *const bpf_map_batch_opts,
-            );
-
-            dbg!("check stale pids count: {}", n);
-            for i in 0..n {
-                if let Err(err) =
-                    fs::metadata(format!("/proc/{}/status", keys[i as usize] as __u32))
-                {
-                    if err.kind() == std::io::ErrorKind::NotFound {
-                        if let Err(_del_err) = pids.delete(&keys[i as usize].to_le_bytes()) {
-                            error!("delete stale pid pid");
-                        } else {
-                            dbg!("stale pid deleted pid");
-                        }
-                    } else {
-                        error!("check stale pids err: {}", err);
-                    }
-                } else {
-                    dbg!("stale pid check : alive");
-                }
-            }
+    // check_stale_pids removes pids map entries of processes that exited without us seeing the event.
+    fn check_stale_pids(&mut self) {
+        let procfs = &mut self.procfs;
+        let dead = delete_stale_pids(self.bpf.maps().pids(), |pids| procfs.dead(pids));
+        debug!("check stale pids dead: {}", dead);
+    }
+}
+
+// delete_stale_pids deletes the entries of the pids map whose pid dead reports, returning how many were dead
+fn delete_stale_pids(m: &dyn BpfMap, dead: impl FnOnce(Vec<u32>) -> Vec<u32>) -> usize {
+    let keys: Vec<u32> = m.keys().iter()
+        .filter_map(|k| k.as_slice().try_into().ok().map(u32::from_le_bytes))
+        .collect();
+    let dead = dead(keys);
+    for pid in &dead {
+        if let Err(err) = m.delete(&pid.to_le_bytes()) {
+            error!("delete stale pid {}: {}", pid, err);
+        }
+    }
+    dead.len()
+}
+
+// python_proc_info detects the python and libc versions of the process and looks their struct offsets
+// up in the embedded databases. Processes of unknown versions are profiled with frame pointers instead.
+fn python_proc_info(pid: u32) -> Option<PythonProcInfo> {
+    let version = match detect_version(pid) {
+        Ok(version) => version,
+        Err(err) => {
+            warn!("python version detection failed for pid {}: {}", pid, err);
+            return None;
+        }
+    };
+    let Some(offsets) = OffsetsDatabase::embedded().find(&version) else {
+        warn!("no python offsets for pid {} version {}", pid, version);
+        return None;
+    };
+    let libc = match libc_config(pid) {
+        Ok(libc) => libc,
+        Err(err) => {
+            warn!("libc detection failed for python pid {}: {}", pid, err);
+            return None;
         }
+    };
+    info!("pid {} runs python {}, libc {:?}", pid, version, libc);
+    Some(PythonProcInfo { version, offsets, libc })
+}
+
+// has_libjvm_mapping reports whether the process has libjvm.so mapped, which catches JVMs
+// started through a launcher or an embedding binary whose exe isn't named java.
+fn has_libjvm_mapping(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/maps", pid)) {
+        Ok(maps) => maps.lines().any(|line| line.ends_with("/libjvm.so")),
+        Err(_) => false,
     }
 }
 
-fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    unsafe { return core::slice::from_raw_parts((p as *const T) as *const u8, mem::size_of::<T>()) }
+// enable_bpf_stats turns on run time accounting for bpf programs. It stays on until the returned fd is closed.
+// Requires linux 5.8+ and CAP_SYS_ADMIN, otherwise program stats are not exported.
+fn enable_bpf_stats() -> Option<OwnedFd> {
+    let fd = unsafe { libbpf_sys::bpf_enable_stats(libbpf_sys::BPF_STATS_RUN_TIME) };
+    if fd < 0 {
+        warn!("enabling bpf stats failed: {}", std::io::Error::from_raw_os_error(-fd));
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd) })
 }
 
 // https://github.com/libbpf/libbpf-rs/blob/ed31040a86388b699524bdfa25893fb2e85a9eb2/examples/runqslower/src/main.rs#L41
@@ -721,19 +1262,303 @@ fn bump_memlock_rlimit() -> Result<()> {
         rlim_max: 128 << 20,
     };
     if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlimit) } != 0 {
-        return Err(InvalidData("Failed to increase rlimit".to_string()));
+        return Err(Error::last_os_error("setrlimit memlock"));
+    }
+    Ok(())
+}
+
+// load_profile_skel loads the profile programs with the lifecycle hooks of the first mode of the
+// candidates the kernel accepts. Only the programs of that mode are loaded, the others stay in the object.
+// A mode the kernel refuses is recorded with its verifier log, see diagnostics.
+fn load_profile_skel<'a>(hook_attach: HookAttach) -> Result<(ProfileSkel<'a>, HookAttach)> {
+    let mut last_failure = None;
+    for mode in hook_attach.candidates() {
+        let (res, log) = capture_libbpf_log(|| open_profile_skel(mode, 0)?.load()
+            .map_err(|e| Error::SessionError(format!("load bpf programs: {}", e))));
+        match res {
+            Ok(bpf) => {
+                info!("loaded process lifecycle hooks as {}", mode.as_str());
+                return Ok((bpf, mode));
+            }
+            Err(err) => {
+                warn!("loading process lifecycle hooks as {} failed: {}", mode.as_str(), err);
+                let log = verbose_load_log(mode).unwrap_or(log);
+                let failure = LoadFailure::new("load", mode, err.to_string(), log);
+                diagnostics::record(failure.clone());
+                last_failure = Some(failure);
+            }
+        }
+    }
+    Err(Error::SessionError(last_failure.unwrap().to_string()))
+}
+
+// verbose_load_log loads the programs of the mode again with the verifier logging every instruction
+// and the register states, which libbpf's own retry after a failure doesn't. None when the mode
+// loaded this time.
+fn verbose_load_log(mode: HookAttach) -> Option<String> {
+    let (res, log) = capture_libbpf_log(|| open_profile_skel(mode, 2)?.load()
+        .map_err(|e| Error::SessionError(format!("load bpf programs: {}", e))));
+    res.is_err().then_some(log)
+}
+
+// open_profile_skel opens the profile object with the hooks of the mode. log_level is the verifier
+// log level of the programs, 0 leaves it to libbpf.
+fn open_profile_skel<'a>(mode: HookAttach, log_level: u32) -> Result<OpenProfileSkel<'a>> {
+    let mut open_skel = ProfileSkelBuilder::default().open()
+        .map_err(|e| Error::SessionError(format!("open bpf object: {}", e)))?;
+    set_hook_autoload(&mut open_skel, mode)
+        .map_err(|e| Error::SessionError(format!("select {} hooks: {}", mode.as_str(), e)))?;
+    if log_level > 0 {
+        for prog in open_skel.obj.progs_iter_mut() {
+            prog.set_log_level(log_level)
+                .map_err(|e| Error::SessionError(format!("set log level of {}: {}", prog.name().to_string_lossy(), e)))?;
+        }
     }
+    Ok(open_skel)
+}
+
+fn set_hook_autoload(open_skel: &mut OpenProfileSkel, mode: HookAttach) -> libbpf_rs::Result<()> {
+    let kprobe = mode == HookAttach::Kprobe;
+    let raw_tp = mode == HookAttach::RawTracepoint;
+    let tp_btf = mode == HookAttach::TpBtf;
+    let mut progs = open_skel.progs_mut();
+    progs.disassociate_ctty().set_autoload(kprobe)?;
+    progs.execve().set_autoload(kprobe)?;
+    progs.execveat().set_autoload(kprobe)?;
+    progs.raw_tp_exec().set_autoload(raw_tp)?;
+    progs.raw_tp_exit().set_autoload(raw_tp)?;
+    progs.tp_btf_exec().set_autoload(tp_btf)?;
+    progs.tp_btf_exit().set_autoload(tp_btf)?;
     Ok(())
 }
 
+// attach_syscall_kprobe attaches the kprobe to the entry of the syscall. The exec programs have no target
+// in their section name because the syscall function differs between archs and kernel versions.
+fn attach_syscall_kprobe(prog: &mut Program, syscall: &str) -> Result<Link> {
+    let func = syscall_symbol(syscall)?;
+    debug!("attaching kprobe {} to {}", prog.name().to_string_lossy(), func);
+    prog.attach_kprobe(false, &func)
+        .map_err(|e| Error::SessionError(format!("attach kprobe {}: {}", func, e)))
+}
+
 // https://github.com/torvalds/linux/blob/928a87efa42302a23bb9554be081a28058495f22/samples/bpf/trace_event_user.c#L152
-fn attach_perf_events(sample_rate: u32, prog: &mut Program) -> Result<Vec<PerfEvent>> {
+fn attach_perf_events(config: PerfEventConfig, prog: &mut Program) -> Result<Vec<PerfEvent>> {
     let nprocs = libbpf_rs::num_possible_cpus().unwrap();
-    Ok((0..nprocs)
-        .map(|cpu| PerfEvent::new(cpu as i32, sample_rate as u64, prog).unwrap())
-        .collect())
+    (0..nprocs)
+        .map(|cpu| PerfEvent::new(cpu as i32, config, prog))
+        .collect()
+}
+
+// PidSamples are the samples of one pid collected in a round, resolved together against the pid's proc table.
+struct PidSamples {
+    pid: u32,
+    comm: String,
+    // target_key is the label fingerprint of the target, the groups of a target share its time slice
+    target_key: u64,
+    target: EbpfTarget,
+    proc: Arc<Mutex<ProcTable>>,
+    samples: Vec<PendingSample>,
+}
+
+struct PendingSample {
+    user_stack: Option<Vec<u8>>,
+    kern_stack: Option<Vec<u8>>,
+    value: u32,
+}
+
+#[derive(Clone, Copy)]
+struct FrameOptions {
+    unknown_symbol_module_offset: bool,
+    unknown_symbol_address: bool,
+    unknown_symbol_symbolizable: bool,
+    deadline: Option<Instant>,
+}
+
+impl FrameOptions {
+    fn past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+}
+
+// symbolization_queue orders the groups for the symbolization workers, round robin over the targets
+// starting with the target of the fewest samples, and the pids of a target smallest first. A target
+// with many or huge pids then can't hold every worker while the other targets wait.
+fn symbolization_queue(groups: Vec<PidSamples>) -> Vec<PidSamples> {
+    let mut by_target: HashMap<u64, Vec<PidSamples>> = HashMap::new();
+    let len = groups.len();
+    for group in groups {
+        by_target.entry(group.target_key).or_default().push(group);
+    }
+    let mut targets: Vec<Vec<PidSamples>> = by_target.into_values().collect();
+    for groups in targets.iter_mut() {
+        // largest first, the smallest is popped first
+        groups.sort_by_key(|g| std::cmp::Reverse(g.samples.len()));
+    }
+    targets.sort_by_key(|groups| groups.iter().map(|g| g.samples.len()).sum::<usize>());
+    let mut queue = Vec::with_capacity(len);
+    while !targets.is_empty() {
+        targets.retain_mut(|groups| {
+            queue.extend(groups.pop());
+            !groups.is_empty()
+        });
+    }
+    queue
+}
+
+// TargetSlice is the symbolization time a target may use in a round, shared by the groups of its pids
+struct TargetSlice {
+    limit_nanos: u64,
+    used_nanos: AtomicU64,
+}
+
+impl TargetSlice {
+    fn new(limit: Duration) -> Self {
+        Self { limit_nanos: limit.as_nanos() as u64, used_nanos: AtomicU64::new(0) }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.used_nanos.load(Ordering::Relaxed) >= self.limit_nanos
+    }
+
+    fn charge(&self, spent: Duration) {
+        self.used_nanos.fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+// resolve_pid_samples symbolizes the stacks of a pid, holding its proc table lock for the whole group.
+// Once the round deadline has passed, or the target used up its slice, user stacks are only walked
+// for their addresses, kernel stacks are still resolved since kallsyms is always loaded.
+fn resolve_pid_samples(
+    group: &PidSamples,
+    kallsyms: Option<&KallsymsIndex>,
+    opts: FrameOptions,
+    slice: Option<&TargetSlice>,
+) -> Vec<(Vec<String>, StackResolveStats)> {
+    let mut proc = group.proc.lock().unwrap();
+    if !opts.past_deadline() {
+        let started = Instant::now();
+        proc.refresh_resource();
+        if let Some(slice) = slice {
+            slice.charge(started.elapsed());
+        }
+    }
+    let mut sb = StackBuilder::new();
+    group.samples.iter().map(|sample| {
+        let mut stats = StackResolveStats::default();
+        sb.reset();
+        sb.append(group.comm.clone());
+        if let Some(stack) = &sample.user_stack {
+            if opts.past_deadline() {
+                walk_stack_addresses(&mut sb, stack);
+                stats.address_only += 1;
+            } else if slice.is_some_and(|s| s.exhausted()) {
+                walk_stack_addresses(&mut sb, stack);
+                stats.throttled += 1;
+            } else {
+                let started = Instant::now();
+                walk_stack(&mut sb, stack, |pc| proc.resolve(pc), &mut stats, opts);
+                if let Some(slice) = slice {
+                    slice.charge(started.elapsed());
+                }
+            }
+        }
+        if let (Some(stack), Some(kallsyms)) = (&sample.kern_stack, kallsyms) {
+            walk_stack(&mut sb, stack, |pc| kallsyms.resolve(pc).cloned(), &mut stats, opts);
+        }
+        sb.stack.reverse();
+        (sb.stack.clone(), stats)
+    }).collect()
+}
+
+fn walk_stack<R: FnMut(u64) -> Option<Symbol>>(
+    sb: &mut StackBuilder,
+    stack: &[u8],
+    mut resolve: R,
+    stats: &mut StackResolveStats,
+    opts: FrameOptions,
+) {
+    if stack.is_empty() {
+        info!("stack is empty");
+        return;
+    }
+    let mut stack_frames = Vec::new();
+    for i in 0..PERF_MAX_STACK_DEPTH {
+        let start = i * 8;
+        let end = start + 8;
+        if end > stack.len() {
+            break;
+        }
+        let instruction_pointer_bytes = &stack[i * 8..(i + 1) * 8];
+        let instruction_pointer = u64::from_le_bytes(instruction_pointer_bytes.try_into().unwrap());
+        if instruction_pointer == 0 {
+            break;
+        }
+
+        let name = if let Some(sym) = resolve(instruction_pointer) {
+            if !sym.name.is_empty() {
+                stats.known += 1;
+                sym.name.clone()
+            } else {
+                stats.unknown_symbols += 1;
+                if !sym.module.is_empty() {
+                    if opts.unknown_symbol_symbolizable {
+                        format!("{}!0x{:x}", sym.module, sym.start)
+                    } else if opts.unknown_symbol_module_offset {
+                        format!("{}+{:x}", sym.module, sym.start)
+                    } else {
+                        sym.module.clone()
+                    }
+                } else {
+                    if opts.unknown_symbol_address {
+                        format!("{:x}", instruction_pointer)
+                    } else {
+                        "[unknown]".to_string()
+                    }
+                }
+            }
+        } else {
+            stats.unknown_modules += 1;
+            "[unknown]".to_string()
+        };
+        stack_frames.push(name);
+    }
+    if stack_frames.len() == PERF_MAX_STACK_DEPTH {
+        stats.truncated += 1;
+        // the bpf program keeps the innermost frames, the marker stands in for the outer ones
+        // so clipped stacks show up as such in flamegraphs
+        stack_frames.push(TRUNCATED_FRAME.to_string());
+    }
+    stack_frames.reverse();
+    for s in stack_frames {
+        sb.append(s);
+    }
 }
 
+// walk_stack_addresses appends the frames of a stack as raw addresses without touching any symbol table.
+fn walk_stack_addresses(sb: &mut StackBuilder, stack: &[u8]) {
+    let mut stack_frames = Vec::new();
+    for ip in stack.chunks_exact(8).take(PERF_MAX_STACK_DEPTH) {
+        let instruction_pointer = u64::from_le_bytes(ip.try_into().unwrap());
+        if instruction_pointer == 0 {
+            break;
+        }
+        stack_frames.push(format!("{:x}", instruction_pointer));
+    }
+    stack_frames.reverse();
+    for s in stack_frames {
+        sb.append(s);
+    }
+}
+
+// KALLSYMS_REFRESH_ROUNDS is how often kernel symbols are re-read to pick up loaded modules
+const KALLSYMS_REFRESH_ROUNDS: u32 = 40;
+
+// TRUNCATED_FRAME replaces the frames of a stack deeper than PERF_MAX_STACK_DEPTH
+const TRUNCATED_FRAME: &str = "[truncated]";
+
+// PERF_MAX_STACK_DEPTH matches the stack depth collected by the bpf program, see stacks.h
+const PERF_MAX_STACK_DEPTH: usize = 127;
+
 struct StackBuilder {
     stack: Vec<String>,
 }
@@ -757,6 +1582,9 @@ struct StackResolveStats {
     known: u32,
     unknown_symbols: u32,
     unknown_modules: u32,
+    truncated: u32,
+    address_only: u32,
+    throttled: u32,
 }
 
 impl StackResolveStats {
@@ -764,18 +1592,103 @@ impl StackResolveStats {
         self.known += other.known;
         self.unknown_symbols += other.unknown_symbols;
         self.unknown_modules += other.unknown_modules;
+        self.truncated += other.truncated;
+        self.address_only += other.address_only;
+        self.throttled += other.throttled;
     }
 }
 
-fn byte_to_value<V>(bytes: &Vec<u8>) -> Option<&V> {
-    if bytes.len() != mem::size_of::<V>() {
-        return None;
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use crate::ebpf::map::map::{delete_keys, drain};
+    use crate::ebpf::map::mock::{MapOp, MockMap};
+
+    use super::delete_stale_pids;
+
+    fn counts() -> MockMap {
+        let m = MockMap::new("counts", 16);
+        for pid in 1u32..=3 {
+            m.insert(&pid.to_ne_bytes(), &(pid * 10).to_ne_bytes());
+        }
+        m
     }
-    let ptr = bytes.as_ptr() as *const V;
-    let value_ref: &V;
-    unsafe {
-        value_ref = &*ptr;
+
+    #[test]
+    fn drain_empties_the_map() {
+        let m = counts();
+        let entries = drain::<u32, u32>(&m).unwrap();
+        assert_eq!(entries, vec![(1, 10), (2, 20), (3, 30)]);
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn drain_skips_entries_deleted_by_a_dead_pid_cleanup() {
+        let m = counts();
+        m.race(&2u32.to_ne_bytes());
+        let entries = drain::<u32, u32>(&m).unwrap();
+        assert_eq!(entries, vec![(1, 10), (3, 30)]);
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn drain_ignores_a_delete_that_lost_the_race() {
+        let m = counts();
+        m.fail(MapOp::Delete, Some(&1u32.to_ne_bytes()), libc::ENOENT);
+        let entries = drain::<u32, u32>(&m).unwrap();
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn drain_fails_on_other_errors() {
+        let m = counts();
+        m.fail(MapOp::Lookup, Some(&2u32.to_ne_bytes()), libc::EPERM);
+        let err = drain::<u32, u32>(&m).unwrap_err();
+        assert_eq!(err.errno(), Some(libc::EPERM));
+        // the entries after the failed one are left for the next round
+        assert_eq!(m.get(&3u32.to_ne_bytes()), Some(30u32.to_ne_bytes().to_vec()));
+    }
+
+    #[test]
+    fn drain_skips_entries_of_the_wrong_size() {
+        let m = counts();
+        m.insert(&[4u8], &40u32.to_ne_bytes());
+        let entries = drain::<u32, u32>(&m).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(m.is_empty());
     }
-    return Some(value_ref);
-}
 
+    #[test]
+    fn delete_keys_counts_failures() {
+        let m = counts();
+        m.fail(MapOp::Delete, None, libc::EBUSY);
+        let keys: Vec<[u8; 4]> = (1u32..=4).map(u32::to_ne_bytes).collect();
+        let (deleted, failed) = delete_keys(&m, keys.iter().map(|k| k.as_slice()));
+        // the injected fault and the missing pid 4
+        assert_eq!((deleted, failed), (2, 2));
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn delete_stale_pids_keeps_live_pids() {
+        let m = MockMap::new("pids", 16);
+        for pid in [10u32, 20, 30] {
+            m.insert(&pid.to_le_bytes(), &[0]);
+        }
+        let dead = delete_stale_pids(&m, |pids| pids.into_iter().filter(|pid| *pid != 20).collect());
+        assert_eq!(dead, 2);
+        assert_eq!(m.len(), 1);
+        assert!(m.get(&20u32.to_le_bytes()).is_some());
+    }
+
+    #[test]
+    fn delete_stale_pids_survives_racing_deletes() {
+        let m = MockMap::new("pids", 16);
+        for pid in [10u32, 20] {
+            m.insert(&pid.to_le_bytes(), &[0]);
+        }
+        m.race(&10u32.to_le_bytes());
+        let dead = delete_stale_pids(&m, |pids| pids);
+        assert_eq!(dead, 2);
+        assert!(m.is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::ebpf::symtab::elf::elfmmap::MappedElfFile;
-use crate::error::Error::{InvalidData, NotFound};
+use crate::error::Error;
+use crate::error::Error::NotFound;
 use crate::error::Result;
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
@@ -47,18 +48,19 @@ impl BuildIdentified for MappedElfFile {
     fn go_build_id(&mut self) -> Result<BuildID> {
         let data_result = self.section_data_by_section_name(".note.gnu.build-id")?;
         let data = data_result.as_slice();
+        let fpath = self.fpath.to_string_lossy().to_string();
         if data.len() < 17 {
-            return Err(InvalidData(".note.gnu.build-id is too small".to_string()));
+            return Err(Error::invalid_file(&fpath, ".note.gnu.build-id is too small"));
         }
 
         let data = &data[16..data.len() - 1];
         if data.len() < 40 || data.iter().filter(|&&b| b == b'/').count() < 2 {
-            return Err(InvalidData(format!("wrong .note.go.buildid {}", "")));
+            return Err(Error::invalid_file(&fpath, "wrong .note.go.buildid"));
         }
 
         let id = String::from_utf8_lossy(data).to_string();
         if id == "redacted" {
-            return Err(InvalidData(format!("blacklisted .note.go.buildid {}", "" /* provide fpath */)));
+            return Err(Error::invalid_file(&fpath, "blacklisted .note.go.buildid"));
         }
 
         Ok(BuildID::new(id, "go".to_string()))
@@ -67,16 +69,17 @@ impl BuildIdentified for MappedElfFile {
     fn gnu_build_id(&mut self) -> Result<BuildID> {
         let data_result = self.section_data_by_section_name(".note.gnu.build-id")?;
         let data = data_result.as_slice();
+        let fpath = self.fpath.to_string_lossy().to_string();
         if data.len() < 16 {
-            return Err(InvalidData(".note.gnu.build-id is too small".to_string()));
+            return Err(Error::invalid_file(&fpath, ".note.gnu.build-id is too small"));
         }
         if &data[12..15] != b"GNU" {
-            return Err(InvalidData(".note.gnu.build-id is not a GNU build-id".to_string()))
+            return Err(Error::invalid_file(&fpath, ".note.gnu.build-id is not a GNU build-id"))
         }
 
         let raw_build_id = &data[16..];
         if raw_build_id.len() != 20 && raw_build_id.len() != 8 {
-            return Err(InvalidData(format!(".note.gnu.build-id has wrong size {}", "" /* provide fpath */)))
+            return Err(Error::invalid_file(&fpath, format!(".note.gnu.build-id has wrong size {}", raw_build_id.len())))
         }
         let build_id_hex = hex::encode(raw_build_id);
         Ok(BuildID::new(build_id_hex, "gnu".to_string()))
@@ -14,7 +14,8 @@ use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use crate::ebpf::symtab::elf::pcindex::PCIndex;
 use crate::ebpf::symtab::elf::symbol_table::{FlatSymbolIndex, SECTION_TYPE_DYN_SYM, SECTION_TYPE_SYM, SectionLinkIndex, SymbolIndex, SymbolNameTable};
 use crate::ebpf::symtab::elf::symbol_table::Name;
-use crate::error::Error::{MapError, NotFound, SymbolError};
+use crate::error::Error;
+use crate::error::Error::{NotFound, SymbolError};
 use crate::error::Result;
 
 #[derive(Debug)]
@@ -38,16 +39,14 @@ impl MappedElfFile {
     pub fn new(fpath: PathBuf) -> Result<Self> {
         dbg!(&fpath);
 
-        let fd = File::open(&fpath);
-        if fd.is_err() {
-            return Err(MapError(fd.err().unwrap().to_string()));
-        }
+        let path = fpath.to_string_lossy().into_owned();
+        let mut fd = File::open(&fpath).map_err(|e| Error::from_io(format!("open {}", path), &e))?;
         let mut buffer = Vec::new();
-        fd.as_ref().borrow_mut().unwrap().read_to_end(&mut buffer).unwrap();
-        let elf = Elf::parse(buffer.as_slice()).unwrap();
+        fd.read_to_end(&mut buffer).map_err(|e| Error::from_io(format!("read {}", path), &e))?;
+        let elf = Elf::parse(buffer.as_slice()).map_err(|e| Error::invalid_file(&path, e.to_string()))?;
 
         let strtab = elf.section_headers.iter()
-            .map(|s| (s.sh_name, elf.shdr_strtab.get_at(s.sh_name).unwrap().to_string()))
+            .map(|s| (s.sh_name, elf.shdr_strtab.get_at(s.sh_name).unwrap_or_default().to_string()))
             .collect::<HashMap<usize, String>>();
 
         Ok(Self {
@@ -56,7 +55,7 @@ impl MappedElfFile {
             section_headers: elf.section_headers,
             strtab,
             fpath,
-            fd: Some(fd.unwrap()),
+            fd: Some(fd),
             string_cache: HashMap::new(),
         })
     }
@@ -72,7 +71,8 @@ impl MappedElfFile {
     }
 
     fn open(&mut self) -> Result<()> {
-        let fd = File::open(&self.fpath).unwrap();
+        let fd = File::open(&self.fpath)
+            .map_err(|e| Error::from_io(format!("open {}", self.fpath.display()), &e))?;
         self.fd = Some(fd);
         Ok(())
     }
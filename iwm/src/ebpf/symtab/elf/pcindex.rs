@@ -52,6 +52,16 @@ impl PCIndex {
         }
     }
 
+    pub(crate) fn memory_size(&self) -> usize {
+        if let Some(i32_vec) = &self.i32 {
+            i32_vec.capacity() * std::mem::size_of::<u32>()
+        } else if let Some(i64_vec) = &self.i64 {
+            i64_vec.capacity() * std::mem::size_of::<u64>()
+        } else {
+            0
+        }
+    }
+
     fn get(&self, idx: usize) -> u64 {
         if let Some(i32_vec) = &self.i32 {
             u64::from(i32_vec[idx])
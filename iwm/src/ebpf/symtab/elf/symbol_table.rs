@@ -1,5 +1,6 @@
 use goblin::elf32::section_header::{SHT_DYNSYM, SHT_SYMTAB};
 use goblin::elf::SectionHeader;
+use serde::{Deserialize, Serialize};
 
 use crate::ebpf::symtab::elf::elfmmap::MappedElfFile;
 use crate::ebpf::symtab::elf::pcindex::PCIndex;
@@ -46,6 +47,20 @@ pub struct SymbolNameTable {
     pub(crate) file: MappedElfFile
 }
 
+impl SymbolNameTable {
+    // memory_size estimates the heap held by the table: the symbol index and the cached strings.
+    pub(crate) fn memory_size(&self) -> usize {
+        let strings: usize = self.file.strtab.values()
+            .chain(self.file.string_cache.values())
+            .map(|s| s.capacity())
+            .sum();
+        self.index.names.capacity() * std::mem::size_of::<Name>()
+            + self.index.links.capacity() * std::mem::size_of::<SectionHeader>()
+            + self.index.values.memory_size()
+            + strings
+    }
+}
+
 impl Resource for SymbolNameTable {
     fn refresh_resource(&mut self) {}
     fn cleanup_resource(&mut self) {
@@ -137,12 +152,12 @@ impl SymbolNameTable {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SymTabDebugInfo {
-    name: String,
-    pub(crate) size: usize,
-    file: String,
-    pub(crate) last_used_round: i32,
+    pub name: String,
+    pub size: usize,
+    pub file: String,
+    pub last_used_round: i32,
 }
 
 impl Default for SymTabDebugInfo {
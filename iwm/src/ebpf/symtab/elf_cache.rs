@@ -1,11 +1,13 @@
+use std::collections::HashSet;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
 
 
 use crate::error::Result;
 use crate::ebpf::symtab::elf::buildid::BuildID;
 use crate::ebpf::symtab::elf::symbol_table::{SymbolNameTable, SymTabDebugInfo};
-use crate::ebpf::symtab::gcache::{debug_info, GCache, GCacheDebugInfo, GCacheOptions};
+use crate::ebpf::symtab::gcache::{debug_info, GCache, GCacheDebugInfo, GCacheOptions, GCacheStats};
 use crate::ebpf::symtab::stat::Stat;
 use crate::ebpf::symtab::symtab::SymbolNameResolver;
 
@@ -74,6 +76,45 @@ impl ElfCache {
         self.same_file_cache.lock().unwrap().cleanup();
     }
 
+    // take_stats returns the build id and same file cache stats since the last call.
+    pub fn take_stats(&self) -> (GCacheStats, GCacheStats) {
+        (
+            self.build_id_cache.lock().unwrap().take_stats(),
+            self.same_file_cache.lock().unwrap().take_stats(),
+        )
+    }
+
+    // heaviest_tables lists the cached symbol tables ordered by estimated memory, largest first.
+    pub fn heaviest_tables(&self, limit: usize) -> Vec<ElfTableMemory> {
+        let mut seen = HashSet::new();
+        let mut res = Vec::new();
+        let mut add = |v: &Arc<Mutex<SymbolNameTable>>, round: i32| {
+            if !seen.insert(Arc::as_ptr(v) as usize) {
+                return;
+            }
+            let table = v.lock().unwrap();
+            res.push(ElfTableMemory {
+                file: table.file.fpath.to_string_lossy().to_string(),
+                symbols: table.index.names.len(),
+                bytes: table.memory_size(),
+                last_used_round: round,
+            });
+        };
+        {
+            let build_id_cache = self.build_id_cache.lock().unwrap();
+            build_id_cache.each_lru(|_, v, round| add(v, round));
+            build_id_cache.each_round(|_, v, round| add(v, round));
+        }
+        {
+            let same_file_cache = self.same_file_cache.lock().unwrap();
+            same_file_cache.each_lru(|_, v, round| add(v, round));
+            same_file_cache.each_round(|_, v, round| add(v, round));
+        }
+        res.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        res.truncate(limit);
+        res
+    }
+
     pub fn debug_info(&self) -> ElfCacheDebugInfo {
         let build_id_cache = debug_info::<BuildID, SymbolNameTable, SymTabDebugInfo>(
             self.build_id_cache.lock().unwrap().deref(),
@@ -95,10 +136,18 @@ impl ElfCache {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct ElfTableMemory {
+    pub file: String,
+    pub symbols: usize,
+    pub bytes: usize,
+    pub last_used_round: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ElfCacheDebugInfo {
-    build_id_cache: GCacheDebugInfo<SymTabDebugInfo>,
-    same_file_cache: GCacheDebugInfo<SymTabDebugInfo>,
+    pub build_id_cache: GCacheDebugInfo<SymTabDebugInfo>,
+    pub same_file_cache: GCacheDebugInfo<SymTabDebugInfo>,
 }
 
 impl ElfCacheDebugInfo {
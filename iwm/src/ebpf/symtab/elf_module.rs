@@ -16,7 +16,7 @@ use crate::ebpf::symtab::elf::elfmmap::{MappedElfFile};
 use crate::ebpf::symtab::elf::symbol_table::{SymbolNameTable};
 use crate::ebpf::symtab::elf_cache::ElfCache;
 use crate::ebpf::symtab::procmap::ProcMap;
-use crate::ebpf::symtab::stat::stat_from_file_info;
+use crate::ebpf::symtab::stat::{stat_from_file_info, stat_from_mapping, Stat};
 use crate::ebpf::symtab::symtab::{NoopSymbolNameResolver, SymbolNameResolver};
 use crate::error::Error::{ELFError, NotFound};
 use crate::error::Result;
@@ -29,7 +29,7 @@ pub struct ElfTableOptions {
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct SymbolOptions {
-    pub python_full_file_path: bool
+    pub python_full_file_path: bool,
 }
 
 impl Default for SymbolOptions {
@@ -39,7 +39,7 @@ impl Default for SymbolOptions {
 }
 
 impl SymbolOptions {
-    fn new(python_full_file_path: bool) -> Self {
+    pub fn new(python_full_file_path: bool) -> Self {
         Self { python_full_file_path }
     }
 }
@@ -52,12 +52,15 @@ pub struct ElfTable {
     loaded_cached: bool,
     options: ElfTableOptions,
     proc_map: Arc<Mutex<ProcMap>>,
-    err: Option<crate::error::Error>
+    // mnt_ns is the mount namespace of the process, part of the same file cache key, see Stat
+    mnt_ns: u64,
+    pub(crate) err: Option<crate::error::Error>
 }
 
 impl ElfTable {
-    pub fn new(proc_map: Arc<Mutex<ProcMap>>, fs: String, options: ElfTableOptions) -> Self {
+    pub fn new(proc_map: Arc<Mutex<ProcMap>>, fs: String, mnt_ns: u64, options: ElfTableOptions) -> Self {
         Self {
+            mnt_ns,
             fs,
             table: Arc::new(Mutex::new(NoopSymbolNameResolver {})),
             base: 0,
@@ -69,7 +72,7 @@ impl ElfTable {
         }
     }
 
-    fn load(&mut self) {
+    pub(crate) fn load(&mut self) {
         if self.loaded { return; }
         self.loaded = true;
 
@@ -78,6 +81,27 @@ impl ElfTable {
             PathBuf::from(format!("{}{}", &self.fs, &pm.pathname))
         };
 
+        // the same file cache is checked before the file is opened, the processes of every container
+        // running the binary share the table of the first one that loaded it
+        let stat = match self.stat(&fs_elf_file_path) {
+            Ok(stat) => stat,
+            Err(err) => {
+                self.on_load_error(&err);
+                return;
+            }
+        };
+        if let Some(symbols) = self.options.elf_cache.get_symbols_by_stat(stat) {
+            // a table loaded from a debug file has the program headers of the binary as well
+            let found = self.find_base(&symbols.lock().unwrap().file);
+            if !found {
+                self.err = Some(NotFound("elf base not found".to_string()));
+                return;
+            }
+            self.table = symbols;
+            self.loaded_cached = true;
+            return;
+        }
+
         let me_result = MappedElfFile::new(fs_elf_file_path.clone());
         let mut me = match me_result {
             Ok(file) => file,
@@ -103,25 +127,12 @@ impl ElfTable {
         };
 
         if let Some(symbols) = self.options.elf_cache.get_symbols_by_build_id(&build_id) {
+            self.options.elf_cache.cache_by_stat(stat, symbols.clone());
             self.table = symbols.clone();
             self.loaded_cached = true;
             return;
         }
 
-        let file_info = match fs::metadata(&fs_elf_file_path) {
-            Ok(info) => info,
-            Err(err) => {
-                self.on_load_error(&ELFError(err.to_string()));
-                return;
-            }
-        };
-
-        if let Some(s) = self.options.elf_cache.get_symbols_by_stat(stat_from_file_info(&file_info)) {
-            self.table = s.clone();
-            self.loaded_cached = true;
-            return;
-        }
-
         if let Some(debug_file_path) = self.find_debug_file(&build_id, me.borrow_mut()) {
             dbg!(&debug_file_path);
             if debug_file_path.is_empty() {
@@ -147,6 +158,7 @@ impl ElfTable {
             }));
             self.table = symbols.clone();
             self.options.elf_cache.cache_by_build_id(build_id, symbols.clone());
+            self.options.elf_cache.cache_by_stat(stat, symbols.clone());
             return;
         }
 
@@ -158,12 +170,22 @@ impl ElfTable {
         }));
 
         self.table = symbols.clone();
-        if build_id.is_empty() {
-            dbg!(stat_from_file_info(&file_info));
-            self.options.elf_cache.cache_by_stat(stat_from_file_info(&file_info), symbols.clone());
-        } else {
+        if !build_id.is_empty() {
             self.options.elf_cache.cache_by_build_id(build_id, symbols.clone());
         }
+        self.options.elf_cache.cache_by_stat(stat, symbols.clone());
+    }
+
+    // stat is the same file cache key of the mapped file. The device and inode of the mapping are those
+    // of the file backing it, the same in every container sharing the image layer, while stat through
+    // the container root may see an overlay.
+    fn stat(&self, fs_elf_file_path: &Path) -> Result<Stat> {
+        let info = fs::metadata(fs_elf_file_path).map_err(|err| ELFError(err.to_string()))?;
+        let pm = self.proc_map.lock().unwrap();
+        if pm.inode == 0 {
+            return Ok(stat_from_file_info(&info, self.mnt_ns));
+        }
+        Ok(stat_from_mapping(pm.dev, pm.inode, &info, self.mnt_ns))
     }
 
     fn find_base(&mut self, e: &MappedElfFile) -> bool {
@@ -187,7 +209,7 @@ impl ElfTable {
         let pm = self.proc_map.lock().unwrap();
         info!("failed to load elf table err: {}, f: {}, fs: {}",
             err.to_string(), &pm.pathname.to_string(), &self.fs.to_string());
-        self.options.metrics.elf_errors.with_label_values(&[err.to_string().as_str()]).inc();
+        self.options.metrics.elf_errors.with_label_values(&[err.kind()]).inc();
     }
 
     fn find_debug_file(&self, build_id: &BuildID, elf_file: &mut MappedElfFile) -> Option<String> {
@@ -277,6 +299,11 @@ impl ElfTable {
         table.resolve(pc)
     }
 
+    // mapped_at reports whether the table was created for a mapping starting at start
+    pub(crate) fn mapped_at(&self, start: u64) -> bool {
+        self.proc_map.lock().unwrap().start_addr == start
+    }
+
     pub fn cleanup(&mut self) {
         let mut table = self.table.lock().unwrap();
         table.cleanup();
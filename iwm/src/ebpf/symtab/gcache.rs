@@ -2,8 +2,10 @@ use std::collections::HashMap;
 extern crate lru;
 
 use lru::LruCache;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::mem;
 use std::num::NonZeroUsize;
 
 use std::sync::{Arc, Mutex};
@@ -19,6 +21,23 @@ pub struct GCache<K: Eq + Hash + Clone, V: Resource> {
     round_cache: HashMap<K, Arc<Mutex<Entry<Arc<Mutex<V>>>>>>,
     lru_cache: LruCache<K, Arc<Mutex<Entry<Arc<Mutex<V>>>>>>,
     round: i32,
+    stats: GCacheStats,
+}
+
+// GCacheStats counts cache lookups and evictions since the last take_stats call.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl GCacheStats {
+    // hit_ratio is the share of the lookups that found an entry, None without lookups
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups != 0).then(|| self.hits as f64 / lookups as f64)
+    }
 }
 
 impl<K: Eq + Hash + Clone, V: Resource> GCache<K, V> {
@@ -27,7 +46,7 @@ impl<K: Eq + Hash + Clone, V: Resource> GCache<K, V> {
         let lru_cache = LruCache::new(lru_cache_size);
         let round_cache = HashMap::new();
 
-        Self { options, round_cache, lru_cache, round: 0 }
+        Self { options, round_cache, lru_cache, round: 0, stats: GCacheStats::default() }
     }
 
     pub fn next_round(&mut self) {
@@ -35,6 +54,23 @@ impl<K: Eq + Hash + Clone, V: Resource> GCache<K, V> {
     }
 
     pub fn get(&mut self, k: &K) -> Option<Arc<Mutex<V>>> {
+        let res = self.lookup(k);
+        if res.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        res
+    }
+
+    // peek returns the cached value without refreshing it or counting a lookup
+    pub fn peek(&self, k: &K) -> Option<Arc<Mutex<V>>> {
+        self.lru_cache.peek(k)
+            .or_else(|| self.round_cache.get(k))
+            .map(|e| e.lock().unwrap().v.clone())
+    }
+
+    fn lookup(&mut self, k: &K) -> Option<Arc<Mutex<V>>> {
         // MutexGuard<Entry<Arc<Mutex<V>>>>
         if let Some(e) = self.lru_cache.get_mut(k) {
             let mut entry = e.lock().unwrap();
@@ -66,7 +102,12 @@ impl<K: Eq + Hash + Clone, V: Resource> GCache<K, V> {
             value.refresh_resource();
         }
         let entry = Arc::new(Mutex::new(e));
-        self.lru_cache.put(k.clone(), entry.clone());
+        if let Some((evicted, _)) = self.lru_cache.push(k.clone(), entry.clone()) {
+            // entries still used this round stay reachable through the round cache
+            if evicted != k && !self.round_cache.contains_key(&evicted) {
+                self.stats.evictions += 1;
+            }
+        }
         self.round_cache.insert(k, entry.clone());
     }
 
@@ -91,11 +132,34 @@ impl<K: Eq + Hash + Clone, V: Resource> GCache<K, V> {
                 value.cleanup_resource();
             });
 
+        let min_round = self.round - self.options.keep_rounds;
+        let lru_cache = &self.lru_cache;
+        let stats = &mut self.stats;
         self.round_cache
-            .retain(|_k, e| {
+            .retain(|k, e| {
                 let entry = e.lock().unwrap();
-                entry.round < self.round-self.options.keep_rounds
+                let keep = entry.round >= min_round;
+                if !keep && !lru_cache.contains(k) {
+                    stats.evictions += 1;
+                }
+                keep
             });
+        // entries unused for keep_rounds are dropped from the lru as well, it bounds how long
+        // tables of exited processes stay around when the cache is not full
+        let stale: Vec<K> = self.lru_cache.iter()
+            .filter(|(_k, e)| e.lock().unwrap().round < min_round)
+            .map(|(k, _e)| k.clone())
+            .collect();
+        for k in stale {
+            self.lru_cache.pop(&k);
+            if !self.round_cache.contains_key(&k) {
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    pub fn take_stats(&mut self) -> GCacheStats {
+        mem::take(&mut self.stats)
     }
 
     pub fn lru_size(&self) -> usize {
@@ -144,13 +208,13 @@ impl Default for GCacheOptions {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct GCacheDebugInfo<T> {
-    lru_size: usize,
-    round_size: usize,
-    current_round: i32,
-    lru_dump: Vec<T>,
-    round_dump: Vec<T>,
+    pub lru_size: usize,
+    pub round_size: usize,
+    pub current_round: i32,
+    pub lru_dump: Vec<T>,
+    pub round_dump: Vec<T>,
 }
 
 impl<T> GCacheDebugInfo<T> {
@@ -191,3 +255,93 @@ pub fn debug_info<K, V, D>(g: &GCache<K, V>, ff: fn(&K, &Arc<Mutex<V>>, i32) ->
     res
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{GCache, GCacheOptions, Resource};
+
+    // Table counts the refreshes and cleanups the cache does, like a ProcTable re-reading its maps
+    #[derive(Default)]
+    struct Table {
+        refreshes: u32,
+        cleanups: u32,
+    }
+
+    impl Resource for Table {
+        fn refresh_resource(&mut self) {
+            self.refreshes += 1;
+        }
+        fn cleanup_resource(&mut self) {
+            self.cleanups += 1;
+        }
+    }
+
+    fn cache(size: usize, keep_rounds: i32) -> GCache<u32, Table> {
+        GCache::new(GCacheOptions { size, keep_rounds })
+    }
+
+    fn round(cache: &mut GCache<u32, Table>, used: &[u32]) {
+        cache.next_round();
+        for pid in used {
+            assert!(cache.get(pid).is_some(), "pid {} was evicted", pid);
+        }
+        cache.cleanup();
+    }
+
+    #[test]
+    fn entries_unused_for_keep_rounds_are_evicted() {
+        let mut cache = cache(16, 3);
+        cache.cache(1, Arc::new(Mutex::new(Table::default())));
+        cache.cache(2, Arc::new(Mutex::new(Table::default())));
+        cache.cleanup();
+        for _ in 0..3 {
+            round(&mut cache, &[2]);
+        }
+        // pid 1 was last used keep_rounds ago
+        assert!(cache.peek(&1).is_some());
+        round(&mut cache, &[2]);
+        assert!(cache.peek(&1).is_none());
+        assert_eq!((cache.lru_size(), cache.round_size()), (1, 1));
+        assert_eq!(cache.take_stats().evictions, 1);
+        // the lru wasn't full, the rounds alone evicted it
+        round(&mut cache, &[]);
+        round(&mut cache, &[]);
+        round(&mut cache, &[]);
+        round(&mut cache, &[]);
+        assert!(cache.peek(&2).is_none());
+        assert_eq!((cache.lru_size(), cache.round_size()), (0, 0));
+    }
+
+    #[test]
+    fn entries_are_refreshed_once_per_round() {
+        let mut cache = cache(16, 3);
+        let table = Arc::new(Mutex::new(Table::default()));
+        cache.cache(1, table.clone());
+        assert_eq!(table.lock().unwrap().refreshes, 1);
+        cache.get(&1);
+        assert_eq!(table.lock().unwrap().refreshes, 1);
+        round(&mut cache, &[1, 1, 1]);
+        round(&mut cache, &[1]);
+        assert_eq!(table.lock().unwrap().refreshes, 3);
+        assert!(table.lock().unwrap().cleanups > 0);
+        let stats = cache.take_stats();
+        assert_eq!((stats.hits, stats.misses), (5, 0));
+    }
+
+    #[test]
+    fn entries_used_this_round_outlive_the_lru() {
+        let mut cache = cache(1, 0);
+        cache.cache(1, Arc::new(Mutex::new(Table::default())));
+        cache.cache(2, Arc::new(Mutex::new(Table::default())));
+        // pid 1 fell out of the lru but is still used by the round
+        assert!(cache.get(&1).is_some());
+        assert_eq!(cache.take_stats().evictions, 0);
+        cache.next_round();
+        cache.cleanup();
+        assert!(cache.peek(&1).is_none());
+        assert!(cache.peek(&2).is_none());
+        assert_eq!(cache.take_stats().evictions, 2);
+    }
+}
@@ -2,32 +2,122 @@ use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 
-use crate::ebpf::symtab::table::{Symbol, SymbolTab};
-use crate::error::Error::SymbolError;
+use crate::ebpf::symtab::table::Symbol;
+use crate::error::Error;
+use crate::error::Error::{NotFound, SymbolError};
 use crate::error::Result;
 
 const KALLSYMS_MODULE: &str = "kernel";
 
-pub fn new_kallsyms() -> Result<SymbolTab> {
+// KallsymsIndex is an immutable, sorted view of the kernel symbols.
+// It is shared behind an Arc and replaced as a whole on refresh, so lookups need no locking.
+pub struct KallsymsIndex {
+    symbols: Vec<Symbol>,
+}
+
+impl KallsymsIndex {
+    fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.start);
+        Self { symbols }
+    }
+
+    pub fn new_empty() -> Self {
+        Self { symbols: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // resolve returns the symbol with the greatest start address not above addr
+    pub fn resolve(&self, addr: u64) -> Option<&Symbol> {
+        if self.symbols.is_empty() || addr < self.symbols[0].start {
+            return None;
+        }
+        let index = match self.symbols.binary_search_by(|sym| sym.start.cmp(&addr)) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+        self.symbols.get(index)
+    }
+}
+
+// syscall_prefix is the prefix of the arch specific syscall wrappers the kernel enters syscalls through
+// since 4.17, e.g. __x64_sys_execve or __arm64_sys_execve. It matches SYS_PREFIX in profile.bpf.h.
+pub fn syscall_prefix() -> &'static str {
+    arch_syscall_prefix(std::env::consts::ARCH)
+}
+
+fn arch_syscall_prefix(arch: &str) -> &'static str {
+    match arch {
+        "x86_64" => "__x64_",
+        "aarch64" => "__arm64_",
+        "s390x" => "__s390x_",
+        "riscv64" => "__riscv_",
+        _ => "__se_",
+    }
+}
+
+// syscall_symbol returns the kernel function to attach a kprobe to for the syscall, the arch wrapper
+// when the kernel has one and the plain sys_ function on older kernels. Names are looked up even when
+// kptr_restrict hides the addresses.
+pub fn syscall_symbol(syscall: &str) -> Result<String> {
+    let file = File::open("/proc/kallsyms").map_err(|e| Error::from_io("open /proc/kallsyms", &e))?;
+    find_syscall_symbol(BufReader::new(file), syscall_prefix(), syscall)
+}
+
+fn find_syscall_symbol<B: BufRead>(kallsyms: B, prefix: &str, syscall: &str) -> Result<String> {
+    let candidates = [format!("{}sys_{}", prefix, syscall), format!("sys_{}", syscall)];
+    let mut found = [false; 2];
+    for line in kallsyms.lines() {
+        let line = line.map_err(|e| Error::from_io("read /proc/kallsyms", &e))?;
+        let Some(name) = line.split_whitespace().nth(2) else {
+            continue;
+        };
+        for (i, candidate) in candidates.iter().enumerate() {
+            if name == candidate {
+                found[i] = true;
+            }
+        }
+        if found[0] {
+            break;
+        }
+    }
+    candidates.into_iter()
+        .zip(found)
+        .find_map(|(candidate, found)| found.then_some(candidate))
+        .ok_or_else(|| NotFound(format!("no kernel function for syscall {}", syscall)))
+}
+
+pub fn new_kallsyms() -> Result<KallsymsIndex> {
     new_kallsyms_from_file("/proc/kallsyms")
 }
 
-fn new_kallsyms_from_file<P: AsRef<Path>>(path: P) -> Result<SymbolTab> {
+fn new_kallsyms_from_file<P: AsRef<Path>>(path: P) -> Result<KallsymsIndex> {
     let file = File::open(path).unwrap();
     let reader = BufReader::new(file);
     new_kallsyms_from_data(reader)
 }
 
-fn new_kallsyms_from_data<B: BufRead>(buf: B) -> Result<SymbolTab> {
+fn new_kallsyms_from_data<B: BufRead>(buf: B) -> Result<KallsymsIndex> {
+    parse_kallsyms(buf, kernel_addr_space(std::env::consts::ARCH))
+}
+
+// kernel_addr_space is the lowest address of the kernel on the arch, user space symbols of bpf
+// programs and modules below it are skipped. arm64 kernels live in the upper half from
+// 0xfff0000000000000 with 52 bit VAs, 0xffff000000000000 with 48 bits.
+fn kernel_addr_space(arch: &str) -> u64 {
+    match arch {
+        "x86_64" => 0x00ffffffffffffff,
+        "aarch64" => 0xfff0000000000000,
+        _ => 0,
+    }
+}
+
+fn parse_kallsyms<B: BufRead>(buf: B, kernel_addr_space: u64) -> Result<KallsymsIndex> {
     let mut syms = Vec::new();
     let mut all_zeros = true;
 
-    let kernel_addr_space = if cfg!(target_arch = "x86_64") {
-        0x00ffffffffffffff
-    } else {
-        0
-    };
-
     for line in buf.lines() {
         let line = line.unwrap();
         if line.is_empty() {
@@ -67,8 +157,61 @@ fn new_kallsyms_from_data<B: BufRead>(buf: B) -> Result<SymbolTab> {
     }
 
     if all_zeros {
-        Ok(SymbolTab::new(Vec::new()))
+        Ok(KallsymsIndex::new(Vec::new()))
     } else {
-        Ok(SymbolTab::new(syms))
+        Ok(KallsymsIndex::new(syms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARM64_KALLSYMS: &str = "\
+ffff800008010000 T _text
+ffff800008020f40 T __arm64_sys_execve
+ffff800008021000 T do_execveat_common
+ffff80000802a000 t sys_execve
+ffff800009a00000 D init_task
+0000000000000000 t bpf_prog_6deef7357e7b4530_sd_fw_ingress [bpf]
+ffff800001234000 t nf_conntrack_in [nf_conntrack]
+";
+
+    #[test]
+    fn syscall_prefixes_match_the_bpf_side() {
+        assert_eq!(arch_syscall_prefix("x86_64"), "__x64_");
+        assert_eq!(arch_syscall_prefix("aarch64"), "__arm64_");
+        assert_eq!(arch_syscall_prefix("s390x"), "__s390x_");
+        assert_eq!(arch_syscall_prefix("riscv64"), "__riscv_");
+        assert_eq!(arch_syscall_prefix("powerpc64"), "__se_");
+    }
+
+    #[test]
+    fn syscall_symbol_prefers_the_arch_wrapper() {
+        let symbol = find_syscall_symbol(ARM64_KALLSYMS.as_bytes(), "__arm64_", "execve").unwrap();
+        assert_eq!(symbol, "__arm64_sys_execve");
+    }
+
+    #[test]
+    fn syscall_symbol_falls_back_to_the_plain_function() {
+        let symbol = find_syscall_symbol(ARM64_KALLSYMS.as_bytes(), "__x64_", "execve").unwrap();
+        assert_eq!(symbol, "sys_execve");
+        assert!(find_syscall_symbol(ARM64_KALLSYMS.as_bytes(), "__arm64_", "execveat").is_err());
+    }
+
+    #[test]
+    fn arm64_kallsyms_keep_the_kernel_and_module_text() {
+        let index = parse_kallsyms(ARM64_KALLSYMS.as_bytes(), kernel_addr_space("aarch64")).unwrap();
+        assert_eq!(index.resolve(0xffff800008020f80).unwrap().name, "__arm64_sys_execve");
+        assert_eq!(index.resolve(0xffff800001234010).unwrap().name, "nf_conntrack_in");
+        // the data symbol and the bpf program below the kernel address space are skipped
+        assert_eq!(index.resolve(0xffff800009a00010).unwrap().name, "sys_execve");
+        assert!(index.resolve(0x10).is_none());
+    }
+
+    #[test]
+    fn hidden_addresses_make_an_empty_index() {
+        let hidden = "0000000000000000 T _text\n0000000000000000 T __arm64_sys_execve\n";
+        assert!(parse_kallsyms(hidden.as_bytes(), 0).unwrap().is_empty());
     }
 }
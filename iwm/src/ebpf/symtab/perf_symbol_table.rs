@@ -6,10 +6,16 @@ use crate::ebpf::symtab::gcache::Resource;
 use crate::ebpf::symtab::procmap::{ProcMap, ProcMapPermissions};
 use crate::ebpf::symtab::symtab::SymbolTable;
 use crate::ebpf::symtab::table::Symbol;
-use crate::error::Error::ProcError;
+use crate::error::Error;
 
+// PerfSymbolTable resolves the JIT compiled code of a process from the perf map the runtime writes to
+// /tmp/perf-PID.map, e.g. a JVM with jcmd Compiler.perfmap or perf-map-agent
 pub struct PerfSymbolTable {
 	pid: i32,
+	// path is the perf map as seen from the host, in the mount namespace and with the pid of the process
+	path: String,
+	// size is the size of the perf map when it was read, the runtime only appends to it
+	size: u64,
 	err: Option<crate::error::Error>,
 	ranges: Vec<ProcMap>
 }
@@ -25,21 +31,25 @@ impl Resource for PerfSymbolTable {
 
 impl SymbolTable for PerfSymbolTable {
 
+	// refresh reads the perf map again when it grew since the last read
 	fn refresh(&mut self) {
-		let perf_path = format!("/tmp/perf-{}.map", self.pid);
-		match fs::read_to_string(&perf_path) {
+		let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+		if size == self.size {
+			return;
+		}
+		match fs::read_to_string(&self.path) {
 			Ok(perf_maps) => {
-				//dbg!(&perf_maps);
-				match self.push_perf_maps(perf_maps.clone()) {
+				self.size = size;
+				self.err = None;
+				match self.push_perf_maps(perf_maps) {
 					Err(e) => { self.err = Some(e); }
 					_ => {}
 				}
 			},
 			Err(e) => {
-				self.err = Some(ProcError(e.to_string()));
+				self.err = Some(Error::proc_error(self.pid as u32, format!("read {}: {}", self.path, e)));
 			}
 		}
-		//dbg!(&self.ranges);
 	}
 	fn cleanup(&mut self) {}
 	fn resolve(&mut self, addr: u64) -> Option<Symbol> {
@@ -56,7 +66,7 @@ impl SymbolTable for PerfSymbolTable {
 		Some(Symbol {
 			start: rr.start_addr.clone(),
 			name: rr.pathname.clone(),
-			module: format!("/tmp/perf-{}.map", self.pid),
+			module: "[jit]".to_string(),
 		})
 	}
 }
@@ -66,6 +76,8 @@ impl PerfSymbolTable {
 	pub fn new(pid: i32) -> Self {
 		Self {
 			pid,
+			path: format!("/proc/{}/root/tmp/perf-{}.map", pid, namespace_pid(pid)),
+			size: 0,
 			ranges: Vec::new(),
 			err: None,
 		}
@@ -77,10 +89,24 @@ impl PerfSymbolTable {
 			Ok(maps) => maps,
 			Err(err) => return Err(err),
 		};
+		// the entries are in the order the code was compiled, later ones win for reused addresses
+		self.ranges.reverse();
+		self.ranges.sort_by_key(|r| r.start_addr);
+		self.ranges.dedup_by_key(|r| r.start_addr);
 		Ok(())
 	}
 }
 
+// namespace_pid is the pid of the process in its own pid namespace, the runtime names the perf map after it
+fn namespace_pid(pid: i32) -> i32 {
+	fs::read_to_string(format!("/proc/{}/status", pid))
+		.ok()
+		.and_then(|status| status.lines()
+			.find_map(|l| l.strip_prefix("NSpid:"))
+			.and_then(|pids| pids.split_whitespace().last()?.parse().ok()))
+		.unwrap_or(pid)
+}
+
 fn binary_search_proc_range(mr: &ProcMap, pc: u64) -> std::cmp::Ordering {
 	if pc < mr.start_addr {
 		Greater
@@ -111,16 +137,16 @@ pub fn parse_perf_maps_executable_modules(perf_maps: &str) -> crate::error::Resu
 	Ok(modules)
 }
 
-// ffff7c045b40 10c arrayof_jint_disjoint_arraycopy
+// ffff7c045b40 10c arrayof_jint_disjoint_arraycopy, the name may contain spaces
 fn parse_perf_map_line(line: &str) -> Option<ProcMap> {
-	let mut parts = line.split(' ');
-	let start_addr_bytes = parts.next().unwrap();
-	let size = parts.next().unwrap();
-	let pathname = line.rsplit(' ').next().unwrap();
+	let mut parts = line.splitn(3, ' ');
+	let start_addr_bytes = parts.next()?;
+	let size = parts.next()?;
+	let pathname = parts.next()?;
 
 	let perms = ProcMapPermissions::default();
-	let start_addr = u64::from_str_radix(start_addr_bytes, 16).unwrap();
-	let end_addr = start_addr + u64::from_str_radix(size, 16).unwrap();
+	let start_addr = u64::from_str_radix(start_addr_bytes.trim_start_matches("0x"), 16).ok()?;
+	let end_addr = start_addr + u64::from_str_radix(size.trim_start_matches("0x"), 16).ok()?;
 
 	let res = ProcMap {
 		start_addr,
@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::debug;
+
+use crate::ebpf::metrics::symtab::SymtabMetrics;
+use crate::ebpf::symtab::elf_cache::ElfCache;
+use crate::ebpf::symtab::elf_module::{ElfTable, ElfTableOptions};
+use crate::ebpf::symtab::procmap::parse_proc_maps;
+use crate::ebpf::symtab::stat::mount_namespace;
+use crate::error::Error;
+use crate::error::Error::NotFound;
+use crate::error::Result;
+
+// IndexQueue loads the symbol tables of freshly exec'd executables in the background, so they are
+// in the elf cache before the first round that resolves the stacks of the process. Executables are
+// deduplicated by device and inode while they wait, and requests over the capacity are dropped,
+// the round then loads the table itself as before.
+pub struct IndexQueue {
+    sender: SyncSender<IndexRequest>,
+    pending: Arc<Mutex<HashSet<(u64, u64)>>>,
+    metrics: Arc<SymtabMetrics>,
+}
+
+struct IndexRequest {
+    pid: u32,
+    exe: (u64, u64),
+}
+
+impl IndexQueue {
+    pub fn new(capacity: usize, elf_cache: Arc<ElfCache>, metrics: Arc<SymtabMetrics>) -> Self {
+        let (sender, receiver) = sync_channel(capacity);
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let options = ElfTableOptions { elf_cache, metrics: metrics.clone() };
+        let worker_pending = pending.clone();
+        thread::Builder::new()
+            .name("symtab-index".to_string())
+            .spawn(move || run(receiver, worker_pending, options))
+            .unwrap();
+        Self { sender, pending, metrics }
+    }
+
+    // enqueue asks for the executable of pid to be indexed
+    pub fn enqueue(&self, pid: u32) {
+        let Ok(info) = fs::metadata(format!("/proc/{}/exe", pid)) else {
+            return;
+        };
+        let exe = (info.dev(), info.ino());
+        let m = &self.metrics;
+        if !self.pending.lock().unwrap().insert(exe) {
+            m.index_queue.with_label_values(&["deduplicated"]).inc();
+            return;
+        }
+        match self.sender.try_send(IndexRequest { pid, exe }) {
+            Ok(()) => {
+                m.index_queue.with_label_values(&["enqueued"]).inc();
+                m.index_queue_depth.inc();
+            }
+            Err(TrySendError::Full(req)) | Err(TrySendError::Disconnected(req)) => {
+                self.pending.lock().unwrap().remove(&req.exe);
+                m.index_queue.with_label_values(&["dropped"]).inc();
+            }
+        }
+    }
+}
+
+fn run(receiver: Receiver<IndexRequest>, pending: Arc<Mutex<HashSet<(u64, u64)>>>, options: ElfTableOptions) {
+    for req in receiver {
+        options.metrics.index_queue_depth.dec();
+        let result = match index(req.pid, &options) {
+            Ok(()) => "indexed",
+            Err(err) => {
+                debug!("indexing executable of pid {}: {}", req.pid, err);
+                "failed"
+            }
+        };
+        options.metrics.index_queue.with_label_values(&[result]).inc();
+        pending.lock().unwrap().remove(&req.exe);
+    }
+}
+
+// index loads the elf table of the executable mapping of pid, which caches its symbols by build id
+// or by file
+fn index(pid: u32, options: &ElfTableOptions) -> Result<()> {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid))
+        .map_err(|e| Error::proc_error(pid, format!("read exe: {}", e)))?;
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|e| Error::proc_error(pid, format!("read maps: {}", e)))?;
+    let map = parse_proc_maps(&maps, true)?
+        .into_iter()
+        .find(|m| Path::new(&m.pathname) == exe)
+        .ok_or_else(|| NotFound(format!("no executable mapping of {} in pid {}", exe.display(), pid)))?;
+    let mut table = ElfTable::new(
+        Arc::new(Mutex::new(map)),
+        format!("/proc/{}/root", pid),
+        mount_namespace(pid as i32),
+        options.clone(),
+    );
+    table.load();
+    match table.err.take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
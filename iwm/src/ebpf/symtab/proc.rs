@@ -5,39 +5,58 @@ use std::fs;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use log::info;
+use serde::{Deserialize, Serialize};
 
 
 use crate::ebpf::symtab::elf::symbol_table::SymTabDebugInfo;
 use crate::ebpf::symtab::elf_module::{ElfTable, ElfTableOptions};
 use crate::ebpf::symtab::gcache::Resource;
-use crate::ebpf::symtab::procmap::{File, ProcMap, ProcMapPermissions};
+use crate::ebpf::symtab::perf_symbol_table::PerfSymbolTable;
+use crate::ebpf::symtab::procmap::{parse_proc_maps, File, ProcMap};
+use crate::ebpf::symtab::stat::mount_namespace;
 use crate::ebpf::symtab::symtab::SymbolTable;
 use crate::ebpf::symtab::table::Symbol;
-use crate::error::Error::ProcError;
+use crate::error::Error;
 use crate::error::Result;
 
 pub struct ProcTable {
-    ranges: Vec<Arc<Mutex<ElfRange>>>,
+    ranges: Vec<ElfRange>,
+    tables: Vec<Arc<Mutex<ElfTable>>>,
     file_to_table: HashMap<File, Arc<Mutex<ElfTable>>>,
     root_fs: PathBuf,
     err: Option<crate::error::Error>,
     pid: i32,
+    // mnt_ns is the mount namespace of the pid, see Stat
+    mnt_ns: u64,
     elf_table_options: ElfTableOptions,
+    // maps_changed is set by the mmap events of the pid, the maps are only re-read when they changed
+    // or when maps_read is older than PROC_MAPS_MAX_AGE, in case events were lost
+    maps_changed: bool,
+    maps_read: Option<Instant>,
+    // perf_map resolves the pcs outside of the mapped files, the JIT compiled code of a JVM
+    perf_map: Option<PerfSymbolTable>,
 }
 unsafe impl Sync for ProcTable {}
 
-#[derive(Debug)]
+// PROC_MAPS_MAX_AGE is how long /proc/pid/maps is trusted without an mmap event
+const PROC_MAPS_MAX_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ProcTableDebugInfo {
-    elf_tables: HashMap<String, SymTabDebugInfo>,
-    size: usize,
-    pid: i32,
-    pub(crate) last_used_round: i32,
+    pub elf_tables: HashMap<String, SymTabDebugInfo>,
+    pub size: usize,
+    pub pid: i32,
+    pub last_used_round: i32,
 }
 
+// ElfRange is an executable mapping of the process, table indexes into ProcTable.tables
 pub struct ElfRange {
-    map_range: Arc<Mutex<ProcMap>>,
-    elf_table: Arc<Mutex<ElfTable>>,
+    start: u64,
+    end: u64,
+    table: usize,
+    pathname: String,
 }
 
 impl Resource for ProcTable {
@@ -54,15 +73,25 @@ impl SymbolTable for ProcTable {
         if self.err.is_some() {
             return;
         }
+        if let Some(perf_map) = self.perf_map.as_mut() {
+            perf_map.refresh();
+        }
+        let fresh = self.maps_read.is_some_and(|read| read.elapsed() < PROC_MAPS_MAX_AGE);
+        if fresh && !self.maps_changed {
+            return;
+        }
+        self.maps_changed = false;
+        self.maps_read = Some(Instant::now());
         let path = format!("/proc/{}/maps", self.pid.to_string());
         self.ranges.clear();
+        self.tables.clear();
         match fs::read_to_string(&path) {
             Ok(proc_maps) => match self.push_proc_maps(proc_maps) {
                 Err(e) => { self.err = Some(e); }
                 _ => {}
             },
             Err(e) => {
-                self.err = Some(ProcError(e.to_string()));
+                self.err = Some(Error::proc_error(self.pid as u32, format!("read {}: {}", path, e)));
             }
         }
     }
@@ -88,32 +117,20 @@ impl SymbolTable for ProcTable {
             .binary_search_by(|e| binary_search_elf_range(e, pc));
 
         if i.is_err() {
-            return Some(Symbol::default());
+            return self.perf_map.as_mut()
+                .and_then(|perf_map| perf_map.resolve(pc))
+                .or(Some(Symbol::default()));
         }
 
-        let rr = &self.ranges.get_mut(i.unwrap()).unwrap();
-        let r = rr.lock().unwrap();
-        let mut et = r.elf_table.lock().unwrap();
+        let r = &self.ranges[i.unwrap()];
+        let mut et = self.tables[r.table].lock().unwrap();
         let module_offset = pc - et.base;
 
-        return match et.resolve(pc) {
-            Some(s) => {
-                let mr = r.map_range.lock().unwrap();
-                Some(Symbol {
-                    start: module_offset,
-                    name: s,
-                    module: mr.pathname.clone(),
-                })
-            }
-            None => {
-                let mr = r.map_range.lock().unwrap();
-                Some(Symbol {
-                    start: module_offset,
-                    name: "".to_string(),
-                    module: mr.pathname.clone(),
-                })
-            }
-        };
+        Some(Symbol {
+            start: module_offset,
+            name: et.resolve(pc).unwrap_or_default(),
+            module: r.pathname.clone(),
+        })
     }
 }
 
@@ -139,12 +156,10 @@ where
     (i, i < x.len() && cmp(&x[i], &target) == Equal)
 }
 
-fn binary_search_elf_range(e: &Arc<Mutex<ElfRange>>, pc: u64) -> std::cmp::Ordering {
-    let m = e.lock().unwrap();
-    let mr = m.map_range.lock().unwrap();
-    if pc < mr.start_addr {
+fn binary_search_elf_range(e: &ElfRange, pc: u64) -> std::cmp::Ordering {
+    if pc < e.start {
         Greater
-    } else if pc >= mr.end_addr {
+    } else if pc >= e.end {
         Less
     } else {
         Equal
@@ -155,31 +170,67 @@ impl ProcTable {
     pub(crate) fn new(pid: i32, elf_table_options: ElfTableOptions) -> Self {
         Self {
             ranges: Vec::new(),
+            tables: Vec::new(),
             file_to_table: HashMap::new(),
             pid,
+            mnt_ns: mount_namespace(pid),
             elf_table_options,
             root_fs: PathBuf::from(format!("/proc/{}/root", pid.to_string())),
             err: None,
+            maps_changed: true,
+            maps_read: None,
+            perf_map: None,
+        }
+    }
+
+    // enable_perf_map makes the pcs outside of the mapped files resolve through the perf map of the
+    // process, for runtimes that JIT compile code and write one
+    pub(crate) fn enable_perf_map(&mut self) {
+        if self.perf_map.is_none() {
+            self.perf_map = Some(PerfSymbolTable::new(self.pid));
+        }
+    }
+
+    // mark_maps_changed makes the next refresh re-read the maps, the process mapped code
+    pub(crate) fn mark_maps_changed(&mut self) {
+        self.maps_changed = true;
+    }
+
+    // inherit_tables seeds the tables of a worker forked from parent without exec. The worker maps the
+    // same files at the same addresses, so the parent's tables resolve its pcs and are shared instead of
+    // loading identical copies for every worker. Tables the worker remapped are replaced on refresh.
+    pub(crate) fn inherit_tables(&mut self, parent: &ProcTable) {
+        for (file, table) in &parent.file_to_table {
+            self.file_to_table.insert(file.clone(), table.clone());
         }
     }
 
     fn push_proc_maps(&mut self, proc_maps: String) -> Result<()> {
         let mut files_to_keep: HashMap<File, ()> = HashMap::new();
-        let maps = match parse_proc_maps_executable_modules(proc_maps.deref(), true) {
+        let maps = match parse_proc_maps(proc_maps.deref(), true) {
             Ok(maps) => maps,
             Err(err) => return Err(err),
         };
 
+        let mut table_index: HashMap<File, usize> = HashMap::new();
         for map in maps {
-            files_to_keep.insert(map.file(), ());
-            let m = Arc::new(Mutex::new(map));
-            if let Some(elf_table) = self.get_elf_table(m.clone()) {
-                self.ranges.push(Arc::new(Mutex::new(ElfRange {
-                    map_range: m.clone(),
-                    elf_table,
-                })));
-            }
+            let file = map.file();
+            files_to_keep.insert(file.clone(), ());
+            let (start, end, pathname) = (map.start_addr, map.end_addr, map.pathname.clone());
+            let table = match table_index.get(&file) {
+                Some(&idx) => idx,
+                None => match self.get_elf_table(Arc::new(Mutex::new(map))) {
+                    Some(elf_table) => {
+                        self.tables.push(elf_table);
+                        table_index.insert(file, self.tables.len() - 1);
+                        self.tables.len() - 1
+                    }
+                    None => continue,
+                },
+            };
+            self.ranges.push(ElfRange { start, end, table, pathname });
         }
+        self.ranges.sort_by_key(|r| r.start);
 
         let mut keys_to_remove = Vec::new();
         for (key, _value) in self.file_to_table.iter() {
@@ -216,7 +267,8 @@ impl ProcTable {
             let r = rr.lock().unwrap();
             //dbg!(self.file_to_table.len());
             let a = self.file_to_table.get(&r.clone().file());
-            if a.is_some() {
+            // a table of the same file mapped at another address has a stale base
+            if a.is_some() && a.unwrap().lock().unwrap().mapped_at(r.start_addr) {
                 return Some(a.unwrap().clone());
             }
         }
@@ -244,107 +296,25 @@ impl ProcTable {
         Some(Arc::new(Mutex::new(ElfTable::new(
             m,
             self.root_fs.to_str().unwrap().to_string(),
+            self.mnt_ns,
             self.elf_table_options.clone(),
         ))))
     }
 }
 
-pub fn parse_proc_maps_executable_modules(
-    proc_maps: &str,
-    executable_only: bool,
-) -> Result<Vec<ProcMap>> {
-    let mut modules = Vec::new();
-    let mut remaining = proc_maps;
-    while !remaining.is_empty() {
-        let nl = remaining
-            .chars()
-            .position(|x| x == '\n')
-            .unwrap_or(remaining.len());
-        let (line, rest) = remaining.split_at(nl);
-        remaining = if rest.is_empty() { rest } else { &rest[1..] };
-        if line.is_empty() {
-            continue;
-        }
-        if let Some(module) = parse_proc_map_line(line, executable_only) {
-            modules.push(module);
-        }
-    }
-    Ok(modules)
-}
-
-// 7f5822ebe000-7f5822ec0000 r--p 00000000 09:00 533429  /usr/lib/x86_64-linux-gnu/ld-linux-x86-64.so.2
-fn parse_proc_map_line(line: &str, executable_only: bool) -> Option<ProcMap> {
-    let mut parts = line.split(' ');
-    let addresses_bytes = parts.next().unwrap();
-    let perms_bytes = parts.next().unwrap();
-    let offset_bytes = parts.next().unwrap();
-    let device_bytes = parts.next().unwrap();
-    let inode_bytes = parts.next().unwrap();
-    let pathname = line.rsplit(' ').next().unwrap();
-
-    let perms = parse_permissions(perms_bytes).unwrap();
-    if executable_only && !perms.execute {
+// forked_from returns the parent of pid when pid was forked from it without exec, i.e. both run the same executable
+pub(crate) fn forked_from(pid: u32) -> Option<u32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm may contain spaces and parens, the fields after it are state and ppid
+    let (_, fields) = stat.rsplit_once(')')?;
+    let ppid: u32 = fields.split_whitespace().nth(1)?.parse().ok()?;
+    if ppid <= 1 {
         return None;
     }
-
-    let (start_addr, end_addr) = parse_addresses(addresses_bytes).unwrap();
-    let offset = i64::from_str_radix(offset_bytes, 16).unwrap();
-    let dev = parse_device(device_bytes).unwrap();
-    let inode = u64::from_str_radix(inode_bytes, 10).unwrap();
-
-    let res = ProcMap {
-        start_addr,
-        end_addr,
-        perms,
-        offset,
-        dev,
-        inode,
-        pathname: pathname.to_string(),
-    };
-    Some(res)
-}
-
-fn parse_permissions(perms_bytes: &str) -> Result<ProcMapPermissions> {
-    let mut perms = ProcMapPermissions {
-        read: false,
-        write: false,
-        execute: false,
-        private: false,
-        shared: false
-    };
-
-    for b in perms_bytes.chars() {
-        match b {
-            'r' => perms.read = true,
-            'w' => perms.write = true,
-            'x' => perms.execute = true,
-            'p' => perms.private = true,
-            _ => {},
-        }
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+    let parent_exe = fs::read_link(format!("/proc/{}/exe", ppid)).ok()?;
+    if exe != parent_exe {
+        return None;
     }
-    Ok(perms)
-}
-
-fn parse_addresses(addresses_bytes: &str) -> Result<(u64, u64), ()> {
-    let mut parts = addresses_bytes.split(|b| b == '-');
-    let start_addr_str = parts.next().unwrap();
-    let end_addr_str = parts.next().unwrap();
-
-    let start_addr = u64::from_str_radix(start_addr_str, 16).unwrap();
-    let end_addr = u64::from_str_radix(end_addr_str, 16).unwrap();
-
-    Ok((start_addr, end_addr))
-}
-
-fn parse_device(device_str: &str) -> Result<u64, ()> {
-    let mut parts = device_str.split(':');
-    let major = u64::from_str_radix(parts.next().unwrap_or(""), 16).unwrap();
-    let minor = u64::from_str_radix(parts.next().unwrap_or(""), 16).unwrap();
-    Ok((major << 20) | minor)
-}
-
-fn token_to_string_unsafe(tok: &[u8]) -> String {
-    let ptr = tok.as_ptr();
-    let len = tok.len();
-    unsafe { String::from_utf8_unchecked(Vec::from_raw_parts(ptr as *mut u8, len, len)) }
+    Some(ppid)
 }
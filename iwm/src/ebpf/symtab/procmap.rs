@@ -1,5 +1,5 @@
-use std::io::{self, BufRead};
-use std::str::FromStr;
+use crate::error::Error;
+use crate::error::Result;
 
 // ProcMapPermissions contains permission settings read from `/proc/[pid]/maps`.
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Clone)]
@@ -74,51 +74,204 @@ impl Default for ProcMap {
     }
 }
 
+// parse_proc_maps parses the lines of /proc/[pid]/maps, optionally only the executable mappings
+pub fn parse_proc_maps(proc_maps: &str, executable_only: bool) -> Result<Vec<ProcMap>> {
+    let mut maps = Vec::new();
+    for line in proc_maps.lines().filter(|l| !l.is_empty()) {
+        let map = parse_proc_map_line(line)?;
+        if executable_only && !map.perms.execute {
+            continue;
+        }
+        maps.push(map);
+    }
+    Ok(maps)
+}
+
+// parse_proc_map_line parses a line of /proc/[pid]/maps:
+//
+// 7f5822ebe000-7f5822ec0000 r--p 00000000 09:00 533429                     /usr/lib/x86_64-linux-gnu/ld-linux-x86-64.so.2
+//
+// The pathname is everything after the inode and its padding, so it keeps spaces and the " (deleted)"
+// suffix of unlinked files. Anonymous mappings have an empty pathname.
+pub fn parse_proc_map_line(line: &str) -> Result<ProcMap> {
+    let invalid = |what: &str| Error::invalid_data(format!("proc map {}: {:?}", what, line));
+    let mut rest = line;
+    let mut field = || {
+        let trimmed = rest.trim_start_matches(' ');
+        let end = trimmed.find(' ').unwrap_or(trimmed.len());
+        let (f, r) = trimmed.split_at(end);
+        rest = r;
+        f
+    };
+    let (addresses, perms, offset, device, inode) = (field(), field(), field(), field(), field());
+    let pathname = rest.trim_start_matches(' ');
+
+    let (start_addr, end_addr) = parse_addresses(addresses).ok_or_else(|| invalid("addresses"))?;
+    let perms = parse_permissions(perms).ok_or_else(|| invalid("permissions"))?;
+    let offset = i64::from_str_radix(offset, 16).map_err(|_| invalid("offset"))?;
+    let dev = parse_device(device).ok_or_else(|| invalid("device"))?;
+    let inode = inode.parse::<u64>().map_err(|_| invalid("inode"))?;
+
+    Ok(ProcMap {
+        start_addr,
+        end_addr,
+        pathname: pathname.to_string(),
+        offset,
+        perms,
+        dev,
+        inode,
+    })
+}
+
 fn parse_permissions(s: &str) -> Option<ProcMapPermissions> {
-    if s.len() < 4 {
+    let b = s.as_bytes();
+    if b.len() != 4 {
         return None;
     }
     Some(ProcMapPermissions {
-        read: s.contains('r'),
-        write: s.contains('w'),
-        execute: s.contains('x'),
-        shared: s.contains('s'),
-        private: s.contains('p'),
+        read: b[0] == b'r',
+        write: b[1] == b'w',
+        execute: b[2] == b'x',
+        shared: b[3] == b's',
+        private: b[3] == b'p',
     })
 }
 
-fn parse_address(s: &str) -> Result<u64, std::num::ParseIntError> {
-    u64::from_str_radix(s, 16)
+fn parse_addresses(s: &str) -> Option<(u64, u64)> {
+    let (start, end) = s.split_once('-')?;
+    Some((u64::from_str_radix(start, 16).ok()?, u64::from_str_radix(end, 16).ok()?))
 }
 
-fn parse_device(s: &str) -> Result<u64, io::Error> {
-    let parts: Vec<&str> = s.split(':').collect();
-    if parts.len() != 2 {
-        return Err(io::Error::new(io::ErrorKind::Other, "Unexpected number of fields"));
-    }
-
-    let major = u32::from_str_radix(parts[0], 16).unwrap();
-    let minor = u32::from_str_radix(parts[1], 16).unwrap();
-
-    Ok(mkdev(major, minor))
+// parse_device parses major:minor into a dev_t as stat(2) reports it, so proc maps and stats of
+// the same file agree
+fn parse_device(s: &str) -> Option<u64> {
+    let (major, minor) = s.split_once(':')?;
+    Some(mkdev(u32::from_str_radix(major, 16).ok()?, u32::from_str_radix(minor, 16).ok()?))
 }
 
 fn mkdev(major: u32, minor: u32) -> u64 {
-    let dev = ((major & 0x00000fff) as u64) << 8;
-    let dev = dev | ((major & 0xfffff000) as u64) << 32;
-    let dev = dev | ((minor & 0x000000ff) as u64) << 0;
-    let dev = dev | ((minor & 0xffffff00) as u64) << 12;
-    dev
+    (((major & 0x00000fff) as u64) << 8)
+        | (((major & 0xfffff000) as u64) << 32)
+        | ((minor & 0x000000ff) as u64)
+        | (((minor & 0xffffff00) as u64) << 12)
 }
 
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // CORPUS is the maps of a containerized JVM, with an unlinked library, a path with spaces and the
+    // kernel's special mappings
+    const CORPUS: &str = "\
+55d4a6b4f000-55d4a6b50000 r--p 00000000 fd:01 2101342                    /usr/lib/jvm/java-17-openjdk-amd64/bin/java
+55d4a6b50000-55d4a6b51000 r-xp 00001000 fd:01 2101342                    /usr/lib/jvm/java-17-openjdk-amd64/bin/java
+55d4a7f3e000-55d4a7f5f000 rw-p 00000000 00:00 0                          [heap]
+7f0e3c000000-7f0e3c021000 rw-p 00000000 00:00 0 
+7f0e44a00000-7f0e45a6e000 r-xp 00000000 fd:01 2101410                    /usr/lib/jvm/java-17-openjdk-amd64/lib/server/libjvm.so
+7f0e45c31000-7f0e45c33000 r-xp 00002000 00:2d 1311                       /tmp/libnetty_transport_native_epoll_x86_6412345.so (deleted)
+7f0e45d00000-7f0e45d10000 r-xp 00000000 103:02 9876543                   /opt/app data/lib/native helper.so
+7f0e45e00000-7f0e45e01000 rw-s 00000000 00:01 4096                       /dev/shm/hsperfdata_1000 (deleted)
+7ffc8a0e5000-7ffc8a106000 rw-p 00000000 00:00 0                          [stack]
+7ffc8a1f6000-7ffc8a1f8000 r-xp 00000000 00:00 0                          [vdso]
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0                  [vsyscall]
+";
+
+    // line renders a mapping the way the kernel does, padding the inode to the pathname column
+    fn line(start: u64, end: u64, perms: &str, offset: u64, (major, minor): (u32, u32), inode: u64, pathname: &str) -> String {
+        let head = format!("{:08x}-{:08x} {} {:08x} {:02x}:{:02x} {}", start, end, perms, offset, major, minor, inode);
+        if pathname.is_empty() {
+            format!("{} ", head)
+        } else {
+            format!("{:<72} {}", head, pathname)
+        }
+    }
+
+    fn pathname() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(String::new()),
+            prop::sample::select(vec!["[heap]", "[stack]", "[vdso]", "[vvar]", "[anon:libc_malloc]"]).prop_map(String::from),
+            ("(/[a-zA-Z0-9._+-][a-zA-Z0-9._+ -]{0,15}){1,4}", any::<bool>())
+                .prop_map(|(path, deleted)| if deleted { format!("{} (deleted)", path) } else { path }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn parses_every_field_back(
+            start in any::<u64>(),
+            len in any::<u64>(),
+            perms in "[r-][w-][x-][ps]",
+            offset in 0..=i64::MAX as u64,
+            major in any::<u32>(),
+            minor in any::<u32>(),
+            inode in any::<u64>(),
+            pathname in pathname(),
+        ) {
+            let end = start.saturating_add(len);
+            let map = parse_proc_map_line(&line(start, end, &perms, offset, (major, minor), inode, &pathname)).unwrap();
+            let p = perms.as_bytes();
+            prop_assert_eq!(map.start_addr, start);
+            prop_assert_eq!(map.end_addr, end);
+            prop_assert_eq!(map.offset, offset as i64);
+            prop_assert_eq!(map.perms.read, p[0] == b'r');
+            prop_assert_eq!(map.perms.write, p[1] == b'w');
+            prop_assert_eq!(map.perms.execute, p[2] == b'x');
+            prop_assert_eq!(map.perms.shared, p[3] == b's');
+            prop_assert_eq!(map.perms.private, p[3] == b'p');
+            prop_assert_eq!(map.dev, mkdev(major, minor));
+            prop_assert_eq!(map.inode, inode);
+            prop_assert_eq!(map.pathname, pathname);
+        }
+
+        #[test]
+        fn never_panics(line in "\\PC*") {
+            let _ = parse_proc_map_line(&line);
+        }
+
+        #[test]
+        fn rejects_truncated_lines(cut in 0..CORPUS.lines().next().unwrap().find(" fd:01").unwrap()) {
+            let first = CORPUS.lines().next().unwrap();
+            prop_assert!(parse_proc_map_line(&first[..cut]).is_err());
+        }
+
+        #[test]
+        fn executable_only_keeps_the_executable_mappings(lines in prop::sample::subsequence(CORPUS.lines().collect::<Vec<_>>(), 0..=11)) {
+            let maps = lines.join("\n");
+            let all = parse_proc_maps(&maps, false).unwrap();
+            let executable = parse_proc_maps(&maps, true).unwrap();
+            prop_assert_eq!(all.len(), lines.len());
+            prop_assert_eq!(executable, all.into_iter().filter(|m| m.perms.execute).collect::<Vec<_>>());
+        }
+    }
 
-fn parse_addresses(s: &str) -> Result<(u64, u64), &'static str> {
-    let i = s.chars().position(|b| b == '-').ok_or("Invalid address").unwrap();
-    let (saddr_bytes, eaddr_bytes) = s.split_at(i);
-    let eaddr_bytes = &eaddr_bytes[1..]; // Move to next byte of '-'
+    #[test]
+    fn parses_the_corpus() {
+        let maps = parse_proc_maps(CORPUS, false).unwrap();
+        assert_eq!(maps.len(), CORPUS.lines().count());
+        assert_eq!(maps[3].pathname, "");
+        assert_eq!(maps[5].pathname, "/tmp/libnetty_transport_native_epoll_x86_6412345.so (deleted)");
+        assert_eq!(maps[6].pathname, "/opt/app data/lib/native helper.so");
+        assert_eq!(maps[6].dev, mkdev(0x103, 0x02));
+        assert_eq!(maps[10].start_addr, 0xffffffffff600000);
 
-    let saddr = parse_address(saddr_bytes).unwrap();
-    let eaddr = parse_address(eaddr_bytes).unwrap();
+        let executable = parse_proc_maps(CORPUS, true).unwrap();
+        assert_eq!(executable.len(), 6);
+        assert!(executable.iter().all(|m| m.perms.execute && m.perms.private));
+    }
 
-    Ok((saddr, eaddr))
-}
\ No newline at end of file
+    // the maps of the test process itself are a real corpus of whatever kernel runs the tests
+    #[test]
+    fn parses_the_maps_of_this_process() {
+        let proc_maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let maps = parse_proc_maps(&proc_maps, false).unwrap();
+        assert_eq!(maps.len(), proc_maps.lines().count());
+        for (map, line) in maps.iter().zip(proc_maps.lines()) {
+            assert!(map.start_addr < map.end_addr, "{}", line);
+            let pathname = line.split_whitespace().skip(5).collect::<Vec<_>>().join(" ");
+            assert_eq!(map.pathname.split_whitespace().collect::<Vec<_>>().join(" "), pathname, "{}", line);
+        }
+        assert!(maps.iter().any(|m| m.perms.execute && m.pathname.starts_with('/')));
+    }
+}
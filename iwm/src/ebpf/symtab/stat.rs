@@ -1,46 +1,51 @@
-
 use std::fs;
-#[cfg(target_os = "linux")]
 use std::os::unix::fs::MetadataExt;
 
-#[cfg(target_os = "linux")]
+// Stat identifies a file by its device and inode rather than its path, so a library shared by the
+// image layers of many containers is indexed once instead of once per container rootfs path. The
+// modification time tells a file rewritten in place from the one that was indexed.
+// Anonymous devices (major 0: overlay upper dirs, tmpfs, fuse) are numbered per mount and the numbers
+// are reused once unmounted, so their files are also keyed by the mount namespace they were seen in.
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub struct Stat {
     dev: u64,
     inode: u64,
+    // mtime is the modification time in nanoseconds
+    mtime: i64,
+    mnt_ns: u64,
 }
 
-#[cfg(target_os = "linux")]
 impl Stat {
-    fn from_file_info(file_info: &fs::Metadata) -> Self {
-        let dev = file_info.dev();
-        let inode = file_info.ino();
-        Stat { dev, inode }
+    pub fn new(dev: u64, inode: u64, mtime: i64, mnt_ns: u64) -> Self {
+        let mnt_ns = if dev_major(dev) == 0 { mnt_ns } else { 0 };
+        Stat { dev, inode, mtime, mnt_ns }
+    }
+
+    fn from_file_info(file_info: &fs::Metadata, mnt_ns: u64) -> Self {
+        Stat::new(file_info.dev(), file_info.ino(), mtime(file_info), mnt_ns)
     }
 }
 
-#[cfg(target_os = "linux")]
-pub fn stat_from_file_info(file_info: &fs::Metadata) -> Stat {
-    Stat::from_file_info(file_info)
+pub fn stat_from_file_info(file_info: &fs::Metadata, mnt_ns: u64) -> Stat {
+    Stat::from_file_info(file_info, mnt_ns)
 }
 
-#[cfg(target_os = "macos")]
-#[derive(Eq, PartialEq, Clone, Copy, Hash)]
-pub struct Stat {
-    dev: u64,
-    inode: u64,
+// stat_from_mapping keys the file by the device and inode of a /proc/pid/maps entry, those of the file
+// backing an overlay, with the modification time of file_info
+pub fn stat_from_mapping(dev: u64, inode: u64, file_info: &fs::Metadata, mnt_ns: u64) -> Stat {
+    Stat::new(dev, inode, mtime(file_info), mnt_ns)
 }
 
-#[cfg(target_os = "macos")]
-impl Stat {
-    fn from_file_info(file_info: &fs::Metadata) -> Self {
-        let dev = file_info.dev();
-        let inode = file_info.ino();
-        Stat { dev, inode }
-    }
+fn mtime(file_info: &fs::Metadata) -> i64 {
+    file_info.mtime().saturating_mul(1_000_000_000).saturating_add(file_info.mtime_nsec())
 }
 
-#[cfg(target_os = "macos")]
-pub fn stat_from_file_info(file_info: &fs::Metadata) -> Stat {
-    Stat::from_file_info(file_info)
-}
\ No newline at end of file
+// mount_namespace returns the inode of the mount namespace of the pid, 0 when it can't be read
+pub fn mount_namespace(pid: i32) -> u64 {
+    fs::metadata(format!("/proc/{}/ns/mnt", pid)).map(|m| m.ino()).unwrap_or(0)
+}
+
+// dev_major is the inverse of the major part of mkdev in procmap.rs
+fn dev_major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xfffff000)
+}
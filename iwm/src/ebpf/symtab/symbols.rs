@@ -4,24 +4,29 @@ use log::{error, info};
 use crate::ebpf::metrics::symtab::SymtabMetrics;
 
 
-use crate::ebpf::symtab::elf_cache::{ElfCache, ElfCacheDebugInfo};
+use crate::ebpf::symtab::elf_cache::{ElfCache, ElfCacheDebugInfo, ElfTableMemory};
 use crate::ebpf::symtab::elf_module::{ElfTableOptions, SymbolOptions};
 use crate::ebpf::symtab::gcache::{debug_info, GCache, GCacheDebugInfo, GCacheOptions};
-use crate::ebpf::symtab::kallsyms::new_kallsyms;
-use crate::ebpf::symtab::proc::{ProcTable, ProcTableDebugInfo};
+use crate::ebpf::symtab::kallsyms::{KallsymsIndex, new_kallsyms};
+use crate::ebpf::symtab::prewarm::IndexQueue;
+use crate::ebpf::symtab::proc::{forked_from, ProcTable, ProcTableDebugInfo};
 use crate::ebpf::symtab::symtab::SymbolNameResolver;
-use crate::ebpf::symtab::table::SymbolTab;
 use crate::error::Result;
 
 pub type PidKey = u32;
 
+// INDEX_QUEUE_CAPACITY bounds the executables waiting to be indexed, exec storms beyond it are
+// indexed by the rounds
+const INDEX_QUEUE_CAPACITY: usize = 256;
+
 // SymbolCache is responsible for resolving PC address to Symbol
 // maintaining a pid -> ProcTable cache
 // resolving kernel symbols
 pub struct SymbolCache {
     pid_cache: GCache<PidKey, ProcTable>,
     elf_cache: Arc<ElfCache>,
-    kallsyms: Option<Arc<Mutex<SymbolTab>>>,
+    kallsyms: Option<Arc<KallsymsIndex>>,
+    index_queue: IndexQueue,
     options: CacheOptions,
     metrics: Arc<SymtabMetrics>,
 }
@@ -41,13 +46,16 @@ impl SymbolCache {
         // }
         let elf_cache = ElfCache::new(options.build_id_cache_options, options.same_file_cache_options).unwrap();
         let pid_cache = GCache::<PidKey, ProcTable>::new(options.pid_cache_options);
+        let elf_cache = Arc::new(elf_cache);
+        let metrics = Arc::new(metrics.clone());
 
         Ok(Self {
             pid_cache,
             kallsyms: None,
-            elf_cache: Arc::new(elf_cache),
+            index_queue: IndexQueue::new(INDEX_QUEUE_CAPACITY, elf_cache.clone(), metrics.clone()),
+            elf_cache,
             options,
-            metrics: Arc::new(metrics.clone()),
+            metrics,
         })
     }
 
@@ -59,6 +67,28 @@ impl SymbolCache {
     pub fn cleanup(&mut self) {
         self.elf_cache.cleanup();
         self.pid_cache.cleanup();
+        self.report_cache_stats();
+    }
+
+    fn report_cache_stats(&mut self) {
+        let (build_id, same_file) = self.elf_cache.take_stats();
+        let caches = [
+            ("pid", self.pid_cache.take_stats()),
+            ("build_id", build_id),
+            ("same_file", same_file),
+        ];
+        for (name, stats) in caches {
+            self.metrics.cache_hits.with_label_values(&[name]).inc_by(stats.hits as f64);
+            self.metrics.cache_misses.with_label_values(&[name]).inc_by(stats.misses as f64);
+            self.metrics.cache_evictions.with_label_values(&[name]).inc_by(stats.evictions as f64);
+            if let Some(ratio) = stats.hit_ratio() {
+                self.metrics.cache_hit_ratio.with_label_values(&[name]).set(ratio);
+            }
+        }
+    }
+
+    pub fn heaviest_elf_tables(&self, limit: usize) -> Vec<ElfTableMemory> {
+        self.elf_cache.heaviest_tables(limit)
     }
 
     pub fn get_proc_table(&mut self, pid: PidKey) -> Option<Arc<Mutex<ProcTable>>> {
@@ -67,42 +97,69 @@ impl SymbolCache {
             return Some(cached.clone());
         }
         info!("sym_cache.get_proc_table({})", &pid);
-        let fresh = Arc::new(Mutex::new(ProcTable::new(
+        let mut table = ProcTable::new(
             pid as i32,
             ElfTableOptions {
                 elf_cache: self.elf_cache.clone(),
                 metrics: self.metrics.clone()
             },
-        )));
+        );
+        // prefork servers (uwsgi, gunicorn) fork workers without exec, they share the tables of the master
+        if let Some(parent) = forked_from(pid).and_then(|ppid| self.pid_cache.get(&ppid)) {
+            table.inherit_tables(&parent.lock().unwrap());
+            self.metrics.forked_proc_tables.inc();
+        }
+        let fresh = Arc::new(Mutex::new(table));
         self.pid_cache.cache(pid, fresh.clone());
         Some(fresh.clone())
     }
 
-    pub fn get_kallsyms(&mut self) -> Arc<Mutex<SymbolTab>> {
+    // index_executable loads the symbols of the executable of a freshly exec'd pid in the background
+    pub fn index_executable(&self, pid: PidKey) {
+        self.index_queue.enqueue(pid);
+    }
+
+    // maps_changed makes the proc table of the pid re-read its maps before it's used next
+    pub fn maps_changed(&self, pid: PidKey) {
+        if let Some(table) = self.pid_cache.peek(&pid) {
+            table.lock().unwrap().mark_maps_changed();
+        }
+    }
+
+    pub fn get_kallsyms(&mut self) -> Arc<KallsymsIndex> {
         if let Some(kallsyms) = &self.kallsyms {
             return kallsyms.clone();
         }
         self.init_kallsyms()
     }
 
-    fn init_kallsyms(&mut self) -> Arc<Mutex<SymbolTab>> {
+    // refresh_kallsyms re-reads the kernel symbols and swaps in the new index.
+    // Rounds that already captured the old index keep using it until they finish.
+    pub fn refresh_kallsyms(&mut self) {
+        if self.kallsyms.is_some() {
+            self.init_kallsyms();
+        }
+    }
+
+    fn init_kallsyms(&mut self) -> Arc<KallsymsIndex> {
         let kallsyms = new_kallsyms().unwrap_or_else(|err| {
             error!("kallsyms init fail err: {}", err);
-            SymbolTab::new(Vec::new())
+            KallsymsIndex::new_empty()
         });
 
-        if kallsyms.symbols.is_empty() {
+        if kallsyms.is_empty() {
             let _ = error!("kallsyms is empty. check your permissions kptr_restrict==0 && sysctl_perf_event_paranoid <= 1 or kptr_restrict==1 &&  CAP_SYSLOG");
         }
 
-        let ks = Arc::new(Mutex::new(kallsyms));
+        let ks = Arc::new(kallsyms);
         self.kallsyms = Some(ks.clone());
-        ks.clone()
+        ks
     }
 
     pub fn update_options(&mut self, options: CacheOptions) {
         self.pid_cache.update(options.pid_cache_options);
         self.elf_cache.update(options.build_id_cache_options, options.same_file_cache_options);
+        self.options = options;
     }
 
     pub fn pid_cache_debug_info(&self) -> GCacheDebugInfo<ProcTableDebugInfo> {
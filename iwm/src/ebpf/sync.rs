@@ -4,6 +4,7 @@ pub enum ProfilingType {
     FramePointers,
     Python,
     TypeError,
+    Java,
 }
 
 impl ProfilingType {
@@ -13,6 +14,7 @@ impl ProfilingType {
             ProfilingType::FramePointers => { 2 }
             ProfilingType::Python => { 3 }
             ProfilingType::TypeError => { 4 }
+            ProfilingType::Java => { 5 }
         }
     }
 }
@@ -20,12 +22,14 @@ impl ProfilingType {
 // #define OP_REQUEST_UNKNOWN_PROCESS_INFO 1
 // #define OP_PID_DEAD 2
 // #define OP_REQUEST_EXEC_PROCESS_INFO 3
+// #define OP_MAPS_CHANGED 4
 
 #[derive(Debug)]
 pub enum PidOp {
     RequestUnknownProcessInfo = 1,
     Dead = 2,
     RequestExecProcessInfo = 3,
+    MapsChanged = 4,
 }
 
 impl PidOp {
@@ -34,6 +38,7 @@ impl PidOp {
             PidOp::RequestUnknownProcessInfo => { 1 }
             PidOp::Dead => { 2 }
             PidOp::RequestExecProcessInfo => { 3 }
+            PidOp::MapsChanged => { 4 }
         }
     }
 }
\ No newline at end of file
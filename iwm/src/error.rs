@@ -4,30 +4,32 @@ use thiserror::Error;
 pub enum Error {
     #[error("data not found: {0}")]
     NotFound(String),
-    #[error("invalid data: {0}")]
-    InvalidData(String),
+    #[error("invalid data: {reason}{}", path.as_ref().map(|p| format!(" ({})", p)).unwrap_or_default())]
+    InvalidData { path: Option<String>, reason: String },
     #[error("must be paused")]
     MustBePaused,
     #[error("closed")]
     Closed,
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
     #[error("end of ring")]
     EndOfRing,
     #[error("end of ring")]
     UnexpectedEof,
     #[error("Unknown event: {0}")]
     UnknownEvent(u32),
-    #[error("OS Error: {0}")]
-    OSError(String),
+    #[error("OS Error: {op}: {}", std::io::Error::from_raw_os_error(*errno))]
+    OSError { op: String, errno: i32 },
     #[error("Symbol Error: {0}")]
     SymbolError(String),
     #[error("ELF Error: {0}")]
     ELFError(String),
-    #[error("Proc Error: {0}")]
-    ProcError(String),
+    #[error("Proc Error: pid {pid}: {reason}")]
+    ProcError { pid: u32, reason: String },
     #[error("Session Error: {0}")]
     SessionError(String),
-    #[error("Map Error: {0}")]
-    MapError(String),
+    #[error("Map Error: {map}: {op}: {}", std::io::Error::from_raw_os_error(*errno))]
+    MapError { map: String, op: String, errno: i32 },
     #[error("Write Error: {0}")]
     WriteError(String),
     #[error("Syscall Error: {0}")]
@@ -36,4 +38,76 @@ pub enum Error {
     PerfBufferError(String),
 }
 
+impl Error {
+    // os_error wraps errno of a failed syscall or libc call, op names the call and its target
+    pub fn os_error(op: impl Into<String>, errno: i32) -> Self {
+        Error::OSError { op: op.into(), errno }
+    }
+
+    pub fn last_os_error(op: impl Into<String>) -> Self {
+        Self::from_io(op, &std::io::Error::last_os_error())
+    }
+
+    pub fn from_io(op: impl Into<String>, err: &std::io::Error) -> Self {
+        Error::os_error(op, err.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    pub fn invalid_data(reason: impl Into<String>) -> Self {
+        Error::InvalidData { path: None, reason: reason.into() }
+    }
+
+    pub fn invalid_file(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        Error::InvalidData { path: Some(path.into()), reason: reason.into() }
+    }
+
+    pub fn proc_error(pid: u32, reason: impl Into<String>) -> Self {
+        Error::ProcError { pid, reason: reason.into() }
+    }
+
+    // errno returns the errno carried by os and map errors
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::OSError { errno, .. } | Error::MapError { errno, .. } => Some(*errno),
+            _ => None,
+        }
+    }
+
+    // kind names the variant, it is used as a metric label instead of the message which carries paths and pids
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "not_found",
+            Error::InvalidData { .. } => "invalid_data",
+            Error::MustBePaused => "must_be_paused",
+            Error::Closed => "closed",
+            Error::DeadlineExceeded => "deadline_exceeded",
+            Error::EndOfRing => "end_of_ring",
+            Error::UnexpectedEof => "unexpected_eof",
+            Error::UnknownEvent(_) => "unknown_event",
+            Error::OSError { .. } => "os",
+            Error::SymbolError(_) => "symbol",
+            Error::ELFError(_) => "elf",
+            Error::ProcError { .. } => "proc",
+            Error::SessionError(_) => "session",
+            Error::MapError { .. } => "map",
+            Error::WriteError(_) => "write",
+            Error::SyscallError(_) => "syscall",
+            Error::PerfBufferError(_) => "perf_buffer",
+        }
+    }
+
+    // is_per_target tells errors that only affect a single process or binary, which callers
+    // should log and skip, from errors that break the whole session.
+    pub fn is_per_target(&self) -> bool {
+        match self {
+            Error::NotFound(_)
+            | Error::InvalidData { path: Some(_), .. }
+            | Error::SymbolError(_)
+            | Error::ELFError(_)
+            | Error::ProcError { .. } => true,
+            Error::OSError { errno, .. } => *errno == libc::ENOENT || *errno == libc::ESRCH,
+            _ => false,
+        }
+    }
+}
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
\ No newline at end of file
@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the server side of the agent's push api, the receiving end of the end-to-end tests
+    tonic_build::configure()
+        .build_server(true)
+        .build_client(false)
+        .bytes([".push.v1.RawSample.raw_profile", ".push.v1.PushChunk.data"])
+        .compile(&["../agent/proto/push/v1/push.proto"], &["../agent/proto/push/v1"])?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::{io, thread};
+
+use log::warn;
+
+use crate::store::Store;
+
+pub const RECEIVED_PATH: &str = "/api/v1/received";
+pub const READY_PATH: &str = "/ready";
+
+// serve answers the queries of the test harness on a thread of its own. It only speaks enough
+// http/1.0 for a GET per connection.
+pub fn serve(addr: SocketAddr, store: Arc<Store>) -> io::Result<()> {
+	let listener = TcpListener::bind(addr)?;
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			let result = stream.and_then(|stream| handle(stream, &store));
+			if let Err(e) = result {
+				warn!("http: {}", e);
+			}
+		}
+	});
+	Ok(())
+}
+
+fn handle(mut stream: TcpStream, store: &Store) -> io::Result<()> {
+	let mut request_line = String::new();
+	BufReader::new(&stream).read_line(&mut request_line)?;
+	let mut parts = request_line.split_whitespace();
+	let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+	let (status, body) = match (method, path) {
+		("GET", RECEIVED_PATH) => ("200 OK", serde_json::to_string(&store.received()).unwrap()),
+		("GET", READY_PATH) => ("200 OK", "ready".to_string()),
+		("GET", _) => ("404 Not Found", "not found".to_string()),
+		_ => ("405 Method Not Allowed", "method not allowed".to_string()),
+	};
+	write!(stream, "HTTP/1.0 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+	stream.flush()
+}
@@ -1,57 +1,57 @@
-use std::io;
-use std::net::TcpListener;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use log4rs;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::Config;
 use log::{info, LevelFilter};
-use polling::{Event, Events, Poller};
+use tonic::transport::Server;
 
-fn main() -> io::Result<()> {
+use crate::push::PushServer;
+use crate::push_api::pusher_service_server::PusherServiceServer;
+use crate::store::Store;
 
+mod http;
+mod push;
+mod store;
+
+pub mod push_api {
+	tonic::include_proto!("push.v1");
+}
+
+pub mod profile {
+	include!("../../iwm/src/gen/profile/profile.v1.rs");
+}
+
+const DEFAULT_GRPC_ADDRESS: &str = "127.0.0.1:4040";
+const DEFAULT_HTTP_ADDRESS: &str = "127.0.0.1:4041";
+
+// server receives the profiles pushed by the agent and serves what it received as json, it is the
+// backend of the end-to-end tests in tools/e2e.
+//
+//   server [grpc address] [http address]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	let stdout = ConsoleAppender::builder().build();
 	let config = Config::builder()
 		.appender(Appender::builder().build("stdout", Box::new(stdout)))
-		.build(Root::builder().appender("stdout").build(LevelFilter::Trace))
+		.build(Root::builder().appender("stdout").build(LevelFilter::Info))
 		.unwrap();
 	let _handle = log4rs::init_config(config).unwrap();
 
-	let l1 = TcpListener::bind("127.0.0.1:8001")?;
-	let l2 = TcpListener::bind("127.0.0.1:8002")?;
-	l1.set_nonblocking(true)?;
-	l2.set_nonblocking(true)?;
-
-	let poller = Poller::new()?;
-	unsafe {
-		poller.add(&l1, Event::readable(1))?;
-		poller.add(&l2, Event::readable(2))?;
-	}
-
-	println!("You can connect to the server using `nc`:");
-	println!(" $ nc 127.0.0.1 8001");
-	println!(" $ nc 127.0.0.1 8002");
-
-	let mut events = Events::new();
-	loop {
-		events.clear();
-		poller.wait(&mut events, None)?;
-		dbg!(&events.len());
-		for ev in events.iter() {
-			dbg!(&ev);
-			match ev.key {
-				1 => {
-					println!("Accept on l1");
-					l1.accept()?;
-					poller.modify(&l1, Event::readable(1))?;
-				}
-				2 => {
-					println!("Accept on l2");
-					l2.accept()?;
-					poller.modify(&l2, Event::readable(2))?;
-				}
-				_ => unreachable!(),
-			}
-		}
-	}
-}
\ No newline at end of file
+	let args: Vec<String> = env::args().collect();
+	let grpc_addr: SocketAddr = args.get(1).map(String::as_str).unwrap_or(DEFAULT_GRPC_ADDRESS).parse()?;
+	let http_addr: SocketAddr = args.get(2).map(String::as_str).unwrap_or(DEFAULT_HTTP_ADDRESS).parse()?;
+
+	let store = Arc::new(Store::default());
+	http::serve(http_addr, store.clone())?;
+	info!("serving received profiles on http://{}{}", http_addr, http::RECEIVED_PATH);
+
+	info!("receiving pushes on {}", grpc_addr);
+	Server::builder()
+		.add_service(PusherServiceServer::new(PushServer::new(store)))
+		.serve(grpc_addr)
+		.await?;
+	Ok(())
+}
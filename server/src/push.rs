@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use log::{debug, warn};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::push_api::pusher_service_server::PusherService;
+use crate::push_api::{LabelPair, PushChunk, PushRequest, PushResponse};
+use crate::store::Store;
+
+// PushServer receives the profiles the agent pushes and records them in the store
+pub struct PushServer {
+	store: Arc<Store>,
+}
+
+impl PushServer {
+	pub fn new(store: Arc<Store>) -> Self {
+		Self { store }
+	}
+
+	fn add(&self, labels: BTreeMap<String, String>, id: String, raw_profile: &[u8]) -> Result<(), Status> {
+		debug!("received profile {} of {} bytes, labels {:?}", id, raw_profile.len(), labels);
+		self.store.add(labels, id, raw_profile).map_err(|e| {
+			warn!("rejecting profile: {}", e);
+			Status::invalid_argument(e)
+		})
+	}
+}
+
+#[tonic::async_trait]
+impl PusherService for PushServer {
+	async fn push(&self, request: Request<PushRequest>) -> Result<Response<PushResponse>, Status> {
+		for series in request.into_inner().series {
+			let labels = to_map(&series.labels);
+			for sample in series.samples {
+				self.add(labels.clone(), sample.id, &sample.raw_profile)?;
+			}
+		}
+		Ok(Response::new(PushResponse {}))
+	}
+
+	// push_stream reassembles the chunks of every sample, the labels are only set on the first chunk
+	async fn push_stream(&self, request: Request<Streaming<PushChunk>>) -> Result<Response<PushResponse>, Status> {
+		let mut stream = request.into_inner();
+		let mut pending: HashMap<String, (BTreeMap<String, String>, Vec<u8>)> = HashMap::new();
+		while let Some(chunk) = stream.message().await? {
+			let (_, data) = pending.entry(chunk.id.clone())
+				.or_insert_with(|| (to_map(&chunk.labels), Vec::new()));
+			data.extend_from_slice(&chunk.data);
+			if chunk.last {
+				let (labels, data) = pending.remove(&chunk.id).unwrap();
+				self.add(labels, chunk.id, &data)?;
+			}
+		}
+		if !pending.is_empty() {
+			return Err(Status::invalid_argument(format!("stream ended with {} incomplete profiles", pending.len())));
+		}
+		Ok(Response::new(PushResponse {}))
+	}
+}
+
+fn to_map(labels: &[LabelPair]) -> BTreeMap<String, String> {
+	labels.iter().map(|l| (l.name.clone(), l.value.clone())).collect()
+}
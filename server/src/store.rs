@@ -0,0 +1,103 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Mutex;
+
+use prost::Message;
+use serde::Serialize;
+
+use crate::profile::Profile;
+
+// Received summarizes a pushed profile, enough to assert on its labels and symbol quality
+#[derive(Serialize, Clone, Debug)]
+pub struct Received {
+	pub id: String,
+	pub labels: BTreeMap<String, String>,
+	pub bytes: usize,
+	pub samples: usize,
+	// frames counts the frames of all samples, unknown_frames those without a resolved symbol
+	pub frames: u64,
+	pub unknown_frames: u64,
+	pub functions: BTreeSet<String>,
+}
+
+// Store keeps the summaries of every received profile in memory, the server only lives as long
+// as a test run
+#[derive(Default)]
+pub struct Store {
+	inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+	ids: HashSet<String>,
+	received: Vec<Received>,
+}
+
+impl Store {
+	// add decodes and records a profile. Profiles retried by the agent carry the same id and
+	// are only recorded once.
+	pub fn add(&self, labels: BTreeMap<String, String>, id: String, raw_profile: &[u8]) -> Result<(), String> {
+		if self.inner.lock().unwrap().ids.contains(&id) {
+			return Ok(());
+		}
+		let received = summarize(labels, id, raw_profile)?;
+		let mut inner = self.inner.lock().unwrap();
+		if inner.ids.insert(received.id.clone()) {
+			inner.received.push(received);
+		}
+		Ok(())
+	}
+
+	pub fn received(&self) -> Vec<Received> {
+		self.inner.lock().unwrap().received.clone()
+	}
+}
+
+fn summarize(labels: BTreeMap<String, String>, id: String, raw_profile: &[u8]) -> Result<Received, String> {
+	if raw_profile.starts_with(&[0x1f, 0x8b]) {
+		return Err(format!("profile {}: gzipped profiles are not supported", id));
+	}
+	let profile = Profile::decode(raw_profile).map_err(|e| format!("profile {}: {}", id, e))?;
+	let string = |i: i64| profile.string_table.get(i as usize).map(String::as_str).unwrap_or_default();
+	let function_names: BTreeMap<u64, &str> = profile.function.iter()
+		.map(|f| (f.id, string(f.name)))
+		.collect();
+	let location_names: BTreeMap<u64, Vec<&str>> = profile.location.iter()
+		.map(|l| (l.id, l.line.iter().filter_map(|line| function_names.get(&line.function_id).copied()).collect()))
+		.collect();
+
+	let mut frames = 0;
+	let mut unknown_frames = 0;
+	let mut functions = BTreeSet::new();
+	for sample in &profile.sample {
+		for id in &sample.location_id {
+			let names = location_names.get(id).map(Vec::as_slice).unwrap_or_default();
+			frames += 1;
+			if names.is_empty() || names.iter().any(|name| is_unknown(name)) {
+				unknown_frames += 1;
+			}
+			functions.extend(names.iter().map(|name| name.to_string()));
+		}
+	}
+	Ok(Received {
+		id,
+		labels,
+		bytes: raw_profile.len(),
+		samples: profile.sample.len(),
+		frames,
+		unknown_frames,
+		functions,
+	})
+}
+
+// is_unknown matches the names the agent gives frames it could not symbolize: [unknown], the
+// address, the module alone, or the module with the offset into it. Python frames are named
+// "<file> <function>" and never match.
+fn is_unknown(name: &str) -> bool {
+	let is_hex = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit());
+	name.is_empty()
+		|| name == "[unknown]"
+		|| name.contains("!0x")
+		|| is_hex(name)
+		|| name.rsplit_once('+').is_some_and(|(_, offset)| is_hex(offset))
+		|| (name.starts_with('/') && !name.contains(' '))
+}
@@ -0,0 +1,99 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::workload::Workload;
+
+const RECEIVED_PATH: &str = "/api/v1/received";
+const READY_PATH: &str = "/ready";
+const SERVICE_NAME: &str = "service_name";
+const METRIC_NAME: &str = "__name__";
+const PROFILE_TYPE: &str = "process_cpu";
+// reserved labels the agent is expected to push, every other __ label must be dropped
+const PUSHED_RESERVED_LABELS: [&str; 2] = [METRIC_NAME, "__delta__"];
+
+// Received is the summary of a pushed profile, as served by the server crate
+#[derive(Deserialize, Debug)]
+pub struct Received {
+    pub id: String,
+    pub labels: BTreeMap<String, String>,
+    pub samples: usize,
+    pub frames: u64,
+    pub unknown_frames: u64,
+    pub functions: BTreeSet<String>,
+}
+
+// ready tells if the server answers on its http address
+pub fn ready(addr: &str) -> bool {
+    get(addr, READY_PATH).is_ok()
+}
+
+// fetch returns the profiles the server received so far
+pub fn fetch(addr: &str) -> Result<Vec<Received>> {
+    let body = get(addr, RECEIVED_PATH)?;
+    serde_json::from_str(&body).with_context(|| format!("decoding {}{}", addr, RECEIVED_PATH))
+}
+
+fn get(addr: &str, path: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr).with_context(|| format!("connecting to {}", addr))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("malformed response from {}", addr))?;
+    if !head.starts_with("HTTP/1.0 200") && !head.starts_with("HTTP/1.1 200") {
+        bail!("{}{}: {}", addr, path, head.lines().next().unwrap_or_default());
+    }
+    Ok(body.to_string())
+}
+
+// check returns the expectations the profiles of the workload failed, none when they arrived with
+// the expected labels, functions and share of symbolized frames
+pub fn check(workload: &Workload, received: &[Received]) -> Vec<String> {
+    let service_name = workload.container_name();
+    // docker reports container names with a leading slash
+    let profiles: Vec<&Received> = received.iter()
+        .filter(|r| r.labels.get(SERVICE_NAME).map(|s| s.trim_start_matches('/')) == Some(service_name.as_str()))
+        .collect();
+    if profiles.is_empty() {
+        return Vec::from([format!("no profiles with {}={}", SERVICE_NAME, service_name)]);
+    }
+
+    let mut failures = Vec::new();
+    for p in &profiles {
+        if p.labels.get(METRIC_NAME).map(String::as_str) != Some(PROFILE_TYPE) {
+            failures.push(format!("profile {}: {}={:?}, expected {}", p.id, METRIC_NAME, p.labels.get(METRIC_NAME), PROFILE_TYPE));
+        }
+        for name in p.labels.keys().filter(|k| k.starts_with("__") && !PUSHED_RESERVED_LABELS.contains(&k.as_str())) {
+            failures.push(format!("profile {}: reserved label {} was pushed", p.id, name));
+        }
+    }
+
+    let functions: BTreeSet<&str> = profiles.iter().flat_map(|p| p.functions.iter().map(String::as_str)).collect();
+    for expected in workload.functions {
+        // native frames may be mangled or carry the module path, python frames the file name
+        if !functions.iter().any(|f| f.contains(expected)) {
+            failures.push(format!("function {} not in any profile", expected));
+        }
+    }
+
+    let frames: u64 = profiles.iter().map(|p| p.frames).sum();
+    let unknown: u64 = profiles.iter().map(|p| p.unknown_frames).sum();
+    let samples: usize = profiles.iter().map(|p| p.samples).sum();
+    if frames == 0 {
+        failures.push(format!("{} profiles with {} samples have no frames", profiles.len(), samples));
+    } else {
+        let symbolized = 1.0 - unknown as f64 / frames as f64;
+        if symbolized < workload.min_symbolized {
+            failures.push(format!("{:.1}% of {} frames symbolized, expected at least {:.1}%",
+                symbolized * 100.0, frames, workload.min_symbolized * 100.0));
+        }
+    }
+    failures
+}
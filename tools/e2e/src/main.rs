@@ -0,0 +1,170 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+
+use crate::workload::Workload;
+
+mod check;
+mod workload;
+
+// ENDPOINT_URL_ENV points the agent at the server, see agent/src/main.rs
+const ENDPOINT_URL_ENV: &str = "IWM_ENDPOINT_URL";
+
+// e2e runs the agent against the push server of the server crate and a container per language
+// runtime, then checks that the profiles of every container arrive at the server with the expected
+// labels, functions and share of symbolized frames. The agent loads bpf programs, so it needs root,
+// and the workloads run in docker.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[arg(long, default_value = "target/debug/agent")]
+    agent: PathBuf,
+    #[arg(long, default_value = "target/debug/server")]
+    server: PathBuf,
+    /// loadgen binary run by the native workload
+    #[arg(long, default_value = "target/debug/loadgen")]
+    loadgen: PathBuf,
+    #[arg(long, default_value = "127.0.0.1:14040")]
+    grpc_address: String,
+    #[arg(long, default_value = "127.0.0.1:14041")]
+    http_address: String,
+    /// only run the workloads of these runtimes, all of them when empty
+    #[arg(long)]
+    runtime: Vec<String>,
+    /// collection interval of the agent, the checks give up after rounds intervals
+    #[arg(long, default_value_t = 15)]
+    collect_interval_secs: u64,
+    #[arg(long, default_value_t = 4)]
+    rounds: u64,
+    /// the output of the agent, kept for looking into failures
+    #[arg(long, default_value = "e2e-agent.log")]
+    agent_log: PathBuf,
+}
+
+// Cleanup stops the processes and removes the containers of the run, also when it fails
+#[derive(Default)]
+struct Cleanup {
+    children: Vec<Child>,
+    containers: Vec<String>,
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if !self.containers.is_empty() {
+            let _ = Command::new("docker").args(["rm", "-f"]).args(&self.containers)
+                .stdout(Stdio::null()).stderr(Stdio::null()).status();
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    for path in [&cli.agent, &cli.server, &cli.loadgen] {
+        if !path.is_file() {
+            bail!("{} not found, build the workspace first", path.display());
+        }
+    }
+    let loadgen = cli.loadgen.canonicalize()?;
+    let workloads: Vec<Workload> = workload::workloads(&loadgen).into_iter()
+        .filter(|w| cli.runtime.is_empty() || cli.runtime.iter().any(|r| r == w.runtime))
+        .collect();
+    if workloads.is_empty() {
+        bail!("no workloads of runtimes {:?}", cli.runtime);
+    }
+
+    let mut cleanup = Cleanup::default();
+    cleanup.children.push(Command::new(&cli.server).args([&cli.grpc_address, &cli.http_address])
+        .spawn().with_context(|| format!("starting {}", cli.server.display()))?);
+    wait_ready(&cli.http_address)?;
+
+    // the agent discovers the containers when it starts, they have to run before it
+    for w in &workloads {
+        cleanup.containers.push(w.container_name());
+        start_container(w)?;
+        println!("started {} workload {}", w.runtime, w.container_name());
+    }
+    let config = write_agent_config(&cli)?;
+    cleanup.children.push(start_agent(&cli.agent, &config, &cli.grpc_address, &cli.agent_log)?);
+    println!("started agent, logging to {}", cli.agent_log.display());
+
+    let deadline = Instant::now() + Duration::from_secs(cli.collect_interval_secs * cli.rounds);
+    let failures = loop {
+        thread::sleep(Duration::from_secs(5));
+        let received = check::fetch(&cli.http_address)?;
+        let failures: Vec<(&str, Vec<String>)> = workloads.iter()
+            .map(|w| (w.runtime, check::check(w, &received)))
+            .filter(|(_, failures)| !failures.is_empty())
+            .collect();
+        if failures.is_empty() || Instant::now() >= deadline {
+            break failures;
+        }
+    };
+
+    if !failures.is_empty() {
+        for (runtime, failures) in &failures {
+            for failure in failures {
+                eprintln!("{}: {}", runtime, failure);
+            }
+        }
+        bail!("{} of {} workloads failed, see {}", failures.len(), workloads.len(), cli.agent_log.display());
+    }
+    println!("profiles of all {} workloads arrived as expected", workloads.len());
+    Ok(())
+}
+
+fn wait_ready(http_address: &str) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !check::ready(http_address) {
+        if Instant::now() >= deadline {
+            bail!("server on {} did not become ready", http_address);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+fn start_container(w: &Workload) -> Result<()> {
+    // a container left over from an interrupted run would hold the name
+    let _ = Command::new("docker").args(["rm", "-f", &w.container_name()])
+        .stdout(Stdio::null()).stderr(Stdio::null()).status();
+    let mut cmd = Command::new("docker");
+    cmd.args(["run", "-d", "--name", &w.container_name()]);
+    for volume in &w.volumes {
+        cmd.args(["-v", volume]);
+    }
+    let out = cmd.arg(w.image).args(&w.command).stderr(Stdio::inherit()).output()
+        .context("running docker")?;
+    if !out.status.success() {
+        bail!("starting {} workload: docker run exited with {}", w.runtime, out.status);
+    }
+    Ok(())
+}
+
+// write_agent_config writes the configuration of the agent next to its log, the checks wait for the
+// rounds of the collect interval the agent is configured with
+fn write_agent_config(cli: &Cli) -> Result<PathBuf> {
+    let path = cli.agent_log.with_extension("yaml");
+    let config = format!("ebpf:\n  collect_interval: {}s\n", cli.collect_interval_secs);
+    fs::write(&path, config).with_context(|| format!("writing {}", path.display()))?;
+    Ok(path)
+}
+
+fn start_agent(agent: &Path, config: &Path, grpc_address: &str, log: &Path) -> Result<Child> {
+    let log = File::create(log).with_context(|| format!("creating {}", log.display()))?;
+    Command::new(agent)
+        .arg("--config")
+        .arg(config)
+        .env(ENDPOINT_URL_ENV, format!("http://{}", grpc_address))
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .with_context(|| format!("starting {}", agent.display()))
+}
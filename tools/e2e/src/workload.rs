@@ -0,0 +1,59 @@
+use std::path::Path;
+
+// Workload is a container running a program of one language runtime with known function names
+pub struct Workload {
+    pub runtime: &'static str,
+    pub image: &'static str,
+    pub command: Vec<String>,
+    // volumes are docker -v specs
+    pub volumes: Vec<String>,
+    // functions must all show up in the profiles of the workload
+    pub functions: &'static [&'static str],
+    // min_symbolized is the minimum share of frames with a resolved symbol, interpreters have
+    // native frames of stripped libraries below the interpreted ones
+    pub min_symbolized: f64,
+}
+
+impl Workload {
+    // container_name is also the service_name the agent infers from the docker discovery
+    pub fn container_name(&self) -> String {
+        format!("iwm-e2e-{}", self.runtime)
+    }
+}
+
+const PYTHON_PROGRAM: &str = r#"
+def e2e_python_leaf(n):
+    s = 0
+    for i in range(n):
+        s += i * i
+    return s
+
+def e2e_python_work():
+    while True:
+        e2e_python_leaf(100000)
+
+e2e_python_work()
+"#;
+
+// workloads are the runtimes the agent profiles. The native workload is the loadgen worker,
+// mounted into the container, so it must be built for the glibc of the image or statically.
+pub fn workloads(loadgen: &Path) -> Vec<Workload> {
+    Vec::from([
+        Workload {
+            runtime: "native",
+            image: "debian:bookworm-slim",
+            command: ["/loadgen", "worker", "--depth", "8", "--fanout", "4"].map(String::from).to_vec(),
+            volumes: Vec::from([format!("{}:/loadgen:ro", loadgen.display())]),
+            functions: &["frame_a", "frame_b", "leaf"],
+            min_symbolized: 0.9,
+        },
+        Workload {
+            runtime: "python",
+            image: "python:3.12-slim",
+            command: Vec::from(["python3".to_string(), "-c".to_string(), PYTHON_PROGRAM.to_string()]),
+            volumes: Vec::new(),
+            functions: &["e2e_python_work", "e2e_python_leaf"],
+            min_symbolized: 0.5,
+        },
+    ])
+}
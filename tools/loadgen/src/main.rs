@@ -0,0 +1,133 @@
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+
+use crate::verify::Scrape;
+
+mod verify;
+mod workload;
+
+// loadgen runs synthetic processes with known stack shapes for soak testing the agent.
+// With --verify it scrapes the agent's metrics before and after the run and fails when
+// the agent collected too few samples or symbolized too few frames.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// spawn and churn the workers
+    Run(RunArgs),
+    /// a single synthetic process, spawned by run
+    Worker(Shape),
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// number of worker processes alive at any time
+    #[arg(long, default_value_t = 8)]
+    processes: usize,
+    #[command(flatten)]
+    shape: Shape,
+    /// replace the oldest worker with a new process every churn_ms, 0 keeps the same workers
+    #[arg(long, default_value_t = 0)]
+    churn_ms: u64,
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+    /// agent http address to verify against, e.g. http://127.0.0.1:12345
+    #[arg(long)]
+    verify: Option<String>,
+    /// minimum number of samples the agent must collect during the run
+    #[arg(long, default_value_t = 1)]
+    min_samples: u64,
+    /// minimum share of resolved symbols, between 0 and 1
+    #[arg(long, default_value_t = 0.9)]
+    min_symbolized: f64,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct Shape {
+    /// depth of the synthetic stacks
+    #[arg(long, default_value_t = 16)]
+    depth: usize,
+    /// number of distinct stacks per worker
+    #[arg(long, default_value_t = 4)]
+    fanout: usize,
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Cmd::Worker(shape) => workload::run(&shape),
+        Cmd::Run(args) => run(&args),
+    }
+}
+
+fn run(args: &RunArgs) -> Result<()> {
+    let before = match &args.verify {
+        Some(addr) => Some(Scrape::fetch(addr)?),
+        None => None,
+    };
+
+    let mut workers = Vec::with_capacity(args.processes);
+    for _ in 0..args.processes {
+        workers.push(spawn_worker(&args.shape)?);
+    }
+    let started = Instant::now();
+    let duration = Duration::from_secs(args.duration_secs);
+    let mut spawned = workers.len();
+    while started.elapsed() < duration {
+        if args.churn_ms == 0 {
+            thread::sleep(duration - started.elapsed());
+            break;
+        }
+        thread::sleep(Duration::from_millis(args.churn_ms).min(duration - started.elapsed()));
+        if !workers.is_empty() {
+            stop_worker(workers.remove(0));
+        }
+        workers.push(spawn_worker(&args.shape)?);
+        spawned += 1;
+    }
+    for worker in workers {
+        stop_worker(worker);
+    }
+    println!("ran {} workers over {:?}", spawned, started.elapsed());
+
+    let (Some(addr), Some(before)) = (&args.verify, before) else {
+        return Ok(());
+    };
+    // give the agent a collection round to pick up the last samples
+    thread::sleep(Duration::from_secs(15));
+    let after = Scrape::fetch(addr)?;
+    let samples = after.samples() - before.samples();
+    let known = after.known_symbols() - before.known_symbols();
+    let unknown = after.unknown_symbols() - before.unknown_symbols();
+    let symbolized = if known + unknown > 0.0 { known / (known + unknown) } else { 0.0 };
+    println!("samples: {} symbolized: {:.3} ({} known, {} unknown)", samples, symbolized, known, unknown);
+    if samples < args.min_samples as f64 {
+        bail!("collected {} samples, expected at least {}", samples, args.min_samples);
+    }
+    if symbolized < args.min_symbolized {
+        bail!("symbolized {:.3} of the symbols, expected at least {:.3}", symbolized, args.min_symbolized);
+    }
+    Ok(())
+}
+
+fn spawn_worker(shape: &Shape) -> Result<Child> {
+    let exe = std::env::current_exe().context("locating the loadgen binary")?;
+    Command::new(exe)
+        .arg("worker")
+        .arg(format!("--depth={}", shape.depth))
+        .arg(format!("--fanout={}", shape.fanout))
+        .spawn()
+        .context("spawning a worker")
+}
+
+fn stop_worker(mut worker: Child) {
+    let _ = worker.kill();
+    let _ = worker.wait();
+}
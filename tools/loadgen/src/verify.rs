@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+const SAMPLES: &str = "iwm_ebpf_samples_collected_total";
+const KNOWN_SYMBOLS: &str = "iwm_symtab_known_symbols_total";
+const UNKNOWN_SYMBOLS: &str = "iwm_symtab_unknown_symbols_total";
+
+// Scrape holds the agent's counters summed over their labels
+pub struct Scrape {
+    totals: HashMap<String, f64>,
+}
+
+impl Scrape {
+    // fetch reads the agent's /metrics, addr is the http address of the agent
+    pub fn fetch(addr: &str) -> Result<Self> {
+        let host = addr.trim_start_matches("http://").trim_end_matches('/');
+        let mut stream = TcpStream::connect(host).with_context(|| format!("connecting to {}", host))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write!(stream, "GET /metrics HTTP/1.0\r\nHost: {}\r\n\r\n", host)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let (head, body) = response.split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("malformed response from {}", host))?;
+        if !head.starts_with("HTTP/1.1 200") && !head.starts_with("HTTP/1.0 200") {
+            bail!("{}/metrics: {}", host, head.lines().next().unwrap_or_default());
+        }
+        Ok(Self::parse(body))
+    }
+
+    fn parse(body: &str) -> Self {
+        let mut totals = HashMap::new();
+        for line in body.lines().filter(|l| !l.starts_with('#')) {
+            let Some((series, value)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            let name = series.split('{').next().unwrap_or_default();
+            if let Ok(value) = value.parse::<f64>() {
+                *totals.entry(name.to_string()).or_insert(0.0) += value;
+            }
+        }
+        Self { totals }
+    }
+
+    fn get(&self, name: &str) -> f64 {
+        self.totals.get(name).copied().unwrap_or(0.0)
+    }
+
+    pub fn samples(&self) -> f64 {
+        self.get(SAMPLES)
+    }
+
+    pub fn known_symbols(&self) -> f64 {
+        self.get(KNOWN_SYMBOLS)
+    }
+
+    pub fn unknown_symbols(&self) -> f64 {
+        self.get(UNKNOWN_SYMBOLS)
+    }
+}
@@ -0,0 +1,61 @@
+use std::hint::black_box;
+
+use anyhow::Result;
+
+use crate::Shape;
+
+// run burns cpu forever in stacks of shape.depth frames. Every iteration takes one of
+// shape.fanout paths, the path decides which of the frame functions is called at each level,
+// so every path is a distinct stack the agent has to symbolize.
+pub fn run(shape: &Shape) -> Result<()> {
+    let fanout = shape.fanout.max(1);
+    let mut i = 0usize;
+    loop {
+        let path = i % fanout;
+        black_box(frame_a(path, shape.depth));
+        i = i.wrapping_add(1);
+    }
+}
+
+#[inline(never)]
+fn next(path: usize, depth: usize) -> u64 {
+    if depth == 0 {
+        return leaf(path);
+    }
+    // two bits of the path per level pick the next frame
+    match (path >> ((depth % 16) * 2)) & 3 {
+        0 => frame_a(path, depth - 1),
+        1 => frame_b(path, depth - 1),
+        2 => frame_c(path, depth - 1),
+        _ => frame_d(path, depth - 1),
+    }
+}
+
+#[inline(never)]
+fn frame_a(path: usize, depth: usize) -> u64 {
+    black_box(next(path, depth)) + 1
+}
+
+#[inline(never)]
+fn frame_b(path: usize, depth: usize) -> u64 {
+    black_box(next(path, depth)) + 2
+}
+
+#[inline(never)]
+fn frame_c(path: usize, depth: usize) -> u64 {
+    black_box(next(path, depth)) + 3
+}
+
+#[inline(never)]
+fn frame_d(path: usize, depth: usize) -> u64 {
+    black_box(next(path, depth)) + 4
+}
+
+#[inline(never)]
+fn leaf(path: usize) -> u64 {
+    let mut x = path as u64;
+    for i in 0..100_000u64 {
+        x = black_box(x.wrapping_mul(6364136223846793005).wrapping_add(i));
+    }
+    x
+}